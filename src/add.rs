@@ -1,8 +1,32 @@
 // add.rs
+use crate::api::{add_api, Method};
+use crate::assets::{add_asset, add_htmx_extension, HtmxExtension, ALPINEJS, HTMX, HYPERSCRIPT};
+use crate::auth::{add_auth, Sessions};
+use crate::content::add_content;
+use crate::csrf::{add_csrf, write_template};
+use crate::database::{add_database, select_orm, Orm};
+use crate::engine::{
+    add_template_engine, generate_maud_page, inject_into_layout, page_view, select_template_engine,
+    TemplateEngine,
+};
+use crate::error_pages::add_error_pages;
+use crate::field::Field;
+use crate::form::add_form;
+use crate::health::add_health;
+use crate::html::{Placement, Snippet, Target};
+use crate::metrics::add_metrics;
+use crate::middleware::{add_middleware, Middleware};
+use crate::model::add_model;
+use crate::oauth::Provider;
+use crate::openapi::{add_docs, DocsUi};
+use crate::rate_limit::{add_rate_limit, LimitKey};
+use crate::resource::add_resource;
+use crate::spec::add_api_from_spec;
+use crate::tracing::add_tracing;
 use crate::{execute::Execute, kraken::MagentaTheme};
 use clap::Subcommand;
-use cliclack::log::{error, info};
-use cliclack::{confirm, input, intro, outro, set_theme, spinner};
+use cliclack::log::error;
+use cliclack::{input, intro, outro, select, set_theme};
 use console::style;
 use proc_macro2::{Ident, Span};
 use quote::quote;
@@ -12,16 +36,175 @@ use std::io::{self, prelude::*, SeekFrom};
 use std::process::{Command, Stdio};
 use toml_edit::Document;
 
+/// The layout every generated page extends.
+pub const BASE_HTML: &str = "templates/base.html";
+
 #[derive(Subcommand)]
 pub enum Add {
     /// Add askama templating engine for html
     Askama,
+    /// Add a template engine: askama, minijinja, tera or maud
+    Templates { engine: Option<TemplateEngine> },
     /// Add everyone's favorite - tailwindcss
     Tailwindcss,
     /// The real solution : HTMX
-    Htmx,
-    /// Create a new askama page
-    Page,
+    Htmx {
+        /// Where the script goes: head, body or block:<name>
+        #[arg(long, default_value = "head")]
+        into: Placement,
+        /// Serve the script from the app instead of the cdn
+        #[arg(long)]
+        vendor: bool,
+    },
+    /// An htmx extension: sse, ws, response-targets, preload or json-enc
+    HtmxExt {
+        extension: HtmxExtension,
+        /// Where the script goes: head, body or block:<name> (defaults to
+        /// right after htmx)
+        #[arg(long)]
+        into: Option<Placement>,
+        /// Serve the script from the app instead of the cdn
+        #[arg(long)]
+        vendor: bool,
+    },
+    /// Alpine.js for client side state
+    Alpinejs {
+        /// Where the script goes: head, body or block:<name>
+        #[arg(long, default_value = "head")]
+        into: Placement,
+        /// Serve the script from the app instead of the cdn
+        #[arg(long)]
+        vendor: bool,
+    },
+    /// _hyperscript for inline event handling
+    Hyperscript {
+        /// Where the script goes: head, body or block:<name>
+        #[arg(long, default_value = "head")]
+        into: Placement,
+        /// Serve the script from the app instead of the cdn
+        #[arg(long)]
+        vendor: bool,
+    },
+    /// Create a new page
+    Page {
+        /// Asked for when left out
+        name: Option<String>,
+        /// Only logged in users see it, needs `krk add auth`
+        #[arg(long)]
+        auth: bool,
+    },
+    /// Serve markdown files from content/<collection> with an atom feed
+    Content {
+        /// e.g. blog or docs
+        collection: String,
+    },
+    /// Shuttle shared postgres with migrations and an AppState
+    Database {
+        /// sqlx, sea-orm or diesel
+        #[arg(long)]
+        orm: Option<Orm>,
+        /// Use this postgres for `shuttle run` instead of a docker container
+        #[arg(long)]
+        local_uri: Option<String>,
+    },
+    /// A table with its migration, struct and queries, e.g.
+    /// `krk add model Post title:String body:Text published:bool`
+    Model {
+        name: String,
+        /// name:Type with Type one of String, Text, bool, i32, i64, f64, Email
+        #[arg(required = true)]
+        fields: Vec<Field>,
+    },
+    /// A model with list, show, new and edit pages, e.g.
+    /// `krk add resource post title:String body:Text`
+    Resource {
+        name: String,
+        /// name:Type like `krk add model`, leave out to reuse an existing model
+        fields: Vec<Field>,
+    },
+    /// JSON handlers under `/api`, e.g. `krk add api users --methods get,post`
+    /// or `krk add api --from openapi.yaml`
+    Api {
+        #[arg(required_unless_present = "from")]
+        name: Option<String>,
+        /// name:Type of the payloads, defaults to `name:String`
+        fields: Vec<Field>,
+        /// get, post, put, patch or delete, comma separated
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "get,post,put,patch,delete"
+        )]
+        methods: Vec<Method>,
+        /// An OpenAPI 3 document, yaml or json, to generate every operation of.
+        /// Re-run it after the spec changes, handler bodies are kept
+        #[arg(long, conflicts_with_all = ["name", "fields"])]
+        from: Option<String>,
+    },
+    /// Offline api reference at `/docs`, with the spec at `/api/openapi.json`
+    Docs {
+        /// swagger or scalar
+        #[arg(long, default_value = "swagger")]
+        ui: DocsUi,
+    },
+    /// Users with argon2 password hashes, sessions and /login, /register and
+    /// /logout pages
+    Auth {
+        /// database or memory
+        #[arg(long, default_value = "database")]
+        sessions: Sessions,
+        /// Log in through github, google or any oidc provider as well, run
+        /// again to add another
+        #[arg(long)]
+        provider: Option<Provider>,
+    },
+    /// A page with a form validated on the server, e.g.
+    /// `krk add form contact name:String email:Email message:Text`
+    Form {
+        name: String,
+        /// name:Type like `krk add model`
+        #[arg(required = true)]
+        fields: Vec<Field>,
+    },
+    /// A token checked on every post, put, patch and delete, added to
+    /// generated forms and sent by htmx
+    Csrf,
+    /// 404 and 500 pages, a router fallback and an AppError for handlers
+    ErrorPages,
+    /// tower-http layers around the whole router, e.g.
+    /// `krk add middleware compression security-headers`
+    Middleware {
+        #[arg(required = true)]
+        kinds: Vec<Middleware>,
+        /// Seconds a request may take with `timeout`, 30 by default
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// An origin `cors` lets call the app, repeat for more
+        #[arg(long = "origin")]
+        origins: Vec<String>,
+    },
+    /// /healthz, /readyz and /version
+    Health,
+    /// Structured logs with a span per request
+    Tracing,
+    /// /metrics for Prometheus, with request counts and latencies per route
+    Metrics,
+    /// `krk add rate-limit auth --per-minute 10`
+    RateLimit {
+        /// The router main.rs nests or merges, e.g. auth
+        group: String,
+        #[arg(long, default_value_t = 60)]
+        per_minute: u32,
+        /// Requests allowed in a row before the rate kicks in
+        #[arg(long, default_value_t = 10)]
+        burst: u32,
+        #[arg(long, value_enum, default_value_t = LimitKey::Ip)]
+        key: LimitKey,
+        /// The address of a proxy whose X-Forwarded-For is believed, * for any
+        /// peer. Replaces the recorded ones
+        #[arg(long = "trusted-proxy")]
+        trusted_proxies: Vec<String>,
+    },
 }
 
 impl Execute for Add {
@@ -40,16 +223,123 @@ impl Execute for Add {
                 add_askama()?;
                 Ok(())
             }
+            Self::Templates { engine } => {
+                let engine = match engine {
+                    Some(engine) => *engine,
+                    None => select_template_engine()?,
+                };
+                add_template_engine(engine)?;
+                Ok(())
+            }
             Self::Tailwindcss => {
                 add_tailwindcss()?;
                 Ok(())
             }
-            Self::Htmx => {
-                add_htmx()?;
+            Self::Htmx { into, vendor } => {
+                add_asset(&HTMX, into, *vendor)?;
+                Ok(())
+            }
+            Self::HtmxExt {
+                extension,
+                into,
+                vendor,
+            } => {
+                add_htmx_extension(*extension, into.as_ref(), *vendor)?;
+                Ok(())
+            }
+            Self::Alpinejs { into, vendor } => {
+                add_asset(&ALPINEJS, into, *vendor)?;
+                Ok(())
+            }
+            Self::Hyperscript { into, vendor } => {
+                add_asset(&HYPERSCRIPT, into, *vendor)?;
+                Ok(())
+            }
+            Self::Page { name, auth } => {
+                add_page(name.as_deref(), *auth)?;
+                Ok(())
+            }
+            Self::Content { collection } => {
+                add_content(collection)?;
+                Ok(())
+            }
+            Self::Database { orm, local_uri } => {
+                let orm = match orm {
+                    Some(orm) => *orm,
+                    None => select_orm()?,
+                };
+                add_database(orm, local_uri.as_deref())?;
+                Ok(())
+            }
+            Self::Model { name, fields } => {
+                add_model(name, fields)?;
+                Ok(())
+            }
+            Self::Resource { name, fields } => {
+                add_resource(name, fields)?;
+                Ok(())
+            }
+            Self::Api {
+                name,
+                fields,
+                methods,
+                from,
+            } => {
+                match (from, name) {
+                    (Some(spec), _) => add_api_from_spec(spec)?,
+                    (None, Some(name)) => add_api(name, fields, methods)?,
+                    (None, None) => error("Give the api a name or a --from spec.")?,
+                }
+                Ok(())
+            }
+            Self::Docs { ui } => {
+                add_docs(*ui)?;
+                Ok(())
+            }
+            Self::Auth { sessions, provider } => {
+                add_auth(*sessions, *provider)?;
+                Ok(())
+            }
+            Self::Form { name, fields } => {
+                add_form(name, fields)?;
+                Ok(())
+            }
+            Self::Csrf => {
+                add_csrf()?;
+                Ok(())
+            }
+            Self::ErrorPages => {
+                add_error_pages()?;
                 Ok(())
             }
-            Self::Page => {
-                add_page()?;
+            Self::Middleware {
+                kinds,
+                timeout,
+                origins,
+            } => {
+                add_middleware(kinds, *timeout, origins)?;
+                Ok(())
+            }
+            Self::Health => {
+                add_health()?;
+                Ok(())
+            }
+            Self::Tracing => {
+                add_tracing()?;
+                Ok(())
+            }
+            Self::Metrics => {
+                add_metrics()?;
+                Ok(())
+            }
+            Self::RateLimit {
+                group,
+                per_minute,
+                burst,
+                key,
+                trusted_proxies,
+            } => {
+                add_rate_limit(group, *per_minute, *burst, *key, trusted_proxies)?;
                 Ok(())
             }
         }
@@ -57,106 +347,168 @@ impl Execute for Add {
 }
 
 pub fn add_askama() -> std::io::Result<()> {
-    match check_feature("askama") {
-        Ok(()) => {
-            let mut spinner = spinner();
-            spinner.start("Adding crates!");
-            add_dependencies();
-            spinner.stop("Crates have arrived!");
-            match create_html_base_file() {
-                Ok(()) => info("Added base.html or layout!")?,
-                Err(_err) => error("Error Adding base.html!")?,
-            }
-            add_feature("askama").unwrap();
-            info("Askama added successfully. 🎉")?;
-            if confirm("Do you want to create a page?")
-                .initial_value(true)
-                .interact()?
-            {
-                add_page()?;
-            }
-        }
-        Err(err) => {
-            error(err)?;
-            info("Failed to add askama!!!")?;
-        }
-    };
-    Ok(())
+    add_template_engine(TemplateEngine::Askama)
 }
 
-pub fn add_tailwindcss() -> std::io::Result<()> {
-    generate_tailwindcss_mod_rs()?;
-    add_module_to_mod_rs("tailwindcss")?;
-    add_module_to_main_rs("tailwindcss")?;
-    call_module_fn_in_main_rs("tailwindcss", Some("styles/tailwind.css"))?;
-
-    if !std::path::Path::new("./tailwind.config.js").exists() {
-        if Command::new("tailwindcss").arg("init").status().is_err() {
-            if Command::new("npx")
-                .args(["tailwindcss", "init"])
-                .status()
-                .is_err()
-            {
-                return Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
-                    "tailwindcss command faied!",
-                ));
-            }
+/// The tailwind major versions kraken knows how to configure.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TailwindVersion {
+    /// `tailwind.config.js` plus `@tailwind` directives.
+    V3,
+    /// CSS-first configuration with `@import "tailwindcss"` and `@source`.
+    V4,
+}
+
+impl TailwindVersion {
+    pub fn major(self) -> i64 {
+        match self {
+            Self::V3 => 3,
+            Self::V4 => 4,
         }
     }
 
-    edit_tailwind_config()?;
+    fn from_major(major: i64) -> Option<Self> {
+        match major {
+            3 => Some(Self::V3),
+            4 => Some(Self::V4),
+            _ => None,
+        }
+    }
 
-    if !std::path::Path::new("./styles/styles.js").exists() {
-        match create_tailwind_base_styles() {
-            Ok(()) => println!("created styles/styles.css."),
-            Err(err) => eprintln!("Error creating styles/styles.css: {}", err),
+    /// The npx package that ships the cli for this version.
+    fn npx_package(self) -> &'static str {
+        match self {
+            Self::V3 => "tailwindcss@3",
+            Self::V4 => "@tailwindcss/cli@4",
         }
     }
+}
 
-    // tailwindcss -i styles/styles.css -o styles/tailwind.css
-    if Command::new("tailwindcss")
-        .args(["-i", "styles/styles.css", "-o", "styles/tailwind.css"])
-        .status()
-        .is_err()
+/// Globs (relative to the project root) tailwind scans for class names.
+pub fn tailwind_sources() -> Vec<&'static str> {
+    match TemplateEngine::current() {
+        TemplateEngine::Maud => vec!["src/**/*.rs"],
+        _ => vec!["templates/**/*.html"],
+    }
+}
+
+/// Looks for the tailwind version in Kraken.toml, package.json and finally the
+/// installed cli.
+pub fn detect_tailwind_version() -> Option<TailwindVersion> {
+    if let Some(version) = get_config("tailwindcss", "version")
+        .and_then(|item| item.as_integer())
+        .and_then(TailwindVersion::from_major)
     {
-        if Command::new("npx")
-            .args([
-                "tailwindcss",
-                "-i",
-                "styles/styles.css",
-                "-o",
-                "styles/tailwind.css",
-            ])
-            .status()
-            .is_err()
+        return Some(version);
+    }
+
+    let version_regex =
+        Regex::new(r#""(?:tailwindcss|@tailwindcss/cli)"\s*:\s*"[^0-9]*(\d+)"#).unwrap();
+    if let Ok(package_json) = read_to_string("package.json") {
+        if let Some(major) = version_regex
+            .captures(&package_json)
+            .and_then(|captures| captures[1].parse().ok())
         {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "tailwindcss command faied!",
-            ));
+            return TailwindVersion::from_major(major);
         }
     }
 
-    if std::path::Path::new("./templates/base.html").exists() {
-        // edit base.html and add some link tag
-        match add_tag_to_head(
-            r#"
-<!-- tailwind deez css -->
-<link rel="stylesheet" href="/styles/tailwind.css" />
-"#,
-            "/styles/tailwind.css",
-        ) {
-            Ok(()) => println!("base.html edited successfully."),
-            Err(err) => eprintln!("Error editing base.html: {}", err),
-        };
-        println!("Tailwindcss added!");
+    installed_tailwind_version()
+}
+
+/// The major version of the `tailwindcss` binary on the PATH, if any.
+fn installed_tailwind_version() -> Option<TailwindVersion> {
+    let output = Command::new("tailwindcss").arg("--help").output().ok()?;
+    let help = String::from_utf8_lossy(&output.stdout).to_string()
+        + &String::from_utf8_lossy(&output.stderr);
+    Regex::new(r"tailwindcss v(\d+)")
+        .unwrap()
+        .captures(&help)
+        .and_then(|captures| captures[1].parse().ok())
+        .and_then(TailwindVersion::from_major)
+}
+
+/// Runs the tailwind cli for `version`: the installed binary when it is that
+/// major version, npx otherwise.
+fn run_tailwind(version: TailwindVersion, args: &[&str]) -> std::io::Result<()> {
+    let mut command = if installed_tailwind_version() == Some(version) {
+        Command::new("tailwindcss")
     } else {
-        println!("No base.html!");
+        let mut npx = Command::new("npx");
+        npx.arg(version.npx_package());
+        npx
+    };
+    match command.args(args).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(io::Error::other(format!(
+            "tailwindcss command failed with {status}!"
+        ))),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tailwindcss command faied!",
+        )),
     }
+}
+
+pub fn select_tailwind_version() -> std::io::Result<TailwindVersion> {
+    let detected = detect_tailwind_version();
+    let major: i64 = select("Pick a tailwindcss version")
+        .item(4, "v4", "CSS-first, no tailwind.config.js")
+        .item(3, "v3", "tailwind.config.js")
+        .initial_value(detected.unwrap_or(TailwindVersion::V4).major())
+        .interact()?;
+    Ok(TailwindVersion::from_major(major).unwrap_or(TailwindVersion::V4))
+}
+
+pub fn add_tailwindcss() -> std::io::Result<()> {
+    let version = select_tailwind_version()?;
+
+    // Re-running the command only switches versions, the route is already there.
+    if !std::path::Path::new("src/kraken/tailwindcss.rs").exists() {
+        generate_tailwindcss_mod_rs()?;
+        add_module_to_mod_rs("tailwindcss")?;
+        add_module_to_main_rs("tailwindcss")?;
+        call_module_fn_in_main_rs("tailwindcss", Some("styles/tailwind.css"))?;
+    }
+
+    if version == TailwindVersion::V3 {
+        if !std::path::Path::new("./tailwind.config.js").exists() {
+            run_tailwind(version, &["init"])?;
+        }
+
+        edit_tailwind_config()?;
+    }
+
+    match create_tailwind_base_styles(version) {
+        Ok(()) => println!("created styles/styles.css."),
+        Err(err) => eprintln!("Error creating styles/styles.css: {}", err),
+    }
+
+    // tailwindcss -i styles/styles.css -o styles/tailwind.css
+    build_tailwind_css(version)?;
+
+    set_config("tailwindcss", "version", version.major())?;
+    add_feature("tailwindcss")?;
+
+    inject_into_layout(
+        &Snippet {
+            asset: "tailwindcss",
+            identities: &["/styles/tailwind.css"],
+            html: r#"<link rel="stylesheet" href="/styles/tailwind.css" />"#,
+        },
+        Target::Head,
+    )?;
+    println!("Tailwindcss added!");
     Ok(())
 }
 
+pub fn build_tailwind_css(version: TailwindVersion) -> std::io::Result<()> {
+    run_tailwind(
+        version,
+        &["-i", "styles/styles.css", "-o", "styles/tailwind.css"],
+    )
+}
+
 fn edit_tailwind_config() -> std::io::Result<()> {
     let file_path = "./tailwind.config.js";
     if !std::path::Path::new(&file_path).exists() {
@@ -166,24 +518,31 @@ fn edit_tailwind_config() -> std::io::Result<()> {
         ));
     }
 
-    let file_content: Vec<String> = fs::read_to_string(&file_path)?
+    let content = tailwind_sources()
+        .iter()
+        .map(|source| format!("\"./{source}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let file_content: Vec<String> = fs::read_to_string(file_path)?
         .lines()
         .map(|line| {
             if line.contains("content: ") {
-                r#"  content: ["./templates/**/*.html"],"#.to_string()
+                format!("  content: [{content}],")
             } else {
                 line.to_string()
             }
         })
         .collect();
 
-    fs::write(&file_path, file_content.join("\n"))
+    fs::write(file_path, file_content.join("\n"))
 }
 
-pub fn add_dependencies() {
+/// Runs `cargo add` with the given arguments, quietly.
+pub fn cargo_add(args: &[&str]) {
     // Create a new Command
     let mut cmd = Command::new("cargo")
-        .args(["add", "askama", "-F", "askama/with-axum", "askama_axum"])
+        .arg("add")
+        .args(args)
         .stdout(Stdio::null()) // Redirect stdout to null sink
         .stderr(Stdio::null()) // Redirect stderr to null sink
         .spawn()
@@ -196,31 +555,47 @@ pub fn add_dependencies() {
     }
 }
 
-pub fn add_htmx() -> std::io::Result<()> {
-    // check_if_base.html_exists
-    if std::path::Path::new("./templates/base.html").exists() {
-        // edit base.html and add some link tag
-        match add_tag_to_head(
-            r#"
-        <!-- cdn deez nuts for htmx -->
-        <script src="https://unpkg.com/htmx.org@1.9.10"></script>
-    "#,
-            "https://unpkg.com/htmx.org@",
-        ) {
-            Ok(()) => println!("base.html edited successfully."),
-            Err(err) => eprintln!("Error editing base.html: {}", err),
-        };
-        println!("Htmx added!");
-    } else {
-        println!("No base.html!");
+/// Prints generated code. `quote!` turns doc comments into `#[doc = "..."]`
+/// attributes, prettyplease prints them as comments again.
+pub fn unparse(code: impl ToString) -> String {
+    let code = code.to_string();
+    match syn::parse_file(&code) {
+        Ok(syntax_tree) => prettyplease::unparse(&syntax_tree),
+        // rustfmt reports what does not parse
+        Err(_) => code,
     }
+}
+
+/// Writes generated code and runs rustfmt on it.
+pub fn write_rust_file(file_path: &str, code: impl ToString) -> std::io::Result<()> {
+    let mut file = File::create(file_path)?;
+
+    // Write the generated code to the file
+    file.write_all(code.to_string().as_bytes())?;
+
+    // Run rustfmt on the prettify file
+    if Command::new("rustfmt")
+        .arg(file_path)
+        .arg("--edition")
+        .arg("2021")
+        .status()
+        .is_err()
+    {
+        error("Failed to run rustfmt")?;
+
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Failed to run rustfmt",
+        ));
+    }
+
     Ok(())
 }
 
 pub fn create_html_base_file() -> Result<(), std::io::Error> {
     // Create the templates directory if it doesn't exist
-    let templates_path = format!("templates");
-    create_dir_all(&templates_path)?;
+    let templates_path = "templates";
+    create_dir_all(templates_path)?;
 
     // Create base.html
     let base_html_path = format!("{templates_path}/base.html");
@@ -245,52 +620,88 @@ pub fn create_html_base_file() -> Result<(), std::io::Error> {
     Ok(())
 }
 
-pub fn create_tailwind_base_styles() -> Result<(), std::io::Error> {
+pub fn create_tailwind_base_styles(version: TailwindVersion) -> Result<(), std::io::Error> {
     // Create the styles directory if it doesn't exist
-    let styles_path = format!("styles");
-    create_dir_all(&styles_path)?;
+    let styles_path = "styles";
+    create_dir_all(styles_path)?;
 
-    // Create base.html
     let base_styles_path = format!("{styles_path}/styles.css");
-    let mut base_styles_file = File::create(&base_styles_path)?;
-    base_styles_file.write_all(
-        r#"@tailwind base;
-@tailwind components;
-@tailwind utilities;
-"#
-        .as_bytes(),
-    )?;
+    let existing = read_to_string(&base_styles_path).unwrap_or_default();
 
-    Ok(())
+    // Keep whatever custom css follows the tailwind entry points
+    let custom_css: Vec<&str> = existing
+        .lines()
+        .filter(|line| {
+            let line = line.trim();
+            !(line.starts_with("@tailwind ")
+                || line.starts_with("@import \"tailwindcss\"")
+                || line.starts_with("@source ")
+                || line.starts_with("@config "))
+        })
+        .collect();
+    let mut custom_css = custom_css.join("\n").trim().to_string();
+    if version == TailwindVersion::V3 {
+        // v4 theme blocks are not valid v3 css
+        custom_css = without_theme_blocks(&custom_css).trim().to_string();
+    }
+
+    let mut styles = match version {
+        TailwindVersion::V3 => {
+            "@tailwind base;\n@tailwind components;\n@tailwind utilities;\n".to_string()
+        }
+        TailwindVersion::V4 => {
+            let mut styles = "@import \"tailwindcss\";\n".to_string();
+            for source in tailwind_sources() {
+                styles += &format!("@source \"../{source}\";\n");
+            }
+            // A v3 config can still carry theme extensions and plugins
+            if std::path::Path::new("./tailwind.config.js").exists() {
+                styles += "@config \"../tailwind.config.js\";\n";
+            }
+            if !custom_css.contains("@theme") {
+                styles += "\n@theme {\n}\n";
+            }
+            styles
+        }
+    };
+    if !custom_css.is_empty() {
+        styles += &format!("\n{custom_css}\n");
+    }
+
+    fs::write(base_styles_path, styles)
 }
 
-pub fn add_tag_to_head(tag: &str, identity: &str) -> Result<(), std::io::Error> {
-    // Read existing content of base.html
-    let mut base_html_content = read_to_string("./templates/base.html")?;
-    if base_html_content.find(identity).is_none() {
-        // Find the index of the closing </head> tag
-        if let Some(head_close_index) = base_html_content.find("</head>") {
-            // Add link tags just before the </head> tag
-            base_html_content.insert_str(head_close_index, tag);
-
-            // Open base.html in write mode and truncate its content
-            let mut base_html_file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open("./templates/base.html")?;
-
-            // Write the modified content back to base.html
-            base_html_file.write_all(base_html_content.as_bytes())?;
-
-            Ok(())
-        } else {
-            eprintln!("Failed to find </head> tag in base.html");
-            Ok(()) // or Err(...) depending on your error handling strategy
+/// `css` without its `@theme { … }` blocks, braces nested inside them
+/// included. An unclosed block is left as it is.
+fn without_theme_blocks(css: &str) -> String {
+    let mut kept = String::new();
+    let mut rest = css;
+    while let Some(start) = rest.find("@theme") {
+        let after = &rest[start + "@theme".len()..];
+        if after.starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_') {
+            kept += &rest[..start + "@theme".len()];
+            rest = after;
+            continue;
         }
-    } else {
-        eprintln!("Htmx script already exists!");
-        Ok(()) // or Err(...) depending on your error handling strategy
+        let Some(open) = after.find('{') else {
+            break;
+        };
+        let mut depth = 0;
+        let close = after[open..].find(|c| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        });
+        let Some(close) = close else {
+            break;
+        };
+        kept += &rest[..start];
+        rest = &after[open + close + 1..];
     }
+    kept + rest
 }
 
 pub fn kraken_toml_exists() -> bool {
@@ -307,31 +718,103 @@ pub fn add_feature(key: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-pub fn check_feature(key: &str) -> Result<(), std::io::Error> {
+/// Drops a feature and its recorded asset from Kraken.toml.
+pub fn remove_feature(key: &str) -> Result<(), std::io::Error> {
+    if kraken_toml_exists() {
+        let toml_content = fs::read_to_string("src/kraken/Kraken.toml")?;
+        let mut doc = toml_content.parse::<Document>().expect("invalid doc");
+        for section in ["features", "assets"] {
+            if let Some(table) = doc
+                .get_mut(section)
+                .and_then(|item| item.as_table_like_mut())
+            {
+                table.remove(key);
+            }
+        }
+        fs::write("src/kraken/Kraken.toml", doc.to_string())?;
+    }
+    Ok(())
+}
+
+/// Sets `key` inside the `[section]` table of Kraken.toml.
+pub fn set_config(
+    section: &str,
+    key: &str,
+    value: impl Into<toml_edit::Value>,
+) -> Result<(), std::io::Error> {
+    if kraken_toml_exists() {
+        let toml_content = fs::read_to_string("src/kraken/Kraken.toml")?;
+        let mut doc = toml_content.parse::<Document>().expect("invalid doc");
+        if doc.get(section).is_none() {
+            doc[section] = toml_edit::table();
+        }
+        doc[section][key] = toml_edit::value(value);
+        fs::write("src/kraken/Kraken.toml", doc.to_string())?;
+    }
+    Ok(())
+}
+
+/// Drops `key` from the `[section]` table of Kraken.toml.
+pub fn remove_config(section: &str, key: &str) -> Result<(), std::io::Error> {
     if kraken_toml_exists() {
-        if fs::read_to_string("src/kraken/Kraken.toml")?
+        let toml_content = fs::read_to_string("src/kraken/Kraken.toml")?;
+        let mut doc = toml_content.parse::<Document>().expect("invalid doc");
+        if let Some(table) = doc
+            .get_mut(section)
+            .and_then(|item| item.as_table_like_mut())
+        {
+            table.remove(key);
+        }
+        fs::write("src/kraken/Kraken.toml", doc.to_string())?;
+    }
+    Ok(())
+}
+
+/// Reads the whole `[section]` table of Kraken.toml.
+pub fn get_section(section: &str) -> Option<toml_edit::Item> {
+    fs::read_to_string("src/kraken/Kraken.toml")
+        .ok()?
+        .parse::<Document>()
+        .ok()?
+        .get(section)
+        .cloned()
+}
+
+/// Reads `key` from the `[section]` table of Kraken.toml.
+pub fn get_config(section: &str, key: &str) -> Option<toml_edit::Item> {
+    fs::read_to_string("src/kraken/Kraken.toml")
+        .ok()?
+        .parse::<Document>()
+        .ok()?
+        .get(section)?
+        .get(key)
+        .cloned()
+}
+
+pub fn check_feature(key: &str) -> Result<(), std::io::Error> {
+    if kraken_toml_exists()
+        && fs::read_to_string("src/kraken/Kraken.toml")?
             .parse::<Document>()
             .expect("invalid doc")["features"]
             .get(key)
             .is_some()
-        {
-            error("Features already exists!")?;
-            return Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                "Features already exists!",
-            ));
-        }
+    {
+        error("Features already exists!")?;
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Features already exists!",
+        ));
     }
     Ok(())
 }
 
 pub fn generate_page_template(page_name: &str, ai_generated_htmx: &str) -> std::io::Result<()> {
     // Create the templates directory if it doesn't exist
-    create_dir_all(&"templates")?;
-    // Create page.html
-    let mut base_html_file = File::create(format!("templates/{page_name}.html"))?;
-    base_html_file.write_all(
-        format!(
+    create_dir_all("templates")?;
+    // Create page.html, post forms get the csrf token
+    write_template(
+        format!("templates/{page_name}.html"),
+        &format!(
             r#"
 <!-- prettier-ignore -->
 {{% extends "base.html" %}}
@@ -339,26 +822,31 @@ pub fn generate_page_template(page_name: &str, ai_generated_htmx: &str) -> std::
 {{% block content %}}
 {ai_generated_htmx}
 {{% endblock %}}"#
-        )
-        .as_bytes(),
-    )?;
-
-    Ok(())
+        ),
+    )
 }
 
-pub fn generate_page_mod_rs(page_name: &str, page_title: &str) -> std::io::Result<()> {
+pub fn generate_page_mod_rs(page_name: &str, page_title: &str, auth: bool) -> std::io::Result<()> {
+    let engine = TemplateEngine::current();
+    if engine == TemplateEngine::Maud {
+        return generate_maud_page(page_name, page_title, auth);
+    }
+
     let page_file = format!("{page_name}.html");
+    let view = page_view(engine, &page_file, quote! { title: &'a str, });
+    let (import, arg) = if auth {
+        (
+            quote! { use crate::kraken::auth::RequireAuth; },
+            quote! { RequireAuth(_user): RequireAuth },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
     let code = quote! {
-        use askama::Template;
-        use askama_axum::IntoResponse;
+        #import
+        #view
 
-        #[derive(Template)]
-        #[template(path = #page_file)]
-        struct TheTemplate<'a> {
-            title: &'a str,
-        }
-
-        pub async fn main() -> impl IntoResponse {
+        pub async fn main(#arg) -> impl IntoResponse {
             TheTemplate {
                 title: #page_title,
             }
@@ -366,30 +854,7 @@ pub fn generate_page_mod_rs(page_name: &str, page_title: &str) -> std::io::Resul
     };
 
     // Change the path to your desired location for the mod.rs file
-    let file_path = format!("src/kraken/{page_name}.rs");
-
-    let mut file = File::create(file_path)?;
-
-    // Write the generated code to the file
-    file.write_all(code.to_string().as_bytes())?;
-
-    // Run rustfmt on the prettify file
-    if Command::new("rustfmt")
-        .arg(format!("src/kraken/{page_name}.rs"))
-        .arg("--edition")
-        .arg("2021")
-        .status()
-        .is_err()
-    {
-        error("Failed to run rustfmt")?;
-
-        return Err(io::Error::new(
-            io::ErrorKind::Unsupported,
-            "Failed to run rustfmt",
-        ));
-    }
-
-    Ok(())
+    write_rust_file(&format!("src/kraken/{page_name}.rs"), code)
 }
 
 pub fn add_kraken_to_main_rs() -> std::io::Result<()> {
@@ -397,6 +862,7 @@ pub fn add_kraken_to_main_rs() -> std::io::Result<()> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open("src/main.rs")?;
 
     // Read the existing content
@@ -404,7 +870,7 @@ pub fn add_kraken_to_main_rs() -> std::io::Result<()> {
     file.read_to_string(&mut content)?;
 
     // Check if the module already exists
-    if !content.contains(&format!("mod kraken;")) {
+    if !content.contains("mod kraken;") {
         // Append the new module
         let new_module_code = quote! {
             mod kraken;
@@ -430,6 +896,7 @@ pub fn add_module_to_mod_rs(module_name: &str) -> std::io::Result<()> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open("src/kraken/mod.rs")?;
 
     // Read the existing content
@@ -472,6 +939,7 @@ pub fn call_module_fn_in_main_rs(module_name: &str, src: Option<&str>) -> std::i
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open("src/main.rs")?;
 
     // Read the existing content
@@ -505,11 +973,43 @@ async fn hello_world() -> &'static str {
     Ok(())
 }
 
+/// Mounts the `router()` of a kraken module under `path`.
+pub fn nest_module_router_in_main_rs(module_name: &str, path: &str) -> std::io::Result<()> {
+    let mut content = read_to_string("src/main.rs")?;
+
+    let nest = format!(".nest(\"{path}\", {module_name}::router())");
+    if content.contains(&nest) {
+        return Ok(());
+    }
+
+    let old = "Router::new()";
+    let new = format!("{old}\n{nest}");
+    content = content.replacen(old, &new, 1);
+
+    fs::write("src/main.rs", content)
+}
+
+/// Merges the `router()` of a kraken module, whose routes carry full paths.
+pub fn merge_module_router_in_main_rs(module_name: &str) -> std::io::Result<()> {
+    let mut content = read_to_string("src/main.rs")?;
+
+    let merge = format!(".merge({module_name}::router())");
+    if content.contains(&merge) {
+        return Ok(());
+    }
+
+    let old = "Router::new()";
+    let new = format!("{old}\n{merge}");
+    content = content.replacen(old, &new, 1);
+
+    fs::write("src/main.rs", content)
+}
+
 fn get_route(module_name: &str) -> &str {
     if module_name == "index" {
         return "";
     }
-    return module_name;
+    module_name
 }
 
 pub fn add_module_to_main_rs(module_name: &str) -> std::io::Result<()> {
@@ -519,6 +1019,7 @@ pub fn add_module_to_main_rs(module_name: &str) -> std::io::Result<()> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open("src/main.rs")?;
 
     // Read the existing content
@@ -563,23 +1064,36 @@ pub fn capitalize(s: &str) -> String {
     }
 }
 
-pub fn add_page() -> std::io::Result<()> {
-    let page_name: String = input("Page name").default_input("index").interact()?;
-    let page_title: String = input("Page title")
-        .default_input(&capitalize(&page_name))
-        .interact()?;
+/// A page at `/<name>`, asking for what was left out. With `auth` only
+/// logged in users see it.
+pub fn add_page(name: Option<&str>, auth: bool) -> std::io::Result<()> {
+    if auth && get_config("features", "auth").is_none() {
+        error("`--auth` needs `krk add auth` first.")?;
+        return Ok(());
+    }
+    let (page_name, page_title) = match name {
+        Some(name) => (name.to_string(), capitalize(name)),
+        None => {
+            let page_name: String = input("Page name").default_input("index").interact()?;
+            let page_title: String = input("Page title")
+                .default_input(&capitalize(&page_name))
+                .interact()?;
+            (page_name, page_title)
+        }
+    };
 
-    if generate_page_template(
-        &page_name,
-        r#"<section class="bg-indigo-400 text-white font-black text-6xl">Hello</section>"#,
-    )
-    .is_err()
+    if TemplateEngine::current().uses_template_files()
+        && generate_page_template(
+            &page_name,
+            r#"<section class="bg-indigo-400 text-white font-black text-6xl">Hello</section>"#,
+        )
+        .is_err()
     {
         outro("An error occured!")?;
         return Ok(());
     }
 
-    if generate_page_mod_rs(&page_name, &page_title).is_err() {
+    if generate_page_mod_rs(&page_name, &page_title, auth).is_err() {
         outro("An error occured!")?;
         return Ok(());
     }
@@ -605,8 +1119,8 @@ pub fn add_page() -> std::io::Result<()> {
 
 pub fn generate_tailwindcss_mod_rs() -> std::io::Result<()> {
     let code = quote! {
-        use askama_axum::{IntoResponse, Response};
         use axum::http::StatusCode;
+        use axum::response::{IntoResponse, Response};
 
         pub async fn main() -> impl IntoResponse {
             Response::builder()
@@ -617,18 +1131,19 @@ pub fn generate_tailwindcss_mod_rs() -> std::io::Result<()> {
         }
     };
 
-    let mut file = File::create("src/kraken/tailwindcss.rs")?;
-
-    // Write the generated code to the file
-    file.write_all(code.to_string().as_bytes())?;
-
-    // Run rustfmt on the prettify file
-    Command::new("rustfmt")
-        .arg("src/kraken/tailwindcss.rs")
-        .arg("--edition")
-        .arg("2021")
-        .status()
-        .expect("Failed to run rustfmt");
+    write_rust_file("src/kraken/tailwindcss.rs", code)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_blocks_are_removed_whole() {
+        let css = "@theme {\n  --color-brand: #123456;\n  @keyframes spin {\n    to { rotate: 360deg; }\n  }\n}\n.brand { color: red; }\n@theme inline {\n}\n";
+        assert_eq!(without_theme_blocks(css), "\n.brand { color: red; }\n\n");
+        // Other at-rules and an unclosed block stay
+        assert_eq!(without_theme_blocks("@themes {}"), "@themes {}");
+        assert_eq!(without_theme_blocks("a {}\n@theme {"), "a {}\n@theme {");
+    }
 }
@@ -0,0 +1,644 @@
+// api.rs
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add, get_section,
+    nest_module_router_in_main_rs, set_config, unparse, write_rust_file,
+};
+use crate::field::{pascal_case, singular, snake_case, Field, FieldType};
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::ValueEnum;
+use cliclack::log::error;
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs::create_dir_all;
+use std::io;
+use std::path::Path;
+
+/// The http methods `krk add api --methods` generates handlers for.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Method {
+    /// `GET /` lists, `GET /:id` shows one
+    Get,
+    /// `POST /` creates
+    Post,
+    /// `PUT /:id` replaces
+    Put,
+    /// `PATCH /:id` changes some fields
+    Patch,
+    /// `DELETE /:id`
+    Delete,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::Post => "post",
+            Self::Put => "put",
+            Self::Patch => "patch",
+            Self::Delete => "delete",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|method| method.name() == name)
+    }
+}
+
+/// The names one `krk add api users` turns into.
+pub struct Api {
+    /// `users`, the module in src/kraken/api and the path below `/api`.
+    pub module: String,
+    /// `User`, the response struct.
+    pub name: String,
+    pub fields: Vec<Field>,
+    pub methods: Vec<Method>,
+}
+
+impl Api {
+    pub fn new(name: &str, fields: &[Field], methods: &[Method]) -> Result<Self, String> {
+        let module = snake_case(name);
+        if syn::parse_str::<Ident>(&module).is_err() || module.starts_with('_') {
+            return Err(format!(
+                "\"{name}\" has to be a rust identifier, e.g. users"
+            ));
+        }
+        let fields = if fields.is_empty() {
+            vec![Field {
+                name: "name".to_string(),
+                ty: FieldType::String,
+            }]
+        } else {
+            fields.to_vec()
+        };
+        let mut unique = Vec::new();
+        for method in methods {
+            if !unique.contains(method) {
+                unique.push(*method);
+            }
+        }
+        Ok(Self {
+            name: pascal_case(&singular(&module)),
+            module,
+            fields,
+            methods: unique,
+        })
+    }
+
+    pub fn path(&self) -> String {
+        format!("src/kraken/api/{}.rs", self.module)
+    }
+
+    fn has(&self, method: Method) -> bool {
+        self.methods.contains(&method)
+    }
+
+    fn ident(&self) -> Ident {
+        Ident::new(&self.name, Span::call_site())
+    }
+
+    /// The request body of post and put, e.g. `NewUser`.
+    fn new_ident(&self) -> Ident {
+        Ident::new(&format!("New{}", self.name), Span::call_site())
+    }
+
+    /// The request body of patch, every field optional, e.g. `UserPatch`.
+    fn patch_ident(&self) -> Ident {
+        Ident::new(&format!("{}Patch", self.name), Span::call_site())
+    }
+}
+
+/// `krk add api users name:String --methods get,post`: json handlers under
+/// `/api/users` with serde payloads and a test per handler.
+pub fn add_api(name: &str, fields: &[Field], methods: &[Method]) -> io::Result<()> {
+    let api = match Api::new(name, fields, methods) {
+        Ok(api) => api,
+        Err(err) => return error(err),
+    };
+    if Path::new(&api.path()).exists() {
+        error(format!("Api {} already exists.", api.module))?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(&api) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add /api/{} ({err}), every change was rolled back.",
+            api.module
+        ))?;
+        return Ok(());
+    }
+
+    let methods: Vec<&str> = api.methods.iter().map(|method| method.name()).collect();
+    outro(format!(
+        "Successfully created: /api/{} with {}. 🎉",
+        api.module,
+        methods.join(", ")
+    ))
+}
+
+fn scaffold(api: &Api) -> io::Result<()> {
+    cargo_add(&["serde", "-F", "derive"]);
+    cargo_add(&["serde_json"]);
+    cargo_add(&["utoipa@5", "-F", "yaml"]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    create_dir_all("src/kraken/api")?;
+    generate_api_rs(api)?;
+    record_api(api)?;
+    generate_api_mod_rs()?;
+
+    // The first api mounts the `/api` router
+    if recorded_apis().len() == 1 {
+        add_module_to_mod_rs("api")?;
+        add_module_to_main_rs("api")?;
+        add_kraken_to_main_rs()?;
+        nest_module_router_in_main_rs("api", "/api")?;
+    }
+    Ok(())
+}
+
+/// Keeps `[api] users = { methods = [...], fields = [...] }` in Kraken.toml.
+fn record_api(api: &Api) -> io::Result<()> {
+    let methods: toml_edit::Array = api.methods.iter().map(|method| method.name()).collect();
+    let fields: toml_edit::Array = api.fields.iter().map(ToString::to_string).collect();
+    let mut record = toml_edit::InlineTable::new();
+    record.insert("methods", methods.into());
+    record.insert("fields", fields.into());
+    set_config("api", &api.module, record)
+}
+
+/// The modules recorded under `[api]`, in the order they were added.
+pub fn recorded_apis() -> Vec<String> {
+    get_section("api")
+        .and_then(|section| {
+            section
+                .as_table_like()
+                .map(|table| table.iter().map(|(key, _)| key.to_string()).collect())
+        })
+        .unwrap_or_default()
+}
+
+/// src/kraken/api/mod.rs nests every recorded api, so it is rewritten whole.
+pub fn generate_api_mod_rs() -> io::Result<()> {
+    let modules: Vec<Ident> = recorded_apis()
+        .iter()
+        .map(|module| Ident::new(module, Span::call_site()))
+        .collect();
+    let paths: Vec<String> = recorded_apis()
+        .iter()
+        .map(|module| format!("/{module}"))
+        .collect();
+    // The types of `krk add api --from`, shared by its modules
+    let schemas = if Path::new("src/kraken/api/schemas.rs").exists() {
+        quote! { pub mod schemas; }
+    } else {
+        quote! {}
+    };
+
+    let code = quote! {
+        //! JSON endpoints, nested under `/api`.
+        use axum::routing::get;
+        use axum::{Json, Router};
+        use utoipa::OpenApi;
+
+        #schemas
+        #(pub mod #modules;)*
+
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            Router::new()
+                .route("/openapi.json", get(spec))
+                #(.nest(#paths, #modules::router()))*
+        }
+
+        async fn spec() -> Json<utoipa::openapi::OpenApi> {
+            Json(openapi())
+        }
+
+        #[derive(OpenApi)]
+        struct ApiDoc;
+
+        /// Every api's routes and payloads, served at `/api/openapi.json` and
+        /// written to a file by `krk openapi`.
+        pub fn openapi() -> utoipa::openapi::OpenApi {
+            let mut doc = ApiDoc::openapi();
+            #(doc.merge(#modules::ApiDoc::openapi());)*
+            doc
+        }
+
+        #[cfg(test)]
+        mod tests {
+            /// `krk openapi` runs this, `KRK_OPENAPI` is the file to write.
+            #[test]
+            #[ignore = "run by krk openapi"]
+            fn write_openapi() {
+                let path = std::env::var("KRK_OPENAPI").unwrap_or("openapi.json".to_string());
+                let doc = super::openapi();
+                let yaml = std::env::var("KRK_OPENAPI_FORMAT").is_ok_and(|format| format == "yaml");
+                let spec = if yaml {
+                    doc.to_yaml().unwrap()
+                } else {
+                    doc.to_pretty_json().unwrap()
+                };
+                std::fs::write(path, spec).unwrap();
+            }
+        }
+    };
+
+    write_rust_file("src/kraken/api/mod.rs", tracing::instrument(unparse(code)))
+}
+
+/// A json value the generated tests send for `field`.
+fn example(field: &Field) -> TokenStream {
+    match field.ty {
+        FieldType::String | FieldType::Text => {
+            let text = format!("Example {}", field.name.replace('_', " "));
+            quote! { #text }
+        }
+        FieldType::Email => quote! { "user@example.com" },
+        FieldType::Bool => quote! { true },
+        FieldType::I32 | FieldType::I64 => quote! { 42 },
+        FieldType::F64 => quote! { 1.5 },
+    }
+}
+
+/// The body the generated tests post, e.g. `{"name":"Example name"}`.
+fn example_json(fields: &[Field]) -> String {
+    let pairs: Vec<String> = fields
+        .iter()
+        .map(|field| format!("\"{}\":{}", field.name, example(field)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// `#[utoipa::path(...)]` laid out by hand, rustfmt leaves attributes it
+/// cannot parse as they are.
+fn openapi_path(method: Method, path: &str, tag: &str, handler: &str, args: &[String]) -> String {
+    // Unique across apis, clients name their functions after it
+    let operation_id = format!("{tag}_{handler}");
+    let mut lines = vec![
+        method.name().to_string(),
+        format!("path = {path:?}"),
+        format!("operation_id = {operation_id:?}"),
+        format!("tag = {tag:?}"),
+    ];
+    lines.extend(args.iter().cloned());
+    format!("\n#[utoipa::path(\n    {}\n)]\n", lines.join(",\n    "))
+}
+
+/// `responses(...)` with one `(status = ...)` per line.
+pub fn responses(responses: &[&str]) -> String {
+    format!(
+        "responses(\n        {}\n    )",
+        responses.join(",\n        ")
+    )
+}
+
+fn generate_api_rs(api: &Api) -> io::Result<()> {
+    let row = api.ident();
+    let new = api.new_ident();
+    let patch = api.patch_ident();
+    let names: Vec<Ident> = api.fields.iter().map(Field::ident).collect();
+    let types: Vec<TokenStream> = api.fields.iter().map(|field| field.ty.rust()).collect();
+    let keys: Vec<&str> = api.fields.iter().map(|field| field.name.as_str()).collect();
+    let examples: Vec<TokenStream> = api.fields.iter().map(example).collect();
+
+    let tag = api.module.as_str();
+    let base = format!("/api/{}", api.module);
+    let by_id_path = format!("{base}/{{id}}");
+    let noun = singular(&api.module).replace('_', " ");
+    let id_param = format!("params((\"id\" = i64, Path, description = \"The {noun} id\"))");
+    let ok = format!(
+        "(status = 200, description = \"The {noun}\", body = {})",
+        api.name
+    );
+    let not_found = format!("(status = 404, description = \"No {noun} with this id\")");
+    let invalid = "(status = 422, description = \"A field is missing or has the wrong type\")";
+
+    let mut root = Vec::new();
+    let mut by_id = Vec::new();
+    let mut paths = Vec::new();
+    let mut handlers = String::new();
+    let mut tests = Vec::new();
+
+    if api.has(Method::Get) {
+        root.push(quote! { get(list) });
+        by_id.push(quote! { get(show) });
+        paths.extend([quote! { list }, quote! { show }]);
+        let every = format!(
+            "(status = 200, description = \"Every {noun}\", body = [{}])",
+            api.name
+        );
+        handlers += &openapi_path(Method::Get, &base, tag, "list", &[responses(&[&every])]);
+        handlers += &quote! {
+            pub async fn list() -> Json<Vec<#row>> {
+                Json(Vec::new())
+            }
+        }
+        .to_string();
+        handlers += &openapi_path(
+            Method::Get,
+            &by_id_path,
+            tag,
+            "show",
+            &[id_param.clone(), responses(&[&ok, &not_found])],
+        );
+        handlers += &quote! {
+            pub async fn show(Path(id): Path<i64>) -> Result<Json<#row>, StatusCode> {
+                Ok(Json(#row {
+                    id,
+                    ..Default::default()
+                }))
+            }
+        }
+        .to_string();
+        tests.push(quote! {
+            #[tokio::test]
+            async fn list() {
+                let (status, json) = send(Request::get("/").body(Body::empty()).unwrap()).await;
+                assert_eq!(status, StatusCode::OK);
+                assert!(json.is_array());
+            }
+
+            #[tokio::test]
+            async fn show() {
+                let (status, json) = send(Request::get("/7").body(Body::empty()).unwrap()).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(json["id"], 7);
+            }
+        });
+    }
+    if api.has(Method::Post) {
+        root.push(quote! { post(create) });
+        paths.push(quote! { create });
+        let created = format!(
+            "(status = 201, description = \"The created {noun}\", body = {})",
+            api.name
+        );
+        handlers += &openapi_path(
+            Method::Post,
+            &base,
+            tag,
+            "create",
+            &[
+                format!("request_body = {new}"),
+                responses(&[&created, invalid]),
+            ],
+        );
+        handlers += &quote! {
+            pub async fn create(Json(payload): Json<#new>) -> (StatusCode, Json<#row>) {
+                let row = #row {
+                    id: 1,
+                    #(#names: payload.#names,)*
+                };
+                (StatusCode::CREATED, Json(row))
+            }
+        }
+        .to_string();
+        tests.push(quote! {
+            #[tokio::test]
+            async fn create() {
+                let (status, json) = send(json_request("POST", "/", EXAMPLE)).await;
+                assert_eq!(status, StatusCode::CREATED);
+                assert_eq!(json["id"], 1);
+                #(assert_eq!(json[#keys], #examples);)*
+            }
+
+            #[tokio::test]
+            async fn create_rejects_missing_fields() {
+                let (status, _) = send(json_request("POST", "/", "{}")).await;
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            }
+        });
+    }
+    if api.has(Method::Put) {
+        by_id.push(quote! { put(update) });
+        paths.push(quote! { update });
+        handlers += &openapi_path(
+            Method::Put,
+            &by_id_path,
+            tag,
+            "update",
+            &[
+                id_param.clone(),
+                format!("request_body = {new}"),
+                responses(&[&ok, &not_found, invalid]),
+            ],
+        );
+        handlers += &quote! {
+            pub async fn update(Path(id): Path<i64>, Json(payload): Json<#new>) -> Json<#row> {
+                Json(#row {
+                    id,
+                    #(#names: payload.#names,)*
+                })
+            }
+        }
+        .to_string();
+        tests.push(quote! {
+            #[tokio::test]
+            async fn update() {
+                let (status, json) = send(json_request("PUT", "/7", EXAMPLE)).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(json["id"], 7);
+            }
+        });
+    }
+    if api.has(Method::Patch) {
+        by_id.push(quote! { patch(modify) });
+        paths.push(quote! { modify });
+        handlers += &openapi_path(
+            Method::Patch,
+            &by_id_path,
+            tag,
+            "modify",
+            &[
+                id_param.clone(),
+                format!("request_body = {patch}"),
+                responses(&[&ok, &not_found, invalid]),
+            ],
+        );
+        handlers += &quote! {
+            pub async fn modify(Path(id): Path<i64>, Json(payload): Json<#patch>) -> Json<#row> {
+                Json(#row {
+                    id,
+                    #(#names: payload.#names.unwrap_or_default(),)*
+                })
+            }
+        }
+        .to_string();
+        tests.push(quote! {
+            #[tokio::test]
+            async fn modify() {
+                let (status, json) = send(json_request("PATCH", "/7", "{}")).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(json["id"], 7);
+            }
+        });
+    }
+    if api.has(Method::Delete) {
+        by_id.push(quote! { delete(remove) });
+        paths.push(quote! { remove });
+        let deleted = format!("(status = 204, description = \"The {noun} is deleted\")");
+        handlers += &openapi_path(
+            Method::Delete,
+            &by_id_path,
+            tag,
+            "remove",
+            &[id_param.clone(), responses(&[&deleted, &not_found])],
+        );
+        handlers += &quote! {
+            pub async fn remove(Path(_id): Path<i64>) -> StatusCode {
+                StatusCode::NO_CONTENT
+            }
+        }
+        .to_string();
+        tests.push(quote! {
+            #[tokio::test]
+            async fn remove() {
+                let request = Request::delete("/7").body(Body::empty()).unwrap();
+                let (status, _) = send(request).await;
+                assert_eq!(status, StatusCode::NO_CONTENT);
+            }
+        });
+    }
+
+    let routes = [("/", &root), ("/:id", &by_id)]
+        .into_iter()
+        .filter(|(_, methods)| !methods.is_empty())
+        .map(|(path, methods)| {
+            let mut chain = methods.iter();
+            let first = chain.next();
+            quote! { .route(#path, routing::#first #(.#chain)*) }
+        });
+
+    let payloads = if api.has(Method::Post) || api.has(Method::Put) {
+        quote! {
+            #[derive(Deserialize, ToSchema)]
+            pub struct #new {
+                #(pub #names: #types,)*
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let patch_payload = if api.has(Method::Patch) {
+        quote! {
+            /// Fields left out keep their value.
+            #[derive(Deserialize, ToSchema)]
+            pub struct #patch {
+                #(pub #names: Option<#types>,)*
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let uses_path = api.methods.iter().any(|method| *method != Method::Post);
+    let path_import = if uses_path {
+        quote! { use axum::extract::Path; }
+    } else {
+        quote! {}
+    };
+    let sends_json = api.has(Method::Post) || api.has(Method::Put) || api.has(Method::Patch);
+    let example_body = if api.has(Method::Post) || api.has(Method::Put) {
+        // A raw string keeps the quotes of the json readable
+        let example: TokenStream = format!("r#\"{}\"#", example_json(&api.fields))
+            .parse()
+            .expect("a raw string literal");
+        quote! {
+            const EXAMPLE: &str = #example;
+        }
+    } else {
+        quote! {}
+    };
+    let json_helpers = if sends_json {
+        quote! {
+            #example_body
+
+            fn json_request(method: &str, uri: &str, body: &str) -> Request<Body> {
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let http_imports = if sends_json {
+        quote! { use axum::http::{header, Request, StatusCode}; }
+    } else {
+        quote! { use axum::http::{Request, StatusCode}; }
+    };
+    let status_import = if api.has(Method::Get) || api.has(Method::Post) || api.has(Method::Delete)
+    {
+        quote! { use axum::http::StatusCode; }
+    } else {
+        quote! {}
+    };
+
+    let head = quote! {
+        //! The handlers echo what they are sent, replace their bodies with
+        //! real queries.
+        #path_import
+        #status_import
+        use axum::routing;
+        use axum::{Json, Router};
+        use serde::{Deserialize, Serialize};
+        use utoipa::{OpenApi, ToSchema};
+
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            Router::new()
+                #(#routes)*
+        }
+
+        /// The routes `krk openapi` documents.
+        #[derive(OpenApi)]
+        #[openapi(paths(#(#paths),*))]
+        pub struct ApiDoc;
+
+        #[derive(Default, Serialize, Deserialize, ToSchema)]
+        pub struct #row {
+            pub id: i64,
+            #(pub #names: #types,)*
+        }
+
+        #payloads
+
+        #patch_payload
+    };
+    let tail = quote! {
+        #[cfg(test)]
+        mod tests {
+            use super::router;
+            use axum::body::{to_bytes, Body};
+            #http_imports
+            use serde_json::Value;
+            use tower::ServiceExt;
+
+            /// Calls the router like a client would, without a server.
+            async fn send(request: Request<Body>) -> (StatusCode, Value) {
+                let response = router::<()>().oneshot(request).await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+                (status, json)
+            }
+
+            #json_helpers
+
+            #(#tests)*
+        }
+    };
+
+    write_rust_file(
+        &api.path(),
+        tracing::instrument(unparse(format!("{head}{handlers}\n{tail}"))),
+    )
+}
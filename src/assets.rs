@@ -0,0 +1,355 @@
+// assets.rs
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs,
+    call_module_fn_in_main_rs, get_config, get_section, set_config, unparse, write_rust_file,
+    BASE_HTML,
+};
+use crate::engine::{inject_into_layout, TemplateEngine};
+use crate::html::{self, Placement, Snippet, Target};
+use crate::middleware;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::ValueEnum;
+use cliclack::log::{error, info, warning};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::process::Command;
+
+/// A pinned client side script kraken can add to base.html.
+pub struct Asset {
+    /// Feature key in Kraken.toml and krk marker name in base.html.
+    pub name: &'static str,
+    pub url: &'static str,
+    /// Identify an unmarked copy of the script, any one of them is enough.
+    pub identities: &'static [&'static str],
+    pub defer: bool,
+    /// htmx extensions are activated with `hx-ext` on `<body>`.
+    pub hx_ext: Option<&'static str>,
+}
+
+pub const HTMX: Asset = Asset {
+    name: "htmx",
+    url: "https://unpkg.com/htmx.org@1.9.10/dist/htmx.min.js",
+    // Extensions live under the same package, only the core path is unique.
+    // Older krk versions added the bare package url.
+    identities: &["/dist/htmx.min.js", "https://unpkg.com/htmx.org@"],
+    defer: false,
+    hx_ext: None,
+};
+
+pub const ALPINEJS: Asset = Asset {
+    name: "alpinejs",
+    url: "https://unpkg.com/alpinejs@3.13.5/dist/cdn.min.js",
+    identities: &["https://unpkg.com/alpinejs@"],
+    defer: true,
+    hx_ext: None,
+};
+
+pub const HYPERSCRIPT: Asset = Asset {
+    name: "hyperscript",
+    url: "https://unpkg.com/hyperscript.org@0.9.12",
+    identities: &["https://unpkg.com/hyperscript.org@"],
+    defer: false,
+    hx_ext: None,
+};
+
+/// The api reference `krk add docs --ui scalar` serves, never injected into
+/// the layout.
+pub const SCALAR: Asset = Asset {
+    name: "scalar",
+    url: "https://cdn.jsdelivr.net/npm/@scalar/api-reference@1.25.0/dist/browser/standalone.js",
+    identities: &["https://cdn.jsdelivr.net/npm/@scalar/api-reference@"],
+    defer: false,
+    hx_ext: None,
+};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum HtmxExtension {
+    /// Server sent events
+    Sse,
+    /// Web sockets
+    Ws,
+    /// Swap error responses into hx-target-* elements
+    ResponseTargets,
+    /// Preload links on hover
+    Preload,
+    /// Send form values as json (add hx-ext="json-enc" where you need it)
+    JsonEnc,
+}
+
+impl HtmxExtension {
+    pub fn asset(self) -> Asset {
+        match self {
+            Self::Sse => Asset {
+                name: "htmx-sse",
+                url: "https://unpkg.com/htmx.org@1.9.10/dist/ext/sse.js",
+                identities: &["/dist/ext/sse.js"],
+                defer: false,
+                hx_ext: Some("sse"),
+            },
+            Self::Ws => Asset {
+                name: "htmx-ws",
+                url: "https://unpkg.com/htmx.org@1.9.10/dist/ext/ws.js",
+                identities: &["/dist/ext/ws.js"],
+                defer: false,
+                hx_ext: Some("ws"),
+            },
+            Self::ResponseTargets => Asset {
+                name: "htmx-response-targets",
+                url: "https://unpkg.com/htmx.org@1.9.10/dist/ext/response-targets.js",
+                identities: &["/dist/ext/response-targets.js"],
+                defer: false,
+                hx_ext: Some("response-targets"),
+            },
+            Self::Preload => Asset {
+                name: "htmx-preload",
+                url: "https://unpkg.com/htmx.org@1.9.10/dist/ext/preload.js",
+                identities: &["/dist/ext/preload.js"],
+                defer: false,
+                hx_ext: Some("preload"),
+            },
+            // Encoding every form as json would break plain html forms
+            Self::JsonEnc => Asset {
+                name: "htmx-json-enc",
+                url: "https://unpkg.com/htmx.org@1.9.10/dist/ext/json-enc.js",
+                identities: &["/dist/ext/json-enc.js"],
+                defer: false,
+                hx_ext: None,
+            },
+        }
+    }
+}
+
+/// Every asset kraken knows, htmx extensions included.
+fn known() -> Vec<Asset> {
+    let mut known = vec![HTMX, ALPINEJS, HYPERSCRIPT];
+    known.extend(
+        HtmxExtension::value_variants()
+            .iter()
+            .map(|extension| extension.asset()),
+    );
+    known
+}
+
+/// The `[assets]` table of Kraken.toml, in the order the assets were added.
+pub fn recorded() -> Vec<(String, String)> {
+    let Some(item) = get_section("assets") else {
+        return vec![];
+    };
+    let Some(table) = item.as_table_like() else {
+        return vec![];
+    };
+    table
+        .iter()
+        .filter_map(|(name, src)| Some((name.to_string(), src.as_str()?.to_string())))
+        .collect()
+}
+
+pub fn is_deferred(name: &str) -> bool {
+    known()
+        .iter()
+        .any(|asset| asset.name == name && asset.defer)
+}
+
+/// The `hx-ext` name of an extension asset, e.g. `sse` for `htmx-sse`.
+pub fn hx_extension_of(name: &str) -> Option<&'static str> {
+    known()
+        .into_iter()
+        .find(|asset| asset.name == name)
+        .and_then(|asset| asset.hx_ext)
+}
+
+/// The `hx-ext` names of every extension enabled in Kraken.toml.
+pub fn enabled_hx_extensions() -> Vec<&'static str> {
+    recorded()
+        .iter()
+        .filter_map(|(name, _)| hx_extension_of(name))
+        .collect()
+}
+
+/// Adds the script tag to base.html and records the asset in Kraken.toml.
+/// With `vendor` the script is downloaded to assets/vendor and served by the
+/// app itself.
+pub fn add_asset(asset: &Asset, into: &Placement, vendor: bool) -> io::Result<()> {
+    add_asset_at(asset, into.target(), vendor)
+}
+
+/// Nothing is downloaded or generated for an asset the layout has already,
+/// and every file is restored if a step fails.
+fn add_asset_at(asset: &Asset, target: Target, vendor: bool) -> io::Result<()> {
+    let engine = TemplateEngine::current();
+    if engine.uses_template_files() && !std::path::Path::new(BASE_HTML).exists() {
+        error("No base.html!")?;
+        return Ok(());
+    }
+    if is_in_layout(asset, engine, vendor)? {
+        warning(format!("{} already exists in the layout!", asset.name))?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    match scaffold(asset, engine, target, vendor) {
+        Ok(true) => Ok(()),
+        // inject_into_layout has said why
+        Ok(false) => transaction.rollback(),
+        Err(err) => {
+            transaction.rollback()?;
+            error(format!(
+                "Failed to add {} ({err}), every change was rolled back.",
+                asset.name
+            ))
+        }
+    }
+}
+
+/// Whether the layout has the asset already, from `src` when it is marked.
+fn is_in_layout(asset: &Asset, engine: TemplateEngine, vendor: bool) -> io::Result<bool> {
+    let src = if vendor {
+        vendored_src(asset)
+    } else {
+        asset.url.to_string()
+    };
+    let same_src = get_config("assets", asset.name)
+        .is_some_and(|recorded| recorded.as_str() == Some(src.as_str()));
+    if engine == TemplateEngine::Maud {
+        return Ok(same_src);
+    }
+    let content = fs::read_to_string(BASE_HTML)?;
+    if html::has_marked(&content, asset.name) {
+        return Ok(same_src);
+    }
+    Ok(html::has_unmarked_copy(&content, asset.identities))
+}
+
+/// Returns whether the asset is now in the layout.
+fn scaffold(
+    asset: &Asset,
+    engine: TemplateEngine,
+    target: Target,
+    vendor: bool,
+) -> io::Result<bool> {
+    let src = if vendor {
+        vendor_asset(asset)?
+    } else {
+        asset.url.to_string()
+    };
+
+    let defer = if asset.defer { " defer" } else { "" };
+    let script = format!(r#"<script{defer} src="{src}"></script>"#);
+    let snippet = Snippet {
+        asset: asset.name,
+        identities: asset.identities,
+        html: &script,
+    };
+    if engine == TemplateEngine::Maud {
+        // The maud layout is generated from what Kraken.toml records
+        record_asset(asset, &src)?;
+        inject_into_layout(&snippet, target)?;
+    } else if inject_into_layout(&snippet, target)? {
+        record_asset(asset, &src)?;
+    } else {
+        return Ok(false);
+    }
+
+    if let Some(extension) = asset.hx_ext {
+        if engine.uses_template_files()
+            && html::add_attribute_value(BASE_HTML, "body", "hx-ext", extension)?
+        {
+            info(format!("Enabled hx-ext=\"{extension}\" on <body>."))?;
+        }
+    }
+    Ok(true)
+}
+
+fn record_asset(asset: &Asset, src: &str) -> io::Result<()> {
+    set_config("assets", asset.name, src)?;
+    add_feature(asset.name)?;
+    middleware::refresh()
+}
+
+/// htmx extensions are useless without htmx, so it is added first. Without an
+/// explicit placement the extension goes right after the htmx script.
+pub fn add_htmx_extension(
+    extension: HtmxExtension,
+    into: Option<&Placement>,
+    vendor: bool,
+) -> io::Result<()> {
+    if get_config("features", HTMX.name).is_none() {
+        warning("htmx extensions need htmx, adding it first.")?;
+        add_asset(&HTMX, into.unwrap_or(&Placement::Head), vendor)?;
+    }
+    let htmx_is_marked = fs::read_to_string(BASE_HTML)
+        .map(|content| content.contains(&format!("<!-- /krk:{} -->", HTMX.name)))
+        .unwrap_or(false);
+    let target = match into {
+        Some(into) => into.target(),
+        None if htmx_is_marked => Target::After(HTMX.name),
+        None => Target::Head,
+    };
+    add_asset_at(&extension.asset(), target, vendor)
+}
+
+/// The path the app serves a vendored script at.
+fn vendored_src(asset: &Asset) -> String {
+    format!("/vendor/{}.js", asset.name)
+}
+
+/// Downloads the pinned script and returns the path the app serves it at.
+pub fn vendor_asset(asset: &Asset) -> io::Result<String> {
+    create_dir_all("assets/vendor")?;
+    let path = format!("assets{}", vendored_src(asset));
+
+    let status = Command::new("curl")
+        .args(["-fsSL", asset.url, "-o", &path])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("curl exited with {status}")));
+    }
+
+    let first_vendored = !std::path::Path::new("src/kraken/vendor.rs").exists();
+    generate_vendor_mod_rs()?;
+    if first_vendored {
+        add_module_to_mod_rs("vendor")?;
+        add_module_to_main_rs("vendor")?;
+        add_kraken_to_main_rs()?;
+        call_module_fn_in_main_rs("vendor", Some("vendor/:file"))?;
+    }
+
+    Ok(vendored_src(asset))
+}
+
+/// Serves every script in assets/vendor, embedded into the binary.
+pub fn generate_vendor_mod_rs() -> io::Result<()> {
+    let mut files: Vec<String> = fs::read_dir("assets/vendor")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file| file.ends_with(".js"))
+        .collect();
+    files.sort();
+    let paths = files
+        .iter()
+        .map(|file| format!("../../assets/vendor/{file}"));
+
+    let code = quote! {
+        use axum::extract::Path;
+        use axum::http::{header, StatusCode};
+        use axum::response::{IntoResponse, Response};
+
+        pub async fn main(Path(file): Path<String>) -> Response {
+            let body = match file.as_str() {
+                #(#files => include_str!(#paths),)*
+                _ => return StatusCode::NOT_FOUND.into_response(),
+            };
+            (
+                [
+                    (header::CONTENT_TYPE, "text/javascript"),
+                    (header::CACHE_CONTROL, "public, max-age=86400"),
+                ],
+                body,
+            )
+                .into_response()
+        }
+    };
+
+    write_rust_file("src/kraken/vendor.rs", unparse(code))
+}
@@ -0,0 +1,416 @@
+// ast.rs
+//! Edits main.rs through its syntax tree. Edits are spliced into the source at
+//! the spans syn reports, so comments and formatting survive, then rustfmt
+//! tidies up.
+use proc_macro2::LineColumn;
+use quote::ToTokens;
+use std::fs;
+use std::io;
+use std::process::Command;
+use syn::spanned::Spanned;
+use syn::{Expr, ExprStruct, FnArg, ItemFn, Local, Member, Pat, Stmt};
+
+pub const MAIN_RS: &str = "src/main.rs";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Byte offset of a proc-macro2 line/column in `source`.
+pub fn offset(source: &str, location: LineColumn) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(location.line - 1)
+        .map(str::len)
+        .sum();
+    line_start
+        + source[line_start..]
+            .chars()
+            .take(location.column)
+            .map(char::len_utf8)
+            .sum::<usize>()
+}
+
+/// The text of `source` that `node` was parsed from.
+pub fn source_text<'a>(source: &'a str, node: &impl Spanned) -> &'a str {
+    let span = node.span();
+    &source[offset(source, span.start())..offset(source, span.end())]
+}
+
+fn parse(source: &str) -> io::Result<syn::File> {
+    syn::parse_file(source).map_err(|err| invalid(&format!("Failed to parse {MAIN_RS}: {err}")))
+}
+
+/// The `#[shuttle_runtime::main]` function, or plain `fn main`.
+fn main_fn(file: &syn::File) -> io::Result<&ItemFn> {
+    let functions = file.items.iter().filter_map(|item| match item {
+        syn::Item::Fn(function) => Some(function),
+        _ => None,
+    });
+    let mut fallback = None;
+    for function in functions {
+        let is_entry = function.attrs.iter().any(|attr| {
+            attr.path()
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "main")
+        });
+        if is_entry {
+            return Ok(function);
+        }
+        if function.sig.ident == "main" {
+            fallback = Some(function);
+        }
+    }
+    fallback.ok_or_else(|| invalid("No main function in src/main.rs"))
+}
+
+/// The `let router = Router::new()...;` statement of the main function.
+fn router_local(function: &ItemFn) -> io::Result<&Local> {
+    function
+        .block
+        .stmts
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Local(local) => Some(local),
+            _ => None,
+        })
+        .find(|local| {
+            local.init.as_ref().is_some_and(|init| {
+                init.expr
+                    .to_token_stream()
+                    .to_string()
+                    .contains("Router :: new")
+            })
+        })
+        .ok_or_else(|| invalid("No `let router = Router::new()` in src/main.rs"))
+}
+
+fn insert(source: &mut String, at: usize, text: &str) -> io::Result<()> {
+    source.insert_str(at, text);
+    // Refuse to write anything that no longer parses
+    parse(source).map(|_| ())
+}
+
+fn save(source: &str) -> io::Result<()> {
+    fs::write(MAIN_RS, source)?;
+    Command::new("rustfmt")
+        .arg(MAIN_RS)
+        .arg("--edition")
+        .arg("2021")
+        .status()
+        .map(|_| ())
+}
+
+/// Adds a parameter, e.g. `#[shuttle_shared_db::Postgres] pool: PgPool`, to
+/// the main function. Returns false if one with that name exists.
+pub fn add_main_param(param: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let function = main_fn(&file)?;
+
+    let new: FnArg = syn::parse_str(param).map_err(|err| invalid(&err.to_string()))?;
+    let name = |arg: &FnArg| match arg {
+        FnArg::Typed(typed) => match typed.pat.as_ref() {
+            Pat::Ident(ident) => Some(ident.ident.to_string()),
+            _ => None,
+        },
+        FnArg::Receiver(_) => None,
+    };
+    if function
+        .sig
+        .inputs
+        .iter()
+        .any(|existing| name(existing) == name(&new))
+    {
+        return Ok(false);
+    }
+
+    let at = offset(&source, function.sig.paren_token.span.close().start());
+    let separator = if function.sig.inputs.is_empty() || function.sig.inputs.trailing_punct() {
+        ""
+    } else {
+        ", "
+    };
+    insert(&mut source, at, &format!("{separator}{param}"))?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Inserts statements at the top of the main function, unless `marker` is
+/// already in its body.
+pub fn prepend_main_stmts(stmts: &str, marker: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let function = main_fn(&file)?;
+    if function
+        .block
+        .to_token_stream()
+        .to_string()
+        .contains(marker)
+    {
+        return Ok(false);
+    }
+
+    let at = offset(&source, function.block.brace_token.span.open().end());
+    insert(&mut source, at, &format!("\n{stmts}\n"))?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Inserts statements right before `let router = ...`, unless `marker` is
+/// already in the main function.
+pub fn insert_before_router(stmts: &str, marker: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let function = main_fn(&file)?;
+    if function
+        .block
+        .to_token_stream()
+        .to_string()
+        .contains(marker)
+    {
+        return Ok(false);
+    }
+
+    let local = router_local(function)?;
+    let at = offset(&source, local.span().start());
+    insert(&mut source, at, &format!("{stmts}\n"))?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Inserts statements right after `let router = ...;`, unless `marker` is
+/// already in the main function.
+pub fn insert_after_router(stmts: &str, marker: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let function = main_fn(&file)?;
+    if function
+        .block
+        .to_token_stream()
+        .to_string()
+        .contains(marker)
+    {
+        return Ok(false);
+    }
+
+    let local = router_local(function)?;
+    let at = offset(&source, local.semi_token.span.end());
+    insert(&mut source, at, &format!("\n{stmts}"))?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Appends a call, e.g. `.with_state(state)`, to the router expression
+/// unless a call to `method` is already chained.
+pub fn chain_router(method: &str, call: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let local = router_local(main_fn(&file)?)?;
+    let Some(init) = &local.init else {
+        return Ok(false);
+    };
+
+    let mut expr = init.expr.as_ref();
+    while let Expr::MethodCall(call) = expr {
+        if call.method == method {
+            return Ok(false);
+        }
+        expr = &call.receiver;
+    }
+
+    let at = offset(&source, local.semi_token.span.start());
+    insert(&mut source, at, call)?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Like [`chain_router`], but the call goes right after the routes, before
+/// `.with_state` and every `.layer`, so those layers wrap it too. Used for
+/// `.fallback(..)`.
+pub fn chain_router_before_layers(method: &str, call: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let local = router_local(main_fn(&file)?)?;
+    let Some(init) = &local.init else {
+        return Ok(false);
+    };
+
+    // The innermost `.with_state` or `.layer`, the chain is walked from the end
+    let mut at = offset(&source, local.semi_token.span.start());
+    let mut expr = init.expr.as_ref();
+    while let Expr::MethodCall(chained) = expr {
+        if chained.method == method {
+            return Ok(false);
+        }
+        if chained.method == "with_state" || chained.method == "layer" {
+            at = offset(&source, chained.dot_token.span.start());
+        }
+        expr = &chained.receiver;
+    }
+
+    insert(&mut source, at, call)?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Wraps the `module::router()` the main router nests or merges in a call,
+/// e.g. `kraken::rate_limit::auth(auth::router())`. Returns false if it is
+/// wrapped already.
+pub fn wrap_module_router(module: &str, wrapper: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let local = router_local(main_fn(&file)?)?;
+    let Some(init) = &local.init else {
+        return Err(invalid("No `let router = Router::new()` in src/main.rs"));
+    };
+    let path_of = |path: &str| {
+        syn::parse_str::<syn::Path>(path)
+            .map(|path| path.to_token_stream().to_string())
+            .map_err(|err| invalid(&err.to_string()))
+    };
+    let wrapper_path = path_of(wrapper)?;
+    let router_path = path_of(&format!("{module}::router"))?;
+    let is_call_to = |expr: &Expr, path: &str| match expr {
+        Expr::Call(call) => match call.func.as_ref() {
+            Expr::Path(func) => func.path.to_token_stream().to_string() == path,
+            _ => false,
+        },
+        _ => false,
+    };
+
+    let mut expr = init.expr.as_ref();
+    while let Expr::MethodCall(chained) = expr {
+        for arg in &chained.args {
+            if is_call_to(arg, &wrapper_path) {
+                return Ok(false);
+            }
+            if is_call_to(arg, &router_path) {
+                let span = arg.span();
+                let (start, end) = (offset(&source, span.start()), offset(&source, span.end()));
+                let router = source[start..end].to_string();
+                source.replace_range(start..end, &format!("{wrapper}({router})"));
+                parse(&source)?;
+                save(&source)?;
+                return Ok(true);
+            }
+        }
+        expr = &chained.receiver;
+    }
+    Err(invalid(&format!(
+        "The router in src/main.rs does not nest or merge `{module}::router()`"
+    )))
+}
+
+/// Adds a `use` (or `mod`) item to the top of main.rs unless it is there.
+pub fn add_item(item: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let new: syn::Item = syn::parse_str(item).map_err(|err| invalid(&err.to_string()))?;
+    let new = new.to_token_stream().to_string();
+    if file
+        .items
+        .iter()
+        .any(|existing| existing.to_token_stream().to_string() == new)
+    {
+        return Ok(false);
+    }
+    insert(&mut source, 0, &format!("{item}\n"))?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// Wraps the router in a layer, e.g. `.layer(kraken::auth::session_layer(sessions))`,
+/// unless that layer is already chained.
+pub fn add_router_layer(layer: &str) -> io::Result<bool> {
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let local = router_local(main_fn(&file)?)?;
+    let call = format!(".layer({layer})");
+    let tokens: proc_macro2::TokenStream =
+        syn::parse_str(&call[1..]).map_err(|err| invalid(&err.to_string()))?;
+    if let Some(init) = &local.init {
+        if init
+            .expr
+            .to_token_stream()
+            .to_string()
+            .contains(&tokens.to_string())
+        {
+            return Ok(false);
+        }
+    }
+    let at = offset(&source, local.semi_token.span.start());
+    insert(&mut source, at, &call)?;
+    save(&source)?;
+    Ok(true)
+}
+
+/// The `let state = kraken::state::AppState { .. };` statement of the main
+/// function.
+fn state_local(function: &ItemFn) -> Option<(&Local, &ExprStruct)> {
+    function.block.stmts.iter().find_map(|stmt| {
+        let Stmt::Local(local) = stmt else {
+            return None;
+        };
+        match local.init.as_ref()?.expr.as_ref() {
+            Expr::Struct(state)
+                if state
+                    .path
+                    .segments
+                    .last()
+                    .is_some_and(|segment| segment.ident == "AppState") =>
+            {
+                Some((local, state))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Adds `field` to the `AppState { .. }` main.rs builds, creating that
+/// statement and `.with_state(state)` when there is none yet. `init`, the
+/// statement defining a local named like the field, goes right before it.
+pub fn add_state_field(field: &str, init: Option<&str>) -> io::Result<()> {
+    insert_before_router("let state = kraken::state::AppState {};", "AppState")?;
+    chain_router("with_state", ".with_state(state)")?;
+
+    let mut source = fs::read_to_string(MAIN_RS)?;
+    let file = parse(&source)?;
+    let function = main_fn(&file)?;
+    let Some((local, state)) = state_local(function) else {
+        return Err(invalid("No `let state = AppState { .. }` in src/main.rs"));
+    };
+    let has_field = state
+        .fields
+        .iter()
+        .any(|existing| matches!(&existing.member, Member::Named(name) if name == field));
+    let defined = function
+        .block
+        .to_token_stream()
+        .to_string()
+        .contains(&format!("let {field} ="));
+
+    // Back to front, so the first offset stays valid
+    let mut changed = false;
+    if !has_field {
+        let at = offset(&source, state.brace_token.span.close().start());
+        let separator = if state.fields.is_empty() || state.fields.trailing_punct() {
+            ""
+        } else {
+            ", "
+        };
+        source.insert_str(at, &format!("{separator}{field}"));
+        changed = true;
+    }
+    if let (Some(init), false) = (init, defined) {
+        let at = offset(&source, local.span().start());
+        source.insert_str(at, &format!("{init}\n"));
+        changed = true;
+    }
+    if changed {
+        parse(&source)?;
+        save(&source)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,913 @@
+// auth.rs
+//! `krk add auth`: tower-sessions, a users model with argon2 password hashes,
+//! login, register and logout pages and a `RequireAuth` extractor.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add,
+    check_feature, generate_page_template, get_config, merge_module_router_in_main_rs, set_config,
+    unparse, write_rust_file,
+};
+use crate::ast;
+use crate::database::Orm;
+use crate::engine::{require_template_files, view, view_imports, TemplateEngine};
+use crate::field::{pascal_case, Field};
+use crate::model::{add_migration, generate_model, Model};
+use crate::oauth::{provider_added, scaffold_provider, Provider};
+use crate::resource::class;
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::ValueEnum;
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where the sessions of `krk add auth` are kept, stored as `sessions` in the
+/// `[auth]` table of Kraken.toml.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Sessions {
+    /// The `sessions` table, logins survive restarts and deploys
+    Database,
+    /// In memory for development, a restart logs everybody out
+    Memory,
+}
+
+impl Sessions {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Database => "database",
+            Self::Memory => "memory",
+        }
+    }
+}
+
+const SESSIONS_SQL: &str = "CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    data TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
+";
+
+/// `krk add auth`, or with `provider` another way to log in. Auth is added
+/// first when the project has none yet. Every file is restored if a step
+/// fails.
+pub fn add_auth(sessions: Sessions, provider: Option<Provider>) -> io::Result<()> {
+    if !require_template_files("auth")? {
+        return Ok(());
+    }
+    if get_config("features", "database").is_none() {
+        error("Auth keeps its users in the database, run `krk add database` first.")?;
+        return Ok(());
+    }
+    let has_auth = get_config("features", "auth").is_some();
+    match provider {
+        Some(provider) if provider_added(provider) => {
+            error(format!("{} login is already there.", provider.label()))?;
+            return Ok(());
+        }
+        None if check_feature("auth").is_err() => {
+            info("Failed to add auth!!!")?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    if !has_auth && Path::new("src/kraken/models/user.rs").exists() {
+        error("A user model already exists, auth brings its own.")?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    let result = match provider {
+        Some(provider) if has_auth => scaffold_provider(provider),
+        Some(provider) => scaffold(sessions).and_then(|()| scaffold_provider(provider)),
+        None => scaffold(sessions),
+    };
+    if let Err(err) = result {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add auth ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    let Some(provider) = provider else {
+        info("Protect a page with `krk add page <name> --auth`, or any handler with a `RequireAuth` argument.")?;
+        return outro("Successfully added /login, /register and /logout. 🎉");
+    };
+    info(format!(
+        "Fill in {} in Secrets.toml, and register <APP_URL>/auth/{}/callback as the callback url.",
+        provider.secrets().join(", "),
+        provider.name()
+    ))?;
+    info(format!(
+        "Accounts are never linked by email, a logged in user links {} by visiting /auth/{}.",
+        provider.label(),
+        provider.name()
+    ))?;
+    info(
+        "`cargo test` runs the login flow against the mock provider in src/kraken/oauth/mock.rs.",
+    )?;
+    outro(format!(
+        "Successfully added {} login at /auth/{}. 🎉",
+        provider.label(),
+        provider.name()
+    ))
+}
+
+fn scaffold(sessions: Sessions) -> io::Result<()> {
+    let orm = Orm::current();
+    // 0.13 is the tower-sessions of axum 0.7
+    cargo_add(&["tower-sessions@0.13"]);
+    // 0.6 moved SaltString and the rng out of password_hash
+    cargo_add(&["argon2@0.5"]);
+    cargo_add(&["serde", "-F", "derive"]);
+    if sessions == Sessions::Database {
+        cargo_add(&["serde_json"]);
+        cargo_add(&["async-trait"]);
+    }
+
+    let fields: Vec<Field> = ["email:Email", "password_hash:String"]
+        .iter()
+        .map(|field| field.parse().expect("valid field"))
+        .collect();
+    let users = Model::new("user", &fields).map_err(io::Error::other)?;
+    generate_model(&users)?;
+    append_find_by(orm, &users, &["email"])?;
+
+    let (mut up, mut down) = (
+        "CREATE UNIQUE INDEX users_email ON users (email);\n".to_string(),
+        "DROP INDEX users_email;\n".to_string(),
+    );
+    if sessions == Sessions::Database {
+        up.push_str(SESSIONS_SQL);
+        down.insert_str(0, "DROP TABLE sessions;\n");
+        generate_sessions_rs(orm)?;
+        add_module_to_mod_rs("sessions")?;
+    }
+    add_migration("add_auth", &up, &down)?;
+
+    generate_auth_templates()?;
+    generate_auth_rs(TemplateEngine::current(), orm)?;
+    add_module_to_mod_rs("auth")?;
+    add_module_to_main_rs("auth")?;
+    add_kraken_to_main_rs()?;
+    merge_module_router_in_main_rs("auth")?;
+
+    let store = match sessions {
+        Sessions::Database => format!(
+            "let sessions = kraken::sessions::DatabaseStore::new(state.{}.clone());",
+            orm.state_field()
+        ),
+        Sessions::Memory => "let sessions = tower_sessions::MemoryStore::default();".to_string(),
+    };
+    ast::insert_before_router(&store, "let sessions")?;
+    ast::add_router_layer("kraken::auth::session_layer(sessions)")?;
+
+    set_config("auth", "sessions", sessions.name())?;
+    add_feature("auth")
+}
+
+/// Appends `find_by_<a>_and_<b>` to a generated model: the row whose text
+/// `columns` equal the arguments, e.g. `find_by_email` for login.
+pub fn append_find_by(orm: Orm, model: &Model, columns: &[&str]) -> io::Result<()> {
+    let ident = model.ident();
+    let function = Ident::new(
+        &format!("find_by_{}", columns.join("_and_")),
+        Span::call_site(),
+    );
+    let args: Vec<Ident> = columns
+        .iter()
+        .map(|column| Ident::new(column, Span::call_site()))
+        .collect();
+    let code = match orm {
+        Orm::Sqlx => {
+            let conditions: Vec<String> = columns
+                .iter()
+                .enumerate()
+                .map(|(index, column)| format!("{column} = ${}", index + 1))
+                .collect();
+            let sql = format!(
+                "SELECT id, {} FROM {} WHERE {}",
+                model.columns(),
+                model.table,
+                conditions.join(" AND ")
+            );
+            quote! {
+                pub async fn #function(pool: &PgPool, #(#args: &str),*) -> sqlx::Result<Option<#ident>> {
+                    sqlx::query_as(#sql)
+                        #(.bind(#args))*
+                        .fetch_optional(pool)
+                        .await
+                }
+            }
+        }
+        Orm::Sea => {
+            let variants = columns
+                .iter()
+                .map(|column| Ident::new(&pascal_case(column), Span::call_site()));
+            quote! {
+                pub async fn #function(db: &DatabaseConnection, #(#args: &str),*) -> Result<Option<#ident>, DbErr> {
+                    use sea_orm::QueryFilter;
+                    Entity::find()
+                        #(.filter(Column::#variants.eq(#args)))*
+                        .one(db)
+                        .await
+                }
+            }
+        }
+        Orm::Diesel => {
+            let table = Ident::new(&model.table, Span::call_site());
+            quote! {
+                pub async fn #function(pool: &DbPool, #(#args: &str),*) -> Result<Option<#ident>> {
+                    let mut connection = pool.get().await?;
+                    Ok(#table::table
+                        #(.filter(#table::#args.eq(#args)))*
+                        .select(#ident::as_select())
+                        .first(&mut connection)
+                        .await
+                        .optional()?)
+                }
+            }
+        }
+    };
+    let path = model.path();
+    let source = fs::read_to_string(&path)?;
+    write_rust_file(&path, format!("{source}\n{code}"))
+}
+
+/// `src/kraken/sessions.rs`, a tower-sessions store over the `sessions`
+/// table with the orm of the project.
+fn generate_sessions_rs(orm: Orm) -> io::Result<()> {
+    let (imports, queries) = match orm {
+        Orm::Sqlx => (
+            quote! { use sqlx::PgPool; },
+            quote! {
+                async fn write(&self, sql: &'static str, record: &Record) -> session_store::Result<u64> {
+                    let result = sqlx::query(sql)
+                        .bind(record.id.to_string())
+                        .bind(encode(record)?)
+                        .bind(record.expiry_date.unix_timestamp())
+                        .execute(&self.pool)
+                        .await
+                        .map_err(backend)?;
+                    Ok(result.rows_affected())
+                }
+
+                async fn remove(&self, sql: &'static str, key: Key) -> session_store::Result<()> {
+                    let query = match key {
+                        Key::Id(id) => sqlx::query(sql).bind(id),
+                        Key::Now(now) => sqlx::query(sql).bind(now),
+                    };
+                    query.execute(&self.pool).await.map_err(backend)?;
+                    Ok(())
+                }
+
+                async fn select(&self, id: &Id) -> session_store::Result<Option<(String, i64)>> {
+                    sqlx::query_as(SELECT)
+                        .bind(id.to_string())
+                        .bind(now())
+                        .fetch_optional(&self.pool)
+                        .await
+                        .map_err(backend)
+                }
+            },
+        ),
+        Orm::Sea => (
+            quote! {
+                use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement, Value};
+            },
+            quote! {
+                async fn write(&self, sql: &'static str, record: &Record) -> session_store::Result<u64> {
+                    let values: [Value; 3] = [
+                        record.id.to_string().into(),
+                        encode(record)?.into(),
+                        record.expiry_date.unix_timestamp().into(),
+                    ];
+                    let statement = Statement::from_sql_and_values(DbBackend::Postgres, sql, values);
+                    let result = self.db.execute(statement).await.map_err(backend)?;
+                    Ok(result.rows_affected())
+                }
+
+                async fn remove(&self, sql: &'static str, key: Key) -> session_store::Result<()> {
+                    let value: Value = match key {
+                        Key::Id(id) => id.into(),
+                        Key::Now(now) => now.into(),
+                    };
+                    let statement = Statement::from_sql_and_values(DbBackend::Postgres, sql, [value]);
+                    self.db.execute(statement).await.map_err(backend)?;
+                    Ok(())
+                }
+
+                async fn select(&self, id: &Id) -> session_store::Result<Option<(String, i64)>> {
+                    let values: [Value; 2] = [id.to_string().into(), now().into()];
+                    let statement = Statement::from_sql_and_values(DbBackend::Postgres, SELECT, values);
+                    let Some(row) = self.db.query_one(statement).await.map_err(backend)? else {
+                        return Ok(None);
+                    };
+                    Ok(Some((
+                        row.try_get("", "data").map_err(backend)?,
+                        row.try_get("", "expires_at").map_err(backend)?,
+                    )))
+                }
+            },
+        ),
+        Orm::Diesel => (
+            quote! {
+                use crate::kraken::database::DbPool;
+                use diesel::sql_types::{BigInt, Text};
+                use diesel::{OptionalExtension, QueryableByName};
+                use diesel_async::RunQueryDsl;
+
+                #[derive(QueryableByName)]
+                struct Row {
+                    #[diesel(sql_type = Text)]
+                    data: String,
+                    #[diesel(sql_type = BigInt)]
+                    expires_at: i64,
+                }
+            },
+            quote! {
+                async fn write(&self, sql: &'static str, record: &Record) -> session_store::Result<u64> {
+                    let mut connection = self.pool.get().await.map_err(backend)?;
+                    let rows = diesel::sql_query(sql)
+                        .bind::<Text, _>(record.id.to_string())
+                        .bind::<Text, _>(encode(record)?)
+                        .bind::<BigInt, _>(record.expiry_date.unix_timestamp())
+                        .execute(&mut connection)
+                        .await
+                        .map_err(backend)?;
+                    Ok(rows as u64)
+                }
+
+                async fn remove(&self, sql: &'static str, key: Key) -> session_store::Result<()> {
+                    let mut connection = self.pool.get().await.map_err(backend)?;
+                    match key {
+                        Key::Id(id) => {
+                            diesel::sql_query(sql)
+                                .bind::<Text, _>(id)
+                                .execute(&mut connection)
+                                .await
+                        }
+                        Key::Now(now) => {
+                            diesel::sql_query(sql)
+                                .bind::<BigInt, _>(now)
+                                .execute(&mut connection)
+                                .await
+                        }
+                    }
+                    .map_err(backend)?;
+                    Ok(())
+                }
+
+                async fn select(&self, id: &Id) -> session_store::Result<Option<(String, i64)>> {
+                    let mut connection = self.pool.get().await.map_err(backend)?;
+                    let row: Option<Row> = diesel::sql_query(SELECT)
+                        .bind::<Text, _>(id.to_string())
+                        .bind::<BigInt, _>(now())
+                        .get_result(&mut connection)
+                        .await
+                        .optional()
+                        .map_err(backend)?;
+                    Ok(row.map(|row| (row.data, row.expires_at)))
+                }
+            },
+        ),
+    };
+    let field_name = Ident::new(orm.state_field(), Span::call_site());
+    let field_type = match orm {
+        Orm::Sqlx => quote! { PgPool },
+        Orm::Sea => quote! { DatabaseConnection },
+        Orm::Diesel => quote! { DbPool },
+    };
+
+    // rustfmt keeps lines it cannot shorten as they were, so the long queries
+    // are written laid out
+    let constants: String = [
+        ("INSERT", "INSERT INTO sessions (id, data, expires_at) VALUES ($1, $2, $3) ON CONFLICT (id) DO NOTHING"),
+        ("UPSERT", "INSERT INTO sessions (id, data, expires_at) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires_at = EXCLUDED.expires_at"),
+        ("SELECT", "SELECT data, expires_at FROM sessions WHERE id = $1 AND expires_at > $2"),
+        ("DELETE", "DELETE FROM sessions WHERE id = $1"),
+        ("DELETE_EXPIRED", "DELETE FROM sessions WHERE expires_at <= $1"),
+    ]
+    .iter()
+    .map(|(name, sql)| format!("const {name}: &str = {sql:?};\n"))
+    .collect();
+
+    let head = quote! {
+        //! Sessions in the `sessions` table, so logins survive restarts and
+        //! deploys.
+        use async_trait::async_trait;
+        use std::fmt;
+        use tower_sessions::cookie::time::OffsetDateTime;
+        use tower_sessions::session::{Id, Record};
+        use tower_sessions::session_store::{self, SessionStore};
+        #imports
+    };
+    let code = quote! {
+        #[derive(Clone)]
+        pub struct DatabaseStore {
+            #field_name: #field_type,
+        }
+
+        impl DatabaseStore {
+            pub fn new(#field_name: #field_type) -> Self {
+                Self { #field_name }
+            }
+
+            #queries
+        }
+
+        impl fmt::Debug for DatabaseStore {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("DatabaseStore")
+            }
+        }
+
+        /// What a delete query is bound to.
+        enum Key {
+            Id(String),
+            Now(i64),
+        }
+
+        fn now() -> i64 {
+            OffsetDateTime::now_utc().unix_timestamp()
+        }
+
+        fn backend(err: impl fmt::Display) -> session_store::Error {
+            session_store::Error::Backend(err.to_string())
+        }
+
+        fn encode(record: &Record) -> session_store::Result<String> {
+            serde_json::to_string(&record.data)
+                .map_err(|err| session_store::Error::Encode(err.to_string()))
+        }
+
+        fn decode(id: Id, data: &str, expires_at: i64) -> session_store::Result<Record> {
+            let decode = |err: &dyn fmt::Display| session_store::Error::Decode(err.to_string());
+            Ok(Record {
+                id,
+                data: serde_json::from_str(data).map_err(|err| decode(&err))?,
+                expiry_date: OffsetDateTime::from_unix_timestamp(expires_at)
+                    .map_err(|err| decode(&err))?,
+            })
+        }
+
+        #[async_trait]
+        impl SessionStore for DatabaseStore {
+            /// Picks another id on a collision. Expired sessions are cleaned up
+            /// whenever one is created.
+            async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+                self.remove(DELETE_EXPIRED, Key::Now(now())).await?;
+                while self.write(INSERT, record).await? == 0 {
+                    record.id = Id::default();
+                }
+                Ok(())
+            }
+
+            async fn save(&self, record: &Record) -> session_store::Result<()> {
+                self.write(UPSERT, record).await?;
+                Ok(())
+            }
+
+            async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
+                self.select(id)
+                    .await?
+                    .map(|(data, expires_at)| decode(*id, &data, expires_at))
+                    .transpose()
+            }
+
+            async fn delete(&self, id: &Id) -> session_store::Result<()> {
+                self.remove(DELETE, Key::Id(id.to_string())).await
+            }
+        }
+    };
+
+    write_rust_file(
+        "src/kraken/sessions.rs",
+        unparse(format!("{head}\n{constants}\n{code}")),
+    )
+}
+
+/// login.html, register.html and logout.html through the page generator.
+fn generate_auth_templates() -> io::Result<()> {
+    let tailwind = get_config("features", "tailwindcss").is_some();
+    let c = |classes: &str| class(tailwind, classes);
+    let input = |kind: &str, name: &str, extra: &str| {
+        format!(
+            r#"<input{} type="{kind}" id="{name}" name="{name}"{extra} required />"#,
+            c("mt-1 w-full rounded border px-3 py-2")
+        )
+    };
+    let field = |label: &str, input: String| {
+        let name = label.to_lowercase();
+        format!(
+            r#"
+    <div>
+      <label{} for="{name}">{label}</label>
+      {input}
+    </div>"#,
+            c("block font-bold")
+        )
+    };
+    let form = |action: &str, password: &str, button: &str, footer: &str| {
+        format!(
+            r#"<main{main}>
+  <h1{h1}>{{{{ title }}}}</h1>
+  {{% if error != "" %}}<p{error}>{{{{ error }}}}</p>{{% endif %}}
+  <form{form} method="post" action="{action}">
+    <input type="hidden" name="next" value="{{{{ next }}}}" />{email}{password}
+    <button{button_class} type="submit">{button}</button>
+  </form>
+  <p{footer_class}>{footer}</p>
+</main>"#,
+            main = c("mx-auto max-w-sm px-4 py-12"),
+            h1 = c("text-4xl font-black"),
+            error = c("mt-4 rounded bg-red-50 px-3 py-2 text-red-600"),
+            form = c("mt-8 space-y-6"),
+            email = field(
+                "Email",
+                input(
+                    "email",
+                    "email",
+                    r#" value="{{ email }}" autocomplete="email""#
+                )
+            ),
+            password = field("Password", input("password", "password", password)),
+            button_class = c(
+                "w-full rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"
+            ),
+            footer_class = c("mt-6 text-sm"),
+        )
+    };
+    let link = c("font-bold hover:underline");
+
+    generate_page_template(
+        "login",
+        &form(
+            "/login",
+            r#" autocomplete="current-password""#,
+            "Log in",
+            &format!(r#"No account yet? <a{link} href="/register">Register</a>"#),
+        ),
+    )?;
+    generate_page_template(
+        "register",
+        &form(
+            "/register",
+            r#" autocomplete="new-password" minlength="8""#,
+            "Register",
+            &format!(r#"Already registered? <a{link} href="/login">Log in</a>"#),
+        ),
+    )?;
+    generate_page_template(
+        "logout",
+        &format!(
+            r#"<main{main}>
+  <h1{h1}>{{{{ title }}}}</h1>
+  <p{p}>You are logged in as {{{{ email }}}}.</p>
+  <form method="post" action="/logout">
+    <button{button} type="submit">Log out</button>
+  </form>
+</main>"#,
+            main = c("mx-auto max-w-sm px-4 py-12"),
+            h1 = c("text-4xl font-black"),
+            p = c("mt-4"),
+            button = c("mt-8 w-full rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"),
+        ),
+    )
+}
+
+fn generate_auth_rs(engine: TemplateEngine, orm: Orm) -> io::Result<()> {
+    let connection = Ident::new(orm.state_field(), Span::call_site());
+    let imports = view_imports(engine);
+    let form_fields = quote! {
+        title: &'static str,
+        email: String,
+        /// Empty unless the last attempt failed.
+        error: String,
+        /// Where to go once logged in.
+        next: String,
+    };
+    let login_view = view(
+        engine,
+        &Ident::new("LoginTemplate", Span::call_site()),
+        "login.html",
+        quote! {},
+        form_fields.clone(),
+    );
+    let register_view = view(
+        engine,
+        &Ident::new("RegisterTemplate", Span::call_site()),
+        "register.html",
+        quote! {},
+        form_fields,
+    );
+    let logout_view = view(
+        engine,
+        &Ident::new("LogoutTemplate", Span::call_site()),
+        "logout.html",
+        quote! {},
+        quote! {
+            title: &'static str,
+            email: String,
+        },
+    );
+
+    let code: TokenStream = quote! {
+        //! Sessions, login, register and logout. A `RequireAuth` argument
+        //! protects a handler, `require_auth` a whole router.
+        use crate::kraken::models::user::{self, UserForm};
+        use crate::kraken::state::AppState;
+        use argon2::password_hash::rand_core::OsRng;
+        use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+        use argon2::Argon2;
+        use axum::async_trait;
+        use axum::extract::{FromRequestParts, Query, Request, State};
+        use axum::http::request::Parts;
+        use axum::http::StatusCode;
+        use axum::middleware::Next;
+        use axum::response::Redirect;
+        use axum::routing::get;
+        use axum::{Form, Router};
+        use serde::Deserialize;
+        use tower_sessions::cookie::time::Duration;
+        use tower_sessions::{Expiry, Session, SessionManagerLayer, SessionStore};
+        #imports
+
+        /// The session key of the [`CurrentUser`].
+        const USER: &str = "user";
+
+        pub fn router() -> Router<AppState> {
+            Router::new()
+                .route("/login", get(login_page).post(login))
+                .route("/register", get(register_page).post(register))
+                .route("/logout", get(logout_page).post(logout))
+        }
+
+        /// Sessions last a week without a visit. The cookie only goes over
+        /// https in release builds.
+        pub fn session_layer<Store: SessionStore + Clone>(store: Store) -> SessionManagerLayer<Store> {
+            SessionManagerLayer::new(store)
+                .with_secure(!cfg!(debug_assertions))
+                .with_expiry(Expiry::OnInactivity(Duration::days(7)))
+        }
+
+        /// Who a session is logged in as.
+        #[derive(Clone, Debug, serde::Serialize, Deserialize)]
+        pub struct CurrentUser {
+            pub id: i64,
+            pub email: String,
+        }
+
+        /// The logged in user. A handler taking it sends everybody else to
+        /// `/login`, and back once they logged in.
+        pub struct RequireAuth(pub CurrentUser);
+
+        #[async_trait]
+        impl<S: Send + Sync> FromRequestParts<S> for RequireAuth {
+            type Rejection = Redirect;
+
+            async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+                let here = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+                let login = format!("/login?next={}", encode(here));
+                let Ok(session) = Session::from_request_parts(parts, state).await else {
+                    return Err(Redirect::to(&login));
+                };
+                match session.get::<CurrentUser>(USER).await {
+                    Ok(Some(user)) => Ok(Self(user)),
+                    _ => Err(Redirect::to(&login)),
+                }
+            }
+        }
+
+        /// For `Router::route_layer(axum::middleware::from_fn(require_auth))`,
+        /// every route of the router needs a login.
+        pub async fn require_auth(_: RequireAuth, request: Request, next: Next) -> axum::response::Response {
+            next.run(request).await
+        }
+
+        #login_view
+
+        #register_view
+
+        #logout_view
+
+        #[derive(Deserialize)]
+        struct NextQuery {
+            next: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct Credentials {
+            email: String,
+            password: String,
+            #[serde(default)]
+            next: String,
+        }
+
+        fn internal(err: impl std::fmt::Display) -> StatusCode {
+            eprintln!("{err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+
+        /// Percent-encodes a path for a query string.
+        fn encode(path: &str) -> String {
+            path.bytes()
+                .map(|byte| match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                        (byte as char).to_string()
+                    }
+                    _ => format!("%{byte:02X}"),
+                })
+                .collect()
+        }
+
+        /// Only paths of this site, `//example.com` is another host.
+        pub fn safe_next(next: &str) -> &str {
+            if next.starts_with('/') && !next.starts_with("//") && !next.starts_with("/\\") {
+                next
+            } else {
+                "/"
+            }
+        }
+
+        fn normalize(email: &str) -> String {
+            email.trim().to_lowercase()
+        }
+
+        /// Argon2 is slow on purpose, so it runs off the async workers.
+        async fn hash(password: String) -> Result<String, StatusCode> {
+            tokio::task::spawn_blocking(move || {
+                let salt = SaltString::generate(&mut OsRng);
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+            })
+            .await
+            .map_err(internal)?
+            .map_err(internal)
+        }
+
+        /// Checked when the email is unknown, so a login takes as long whether or
+        /// not the account exists. Only its argon2 parameters matter, they are
+        /// the ones `Argon2::default()` hashes with.
+        const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$/upMj3FyUKP+70Z7MAxanQ$eC4BtOE9zIFvYVh4nmv5urqI+sFmPAdcSOkaoMV+A4k";
+
+        async fn verify(password: String, hash: String) -> bool {
+            tokio::task::spawn_blocking(move || {
+                PasswordHash::new(&hash).is_ok_and(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+            })
+            .await
+            .unwrap_or(false)
+        }
+
+        /// A new session id on login, so an id planted before can't be used.
+        pub async fn log_in(session: &Session, user: CurrentUser) -> Result<(), StatusCode> {
+            session.cycle_id().await.map_err(internal)?;
+            session.insert(USER, user).await.map_err(internal)
+        }
+
+        async fn login_page(Query(query): Query<NextQuery>) -> impl IntoResponse {
+            LoginTemplate {
+                title: "Log in",
+                email: String::new(),
+                error: String::new(),
+                next: query.next.unwrap_or_default(),
+            }
+        }
+
+        async fn login(
+            State(state): State<AppState>,
+            session: Session,
+            Form(form): Form<Credentials>,
+        ) -> Result<axum::response::Response, StatusCode> {
+            let email = normalize(&form.email);
+            let row = user::find_by_email(&state.#connection, &email)
+                .await
+                .map_err(internal)?;
+            let verified = match &row {
+                Some(row) => verify(form.password, row.password_hash.clone()).await,
+                None => {
+                    verify(form.password, DUMMY_HASH.to_string()).await;
+                    false
+                }
+            };
+            let Some(row) = row.filter(|_| verified) else {
+                let page = LoginTemplate {
+                    title: "Log in",
+                    email,
+                    error: "Wrong email or password.".to_string(),
+                    next: form.next,
+                };
+                return Ok((StatusCode::UNAUTHORIZED, page).into_response());
+            };
+            log_in(&session, CurrentUser { id: row.id, email: row.email }).await?;
+            Ok(Redirect::to(safe_next(&form.next)).into_response())
+        }
+
+        async fn register_page(Query(query): Query<NextQuery>) -> impl IntoResponse {
+            RegisterTemplate {
+                title: "Register",
+                email: String::new(),
+                error: String::new(),
+                next: query.next.unwrap_or_default(),
+            }
+        }
+
+        async fn register(
+            State(state): State<AppState>,
+            session: Session,
+            Form(form): Form<Credentials>,
+        ) -> Result<axum::response::Response, StatusCode> {
+            let email = normalize(&form.email);
+            let error = if !email.contains('@') || email.chars().count() > 255 {
+                "Enter a valid email address."
+            } else if form.password.chars().count() < 8 {
+                "The password needs at least 8 characters."
+            } else if user::find_by_email(&state.#connection, &email)
+                .await
+                .map_err(internal)?
+                .is_some()
+            {
+                "There is an account with this email already."
+            } else {
+                ""
+            };
+            if !error.is_empty() {
+                let page = RegisterTemplate {
+                    title: "Register",
+                    email,
+                    error: error.to_string(),
+                    next: form.next,
+                };
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+            }
+
+            let password_hash = hash(form.password).await?;
+            let row = user::create(&state.#connection, &UserForm { email, password_hash })
+                .await
+                .map_err(internal)?;
+            log_in(&session, CurrentUser { id: row.id, email: row.email }).await?;
+            Ok(Redirect::to(safe_next(&form.next)).into_response())
+        }
+
+        async fn logout_page(RequireAuth(user): RequireAuth) -> impl IntoResponse {
+            LogoutTemplate {
+                title: "Log out",
+                email: user.email,
+            }
+        }
+
+        async fn logout(session: Session) -> Result<Redirect, StatusCode> {
+            session.flush().await.map_err(internal)?;
+            Ok(Redirect::to("/login"))
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn next_stays_on_this_site() {
+                assert_eq!(safe_next("/dashboard?tab=1"), "/dashboard?tab=1");
+                assert_eq!(safe_next("//example.com"), "/");
+                assert_eq!(safe_next("/\\example.com"), "/");
+                assert_eq!(safe_next("https://example.com"), "/");
+                assert_eq!(safe_next(""), "/");
+            }
+
+            #[test]
+            fn next_is_encoded() {
+                assert_eq!(encode("/a b?c=1&d"), "/a%20b%3Fc%3D1%26d");
+            }
+
+            #[tokio::test]
+            async fn passwords_verify_against_their_hash() {
+                let hash = hash("correct horse".to_string()).await.unwrap();
+                assert!(verify("correct horse".to_string(), hash.clone()).await);
+                assert!(!verify("battery staple".to_string(), hash).await);
+            }
+
+            #[test]
+            fn the_dummy_hash_costs_as_much_as_a_real_one() {
+                let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+                let params = argon2::Params::try_from(&dummy).unwrap();
+                let default = argon2::Params::default();
+                assert_eq!(dummy.algorithm, argon2::Algorithm::default().ident());
+                assert_eq!(
+                    (params.m_cost(), params.t_cost(), params.p_cost()),
+                    (default.m_cost(), default.t_cost(), default.p_cost())
+                );
+            }
+        }
+    };
+
+    write_rust_file("src/kraken/auth.rs", tracing::instrument(unparse(code)))
+}
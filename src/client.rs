@@ -0,0 +1,1016 @@
+// client.rs
+//! `krk gen client --lang ts`: a typed `fetch` client for the JSON endpoints.
+//! It is read from the handlers and serde types under src/kraken/api rather
+//! than from the spec, so it needs no build and `krk dev` can keep it current.
+use crate::add::{get_config, set_config};
+use crate::execute::Execute;
+use crate::field::snake_case;
+use crate::kraken::MagentaTheme;
+use clap::{Subcommand, ValueEnum};
+use cliclack::log::{error, warning};
+use cliclack::{intro, outro, set_theme};
+use console::style;
+use proc_macro2::{TokenStream, TokenTree};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+use syn::ext::IdentExt;
+use syn::{
+    Attribute, Expr, ExprLit, Fields, FnArg, GenericArgument, Item, ItemFn, Lit, LitStr, Meta,
+    PathArguments, ReturnType, Type,
+};
+
+const API_DIR: &str = "src/kraken/api";
+const DEFAULT_TS_OUTPUT: &str = "client/api.ts";
+
+#[derive(Subcommand)]
+pub enum Gen {
+    /// A typed client for the generated apis
+    Client {
+        /// The client language
+        #[arg(long, default_value = "ts")]
+        lang: Lang,
+        /// Defaults to the last output, or client/api.ts
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+impl Execute for Gen {
+    fn execute(&self) -> anyhow::Result<()> {
+        set_theme(MagentaTheme);
+        intro(style(" kraken ").on_magenta().black())?;
+
+        match self {
+            Self::Client { lang, output } => {
+                gen_client(*lang, output.as_deref())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Lang {
+    /// TypeScript on top of `fetch`
+    Ts,
+}
+
+impl Lang {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ts => "ts",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|lang| lang.name() == name)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// `krk gen client`: writes the client and remembers where, for `krk dev`.
+pub fn gen_client(lang: Lang, output: Option<&str>) -> io::Result<()> {
+    if !Path::new(API_DIR).join("mod.rs").exists() {
+        error("No api yet, run `krk add api <name>` first.")?;
+        return Ok(());
+    }
+
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => get_config("client", "output")
+            .and_then(|item| item.as_str().map(str::to_string))
+            .unwrap_or_else(|| DEFAULT_TS_OUTPUT.to_string()),
+    };
+
+    let client = match Client::read() {
+        Ok(client) => client,
+        Err(err) => {
+            error(format!("Failed to read {API_DIR}: {err}"))?;
+            return Ok(());
+        }
+    };
+    for skipped in &client.skipped {
+        warning(skipped)?;
+    }
+    write_if_changed(&output, &client.typescript())?;
+
+    set_config("client", "lang", lang.name())?;
+    set_config("client", "output", output.as_str())?;
+
+    outro(format!(
+        "Successfully wrote {} endpoints to {output}. 🎉",
+        client.endpoints.len()
+    ))
+}
+
+/// The output `krk gen client` recorded, if it ran before.
+pub fn recorded_client() -> Option<(Lang, String)> {
+    let lang = Lang::from_name(get_config("client", "lang")?.as_str()?)?;
+    let output = get_config("client", "output")?.as_str()?.to_string();
+    Some((lang, output))
+}
+
+/// Rewrites the recorded client, `Ok(true)` when its content changed.
+pub fn regenerate_client(output: &str) -> io::Result<bool> {
+    write_if_changed(output, &Client::read()?.typescript())
+}
+
+fn write_if_changed(output: &str, content: &str) -> io::Result<bool> {
+    if fs::read_to_string(output).is_ok_and(|old| old == content) {
+        return Ok(false);
+    }
+    if let Some(parent) = Path::new(output).parent() {
+        create_dir_all(parent)?;
+    }
+    fs::write(output, content)?;
+    Ok(true)
+}
+
+struct Property {
+    name: String,
+    docs: Vec<String>,
+    ty: String,
+    optional: bool,
+}
+
+enum Declaration {
+    Interface(Vec<Property>),
+    Alias(String),
+}
+
+struct Endpoint {
+    name: String,
+    method: String,
+    /// As utoipa documents it, `/api/users/{id}`.
+    path: String,
+    docs: Vec<String>,
+    path_params: Vec<(String, String)>,
+    query: Option<(String, bool)>,
+    body: Option<String>,
+    response: String,
+}
+
+#[derive(Default)]
+struct Client {
+    declarations: Vec<(String, Vec<String>, Declaration)>,
+    endpoints: Vec<Endpoint>,
+    /// Why some items are left out of the client.
+    skipped: Vec<String>,
+}
+
+impl Client {
+    fn read() -> io::Result<Self> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(API_DIR)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "rs") {
+                let source = fs::read_to_string(&path)?;
+                let file = syn::parse_file(&source)
+                    .map_err(|err| invalid(format!("{}: {err}", path.display())))?;
+                let module = path.file_stem().unwrap().to_string_lossy().to_string();
+                files.push((module, file));
+            }
+        }
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Every serde type first, so a field can tell a known name from one
+        // the client can't describe.
+        let known: HashSet<String> = files
+            .iter()
+            .flat_map(|(_, file)| &file.items)
+            .filter_map(|item| match item {
+                Item::Struct(item) if derives_serde(&item.attrs) => Some(item.ident.to_string()),
+                Item::Enum(item) if derives_serde(&item.attrs) => Some(item.ident.to_string()),
+                Item::Type(item) => Some(item.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        let loose: HashSet<String> = files
+            .iter()
+            .flat_map(|(_, file)| &file.items)
+            .filter_map(|item| match item {
+                Item::Struct(item) if derives_serde(&item.attrs) => Some(item),
+                _ => None,
+            })
+            .filter(|item| {
+                item.fields.iter().all(|field| {
+                    last_segment(&field.ty).is_some_and(|(ident, _)| ident == "Option")
+                })
+            })
+            .map(|item| item.ident.to_string())
+            .collect();
+
+        let mut client = Self::default();
+        let mut names = HashSet::new();
+        for (module, file) in &files {
+            for item in &file.items {
+                client.declare(item, &known, &mut names);
+            }
+
+            let routed = routed_handlers(&file.items);
+            for item in &file.items {
+                let Item::Fn(function) = item else { continue };
+                let Some(attr) = function.attrs.iter().find(|attr| is_utoipa_path(attr)) else {
+                    continue;
+                };
+                if routed
+                    .as_ref()
+                    .is_some_and(|routed| !routed.contains(&function.sig.ident.to_string()))
+                {
+                    continue;
+                }
+                match endpoint(module, function, attr, &known, &loose) {
+                    Ok(endpoint) => client.endpoints.push(endpoint),
+                    Err(reason) => client.skipped.push(format!(
+                        "{module}::{} is left out: {reason}.",
+                        function.sig.ident
+                    )),
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for endpoint in &mut client.endpoints {
+            let mut name = endpoint.name.clone();
+            let mut n = 2;
+            while !seen.insert(name.clone()) {
+                name = format!("{}{n}", endpoint.name);
+                n += 1;
+            }
+            endpoint.name = name;
+        }
+        Ok(client)
+    }
+
+    fn declare(&mut self, item: &Item, known: &HashSet<String>, names: &mut HashSet<String>) {
+        let (ident, attrs, declaration) = match item {
+            Item::Struct(item) if derives_serde(&item.attrs) => {
+                let declaration = match &item.fields {
+                    Fields::Named(fields) => {
+                        let rename_all = serde_value(&item.attrs, "rename_all");
+                        let properties = fields
+                            .named
+                            .iter()
+                            .filter(|field| !serde_flag(&field.attrs, "skip"))
+                            .map(|field| {
+                                let ident = field.ident.as_ref().unwrap().unraw().to_string();
+                                let name = serde_value(&field.attrs, "rename")
+                                    .unwrap_or_else(|| rename(&ident, rename_all.as_deref()));
+                                let optional = last_segment(&field.ty)
+                                    .is_some_and(|(ident, _)| ident == "Option");
+                                Property {
+                                    name,
+                                    docs: docs(&field.attrs),
+                                    ty: ts_type(&field.ty, known),
+                                    optional,
+                                }
+                            })
+                            .collect();
+                        Declaration::Interface(properties)
+                    }
+                    Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                        Declaration::Alias(ts_type(&fields.unnamed[0].ty, known))
+                    }
+                    Fields::Unnamed(fields) => Declaration::Alias(format!(
+                        "[{}]",
+                        fields
+                            .unnamed
+                            .iter()
+                            .map(|field| ts_type(&field.ty, known))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                    Fields::Unit => Declaration::Alias("null".to_string()),
+                };
+                (&item.ident, &item.attrs, declaration)
+            }
+            Item::Enum(item) if derives_serde(&item.attrs) => {
+                let rename_all = serde_value(&item.attrs, "rename_all");
+                let unit = item
+                    .variants
+                    .iter()
+                    .all(|variant| matches!(variant.fields, Fields::Unit));
+                let ty = if unit {
+                    item.variants
+                        .iter()
+                        .map(|variant| {
+                            let name = serde_value(&variant.attrs, "rename").unwrap_or_else(|| {
+                                rename_variant(&variant.ident.to_string(), rename_all.as_deref())
+                            });
+                            format!("{name:?}")
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" | ")
+                } else {
+                    "unknown".to_string()
+                };
+                (&item.ident, &item.attrs, Declaration::Alias(ty))
+            }
+            Item::Type(item) => (
+                &item.ident,
+                &item.attrs,
+                Declaration::Alias(ts_type(&item.ty, known)),
+            ),
+            _ => return,
+        };
+
+        let name = ident.to_string();
+        if !names.insert(name.clone()) {
+            self.skipped.push(format!(
+                "{name} is declared twice, the client keeps the first."
+            ));
+            return;
+        }
+        self.declarations.push((name, docs(attrs), declaration));
+    }
+
+    fn typescript(&self) -> String {
+        let mut ts = String::from(
+            "// Generated by `krk gen client` from src/kraken/api, every run rewrites it.\n\n",
+        );
+
+        for (name, docs, declaration) in &self.declarations {
+            ts.push_str(&js_doc(docs, ""));
+            match declaration {
+                Declaration::Interface(properties) => {
+                    ts.push_str(&format!("export interface {name} {{\n"));
+                    for property in properties {
+                        ts.push_str(&js_doc(&property.docs, "  "));
+                        let optional = if property.optional { "?" } else { "" };
+                        ts.push_str(&format!(
+                            "  {}{optional}: {};\n",
+                            property_name(&property.name),
+                            property.ty
+                        ));
+                    }
+                    ts.push_str("}\n\n");
+                }
+                Declaration::Alias(ty) => ts.push_str(&format!("export type {name} = {ty};\n\n")),
+            }
+        }
+
+        ts.push_str(RUNTIME);
+
+        for endpoint in &self.endpoints {
+            let mut params: Vec<String> = endpoint
+                .path_params
+                .iter()
+                .map(|(name, ty)| format!("{name}: {ty}"))
+                .collect();
+            if let Some(body) = &endpoint.body {
+                params.push(format!("body: {body}"));
+            }
+            if let Some((query, optional)) = &endpoint.query {
+                let optional = if *optional { "?" } else { "" };
+                params.push(format!("query{optional}: {query}"));
+            }
+
+            let mut path = endpoint.path.clone();
+            for (template, name) in path_templates(&endpoint.path)
+                .iter()
+                .zip(&endpoint.path_params)
+            {
+                path = path.replace(
+                    &format!("{{{template}}}"),
+                    &format!("${{encodeURIComponent(String({}))}}", name.0),
+                );
+            }
+            let path = if endpoint.path_params.is_empty() {
+                format!("{path:?}")
+            } else {
+                format!("`{path}`")
+            };
+            let query = if endpoint.query.is_some() {
+                "query"
+            } else {
+                "undefined"
+            };
+            let args = match &endpoint.body {
+                Some(_) => format!(", {query}, body"),
+                None if endpoint.query.is_some() => ", query".to_string(),
+                None => String::new(),
+            };
+
+            let mut docs = endpoint.docs.clone();
+            if !docs.is_empty() {
+                docs.push(String::new());
+            }
+            docs.push(format!("`{} {}`", endpoint.method, endpoint.path));
+            let response = &endpoint.response;
+            ts.push_str(&js_doc(&docs, ""));
+            ts.push_str(&format!(
+                "export function {}({}): Promise<{response}> {{\n  return request<{response}>(\"{}\", {path}{args});\n}}\n\n",
+                endpoint.name,
+                params.join(", "),
+                endpoint.method,
+            ));
+        }
+
+        ts.truncate(ts.trim_end().len());
+        ts.push('\n');
+        ts
+    }
+}
+
+/// Shared by every endpoint function.
+const RUNTIME: &str = r#"/** Thrown for every response outside 2xx, with the body the api sent. */
+export class ApiError extends globalThis.Error {
+  status: number;
+  body: string;
+
+  constructor(status: number, body: string) {
+    super(`${status} ${body}`);
+    this.name = "ApiError";
+    this.status = status;
+    this.body = body;
+  }
+}
+
+let baseUrl = "";
+
+/** Where the api is served from, the page's own origin by default. */
+export function setBaseUrl(url: string): void {
+  baseUrl = url.replace(/\/+$/, "");
+}
+
+async function request<T>(method: string, path: string, query?: object, body?: unknown): Promise<T> {
+  const search = new URLSearchParams();
+  for (const [key, value] of Object.entries(query ?? {})) {
+    for (const item of Array.isArray(value) ? value : [value]) {
+      if (item !== undefined && item !== null) {
+        search.append(key, String(item));
+      }
+    }
+  }
+  const url = baseUrl + path + (search.toString() ? `?${search}` : "");
+
+  const headers: Record<string, string> = {};
+  if (body !== undefined) {
+    headers["Content-Type"] = "application/json";
+  }
+  // The token `krk add csrf` puts in the page, unsafe requests without a body need it
+  const token = csrfToken();
+  if (token) {
+    headers["x-csrf-token"] = token;
+  }
+
+  const response = await fetch(url, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  const text = await response.text();
+  if (!response.ok) {
+    throw new ApiError(response.status, text);
+  }
+  if (!text) {
+    return undefined as T;
+  }
+  const json = response.headers.get("Content-Type")?.includes("json");
+  return (json ? JSON.parse(text) : text) as T;
+}
+
+function csrfToken(): string | undefined {
+  if (typeof document === "undefined") {
+    return undefined;
+  }
+  return document.querySelector<HTMLMetaElement>('meta[name="csrf-token"]')?.content;
+}
+
+"#;
+
+fn endpoint(
+    module: &str,
+    function: &ItemFn,
+    attr: &Attribute,
+    known: &HashSet<String>,
+    loose: &HashSet<String>,
+) -> Result<Endpoint, String> {
+    let Meta::List(list) = &attr.meta else {
+        return Err("its #[utoipa::path] has no arguments".to_string());
+    };
+    let tokens: Vec<TokenTree> = list.tokens.clone().into_iter().collect();
+    let method = match tokens.first() {
+        Some(TokenTree::Ident(method)) => method.to_string().to_uppercase(),
+        _ => return Err("its #[utoipa::path] names no method".to_string()),
+    };
+    let path = attr_value(&tokens, "path").ok_or("its #[utoipa::path] has no path")?;
+    let operation_id = attr_value(&tokens, "operation_id")
+        .unwrap_or_else(|| format!("{module}_{}", function.sig.ident));
+
+    let templates = path_templates(&path);
+    let mut endpoint = Endpoint {
+        name: camel_case(&operation_id),
+        method,
+        path,
+        docs: docs(&function.attrs),
+        path_params: Vec::new(),
+        query: None,
+        body: None,
+        response: response_type(&function.sig.output, known),
+    };
+
+    for input in &function.sig.inputs {
+        let FnArg::Typed(input) = input else { continue };
+        let Some((extractor, Some(inner))) = last_segment(&input.ty) else {
+            continue;
+        };
+        match extractor.as_str() {
+            "Path" => {
+                let types: Vec<String> = match inner {
+                    Type::Tuple(tuple) => tuple.elems.iter().map(|ty| ts_type(ty, known)).collect(),
+                    ty if templates.len() == 1 => vec![ts_type(ty, known)],
+                    _ => vec!["string".to_string(); templates.len()],
+                };
+                if types.len() != templates.len() {
+                    return Err(format!(
+                        "it extracts {} path parameters from {}",
+                        types.len(),
+                        endpoint.path
+                    ));
+                }
+                endpoint.path_params = templates
+                    .iter()
+                    .map(|template| param_name(template))
+                    .zip(types)
+                    .collect();
+            }
+            "Query" => {
+                // A query of nothing but optional fields can be left out.
+                let optional = last_segment(inner).is_some_and(|(name, _)| loose.contains(&name));
+                endpoint.query = Some((ts_type(inner, known), optional));
+            }
+            "Json" => endpoint.body = Some(ts_type(inner, known)),
+            _ => {}
+        }
+    }
+
+    Ok(endpoint)
+}
+
+fn is_utoipa_path(attr: &Attribute) -> bool {
+    let segments: Vec<String> = attr
+        .path()
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments == ["utoipa", "path"]
+}
+
+/// The handlers of `#[openapi(paths(...))]`, the ones the router serves.
+/// `krk add api --from` keeps handlers that left the spec but drops them
+/// there.
+fn routed_handlers(items: &[Item]) -> Option<BTreeSet<String>> {
+    items.iter().find_map(|item| {
+        let Item::Struct(item) = item else {
+            return None;
+        };
+        let attr = item
+            .attrs
+            .iter()
+            .find(|attr| attr.path().is_ident("openapi"))?;
+        let Meta::List(list) = &attr.meta else {
+            return None;
+        };
+        let mut tokens = list.tokens.clone().into_iter();
+        while let Some(token) = tokens.next() {
+            if matches!(&token, TokenTree::Ident(ident) if ident == "paths") {
+                let Some(TokenTree::Group(group)) = tokens.next() else {
+                    return None;
+                };
+                return Some(path_idents(group.stream()));
+            }
+        }
+        None
+    })
+}
+
+/// The last segment of every path in `a, b::c, d`.
+fn path_idents(tokens: TokenStream) -> BTreeSet<String> {
+    let mut idents = BTreeSet::new();
+    let mut last = None;
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => last = Some(ident.to_string()),
+            TokenTree::Punct(punct) if punct.as_char() == ',' => idents.extend(last.take()),
+            _ => {}
+        }
+    }
+    idents.extend(last);
+    idents
+}
+
+/// The string after `key =` in `#[utoipa::path(...)]`.
+fn attr_value(tokens: &[TokenTree], key: &str) -> Option<String> {
+    tokens.windows(3).find_map(|window| match window {
+        [TokenTree::Ident(ident), TokenTree::Punct(punct), TokenTree::Literal(literal)]
+            if ident == key && punct.as_char() == '=' =>
+        {
+            syn::parse_str::<LitStr>(&literal.to_string())
+                .ok()
+                .map(|literal| literal.value())
+        }
+        _ => None,
+    })
+}
+
+fn path_templates(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn derives_serde(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|attr| {
+        attr.path().is_ident("derive")
+            && matches!(&attr.meta, Meta::List(list) if list.tokens.clone().into_iter().any(
+                |token| matches!(&token, TokenTree::Ident(ident) if ident == "Serialize" || ident == "Deserialize")
+            ))
+    })
+}
+
+/// The string of `#[serde(key = "...")]`.
+fn serde_value(attrs: &[Attribute], key: &str) -> Option<String> {
+    let mut value = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident(key) {
+                value = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.input.peek(syn::Token![=]) {
+                meta.value()?.parse::<Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                meta.parse_nested_meta(|nested| {
+                    nested.value()?.parse::<Expr>()?;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        });
+    }
+    value
+}
+
+/// Whether `#[serde(flag)]` is set.
+fn serde_flag(attrs: &[Attribute], flag: &str) -> bool {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+        .any(|attr| match &attr.meta {
+            Meta::List(list) => list
+                .tokens
+                .clone()
+                .into_iter()
+                .any(|token| matches!(&token, TokenTree::Ident(ident) if ident == flag)),
+            _ => false,
+        })
+}
+
+/// A field name under `#[serde(rename_all = "...")]`.
+fn rename(field: &str, rule: Option<&str>) -> String {
+    match rule {
+        Some("camelCase") => camel_case(field),
+        Some("PascalCase") => {
+            let camel = camel_case(field);
+            let mut chars = camel.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+                .unwrap_or_default()
+        }
+        // Fields are snake_case already, serde leaves their underscores alone
+        Some("lowercase") => field.to_string(),
+        Some("UPPERCASE") => field.to_uppercase(),
+        Some("SCREAMING_SNAKE_CASE") => field.to_uppercase(),
+        Some("kebab-case") => field.replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => field.replace('_', "-").to_uppercase(),
+        _ => field.to_string(),
+    }
+}
+
+/// A variant name under `#[serde(rename_all = "...")]`.
+fn rename_variant(variant: &str, rule: Option<&str>) -> String {
+    match rule {
+        Some("lowercase") => variant.to_lowercase(),
+        Some("UPPERCASE") => variant.to_uppercase(),
+        Some("PascalCase") | None => variant.to_string(),
+        Some(rule) => rename(&snake_case(variant), Some(rule)),
+    }
+}
+
+/// `list_pets` and `listPets` are both `listPets`.
+fn camel_case(name: &str) -> String {
+    let mut camel = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' || c == '-' {
+            upper = !camel.is_empty();
+        } else if upper {
+            camel.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+const RESERVED: [&str; 38] = [
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "import",
+    "in",
+    "instanceof",
+    "new",
+    "null",
+    "return",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "body",
+    "query",
+];
+
+/// `{pet_id}` is passed as `petId`, reserved words and the other
+/// parameters get a trailing underscore.
+fn param_name(template: &str) -> String {
+    let mut name: String = camel_case(template)
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_' || *c == '$')
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    if RESERVED.contains(&name.as_str()) {
+        name.push('_');
+    }
+    name
+}
+
+/// Quoted unless it is a plain identifier.
+fn property_name(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '$');
+    if plain {
+        name.to_string()
+    } else {
+        format!("{name:?}")
+    }
+}
+
+fn docs(attrs: &[Attribute]) -> Vec<String> {
+    attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(meta) if meta.path.is_ident("doc") => match &meta.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(doc), ..
+                }) => Some(doc.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|doc| doc.strip_prefix(' ').unwrap_or(&doc).trim_end().to_string())
+        .collect()
+}
+
+fn js_doc(docs: &[String], indent: &str) -> String {
+    match docs {
+        [] => String::new(),
+        [line] => format!("{indent}/** {} */\n", line.replace("*/", "* /")),
+        lines => {
+            let mut doc = format!("{indent}/**\n");
+            for line in lines {
+                let line = line.replace("*/", "* /");
+                if line.is_empty() {
+                    doc.push_str(&format!("{indent} *\n"));
+                } else {
+                    doc.push_str(&format!("{indent} * {line}\n"));
+                }
+            }
+            doc.push_str(&format!("{indent} */\n"));
+            doc
+        }
+    }
+}
+
+/// The name and the first generic argument of the type's last segment.
+fn last_segment(ty: &Type) -> Option<(String, Option<&Type>)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    let inner = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => {
+            arguments.args.iter().find_map(|argument| match argument {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+        }
+        _ => None,
+    };
+    Some((segment.ident.to_string(), inner))
+}
+
+/// How the JSON of a rust type looks in TypeScript. Names the client doesn't
+/// declare are `unknown`.
+fn ts_type(ty: &Type, known: &HashSet<String>) -> String {
+    match ty {
+        Type::Reference(reference) => ts_type(&reference.elem, known),
+        Type::Paren(paren) => ts_type(&paren.elem, known),
+        Type::Group(group) => ts_type(&group.elem, known),
+        Type::Slice(slice) => array(ts_type(&slice.elem, known)),
+        Type::Array(array_type) => array(ts_type(&array_type.elem, known)),
+        Type::Tuple(tuple) if tuple.elems.is_empty() => "null".to_string(),
+        Type::Tuple(tuple) => format!(
+            "[{}]",
+            tuple
+                .elems
+                .iter()
+                .map(|ty| ts_type(ty, known))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Type::Path(path) => {
+            let Some(segment) = path.path.segments.last() else {
+                return "unknown".to_string();
+            };
+            let arguments: Vec<&Type> = match &segment.arguments {
+                PathArguments::AngleBracketed(arguments) => arguments
+                    .args
+                    .iter()
+                    .filter_map(|argument| match argument {
+                        GenericArgument::Type(ty) => Some(ty),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let name = segment.ident.to_string();
+            match (name.as_str(), arguments.as_slice()) {
+                ("String" | "str" | "char" | "Uuid" | "Ulid" | "Decimal", _) => {
+                    "string".to_string()
+                }
+                (
+                    "DateTime" | "NaiveDate" | "NaiveDateTime" | "NaiveTime" | "Date"
+                    | "OffsetDateTime" | "PrimitiveDateTime" | "Time",
+                    _,
+                ) => "string".to_string(),
+                (
+                    "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64"
+                    | "u128" | "usize" | "f32" | "f64",
+                    _,
+                ) => "number".to_string(),
+                ("bool", _) => "boolean".to_string(),
+                ("Option", [inner]) => match ts_type(inner, known) {
+                    unknown if unknown == "unknown" => unknown,
+                    ty => format!("{ty} | null"),
+                },
+                ("Vec" | "VecDeque" | "HashSet" | "BTreeSet", [inner]) => {
+                    array(ts_type(inner, known))
+                }
+                ("HashMap" | "BTreeMap", [_, value]) => {
+                    format!("Record<string, {}>", ts_type(value, known))
+                }
+                ("Box" | "Arc" | "Rc" | "Json", [inner]) => ts_type(inner, known),
+                ("Cow", [inner]) => ts_type(inner, known),
+                _ if known.contains(&name) => name,
+                _ => "unknown".to_string(),
+            }
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+fn array(element: String) -> String {
+    if element.contains(' ') {
+        format!("({element})[]")
+    } else {
+        format!("{element}[]")
+    }
+}
+
+/// What a handler answers with: `Json<T>`, `(StatusCode, Json<T>)` or a
+/// `Result` of either. A bare status has no body.
+fn response_type(output: &ReturnType, known: &HashSet<String>) -> String {
+    match output {
+        ReturnType::Default => "void".to_string(),
+        ReturnType::Type(_, ty) => response_body(ty, known),
+    }
+}
+
+fn response_body(ty: &Type, known: &HashSet<String>) -> String {
+    match ty {
+        Type::Tuple(tuple) if tuple.elems.is_empty() => "void".to_string(),
+        Type::Tuple(tuple) => response_body(tuple.elems.last().unwrap(), known),
+        Type::Paren(paren) => response_body(&paren.elem, known),
+        _ => match last_segment(ty) {
+            Some((name, Some(inner))) if name == "Result" => response_body(inner, known),
+            Some((name, Some(inner))) if name == "Json" => ts_type(inner, known),
+            Some((name, Some(_))) if name == "Html" => "string".to_string(),
+            Some((name, None)) if name == "StatusCode" => "void".to_string(),
+            Some((name, None)) if name == "String" => "string".to_string(),
+            Some(_) if matches!(ty, Type::Path(_)) => "unknown".to_string(),
+            _ => match ty {
+                Type::Reference(_) => "string".to_string(),
+                _ => "unknown".to_string(),
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(ty: &str, known: &[&str]) -> String {
+        let known = known.iter().map(|name| name.to_string()).collect();
+        ts_type(&syn::parse_str(ty).unwrap(), &known)
+    }
+
+    #[test]
+    fn rename_follows_serde() {
+        assert_eq!(rename("pet_id", None), "pet_id");
+        assert_eq!(rename("pet_id", Some("camelCase")), "petId");
+        assert_eq!(rename("pet_id", Some("PascalCase")), "PetId");
+        assert_eq!(rename("pet_id", Some("lowercase")), "pet_id");
+        assert_eq!(rename("pet_id", Some("UPPERCASE")), "PET_ID");
+        assert_eq!(rename("pet_id", Some("SCREAMING_SNAKE_CASE")), "PET_ID");
+        assert_eq!(rename("pet_id", Some("kebab-case")), "pet-id");
+        assert_eq!(rename("pet_id", Some("SCREAMING-KEBAB-CASE")), "PET-ID");
+    }
+
+    #[test]
+    fn rename_variant_follows_serde() {
+        assert_eq!(rename_variant("PetId", None), "PetId");
+        assert_eq!(rename_variant("PetId", Some("lowercase")), "petid");
+        assert_eq!(rename_variant("PetId", Some("UPPERCASE")), "PETID");
+        assert_eq!(rename_variant("PetId", Some("snake_case")), "pet_id");
+        assert_eq!(rename_variant("PetId", Some("camelCase")), "petId");
+        assert_eq!(rename_variant("PetId", Some("kebab-case")), "pet-id");
+    }
+
+    #[test]
+    fn camel_case_joins_words() {
+        assert_eq!(camel_case("list_pets"), "listPets");
+        assert_eq!(camel_case("listPets"), "listPets");
+        assert_eq!(camel_case("show-pet-by-id"), "showPetById");
+        assert_eq!(camel_case("_private"), "private");
+    }
+
+    #[test]
+    fn ts_type_maps_rust_types() {
+        assert_eq!(ts("String", &[]), "string");
+        assert_eq!(ts("&str", &[]), "string");
+        assert_eq!(ts("chrono::DateTime<Utc>", &[]), "string");
+        assert_eq!(ts("i64", &[]), "number");
+        assert_eq!(ts("bool", &[]), "boolean");
+        assert_eq!(ts("()", &[]), "null");
+        assert_eq!(ts("(i32, String)", &[]), "[number, string]");
+        assert_eq!(ts("Option<String>", &[]), "string | null");
+        assert_eq!(ts("Vec<Option<i32>>", &[]), "(number | null)[]");
+        assert_eq!(ts("HashMap<String, bool>", &[]), "Record<string, boolean>");
+        assert_eq!(ts("Json<Vec<Pet>>", &["Pet"]), "Pet[]");
+        assert_eq!(ts("Pet", &[]), "unknown");
+        assert_eq!(ts("Option<Pet>", &[]), "unknown");
+    }
+}
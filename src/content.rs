@@ -0,0 +1,404 @@
+// content.rs
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, capitalize,
+    cargo_add, nest_module_router_in_main_rs, set_config, unparse, write_rust_file,
+};
+use crate::engine::{require_template_files, view, view_imports, TemplateEngine};
+use crate::tracing;
+use cliclack::log::{error, info};
+use cliclack::{outro, spinner};
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+/// `krk add content <collection>`: markdown files in `content/<collection>`
+/// served as a list, detail pages, tag pages and an atom feed.
+pub fn add_content(collection: &str) -> io::Result<()> {
+    if !require_template_files("content")? {
+        return Ok(());
+    }
+    if syn::parse_str::<Ident>(collection).is_err() {
+        error(format!(
+            "\"{collection}\" has to be a valid rust module name, e.g. blog or docs."
+        ))?;
+        return Ok(());
+    }
+    if Path::new(&format!("src/kraken/{collection}.rs")).exists() {
+        error(format!("Collection {collection} already exists."))?;
+        return Ok(());
+    }
+
+    if !Path::new("src/kraken/content.rs").exists() {
+        let mut spinner = spinner();
+        spinner.start("Adding crates!");
+        cargo_add(&["pulldown-cmark"]);
+        cargo_add(&["serde", "-F", "derive"]);
+        cargo_add(&["toml"]);
+        cargo_add(&["serde_yaml"]);
+        spinner.stop("Crates have arrived!");
+
+        generate_content_mod_rs()?;
+        add_module_to_mod_rs("content")?;
+    }
+
+    let engine = TemplateEngine::current();
+    generate_collection_templates(collection)?;
+    generate_collection_mod_rs(engine, collection)?;
+    create_sample_entry(collection)?;
+
+    add_module_to_mod_rs(collection)?;
+    add_module_to_main_rs(collection)?;
+    add_kraken_to_main_rs()?;
+    nest_module_router_in_main_rs(collection, &format!("/{collection}"))?;
+
+    set_config("content", collection, format!("content/{collection}"))?;
+    add_feature("content")?;
+
+    info(format!(
+        "Write markdown with +++ toml +++ or --- yaml --- front matter in content/{collection}."
+    ))?;
+    outro(format!(
+        "Successfully created: /{collection} collection. 🎉"
+    ))
+}
+
+/// The shared markdown loader every collection uses.
+fn generate_content_mod_rs() -> io::Result<()> {
+    let code = quote! {
+        use pulldown_cmark::{html, Options, Parser};
+        use serde::{Deserialize, Serialize};
+        use std::fs;
+
+        #[derive(Deserialize)]
+        struct FrontMatter {
+            title: String,
+            date: String,
+            slug: Option<String>,
+            #[serde(default)]
+            summary: String,
+            #[serde(default)]
+            tags: Vec<String>,
+            #[serde(default)]
+            draft: bool,
+        }
+
+        #[derive(Clone, Serialize)]
+        pub struct Entry {
+            pub slug: String,
+            pub title: String,
+            /// `YYYY-MM-DD` or an RFC 3339 date time.
+            pub date: String,
+            pub summary: String,
+            pub tags: Vec<String>,
+            pub draft: bool,
+            pub html: String,
+        }
+
+        /// Reads every markdown file of `content/<collection>`, newest first.
+        /// Drafts are only kept in debug builds.
+        pub fn load(collection: &str) -> Vec<Entry> {
+            let dir = format!("content/{collection}");
+            let files = fs::read_dir(&dir).unwrap_or_else(|err| panic!("Failed to read {dir}: {err}"));
+
+            let mut entries: Vec<Entry> = files
+                .filter_map(|file| file.ok())
+                .map(|file| file.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "md"))
+                .map(|path| {
+                    let source = fs::read_to_string(&path)
+                        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", path.display()));
+                    let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                    parse(&file_stem, &source)
+                        .unwrap_or_else(|err| panic!("Invalid {}: {err}", path.display()))
+                })
+                .filter(|entry| cfg!(debug_assertions) || !entry.draft)
+                .collect();
+            entries.sort_by(|a, b| b.date.cmp(&a.date));
+            entries
+        }
+
+        fn parse(file_stem: &str, source: &str) -> Result<Entry, String> {
+            let (front_matter, markdown) = split_front_matter(source)?;
+
+            // `get` is None when byte 10 is inside a character
+            let is_date = front_matter.date.get(..10).is_some_and(|date| {
+                date.chars()
+                    .enumerate()
+                    .all(|(index, c)| if index == 4 || index == 7 { c == '-' } else { c.is_ascii_digit() })
+            });
+            if !is_date {
+                return Err(format!("date \"{}\" is not YYYY-MM-DD", front_matter.date));
+            }
+
+            let mut html = String::new();
+            html::push_html(&mut html, Parser::new_ext(markdown, Options::all()));
+
+            Ok(Entry {
+                slug: front_matter.slug.unwrap_or_else(|| file_stem.to_string()),
+                title: front_matter.title,
+                date: front_matter.date,
+                summary: front_matter.summary,
+                tags: front_matter.tags,
+                draft: front_matter.draft,
+                html,
+            })
+        }
+
+        /// `+++` fences toml front matter, `---` fences yaml.
+        fn split_front_matter(source: &str) -> Result<(FrontMatter, &str), String> {
+            let source = source.trim_start_matches('\u{feff}').trim_start();
+            let (fence, is_toml) = if source.starts_with("+++") {
+                ("+++", true)
+            } else if source.starts_with("---") {
+                ("---", false)
+            } else {
+                return Err("missing +++ toml or --- yaml front matter".to_string());
+            };
+
+            let (front_matter, markdown) = source[3..]
+                .split_once(&format!("\n{fence}"))
+                .ok_or("unclosed front matter")?;
+            let markdown = markdown.split_once('\n').map_or("", |(_, markdown)| markdown);
+
+            let front_matter: FrontMatter = if is_toml {
+                let mut table: toml::Table = front_matter.parse().map_err(|err| format!("{err}"))?;
+                if let Some(toml::Value::Datetime(date)) = table.get("date") {
+                    let date = date.to_string();
+                    table.insert("date".to_string(), toml::Value::String(date));
+                }
+                toml::Value::Table(table)
+                    .try_into()
+                    .map_err(|err| format!("{err}"))?
+            } else {
+                serde_yaml::from_str(front_matter).map_err(|err| format!("{err}"))?
+            };
+
+            Ok((front_matter, markdown))
+        }
+
+        fn escape(text: &str) -> String {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        }
+
+        fn rfc3339(date: &str) -> String {
+            if date.len() == 10 {
+                format!("{date}T00:00:00Z")
+            } else {
+                date.to_string()
+            }
+        }
+
+        /// An atom feed of `entries`, `url` is the absolute url of the collection.
+        pub fn atom(title: &str, url: &str, entries: &[Entry]) -> String {
+            let updated = entries.first().map_or_else(String::new, |entry| rfc3339(&entry.date));
+            let mut feed = format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n  <title>{}</title>\n  <id>{url}</id>\n  <link href=\"{url}\"/>\n  <link rel=\"self\" href=\"{url}/feed.xml\"/>\n  <updated>{updated}</updated>\n",
+                escape(title)
+            );
+            for entry in entries {
+                feed += &format!(
+                    "  <entry>\n    <title>{}</title>\n    <id>{url}/{slug}</id>\n    <link href=\"{url}/{slug}\"/>\n    <updated>{}</updated>\n    <summary>{}</summary>\n    <content type=\"html\">{}</content>\n  </entry>\n",
+                    escape(&entry.title),
+                    rfc3339(&entry.date),
+                    escape(&entry.summary),
+                    escape(&entry.html),
+                    slug = entry.slug,
+                );
+            }
+            feed + "</feed>\n"
+        }
+    };
+
+    write_rust_file("src/kraken/content.rs", unparse(code))
+}
+
+/// Routes and views of one collection.
+fn generate_collection_mod_rs(engine: TemplateEngine, collection: &str) -> io::Result<()> {
+    let title = capitalize(collection);
+    let index_template = format!("{collection}/index.html");
+    let entry_template = format!("{collection}/entry.html");
+    let imports = view_imports(engine);
+    let index_view = view(
+        engine,
+        &Ident::new("IndexTemplate", Span::call_site()),
+        &index_template,
+        quote! {},
+        quote! {
+            title: &'static str,
+            tag: String,
+            entries: Vec<&'static Entry>,
+        },
+    );
+    let entry_view = view(
+        engine,
+        &Ident::new("EntryTemplate", Span::call_site()),
+        &entry_template,
+        quote! {},
+        quote! {
+            title: &'static str,
+            entry: &'static Entry,
+        },
+    );
+
+    let code = quote! {
+        use crate::kraken::content::{self, Entry};
+        use axum::extract::Path;
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::routing::get;
+        use axum::Router;
+        use std::sync::OnceLock;
+        #imports
+
+        static ENTRIES: OnceLock<Vec<Entry>> = OnceLock::new();
+
+        fn entries() -> &'static [Entry] {
+            ENTRIES.get_or_init(|| content::load(#collection))
+        }
+
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            entries();
+
+            Router::new()
+                .route("/", get(index))
+                .route("/feed.xml", get(feed))
+                .route("/tags/:tag", get(tag))
+                .route("/:slug", get(entry))
+        }
+
+        #index_view
+
+        #entry_view
+
+        async fn index() -> impl IntoResponse {
+            IndexTemplate {
+                title: #title,
+                tag: String::new(),
+                entries: entries().iter().collect(),
+            }
+        }
+
+        async fn tag(Path(tag): Path<String>) -> impl IntoResponse {
+            IndexTemplate {
+                title: #title,
+                entries: entries()
+                    .iter()
+                    .filter(|entry| entry.tags.contains(&tag))
+                    .collect(),
+                tag,
+            }
+        }
+
+        async fn entry(Path(slug): Path<String>) -> impl IntoResponse {
+            match entries().iter().find(|entry| entry.slug == slug) {
+                Some(entry) => EntryTemplate {
+                    title: &entry.title,
+                    entry,
+                }
+                .into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+
+        async fn feed(headers: HeaderMap) -> impl IntoResponse {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("localhost");
+            let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+                "http"
+            } else {
+                "https"
+            };
+            let url = format!("{scheme}://{host}/{}", #collection);
+            (
+                [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+                content::atom(#title, &url, entries()),
+            )
+        }
+    };
+
+    write_rust_file(
+        &format!("src/kraken/{collection}.rs"),
+        tracing::instrument(code.to_string()),
+    )
+}
+
+fn generate_collection_templates(collection: &str) -> io::Result<()> {
+    create_dir_all(format!("templates/{collection}"))?;
+
+    fs::write(
+        format!("templates/{collection}/index.html"),
+        format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block head %}}
+<link rel="alternate" type="application/atom+xml" title="{{{{ title }}}}" href="/{collection}/feed.xml" />
+{{% endblock %}}
+{{% block content %}}
+<main class="mx-auto max-w-2xl px-4 py-12">
+  <h1 class="text-4xl font-black">{{{{ title }}}}{{% if tag != "" %}} #{{{{ tag }}}}{{% endif %}}</h1>
+  <ul class="mt-8 space-y-8">
+    {{% for entry in entries %}}
+    <li>
+      <a class="text-2xl font-bold hover:underline" href="/{collection}/{{{{ entry.slug }}}}">{{{{ entry.title }}}}</a>
+      <p class="text-sm text-gray-500">{{{{ entry.date }}}}</p>
+      <p class="mt-2">{{{{ entry.summary }}}}</p>
+    </li>
+    {{% endfor %}}
+  </ul>
+</main>
+{{% endblock %}}
+"#
+        ),
+    )?;
+
+    fs::write(
+        format!("templates/{collection}/entry.html"),
+        format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block content %}}
+<main class="mx-auto max-w-2xl px-4 py-12">
+  <a class="text-sm hover:underline" href="/{collection}">&larr; {collection}</a>
+  <h1 class="mt-4 text-4xl font-black">{{{{ entry.title }}}}</h1>
+  <p class="text-sm text-gray-500">{{{{ entry.date }}}}</p>
+  <p class="mt-2 space-x-2">
+    {{% for tag in entry.tags %}}
+    <a class="text-sm hover:underline" href="/{collection}/tags/{{{{ tag }}}}">#{{{{ tag }}}}</a>
+    {{% endfor %}}
+  </p>
+  <article class="prose mt-8">
+    {{{{ entry.html|safe }}}}
+  </article>
+</main>
+{{% endblock %}}
+"#
+        ),
+    )
+}
+
+fn create_sample_entry(collection: &str) -> io::Result<()> {
+    create_dir_all(format!("content/{collection}"))?;
+    let path = format!("content/{collection}/hello-world.md");
+    if Path::new(&path).exists() {
+        return Ok(());
+    }
+    fs::write(
+        path,
+        r#"+++
+title = "Hello, world!"
+date = 2024-01-01
+summary = "The first entry, written in markdown."
+tags = ["kraken"]
+draft = false
++++
+
+Edit or replace this file, every `.md` file in this folder becomes an entry.
+"#,
+    )
+}
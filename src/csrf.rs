@@ -0,0 +1,387 @@
+// csrf.rs
+//! `krk add csrf`: a double submit token checked on every unsafe request, sent
+//! back by post forms in a hidden input and by htmx in a header.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, check_feature, get_config,
+    unparse, write_rust_file, BASE_HTML,
+};
+use crate::ast;
+use crate::engine::{
+    generate_maud_layout, generate_templates_mod_rs, inject_into_layout, TemplateEngine,
+};
+use crate::html::{self, Snippet, Target};
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info};
+use cliclack::outro;
+use quote::quote;
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The hidden input, as the template engine calls the generated helper.
+fn input_tag(engine: TemplateEngine) -> &'static str {
+    match engine {
+        TemplateEngine::Minijinja => "{{ csrf_input() }}",
+        TemplateEngine::Tera => "{{ csrf_input() | safe }}",
+        _ => "{{ crate::kraken::csrf::input()|safe }}",
+    }
+}
+
+fn token_expression(engine: TemplateEngine) -> &'static str {
+    match engine {
+        TemplateEngine::Minijinja | TemplateEngine::Tera => "{{ csrf_token() }}",
+        _ => "{{ crate::kraken::csrf::token() }}",
+    }
+}
+
+/// Puts the hidden token input into every post form of `html` that doesn't
+/// have it yet. Leaves `html` alone unless the project has csrf.
+pub fn with_token_inputs(html: &str) -> String {
+    let engine = TemplateEngine::current();
+    if get_config("features", "csrf").is_none() || !engine.uses_template_files() {
+        return html.to_string();
+    }
+    let form = Regex::new(r"(?i)<form\b[^>]*>").unwrap();
+    let post = Regex::new(r#"(?i)\smethod\s*=\s*["']?post\b"#).unwrap();
+
+    let mut protected = String::with_capacity(html.len());
+    let mut copied = 0;
+    for tag in form.find_iter(html) {
+        let body = &html[tag.end()..];
+        let body = &body[..body
+            .to_ascii_lowercase()
+            .find("</form")
+            .unwrap_or(body.len())];
+        if !post.is_match(tag.as_str()) || body.contains("csrf") {
+            continue;
+        }
+        let line_start = html[..tag.start()].rfind('\n').map_or(0, |index| index + 1);
+        let line = &html[line_start..];
+        let indent = &line[..line.len() - line.trim_start().len()];
+        protected.push_str(&html[copied..tag.end()]);
+        protected.push_str(&format!("\n{indent}  {}", input_tag(engine)));
+        copied = tag.end();
+    }
+    protected.push_str(&html[copied..]);
+    protected
+}
+
+/// Writes a generated template, with the token in its post forms.
+pub fn write_template(path: impl AsRef<Path>, html: &str) -> io::Result<()> {
+    fs::write(path, with_token_inputs(html))
+}
+
+/// Every file is restored if a step fails.
+pub fn add_csrf() -> io::Result<()> {
+    if check_feature("csrf").is_err() {
+        info("Failed to add csrf!!!")?;
+        return Ok(());
+    }
+    let engine = TemplateEngine::current();
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(engine) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add csrf ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    if engine.uses_template_files() {
+        info(format!(
+            "New post forms get the token from `{}`.",
+            input_tag(engine)
+        ))?;
+    } else {
+        info("Post forms need `(PreEscaped(crate::kraken::csrf::input()))`.")?;
+    }
+    info("`krk gen client` and other scripts send the csrf-token meta tag as the x-csrf-token header.")?;
+    outro("Successfully added csrf protection to every unsafe request. 🎉")
+}
+
+fn scaffold(engine: TemplateEngine) -> io::Result<()> {
+    cargo_add(&["rand@0.8"]);
+    cargo_add(&["form_urlencoded"]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    generate_csrf_rs()?;
+    add_module_to_mod_rs("csrf")?;
+    add_kraken_to_main_rs()?;
+    ast::add_router_layer("axum::middleware::from_fn(kraken::csrf::protect)")?;
+
+    // Templates, the renderer and the maud layout all follow the feature
+    add_feature("csrf")?;
+    match engine {
+        TemplateEngine::Maud => return generate_maud_layout(),
+        TemplateEngine::Minijinja | TemplateEngine::Tera => generate_templates_mod_rs(engine)?,
+        TemplateEngine::Askama => {}
+    }
+
+    let token = token_expression(engine);
+    inject_into_layout(
+        &Snippet {
+            asset: "csrf",
+            identities: &[r#"name="csrf-token""#],
+            html: &format!(r#"<meta name="csrf-token" content="{token}" />"#),
+        },
+        Target::Head,
+    )?;
+    if html::set_attribute(
+        BASE_HTML,
+        "body",
+        "hx-headers",
+        &format!(r#"{{"x-csrf-token": "{token}"}}"#),
+    )? {
+        info("Added hx-headers to <body>, htmx sends the token with every request.")?;
+    }
+    protect_templates(Path::new("templates"))
+}
+
+/// Adds the hidden input to the post forms that were generated before.
+fn protect_templates(dir: &Path) -> io::Result<()> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            protect_templates(&path)?;
+            continue;
+        }
+        if path.extension().is_none_or(|extension| extension != "html") {
+            continue;
+        }
+        let html = fs::read_to_string(&path)?;
+        let protected = with_token_inputs(&html);
+        if protected != html {
+            fs::write(&path, protected)?;
+            info(format!(
+                "Added the token to the forms of {}.",
+                path.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn generate_csrf_rs() -> io::Result<()> {
+    write_rust_file(
+        "src/kraken/csrf.rs",
+        unparse(quote! {
+            //! Double submit csrf protection. Every visitor gets a random token in a
+            //! cookie, and unsafe requests have to send the same token back. Other
+            //! sites can make a browser send the cookie, but they can't read it.
+            use axum::body::{to_bytes, Body};
+            use axum::extract::Request;
+            use axum::http::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+            use axum::http::{HeaderMap, HeaderValue, StatusCode};
+            use axum::middleware::Next;
+            use axum::response::{IntoResponse, Response};
+
+            pub const COOKIE_NAME: &str = "csrf";
+            /// The hidden input of post forms.
+            pub const FIELD: &str = "_csrf";
+            /// What htmx sends through `hx-headers` on `<body>`.
+            pub const HEADER: &str = "x-csrf-token";
+            /// Larger form posts are refused before their token is read.
+            const FORM_LIMIT: usize = 2 * 1024 * 1024;
+
+            tokio::task_local! {
+                static TOKEN: String;
+            }
+
+            /// The token of the request being handled, empty outside of [`protect`].
+            pub fn token() -> String {
+                TOKEN.try_with(Clone::clone).unwrap_or_default()
+            }
+
+            /// The hidden input a post form sends the token back in.
+            pub fn input() -> String {
+                format!(r#"<input type="hidden" name="{FIELD}" value="{}" />"#, token())
+            }
+
+            /// Hands out the token and refuses unsafe requests that don't send it
+            /// back in the `x-csrf-token` header or the `_csrf` form field. JSON
+            /// requests pass, browsers only send them cross site after a CORS
+            /// preflight.
+            pub async fn protect(request: Request, next: Next) -> Response {
+                let cookie = cookie_token(request.headers());
+                let request = if request.method().is_safe() || is_json(request.headers()) {
+                    request
+                } else {
+                    let Some(expected) = cookie.as_deref() else {
+                        return forbidden();
+                    };
+                    let (request, submitted) = match submitted_token(request).await {
+                        Ok(submitted) => submitted,
+                        Err(response) => return response,
+                    };
+                    if !submitted.is_some_and(|submitted| same(&submitted, expected)) {
+                        return forbidden();
+                    }
+                    request
+                };
+
+                let token = cookie.clone().unwrap_or_else(random);
+                let mut response = TOKEN.scope(token.clone(), next.run(request)).await;
+                if cookie.is_none() {
+                    let cookie = format!("{COOKIE_NAME}={token}; Path=/; HttpOnly; SameSite=Lax");
+                    if let Ok(value) = HeaderValue::from_str(&cookie) {
+                        response.headers_mut().append(SET_COOKIE, value);
+                    }
+                }
+                response
+            }
+
+            fn forbidden() -> Response {
+                (
+                    StatusCode::FORBIDDEN,
+                    "The page expired, reload it and try again.",
+                )
+                    .into_response()
+            }
+
+            /// 32 random bytes as hex.
+            fn random() -> String {
+                rand::random::<[u8; 32]>()
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect()
+            }
+
+            /// Only tokens this module could have handed out, they end up in html
+            /// unescaped.
+            fn cookie_token(headers: &HeaderMap) -> Option<String> {
+                headers
+                    .get_all(COOKIE)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|cookies| cookies.split(';'))
+                    .filter_map(|cookie| cookie.trim().split_once('='))
+                    .find(|(name, _)| *name == COOKIE_NAME)
+                    .map(|(_, token)| token.to_string())
+                    .filter(|token| token.len() == 64 && token.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            }
+
+            fn content_type(headers: &HeaderMap) -> &str {
+                headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+            }
+
+            fn is_json(headers: &HeaderMap) -> bool {
+                content_type(headers).starts_with("application/json")
+            }
+
+            /// The header, or the field of a urlencoded form. The body is read to
+            /// find it and put back for the handler. Multipart forms have to send
+            /// the header.
+            async fn submitted_token(request: Request) -> Result<(Request, Option<String>), Response> {
+                if let Some(header) = request.headers().get(HEADER) {
+                    let token = header.to_str().ok().map(str::to_string);
+                    return Ok((request, token));
+                }
+                if !content_type(request.headers()).starts_with("application/x-www-form-urlencoded") {
+                    return Ok((request, None));
+                }
+
+                let (parts, body) = request.into_parts();
+                let bytes = to_bytes(body, FORM_LIMIT)
+                    .await
+                    .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+                let token = form_urlencoded::parse(&bytes)
+                    .find(|(name, _)| name == FIELD)
+                    .map(|(_, token)| token.into_owned());
+                Ok((Request::from_parts(parts, Body::from(bytes)), token))
+            }
+
+            /// Compares in constant time, so the token can't be guessed byte by byte.
+            fn same(submitted: &str, expected: &str) -> bool {
+                submitted.len() == expected.len()
+                    && submitted
+                        .bytes()
+                        .zip(expected.bytes())
+                        .fold(0, |difference, (a, b)| difference | (a ^ b))
+                        == 0
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use axum::middleware::from_fn;
+                use axum::routing::get;
+                use axum::Router;
+                use tower::ServiceExt;
+
+                fn app() -> Router {
+                    Router::new()
+                        .route("/", get(|| async { input() }).post(|body: String| async move { body }))
+                        .layer(from_fn(protect))
+                }
+
+                fn post(cookie: Option<&str>) -> axum::http::request::Builder {
+                    let request = Request::post("/").header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+                    match cookie {
+                        Some(token) => request.header(COOKIE, format!("theme=dark; {COOKIE_NAME}={token}")),
+                        None => request,
+                    }
+                }
+
+                async fn send(request: Request) -> (StatusCode, String) {
+                    let response = app().oneshot(request).await.unwrap();
+                    let status = response.status();
+                    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    (status, String::from_utf8(body.to_vec()).unwrap())
+                }
+
+                #[tokio::test]
+                async fn a_visit_hands_out_the_token_the_form_shows() {
+                    let response = app()
+                        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+                        .await
+                        .unwrap();
+                    let cookie = response.headers()[SET_COOKIE].to_str().unwrap().to_string();
+                    let token = &cookie[COOKIE_NAME.len() + 1..COOKIE_NAME.len() + 65];
+                    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    assert!(String::from_utf8(body.to_vec()).unwrap().contains(token));
+                }
+
+                #[tokio::test]
+                async fn a_form_with_the_token_passes_with_its_body() {
+                    let token = random();
+                    let body = format!("name=Ada&{FIELD}={token}");
+                    let request = post(Some(&token)).body(Body::from(body.clone())).unwrap();
+                    assert_eq!(send(request).await, (StatusCode::OK, body));
+                }
+
+                #[tokio::test]
+                async fn htmx_sends_the_token_in_a_header() {
+                    let token = random();
+                    let request = post(Some(&token))
+                        .header(HEADER, &token)
+                        .body(Body::from("name=Ada"))
+                        .unwrap();
+                    assert_eq!(send(request).await.0, StatusCode::OK);
+                }
+
+                #[tokio::test]
+                async fn a_missing_or_wrong_token_is_refused() {
+                    let token = random();
+                    let without_field = post(Some(&token)).body(Body::from("name=Ada")).unwrap();
+                    assert_eq!(send(without_field).await.0, StatusCode::FORBIDDEN);
+
+                    let body = format!("{FIELD}={}", random());
+                    let wrong = post(Some(&token)).body(Body::from(body)).unwrap();
+                    assert_eq!(send(wrong).await.0, StatusCode::FORBIDDEN);
+
+                    let body = format!("{FIELD}={token}");
+                    let without_cookie = post(None).body(Body::from(body)).unwrap();
+                    assert_eq!(send(without_cookie).await.0, StatusCode::FORBIDDEN);
+                }
+            }
+        }),
+    )
+}
@@ -0,0 +1,401 @@
+// database.rs
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, check_feature, get_config,
+    set_config, unparse, write_rust_file,
+};
+use crate::ast;
+use crate::health;
+use crate::state::{add_state_field, StateField};
+use clap::ValueEnum;
+use cliclack::log::{error, info, warning};
+use cliclack::{outro, select, spinner};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+/// sea-orm and the `sea-orm-migration` of the migration crate have to share a
+/// version, or the migrator gets a connection type it does not know.
+const SEA_ORM: &str = "sea-orm@1.1";
+
+/// How the app talks to postgres, stored as `orm` in the `[database]` table
+/// of Kraken.toml so generators emit matching query code.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Orm {
+    /// Plain sql, checked at compile time
+    Sqlx,
+    /// SeaORM, an async ORM with a migration crate
+    #[value(name = "sea-orm")]
+    Sea,
+    /// Query builder over diesel-async with a deadpool
+    Diesel,
+}
+
+impl Orm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sqlx => "sqlx",
+            Self::Sea => "sea-orm",
+            Self::Diesel => "diesel",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Sqlx, Self::Sea, Self::Diesel]
+            .into_iter()
+            .find(|orm| orm.name() == name)
+    }
+
+    /// The configured orm. Databases from before the choice existed used sqlx.
+    pub fn current() -> Self {
+        get_config("database", "orm")
+            .and_then(|item| item.as_str().and_then(Self::from_name))
+            .unwrap_or(Self::Sqlx)
+    }
+
+    /// The `AppState` field holding the connection.
+    pub fn state_field(self) -> &'static str {
+        match self {
+            Self::Sea => "db",
+            _ => "pool",
+        }
+    }
+
+    /// The type of the connection field of `AppState`.
+    fn state_type(self) -> &'static str {
+        match self {
+            Self::Sqlx => "sqlx::PgPool",
+            Self::Sea => "sea_orm::DatabaseConnection",
+            Self::Diesel => "crate::kraken::database::DbPool",
+        }
+    }
+
+    fn dependencies(self) -> Vec<Vec<&'static str>> {
+        match self {
+            Self::Sqlx => vec![
+                vec!["shuttle-shared-db", "-F", "postgres,sqlx"],
+                vec![
+                    "sqlx",
+                    "-F",
+                    "runtime-tokio,tls-rustls,postgres,macros,migrate",
+                ],
+            ],
+            Self::Sea => vec![
+                vec!["shuttle-shared-db", "-F", "postgres"],
+                vec![SEA_ORM, "-F", "sqlx-postgres,runtime-tokio-rustls,macros"],
+                vec!["migration", "--path", "migration"],
+            ],
+            Self::Diesel => vec![
+                vec!["shuttle-shared-db", "-F", "postgres"],
+                vec!["diesel", "-F", "postgres_backend"],
+                vec![
+                    "diesel-async",
+                    "-F",
+                    "postgres,deadpool,async-connection-wrapper",
+                ],
+                vec!["diesel_migrations"],
+            ],
+        }
+    }
+
+    /// The parameter shuttle provisions the database through. sqlx gets a
+    /// pool, the others a connection string.
+    fn main_param(self, local_uri: Option<&str>) -> String {
+        let attribute = match local_uri {
+            Some(uri) => format!("#[shuttle_shared_db::Postgres(local_uri = {uri:?})]"),
+            None => "#[shuttle_shared_db::Postgres]".to_string(),
+        };
+        match self {
+            Self::Sqlx => format!("{attribute} pool: PgPool"),
+            _ => format!("{attribute} database_url: String"),
+        }
+    }
+
+    /// What the main function runs before building the router.
+    fn setup(self) -> String {
+        match self {
+            Self::Sqlx => {
+                "kraken::database::migrate(&pool).await.expect(\"Failed to run migrations\");"
+                    .to_string()
+            }
+            _ => format!(
+                "let {} = kraken::database::connect(&database_url).await.expect(\"Failed to connect to the database\");",
+                self.state_field()
+            ),
+        }
+    }
+}
+
+pub fn select_orm() -> io::Result<Orm> {
+    let name: &str = select("Pick how to talk to the database")
+        .item("sqlx", "sqlx", "Plain sql, checked at compile time")
+        .item("sea-orm", "SeaORM", "Async ORM with a migration crate")
+        .item("diesel", "Diesel", "Query builder, diesel-async")
+        .interact()?;
+    Ok(Orm::from_name(name).unwrap_or(Orm::Sqlx))
+}
+
+/// `krk add database`: a shuttle shared postgres connection in `AppState`,
+/// with pending migrations applied at startup. `local_uri` points
+/// `shuttle run` at an existing postgres instead of a docker container.
+pub fn add_database(orm: Orm, local_uri: Option<&str>) -> io::Result<()> {
+    if check_feature("database").is_err() {
+        error(format!(
+            "This project already has a database with {}!",
+            Orm::current().name()
+        ))?;
+        return info("Failed to add database!!!");
+    }
+
+    // The migration crate has to exist before cargo can depend on it
+    match orm {
+        Orm::Sea => create_migration_crate()?,
+        _ => create_migrations_dir()?,
+    }
+
+    let mut spinner = spinner();
+    spinner.start("Adding crates!");
+    for dependency in orm.dependencies() {
+        cargo_add(&dependency);
+    }
+    spinner.stop("Crates have arrived!");
+
+    generate_database_mod_rs(orm)?;
+    generate_models_mod_rs(orm)?;
+    add_module_to_mod_rs("database")?;
+    add_module_to_mod_rs("models")?;
+    if orm == Orm::Diesel {
+        generate_diesel_schema()?;
+        add_module_to_mod_rs("schema")?;
+    }
+    add_kraken_to_main_rs()?;
+
+    if orm == Orm::Sqlx {
+        ast::add_item("use sqlx::PgPool;")?;
+    }
+    ast::add_main_param(&orm.main_param(local_uri))?;
+    ast::prepend_main_stmts(&orm.setup(), "kraken :: database ::")?;
+    add_state_field(&StateField {
+        name: orm.state_field(),
+        ty: orm.state_type(),
+        init: None,
+        doc: "The database connection pool.",
+    })?;
+
+    set_config("database", "engine", "postgres")?;
+    set_config("database", "orm", orm.name())?;
+    add_feature("database")?;
+    // /readyz pings the pool from now on
+    health::refresh()?;
+
+    match orm {
+        Orm::Sqlx => info("Add migrations with `sqlx migrate add <name>`, they run on startup.")?,
+        Orm::Sea => {
+            info("Add migrations with `sea-orm-cli migrate generate <name>`, they run on startup.")?
+        }
+        Orm::Diesel => {
+            info("Add migrations with `diesel migration generate <name>`, they run on startup.")?
+        }
+    }
+    info("`DATABASE_URL=postgres://... cargo test` runs the database tests in src/kraken/database.rs against any postgres.")?;
+    outro(format!(
+        "Database with {} added successfully. 🎉",
+        orm.name()
+    ))
+}
+
+/// sqlx and diesel embed `migrations/` at compile time, so cargo has to
+/// rebuild when a migration is added.
+fn create_migrations_dir() -> io::Result<()> {
+    create_dir_all("migrations")?;
+    if fs::read_dir("migrations")?.next().is_none() {
+        fs::write("migrations/.gitkeep", "")?;
+    }
+
+    let rerun = "println!(\"cargo:rerun-if-changed=migrations\");";
+    if !Path::new("build.rs").exists() {
+        return write_rust_file("build.rs", format!("fn main() {{\n    {rerun}\n}}\n"));
+    }
+    if !fs::read_to_string("build.rs")?.contains("rerun-if-changed=migrations") {
+        warning(format!(
+            "Add `{rerun}` to build.rs so new migrations are picked up."
+        ))?;
+    }
+    Ok(())
+}
+
+/// The `migration` crate `sea-orm-cli migrate` works with.
+fn create_migration_crate() -> io::Result<()> {
+    let version = SEA_ORM.trim_start_matches("sea-orm@");
+    if Path::new("migration/Cargo.toml").exists() {
+        return Ok(());
+    }
+    create_dir_all("migration/src")?;
+    fs::write(
+        "migration/Cargo.toml",
+        format!(
+            r#"[package]
+name = "migration"
+version = "0.1.0"
+edition = "2021"
+publish = false
+
+[lib]
+name = "migration"
+path = "src/lib.rs"
+
+[dependencies]
+sea-orm-migration = {{ version = "{version}", features = ["sqlx-postgres", "runtime-tokio-rustls"] }}
+tokio = {{ version = "1", features = ["macros", "rt-multi-thread"] }}
+"#
+        ),
+    )?;
+    write_rust_file(
+        "migration/src/lib.rs",
+        quote! {
+            pub use sea_orm_migration::prelude::*;
+
+            pub struct Migrator;
+
+            #[async_trait::async_trait]
+            impl MigratorTrait for Migrator {
+                fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+                    vec![]
+                }
+            }
+        },
+    )?;
+    write_rust_file(
+        "migration/src/main.rs",
+        quote! {
+            use sea_orm_migration::prelude::*;
+
+            #[tokio::main]
+            async fn main() {
+                cli::run_cli(migration::Migrator).await;
+            }
+        },
+    )
+}
+
+fn generate_database_mod_rs(orm: Orm) -> io::Result<()> {
+    let code = match orm {
+        Orm::Sqlx => quote! {
+            use sqlx::migrate::MigrateError;
+            use sqlx::PgPool;
+
+            /// Applies the migrations in `migrations/` that have not run yet.
+            pub async fn migrate(pool: &PgPool) -> Result<(), MigrateError> {
+                sqlx::migrate!().run(pool).await
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use sqlx::PgPool;
+
+                /// Every #[sqlx::test] gets a fresh database on the server in
+                /// DATABASE_URL with the migrations applied.
+                #[sqlx::test]
+                async fn migrations_apply(pool: PgPool) {
+                    let one: i32 = sqlx::query_scalar("SELECT 1")
+                        .fetch_one(&pool)
+                        .await
+                        .unwrap();
+                    assert_eq!(one, 1);
+                }
+            }
+        },
+        Orm::Sea => quote! {
+            use migration::{Migrator, MigratorTrait};
+            use sea_orm::{Database, DatabaseConnection, DbErr};
+
+            /// Connects and applies the migrations of the `migration` crate
+            /// that have not run yet.
+            pub async fn connect(url: &str) -> Result<DatabaseConnection, DbErr> {
+                let db = Database::connect(url).await?;
+                Migrator::up(&db, None).await?;
+                Ok(db)
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use sea_orm::ConnectionTrait;
+
+                #[tokio::test]
+                async fn migrations_apply() {
+                    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+                    let db = super::connect(&url).await.unwrap();
+                    db.execute_unprepared("SELECT 1").await.unwrap();
+                }
+            }
+        },
+        Orm::Diesel => quote! {
+            use diesel::Connection;
+            use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
+            use diesel_async::pooled_connection::deadpool::Pool;
+            use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+            use diesel_async::AsyncPgConnection;
+            use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+            use std::error::Error;
+
+            pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+            pub type DbPool = Pool<AsyncPgConnection>;
+
+            /// Applies the migrations in `migrations/` that have not run yet
+            /// and opens a connection pool.
+            pub async fn connect(url: &str) -> Result<DbPool, Box<dyn Error + Send + Sync>> {
+                let migrations_url = url.to_string();
+                tokio::task::spawn_blocking(move || {
+                    let mut connection =
+                        AsyncConnectionWrapper::<AsyncPgConnection>::establish(&migrations_url)?;
+                    connection.run_pending_migrations(MIGRATIONS)?;
+                    Ok::<_, Box<dyn Error + Send + Sync>>(())
+                })
+                .await??;
+
+                let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
+                Ok(Pool::builder(manager).build()?)
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use diesel_async::RunQueryDsl;
+
+                #[tokio::test]
+                async fn migrations_apply() {
+                    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+                    let pool = super::connect(&url).await.unwrap();
+                    let mut connection = pool.get().await.unwrap();
+                    diesel::sql_query("SELECT 1")
+                        .execute(&mut connection)
+                        .await
+                        .unwrap();
+                }
+            }
+        },
+    };
+    write_rust_file("src/kraken/database.rs", unparse(code))
+}
+
+/// `src/kraken/models.rs`, the parent of one module per table.
+fn generate_models_mod_rs(orm: Orm) -> io::Result<()> {
+    let doc = match orm {
+        Orm::Sqlx => "//! Row types and their queries, one module per table.\n",
+        Orm::Sea => "//! SeaORM entities, one module per table.\n",
+        Orm::Diesel => "//! Diesel models and their queries, one module per table of schema.rs.\n",
+    };
+    fs::write("src/kraken/models.rs", doc)
+}
+
+/// `diesel print-schema` writes the table! macros to `src/kraken/schema.rs`.
+fn generate_diesel_schema() -> io::Result<()> {
+    fs::write(
+        "diesel.toml",
+        "[print_schema]\nfile = \"src/kraken/schema.rs\"\n\n[migrations_directory]\ndir = \"migrations\"\n",
+    )?;
+    fs::write(
+        "src/kraken/schema.rs",
+        "//! Generated by `diesel print-schema`.\n",
+    )
+}
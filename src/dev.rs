@@ -0,0 +1,159 @@
+// dev.rs
+//! `krk dev`: `cargo shuttle run` that restarts when the project changes and
+//! keeps the client of `krk gen client` in step with the api.
+use crate::client::{recorded_client, regenerate_client};
+use cliclack::log::{error, info, success, warning};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+/// What the running binary is built from.
+const WATCHED: [&str; 7] = [
+    "src",
+    "templates",
+    "content",
+    "migrations",
+    "Cargo.toml",
+    "Secrets.toml",
+    "Secrets.dev.toml",
+];
+const API_DIR: &str = "src/kraken/api";
+const POLL: Duration = Duration::from_millis(500);
+
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+pub fn dev(args: &[String]) -> io::Result<()> {
+    let client = recorded_client().map(|(_, output)| output);
+    if let Some(output) = &client {
+        refresh_client(output)?;
+    }
+
+    let mut seen = snapshot(client.as_deref())?;
+    let mut server = Server(None);
+    server.restart(args)?;
+    loop {
+        sleep(POLL);
+
+        if let Some(child) = server.0.as_mut() {
+            if let Some(status) = child.try_wait()? {
+                warning(format!("The app stopped ({status}), waiting for changes."))?;
+                server.0 = None;
+            }
+        }
+
+        let mut now = snapshot(client.as_deref())?;
+        if now == seen {
+            continue;
+        }
+        // Editors write a file in more than one step.
+        sleep(POLL);
+        now = snapshot(client.as_deref())?;
+
+        let api_changed = changed(&seen, &now).any(|path| path.starts_with(API_DIR));
+        seen = now;
+        if let (Some(output), true) = (&client, api_changed) {
+            refresh_client(output)?;
+        }
+
+        info("Changes detected, restarting!")?;
+        server.restart(args)?;
+    }
+}
+
+/// The running `cargo shuttle run`, stopped however `krk dev` returns.
+struct Server(Option<Child>);
+
+impl Server {
+    fn restart(&mut self, args: &[String]) -> io::Result<()> {
+        if let Some(child) = self.0.take() {
+            stop(child)?;
+        }
+        self.0 = Some(run(args)?);
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(child) = self.0.take() {
+            let _ = stop(child);
+        }
+    }
+}
+
+fn run(args: &[String]) -> io::Result<Child> {
+    Command::new("cargo")
+        .args(["shuttle", "run"])
+        .args(args)
+        .spawn()
+}
+
+/// SIGTERM lets `cargo shuttle run` stop the app it started, a kill would
+/// leave it holding the port.
+fn stop(mut child: Child) -> io::Result<()> {
+    let terminated = cfg!(unix)
+        && Command::new("kill")
+            .arg(child.id().to_string())
+            .status()
+            .is_ok_and(|status| status.success());
+    if !terminated {
+        child.kill()?;
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn refresh_client(output: &str) -> io::Result<()> {
+    match regenerate_client(output) {
+        Ok(true) => success(format!("Regenerated {output}.")),
+        Ok(false) => Ok(()),
+        Err(err) => error(format!("Failed to regenerate {output}: {err}")),
+    }
+}
+
+fn changed<'a>(before: &'a Snapshot, after: &'a Snapshot) -> impl Iterator<Item = &'a PathBuf> {
+    let modified = after
+        .iter()
+        .filter(|(path, time)| before.get(*path) != Some(time))
+        .map(|(path, _)| path);
+    let removed = before.keys().filter(|path| !after.contains_key(*path));
+    modified.chain(removed)
+}
+
+/// The modification time of every watched file but the generated client.
+fn snapshot(client: Option<&str>) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    for root in WATCHED {
+        collect(Path::new(root), &mut snapshot)?;
+    }
+    if let Some(client) = client {
+        snapshot.remove(Path::new(client));
+    }
+    Ok(snapshot)
+}
+
+/// Files and directories removed while they are scanned are skipped, the next
+/// snapshot sees them gone.
+fn collect(path: &Path, snapshot: &mut Snapshot) -> io::Result<()> {
+    if path.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => collect(&entry.path(), snapshot)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+    } else if let Ok(metadata) = fs::metadata(path) {
+        snapshot.insert(path.to_path_buf(), metadata.modified()?);
+    }
+    Ok(())
+}
@@ -0,0 +1,462 @@
+// engine.rs
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, add_page, cargo_add, check_feature,
+    create_html_base_file, get_config, remove_feature, set_config, write_rust_file, BASE_HTML,
+};
+use crate::assets;
+use crate::html::{self, Snippet, Target};
+use clap::ValueEnum;
+use cliclack::log::{error, info};
+use cliclack::{confirm, select, spinner};
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::io;
+
+/// The template engine a project renders html with, stored as
+/// `template_engine` in the `[kraken]` table of Kraken.toml.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TemplateEngine {
+    /// Compile-time jinja templates
+    Askama,
+    /// Runtime jinja templates, reloaded in debug builds
+    Minijinja,
+    /// Runtime jinja2/django templates, reloaded in debug builds
+    Tera,
+    /// Compile-time html markup in rust
+    Maud,
+}
+
+impl TemplateEngine {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Askama => "askama",
+            Self::Minijinja => "minijinja",
+            Self::Tera => "tera",
+            Self::Maud => "maud",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Askama, Self::Minijinja, Self::Tera, Self::Maud]
+            .into_iter()
+            .find(|engine| engine.name() == name)
+    }
+
+    /// The configured engine. Projects from before the choice existed used
+    /// askama.
+    pub fn current() -> Self {
+        get_config("kraken", "template_engine")
+            .and_then(|item| item.as_str().and_then(Self::from_name))
+            .unwrap_or(Self::Askama)
+    }
+
+    /// Whether the engine is configured at all.
+    pub fn configured() -> bool {
+        get_config("kraken", "template_engine").is_some()
+            || get_config("features", Self::Askama.name()).is_some()
+    }
+
+    /// Engines that read `templates/*.html` files.
+    pub fn uses_template_files(self) -> bool {
+        self != Self::Maud
+    }
+
+    fn dependencies(self) -> Vec<Vec<&'static str>> {
+        match self {
+            Self::Askama => vec![vec!["askama", "-F", "askama/with-axum", "askama_axum"]],
+            Self::Minijinja => vec![
+                vec!["minijinja", "-F", "loader"],
+                vec!["serde", "-F", "derive"],
+            ],
+            Self::Tera => vec![vec!["tera"], vec!["serde", "-F", "derive"]],
+            Self::Maud => vec![vec!["maud", "-F", "axum"]],
+        }
+    }
+}
+
+pub fn select_template_engine() -> io::Result<TemplateEngine> {
+    let name: &str = select("Pick a template engine")
+        .item("askama", "Askama", "Compile-time jinja")
+        .item("minijinja", "Minijinja", "Runtime jinja, hot reload")
+        .item("tera", "Tera", "Runtime jinja2, hot reload")
+        .item("maud", "Maud", "Html markup in rust")
+        .interact()?;
+    Ok(TemplateEngine::from_name(name).unwrap_or(TemplateEngine::Askama))
+}
+
+pub fn add_template_engine(engine: TemplateEngine) -> io::Result<()> {
+    if let Err(err) = check_feature(engine.name()) {
+        error(err)?;
+        info(format!("Failed to add {}!!!", engine.name()))?;
+        return Ok(());
+    }
+    if TemplateEngine::configured() {
+        let current = TemplateEngine::current();
+        error(format!(
+            "This project already renders with {}!",
+            current.name()
+        ))?;
+        info(format!("Failed to add {}!!!", engine.name()))?;
+        return Ok(());
+    }
+
+    let mut spinner = spinner();
+    spinner.start("Adding crates!");
+    for dependency in engine.dependencies() {
+        cargo_add(&dependency);
+    }
+    spinner.stop("Crates have arrived!");
+
+    set_config("kraken", "template_engine", engine.name())?;
+
+    let layout = match engine {
+        TemplateEngine::Maud => generate_maud_layout(),
+        _ => create_html_base_file(),
+    };
+    match layout {
+        Ok(()) => info("Added base.html or layout!")?,
+        Err(_err) => error("Error Adding base.html!")?,
+    }
+
+    if matches!(engine, TemplateEngine::Minijinja | TemplateEngine::Tera) {
+        generate_templates_mod_rs(engine)?;
+        add_module_to_mod_rs("templates")?;
+        add_kraken_to_main_rs()?;
+    }
+
+    add_feature(engine.name())?;
+    info(format!("{} added successfully. 🎉", engine.name()))?;
+    if confirm("Do you want to create a page?")
+        .initial_value(true)
+        .interact()?
+    {
+        add_page(None, false)?;
+    }
+    Ok(())
+}
+
+/// The shared renderer of the runtime engines. Templates are parsed once in
+/// release builds and re-read on every render in debug builds. With csrf the
+/// templates can call `csrf_token()` and `csrf_input()`.
+pub fn generate_templates_mod_rs(engine: TemplateEngine) -> io::Result<()> {
+    let csrf = get_config("features", "csrf").is_some();
+    let (imports, environment, render) = match engine {
+        TemplateEngine::Minijinja => (
+            quote! { use minijinja::Environment; },
+            {
+                let functions = csrf.then(|| {
+                    quote! {
+                        environment.add_function("csrf_token", crate::kraken::csrf::token);
+                        environment.add_function("csrf_input", || {
+                            minijinja::Value::from_safe_string(crate::kraken::csrf::input())
+                        });
+                    }
+                });
+                quote! {
+                    type Templates = Environment<'static>;
+
+                    fn load() -> Templates {
+                        let mut environment = Environment::new();
+                        environment.set_loader(minijinja::path_loader("templates"));
+                        #functions
+                        environment
+                    }
+                }
+            },
+            quote! {
+                templates
+                    .get_template(name)
+                    .and_then(|template| template.render(context))
+                    .map_err(|err| err.to_string())
+            },
+        ),
+        _ => (
+            if csrf {
+                quote! {
+                    use std::collections::HashMap;
+                    use tera::{Context, Tera};
+                }
+            } else {
+                quote! { use tera::{Context, Tera}; }
+            },
+            if csrf {
+                quote! {
+                    type Templates = Tera;
+
+                    fn load() -> Templates {
+                        let mut tera = Tera::new("templates/**/*.html").expect("Failed to parse templates");
+                        tera.register_function("csrf_token", |_: &HashMap<String, tera::Value>| {
+                            Ok(crate::kraken::csrf::token().into())
+                        });
+                        tera.register_function("csrf_input", |_: &HashMap<String, tera::Value>| {
+                            Ok(crate::kraken::csrf::input().into())
+                        });
+                        tera
+                    }
+                }
+            } else {
+                quote! {
+                    type Templates = Tera;
+
+                    fn load() -> Templates {
+                        Tera::new("templates/**/*.html").expect("Failed to parse templates")
+                    }
+                }
+            },
+            quote! {
+                Context::from_serialize(context)
+                    .and_then(|context| templates.render(name, &context))
+                    .map_err(|err| err.to_string())
+            },
+        ),
+    };
+
+    write_rust_file(
+        "src/kraken/templates.rs",
+        quote! {
+            use axum::http::StatusCode;
+            use axum::response::{Html, IntoResponse, Response};
+            use serde::Serialize;
+            use std::sync::OnceLock;
+            #imports
+
+            #environment
+
+            pub fn render(name: &str, context: impl Serialize) -> Response {
+                static TEMPLATES: OnceLock<Templates> = OnceLock::new();
+                let reloaded;
+                let templates = if cfg!(debug_assertions) {
+                    reloaded = load();
+                    &reloaded
+                } else {
+                    TEMPLATES.get_or_init(load)
+                };
+
+                let rendered: Result<String, String> = #render;
+                match rendered {
+                    Ok(html) => Html(html).into_response(),
+                    Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+                }
+            }
+        },
+    )
+}
+
+/// The view type of a jinja style page, the handler returns it as is.
+/// `fields` become the template context.
+pub fn page_view(engine: TemplateEngine, template: &str, fields: TokenStream) -> TokenStream {
+    let imports = match engine {
+        TemplateEngine::Askama => quote! {
+            use askama::Template;
+            use askama_axum::IntoResponse;
+        },
+        _ => view_imports(engine),
+    };
+    let view = view(
+        engine,
+        &Ident::new("TheTemplate", Span::call_site()),
+        template,
+        quote! { <'a> },
+        fields,
+    );
+    quote! {
+        #imports
+
+        #view
+    }
+}
+
+/// What a module defining views with [`view`] has to import.
+pub fn view_imports(engine: TemplateEngine) -> TokenStream {
+    match engine {
+        TemplateEngine::Askama => quote! {
+            use askama::Template;
+            use axum::response::IntoResponse;
+        },
+        _ => quote! {
+            use axum::response::{IntoResponse, Response};
+            use serde::Serialize;
+        },
+    }
+}
+
+/// A struct rendering `template` with its fields as context. Askama derives
+/// the response, the runtime engines go through `kraken::templates`.
+pub fn view(
+    engine: TemplateEngine,
+    ident: &Ident,
+    template: &str,
+    generics: TokenStream,
+    fields: TokenStream,
+) -> TokenStream {
+    match engine {
+        TemplateEngine::Askama => quote! {
+            #[derive(Template)]
+            #[template(path = #template)]
+            struct #ident #generics {
+                #fields
+            }
+        },
+        _ => quote! {
+            #[derive(Serialize)]
+            struct #ident #generics {
+                #fields
+            }
+
+            impl #generics IntoResponse for #ident #generics {
+                fn into_response(self) -> Response {
+                    crate::kraken::templates::render(#template, self)
+                }
+            }
+        },
+    }
+}
+
+/// Scaffolds that write html templates need a jinja style engine.
+pub fn require_template_files(command: &str) -> io::Result<bool> {
+    let engine = TemplateEngine::current();
+    if engine.uses_template_files() {
+        return Ok(true);
+    }
+    error(format!(
+        "`krk add {command}` writes html templates, which {} does not use.",
+        engine.name()
+    ))?;
+    Ok(false)
+}
+
+/// A maud page: the markup lives in the handler and is wrapped in the layout.
+/// `auth` keeps the page to logged in users.
+pub fn generate_maud_page(page_name: &str, page_title: &str, auth: bool) -> io::Result<()> {
+    let (import, arg) = if auth {
+        (
+            "use crate::kraken::auth::RequireAuth;\n",
+            "RequireAuth(_user): RequireAuth",
+        )
+    } else {
+        ("", "")
+    };
+    write_rust_file(
+        &format!("src/kraken/{page_name}.rs"),
+        format!(
+            r#"{import}use crate::kraken::layout;
+use maud::{{html, Markup}};
+
+pub async fn main({arg}) -> Markup {{
+    layout::base(
+        {page_title:?},
+        html! {{
+            section class="bg-indigo-400 text-white font-black text-6xl" {{ "Hello" }}
+        }},
+    )
+}}
+"#
+        ),
+    )
+}
+
+/// Puts a head or body snippet into the layout of the current engine. The maud
+/// layout is rust, so it is regenerated from Kraken.toml instead. Returns
+/// whether the snippet is now in the layout.
+pub fn inject_into_layout(snippet: &Snippet, target: Target) -> io::Result<bool> {
+    if TemplateEngine::current() == TemplateEngine::Maud {
+        generate_maud_layout()?;
+        return Ok(true);
+    }
+    if !std::path::Path::new(BASE_HTML).exists() {
+        error("No base.html!")?;
+        return Ok(false);
+    }
+    html::inject_and_report(BASE_HTML, snippet, target)
+}
+
+/// Drops an asset from the layout of the current engine and from
+/// Kraken.toml. Returns whether there was one.
+pub fn remove_from_layout(asset: &str) -> io::Result<bool> {
+    if TemplateEngine::current() == TemplateEngine::Maud {
+        if get_config("features", asset).is_none() {
+            return Ok(false);
+        }
+        remove_feature(asset)?;
+        generate_maud_layout()?;
+        return Ok(true);
+    }
+
+    if !html::remove(BASE_HTML, asset)? {
+        return Ok(false);
+    }
+    if let Some(extension) = assets::hx_extension_of(asset) {
+        html::remove_attribute_value(BASE_HTML, "body", "hx-ext", extension)?;
+    }
+    remove_feature(asset)?;
+    Ok(true)
+}
+
+/// `src/kraken/layout.rs`, built from the assets recorded in Kraken.toml.
+pub fn generate_maud_layout() -> io::Result<()> {
+    let mut head = vec![];
+    if get_config("features", "tailwindcss").is_some() {
+        head.push(r#"link rel="stylesheet" href="/styles/tailwind.css";"#.to_string());
+    }
+    for (name, src) in assets::recorded() {
+        let defer = if assets::is_deferred(&name) {
+            " defer"
+        } else {
+            ""
+        };
+        head.push(format!("script{defer} src={src:?} {{}}"));
+    }
+    let csrf = get_config("features", "csrf").is_some();
+    if csrf {
+        head.push(r#"meta name="csrf-token" content=(csrf::token());"#.to_string());
+    }
+    let head: String = head
+        .iter()
+        .map(|line| format!("\n                {line}"))
+        .collect();
+
+    let extensions = assets::enabled_hx_extensions().join(",");
+    let mut body = "body".to_string();
+    if !extensions.is_empty() {
+        body.push_str(&format!(" hx-ext={extensions:?}"));
+    }
+    if csrf {
+        body.push_str(r##" hx-headers=(format!(r#"{{"x-csrf-token": "{}"}}"#, csrf::token()))"##);
+    }
+
+    let first_layout = !std::path::Path::new("src/kraken/layout.rs").exists();
+    write_rust_file(
+        "src/kraken/layout.rs",
+        format!(
+            r#"//! Generated by kraken from Kraken.toml, changes are overwritten.
+{csrf_import}use maud::{{html, Markup, DOCTYPE}};
+
+pub fn base(title: &str, content: Markup) -> Markup {{
+    html! {{
+        (DOCTYPE)
+        html lang="en" {{
+            head {{
+                meta charset="UTF-8";
+                meta name="viewport" content="width=device-width, initial-scale=1.0";
+                title {{ (title) }}{head}
+            }}
+            {body} {{
+                (content)
+            }}
+        }}
+    }}
+}}
+"#,
+            csrf_import = if csrf {
+                "use crate::kraken::csrf;\n"
+            } else {
+                ""
+            },
+        ),
+    )?;
+    if first_layout {
+        add_module_to_mod_rs("layout")?;
+        add_kraken_to_main_rs()?;
+    }
+    Ok(())
+}
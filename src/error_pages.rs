@@ -0,0 +1,342 @@
+// error_pages.rs
+//! `krk add error-pages`: templates/404.html and templates/500.html, a router
+//! fallback, and an `AppError` handlers return with `?`.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, check_feature, get_config,
+    unparse, write_rust_file,
+};
+use crate::ast;
+use crate::engine::{require_template_files, view, view_imports, TemplateEngine};
+use crate::rate_limit;
+use crate::resource::class;
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+const ERRORS_RS: &str = "src/kraken/errors.rs";
+
+fn rate_limited() -> bool {
+    get_config("features", "rate_limit").is_some()
+}
+
+/// Every file is restored if a step fails.
+pub fn add_error_pages() -> io::Result<()> {
+    if check_feature("error_pages").is_err() {
+        info("Failed to add error pages!!!")?;
+        return Ok(());
+    }
+    if !require_template_files("error-pages")? {
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(TemplateEngine::current()) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add error pages ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    info("Handlers return `Result<_, kraken::errors::AppError>`, `?` logs any error and answers with the 500 page.")?;
+    info("Requests under /api and those that don't accept html get JSON instead.")?;
+    outro("Successfully added the 404 and 500 pages. 🎉")
+}
+
+fn scaffold(engine: TemplateEngine) -> io::Result<()> {
+    cargo_add(&["serde", "-F", "derive"]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    generate_error_templates()?;
+    generate_errors_rs(engine)?;
+    add_module_to_mod_rs("errors")?;
+    add_kraken_to_main_rs()?;
+    ast::chain_router_before_layers("fallback", ".fallback(kraken::errors::not_found)")?;
+    ast::add_router_layer("axum::middleware::from_fn(kraken::errors::negotiate)")?;
+    add_feature("error_pages")?;
+    // Limited routers answer with the 429 page from now on
+    rate_limit::refresh()
+}
+
+/// Adds what later features need, e.g. the 429 page once routes are rate
+/// limited. Does nothing without error pages.
+pub fn refresh() -> io::Result<()> {
+    if !Path::new(ERRORS_RS).exists() {
+        return Ok(());
+    }
+    generate_error_templates()?;
+    generate_errors_rs(TemplateEngine::current())
+}
+
+/// The pages extend base.html. Pages that already exist are kept.
+fn generate_error_templates() -> io::Result<()> {
+    let tailwind = get_config("features", "tailwindcss").is_some();
+    let c = |classes: &str| class(tailwind, classes);
+    create_dir_all("templates")?;
+
+    let mut pages = vec![
+        (
+            "404",
+            "Page not found",
+            "There is nothing at this address, it may have moved.",
+        ),
+        (
+            "500",
+            "Something went wrong",
+            "The error was logged, please try again in a moment.",
+        ),
+    ];
+    if rate_limited() {
+        pages.push((
+            "429",
+            "Too many requests",
+            "Slow down a little and try again in a minute.",
+        ));
+    }
+    for (status, title, message) in pages {
+        let path = format!("templates/{status}.html");
+        if Path::new(&path).exists() {
+            continue;
+        }
+        fs::write(
+            path,
+            format!(
+                r#"{{% extends "base.html" %}}
+{{% block title %}}{title}{{% endblock %}}
+{{% block content %}}
+<main{main}>
+  <p{status_class}>{status}</p>
+  <h1{h1}>{title}</h1>
+  <p{p}>{message}</p>
+  <a{a} href="/">Back to the home page</a>
+</main>
+{{% endblock %}}
+"#,
+                main = c("mx-auto max-w-2xl px-4 py-24 text-center"),
+                status_class = c("text-sm font-bold text-indigo-600"),
+                h1 = c("mt-2 text-4xl font-black"),
+                p = c("mt-4 text-gray-600"),
+                a = c("mt-8 inline-block rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"),
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+fn generate_errors_rs(engine: TemplateEngine) -> io::Result<()> {
+    let imports = match engine {
+        TemplateEngine::Askama => quote! {
+            use askama::Template;
+            use axum::response::{IntoResponse, Response};
+            use serde::Serialize;
+        },
+        _ => view_imports(engine),
+    };
+    let not_found_page = view(
+        engine,
+        &Ident::new("NotFoundPage", Span::call_site()),
+        "404.html",
+        quote! {},
+        quote! {},
+    );
+    let error_page = view(
+        engine,
+        &Ident::new("ErrorPage", Span::call_site()),
+        "500.html",
+        quote! {},
+        quote! {},
+    );
+    let (too_many_page, too_many_variant, too_many_arm) = if rate_limited() {
+        let page = view(
+            engine,
+            &Ident::new("TooManyRequestsPage", Span::call_site()),
+            "429.html",
+            quote! {},
+            quote! {},
+        );
+        let variant = quote! {
+            /// The 429 page of rate limited routes.
+            TooManyRequests,
+        };
+        let arm = quote! {
+            Self::TooManyRequests => respond(StatusCode::TOO_MANY_REQUESTS, TooManyRequestsPage {}, "Too many requests"),
+        };
+        (Some(page), Some(variant), Some(arm))
+    } else {
+        (None, None, None)
+    };
+
+    // With tracing the conversions get spans and the cause is logged as an
+    // event of the request
+    let (from_attr, log_error, into_response_attr, log_cause) = if tracing::traced() {
+        (
+            quote! { #[tracing::instrument(level = "debug", skip_all)] },
+            quote! { tracing::debug!(error = tracing::field::display(&err), "handler failed"); },
+            quote! { #[tracing::instrument(skip_all)] },
+            quote! { tracing::error!(cause = tracing::field::display(&cause), "internal server error"); },
+        )
+    } else {
+        (
+            quote! {},
+            quote! {},
+            quote! {},
+            quote! { eprintln!("Internal server error: {cause}"); },
+        )
+    };
+
+    write_rust_file(
+        ERRORS_RS,
+        unparse(quote! {
+            //! The 404 and 500 pages. Handlers return `Result<_, AppError>` and use
+            //! `?` on any error: the cause is logged, browsers get templates/500.html
+            //! and api clients a JSON error.
+            use axum::extract::Request;
+            use axum::http::header::ACCEPT;
+            use axum::http::{HeaderMap, StatusCode};
+            use axum::middleware::Next;
+            use axum::Json;
+            use std::error::Error;
+            #imports
+
+            tokio::task_local! {
+                static WANTS_HTML: bool;
+            }
+
+            #not_found_page
+
+            #error_page
+
+            #too_many_page
+
+            /// What a handler fails with, `?` turns any error into `Internal`.
+            #[derive(Debug)]
+            pub enum AppError {
+                /// The 404 page.
+                NotFound,
+                /// The 500 page. The cause is logged, never shown.
+                Internal(Box<dyn Error + Send + Sync>),
+                #too_many_variant
+            }
+
+            impl<E: Error + Send + Sync + 'static> From<E> for AppError {
+                #from_attr
+                fn from(err: E) -> Self {
+                    #log_error
+                    Self::Internal(Box::new(err))
+                }
+            }
+
+            impl IntoResponse for AppError {
+                #into_response_attr
+                fn into_response(self) -> Response {
+                    match self {
+                        Self::NotFound => respond(StatusCode::NOT_FOUND, NotFoundPage {}, "Not found"),
+                        #too_many_arm
+                        Self::Internal(cause) => {
+                            #log_cause
+                            respond(StatusCode::INTERNAL_SERVER_ERROR, ErrorPage {}, "Internal server error")
+                        }
+                    }
+                }
+            }
+
+            #[derive(Serialize)]
+            struct ErrorBody {
+                error: &'static str,
+            }
+
+            /// The page for html requests, `{"error": message}` for the others.
+            fn respond(status: StatusCode, page: impl IntoResponse, message: &'static str) -> Response {
+                if WANTS_HTML.try_with(|html| *html).unwrap_or(false) {
+                    (status, page).into_response()
+                } else {
+                    (status, Json(ErrorBody { error: message })).into_response()
+                }
+            }
+
+            /// The fallback of the router, every unknown route gets the 404 page.
+            pub async fn not_found() -> AppError {
+                AppError::NotFound
+            }
+
+            /// Notes whether the request wants html, so an `AppError` knows what to
+            /// answer with.
+            pub async fn negotiate(request: Request, next: Next) -> Response {
+                let html = wants_html(request.uri().path(), request.headers());
+                WANTS_HTML.scope(html, next.run(request)).await
+            }
+
+            /// Browsers and htmx get pages, `/api` and everything else JSON.
+            fn wants_html(path: &str, headers: &HeaderMap) -> bool {
+                if path == "/api" || path.starts_with("/api/") {
+                    return false;
+                }
+                headers.contains_key("hx-request")
+                    || headers
+                        .get(ACCEPT)
+                        .and_then(|accept| accept.to_str().ok())
+                        .is_some_and(|accept| accept.contains("text/html"))
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use axum::body::{to_bytes, Body};
+                use axum::middleware::from_fn;
+                use axum::routing::get;
+                use axum::Router;
+                use tower::ServiceExt;
+
+                fn app() -> Router {
+                    Router::new()
+                        .route(
+                            "/fails",
+                            get(|| async { Err::<(), _>(AppError::from(std::io::Error::other("disk on fire"))) }),
+                        )
+                        .fallback(not_found)
+                        .layer(from_fn(negotiate))
+                }
+
+                async fn get_page(path: &str, accept: &str) -> (StatusCode, String) {
+                    let request = Request::get(path).header(ACCEPT, accept).body(Body::empty()).unwrap();
+                    let response = app().oneshot(request).await.unwrap();
+                    let status = response.status();
+                    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    (status, String::from_utf8(body.to_vec()).unwrap())
+                }
+
+                #[tokio::test]
+                async fn unknown_routes_get_the_404_page() {
+                    let (status, body) = get_page("/nope", "text/html").await;
+                    assert_eq!(status, StatusCode::NOT_FOUND);
+                    assert!(body.contains("Page not found"));
+                }
+
+                #[tokio::test]
+                async fn errors_get_the_500_page_without_the_cause() {
+                    let (status, body) = get_page("/fails", "text/html,application/xhtml+xml").await;
+                    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                    assert!(body.contains("Something went wrong"));
+                    assert!(!body.contains("disk on fire"));
+                }
+
+                #[tokio::test]
+                async fn api_clients_get_json() {
+                    let (status, body) = get_page("/fails", "application/json").await;
+                    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                    assert_eq!(body, r#"{"error":"Internal server error"}"#);
+
+                    let (status, body) = get_page("/api/nope", "text/html").await;
+                    assert_eq!(status, StatusCode::NOT_FOUND);
+                    assert_eq!(body, r#"{"error":"Not found"}"#);
+                }
+            }
+        }),
+    )
+}
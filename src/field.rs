@@ -0,0 +1,405 @@
+// field.rs
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fmt;
+use std::str::FromStr;
+
+/// A column type of `krk add model`, e.g. the `Text` of `body:Text`. Each maps
+/// to one sql type, one rust type and one form input.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Text,
+    Bool,
+    I32,
+    I64,
+    F64,
+    Email,
+}
+
+impl FieldType {
+    const ALL: [Self; 7] = [
+        Self::String,
+        Self::Text,
+        Self::Bool,
+        Self::I32,
+        Self::I64,
+        Self::F64,
+        Self::Email,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::String => "String",
+            Self::Text => "Text",
+            Self::Bool => "bool",
+            Self::I32 => "i32",
+            Self::I64 => "i64",
+            Self::F64 => "f64",
+            Self::Email => "Email",
+        }
+    }
+
+    /// The postgres column, without the column name.
+    pub fn sql(self) -> &'static str {
+        match self {
+            Self::String | Self::Email => "VARCHAR(255) NOT NULL",
+            Self::Text => "TEXT NOT NULL",
+            Self::Bool => "BOOLEAN NOT NULL DEFAULT FALSE",
+            Self::I32 => "INTEGER NOT NULL",
+            Self::I64 => "BIGINT NOT NULL",
+            Self::F64 => "DOUBLE PRECISION NOT NULL",
+        }
+    }
+
+    pub fn rust(self) -> TokenStream {
+        match self {
+            Self::String | Self::Text | Self::Email => quote! { String },
+            Self::Bool => quote! { bool },
+            Self::I32 => quote! { i32 },
+            Self::I64 => quote! { i64 },
+            Self::F64 => quote! { f64 },
+        }
+    }
+
+    pub fn is_copy(self) -> bool {
+        matches!(self, Self::Bool | Self::I32 | Self::I64 | Self::F64)
+    }
+
+    /// The sql type of diesel's `table!`.
+    pub fn diesel(self) -> &'static str {
+        match self {
+            Self::String | Self::Email => "Varchar",
+            Self::Text => "Text",
+            Self::Bool => "Bool",
+            Self::I32 => "Int4",
+            Self::I64 => "Int8",
+            Self::F64 => "Float8",
+        }
+    }
+
+    /// The `type` of the form `<input>`, `textarea` is its own element.
+    pub fn input(self) -> &'static str {
+        match self {
+            Self::String => "text",
+            Self::Text => "textarea",
+            Self::Bool => "checkbox",
+            Self::I32 | Self::I64 | Self::F64 => "number",
+            Self::Email => "email",
+        }
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let lowercase = name.to_lowercase();
+        let canonical = match lowercase.as_str() {
+            "str" | "varchar" => "string",
+            "boolean" => "bool",
+            "int" | "integer" => "i32",
+            "bigint" => "i64",
+            "float" | "double" => "f64",
+            other => other,
+        };
+        Self::ALL
+            .into_iter()
+            .find(|ty| ty.name().eq_ignore_ascii_case(canonical))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|ty| ty.name()).collect();
+                format!("unknown type \"{name}\", use one of {}", names.join(", "))
+            })
+    }
+}
+
+/// The keywords postgres reserves, a column can't be named after them
+/// without quotes.
+const SQL_RESERVED: &[&str] = &[
+    "all",
+    "analyse",
+    "analyze",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "asymmetric",
+    "authorization",
+    "binary",
+    "both",
+    "case",
+    "cast",
+    "check",
+    "collate",
+    "collation",
+    "column",
+    "concurrently",
+    "constraint",
+    "create",
+    "cross",
+    "current_catalog",
+    "current_date",
+    "current_role",
+    "current_schema",
+    "current_time",
+    "current_timestamp",
+    "current_user",
+    "default",
+    "deferrable",
+    "desc",
+    "distinct",
+    "do",
+    "else",
+    "end",
+    "except",
+    "false",
+    "fetch",
+    "for",
+    "foreign",
+    "freeze",
+    "from",
+    "full",
+    "grant",
+    "group",
+    "having",
+    "ilike",
+    "in",
+    "initially",
+    "inner",
+    "intersect",
+    "into",
+    "is",
+    "isnull",
+    "join",
+    "lateral",
+    "leading",
+    "left",
+    "like",
+    "limit",
+    "localtime",
+    "localtimestamp",
+    "natural",
+    "not",
+    "notnull",
+    "null",
+    "offset",
+    "on",
+    "only",
+    "or",
+    "order",
+    "outer",
+    "overlaps",
+    "placing",
+    "primary",
+    "references",
+    "returning",
+    "right",
+    "select",
+    "session_user",
+    "similar",
+    "some",
+    "symmetric",
+    "system_user",
+    "table",
+    "tablesample",
+    "then",
+    "to",
+    "trailing",
+    "true",
+    "union",
+    "unique",
+    "user",
+    "using",
+    "variadic",
+    "verbose",
+    "when",
+    "where",
+    "window",
+    "with",
+];
+
+/// A `name:Type` argument.
+#[derive(Clone)]
+pub struct Field {
+    pub name: String,
+    pub ty: FieldType,
+}
+
+impl Field {
+    pub fn ident(&self) -> Ident {
+        Ident::new(&self.name, Span::call_site())
+    }
+
+    /// "Published at" for `published_at`.
+    pub fn label(&self) -> String {
+        let label = self.name.replace('_', " ");
+        let mut chars = label.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => label,
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (name, ty) = arg
+            .split_once(':')
+            .ok_or_else(|| format!("\"{arg}\" is not name:Type"))?;
+        let is_snake_case = name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_snake_case || syn::parse_str::<Ident>(name).is_err() {
+            return Err(format!("\"{name}\" has to be a snake_case rust identifier"));
+        }
+        if name == "id" {
+            return Err("id is added to every model".to_string());
+        }
+        if SQL_RESERVED.contains(&name) {
+            return Err(format!(
+                "\"{name}\" is reserved in sql, name it e.g. {name}_name or {name}_id"
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            ty: ty.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.ty.name())
+    }
+}
+
+/// `blog_post` for `BlogPost` or `blog-post`.
+pub fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (index, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if index > 0 && !snake.ends_with('_') {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else if c == '-' || c == ' ' {
+            snake.push('_');
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// `BlogPost` for `blog_post`.
+pub fn pascal_case(snake: &str) -> String {
+    snake
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Table names are plural: `posts`, `categories`, `boxes`.
+pub fn plural(word: &str) -> String {
+    let consonant_y = word.ends_with('y')
+        && !word.ends_with("ay")
+        && !word.ends_with("ey")
+        && !word.ends_with("oy")
+        && !word.ends_with("uy");
+    if consonant_y {
+        format!("{}ies", &word[..word.len() - 1])
+    } else if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        format!("{word}es")
+    } else {
+        format!("{word}s")
+    }
+}
+
+/// `post` for `posts`, undoes [`plural`].
+pub fn singular(word: &str) -> String {
+    if let Some(stem) = word.strip_suffix("ies") {
+        format!("{stem}y")
+    } else if ["ses", "xes", "zes", "ches", "shes"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        word[..word.len() - 2].to_string()
+    } else if word.ends_with('s') && !word.ends_with("ss") {
+        word[..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_parse_from_name_and_type() {
+        let field: Field = "published_at:bigint".parse().unwrap();
+        assert_eq!(field.name, "published_at");
+        assert!(field.ty == FieldType::I64);
+        assert_eq!(field.to_string(), "published_at:i64");
+        assert_eq!(field.label(), "Published at");
+    }
+
+    #[test]
+    fn fields_refuse_bad_names_and_types() {
+        for arg in ["title", "Title:String", "2fa:bool", "type:String", "id:i64"] {
+            assert!(arg.parse::<Field>().is_err(), "{arg}");
+        }
+        let err = "title:Uuid".parse::<Field>().map(|_| ()).unwrap_err();
+        assert!(err.starts_with("unknown type \"Uuid\""));
+    }
+
+    #[test]
+    fn fields_refuse_sql_keywords() {
+        for name in ["order", "user", "group", "select"] {
+            let err = format!("{name}:String")
+                .parse::<Field>()
+                .map(|_| ())
+                .unwrap_err();
+            assert!(err.contains("reserved in sql"), "{err}");
+        }
+        assert!("order_id:i64".parse::<Field>().is_ok());
+    }
+
+    #[test]
+    fn snake_case_splits_words() {
+        assert_eq!(snake_case("BlogPost"), "blog_post");
+        assert_eq!(snake_case("blog-post"), "blog_post");
+        assert_eq!(snake_case("blog post"), "blog_post");
+        assert_eq!(snake_case("blog_post"), "blog_post");
+        assert_eq!(pascal_case("blog_post"), "BlogPost");
+    }
+
+    #[test]
+    fn plural_and_singular_undo_each_other() {
+        for (word, words) in [
+            ("post", "posts"),
+            ("category", "categories"),
+            ("day", "days"),
+            ("box", "boxes"),
+            ("church", "churches"),
+            ("address", "addresses"),
+        ] {
+            assert_eq!(plural(word), words);
+            assert_eq!(singular(words), word);
+        }
+        assert_eq!(singular("glass"), "glass");
+    }
+}
@@ -0,0 +1,423 @@
+// form.rs
+//! `krk add form contact name:String email:Email message:Text`: a page with a
+//! form validated on the server by `validator`, re-rendered with its errors,
+//! and swapped in place when htmx posts it.
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, capitalize, cargo_add,
+    get_config, merge_module_router_in_main_rs, unparse, write_rust_file,
+};
+use crate::assets::{add_asset, HTMX};
+use crate::csrf::write_template;
+use crate::engine::{require_template_files, view, view_imports, TemplateEngine};
+use crate::field::{Field, FieldType};
+use crate::html::Placement;
+use crate::model::Model;
+use crate::resource::{class, form_input};
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, warning};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+/// Every file is restored if a step fails.
+pub fn add_form(name: &str, fields: &[Field]) -> io::Result<()> {
+    if !require_template_files("form")? {
+        return Ok(());
+    }
+    // Only the names of a model, there is no table
+    let form = match Model::new(name, fields) {
+        Ok(form) => form,
+        Err(err) => return error(err),
+    };
+    if Path::new(&format!("src/kraken/{}.rs", form.module)).exists() {
+        error(format!("src/kraken/{}.rs already exists.", form.module))?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(&form) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add the {} form ({err}), every change was rolled back.",
+            form.module
+        ))?;
+        return Ok(());
+    }
+
+    outro(format!(
+        "Successfully created: {} with a validated form. 🎉",
+        route(&form)
+    ))
+}
+
+fn route(form: &Model) -> String {
+    format!("/{}", form.module.replace('_', "-"))
+}
+
+fn scaffold(form: &Model) -> io::Result<()> {
+    if get_config("features", HTMX.name).is_none() {
+        warning("The form swaps itself in place with htmx, adding it first.")?;
+        add_asset(&HTMX, &Placement::Head, false)?;
+    }
+    cargo_add(&["validator@0.20", "-F", "derive"]);
+    cargo_add(&["serde", "-F", "derive"]);
+
+    generate_form_templates(form)?;
+    generate_form_rs(TemplateEngine::current(), form)?;
+
+    add_module_to_mod_rs(&form.module)?;
+    add_module_to_main_rs(&form.module)?;
+    add_kraken_to_main_rs()?;
+    merge_module_router_in_main_rs(&form.module)
+}
+
+/// `#[validate(...)]` of one field, the first failing check is shown.
+fn validation(field: &Field) -> Option<TokenStream> {
+    let required = format!("{} is required.", field.label());
+    let too_long = format!("{} is at most 255 characters.", field.label());
+    let not_email = format!("{} is not an email address.", field.label());
+    if let Some(parse) = number_check(field.ty) {
+        let not_number = if field.ty == FieldType::F64 {
+            format!("{} is not a number.", field.label())
+        } else {
+            format!("{} is not a whole number.", field.label())
+        };
+        let parse = parse.to_string();
+        return Some(quote! {
+            #[validate(
+                custom(function = "required", message = #required),
+                custom(function = #parse, message = #not_number)
+            )]
+        });
+    }
+    match field.ty {
+        FieldType::String => Some(quote! {
+            #[validate(
+                custom(function = "required", message = #required),
+                length(max = 255, message = #too_long)
+            )]
+        }),
+        FieldType::Email => Some(quote! {
+            #[validate(
+                custom(function = "required", message = #required),
+                email(message = #not_email),
+                length(max = 255, message = #too_long)
+            )]
+        }),
+        FieldType::Text => Some(quote! {
+            #[validate(custom(function = "required", message = #required))]
+        }),
+        _ => None,
+    }
+}
+
+/// The function checking that a number field parses. The form keeps numbers
+/// as the text that was typed, so a bad one is shown again with its error
+/// instead of failing to deserialize.
+fn number_check(ty: FieldType) -> Option<Ident> {
+    matches!(ty, FieldType::I32 | FieldType::I64 | FieldType::F64)
+        .then(|| Ident::new(&format!("is_{}", ty.rust()), Span::call_site()))
+}
+
+/// A value that passes `validation`, for the generated test.
+fn valid_example(field: &Field) -> TokenStream {
+    let name = field.ident();
+    match field.ty {
+        FieldType::Email => quote! { #name: "ada@example.com".to_string(), },
+        FieldType::String | FieldType::Text => quote! { #name: "Ada".to_string(), },
+        FieldType::I32 | FieldType::I64 => quote! { #name: "42".to_string(), },
+        FieldType::F64 => quote! { #name: "4.2".to_string(), },
+        _ => quote! { #name: Default::default(), },
+    }
+}
+
+fn generate_form_rs(engine: TemplateEngine, form: &Model) -> io::Result<()> {
+    let module = &form.module;
+    let route = route(form);
+    let sent_route = format!("{route}?sent=true");
+    let title = capitalize(&module.replace('_', " "));
+    let ident = form.form_ident();
+    let errors = Ident::new(&format!("{}Errors", form.name), Span::call_site());
+
+    let names: Vec<Ident> = form.fields.iter().map(Field::ident).collect();
+    let keys: Vec<&str> = form
+        .fields
+        .iter()
+        .map(|field| field.name.as_str())
+        .collect();
+    let fields = form.fields.iter().map(|field| {
+        let name = field.ident();
+        let ty = match number_check(field.ty) {
+            Some(_) => quote! { String },
+            None => field.ty.rust(),
+        };
+        let checks = validation(field);
+        // Unchecked checkboxes are missing from the form
+        let default = (field.ty == FieldType::Bool).then(|| quote! { #[serde(default)] });
+        quote! {
+            #checks
+            #default
+            pub #name: #ty,
+        }
+    });
+    let required = form
+        .fields
+        .iter()
+        .any(|field| validation(field).is_some())
+        .then(|| {
+            quote! {
+                /// Whitespace alone doesn't count as filled in.
+                fn required(value: &str) -> Result<(), ValidationError> {
+                    if value.trim().is_empty() {
+                        return Err(ValidationError::new("required"));
+                    }
+                    Ok(())
+                }
+            }
+        });
+    let parsers = [FieldType::I32, FieldType::I64, FieldType::F64]
+        .into_iter()
+        .filter(|ty| form.fields.iter().any(|field| field.ty == *ty))
+        .filter_map(|ty| {
+            let parse = number_check(ty)?;
+            let rust = ty.rust();
+            Some(quote! {
+                fn #parse(value: &str) -> Result<(), ValidationError> {
+                    match value.trim().parse::<#rust>() {
+                        Ok(_) => Ok(()),
+                        Err(_) => Err(ValidationError::new("number")),
+                    }
+                }
+            })
+        });
+    let validation_error = required.as_ref().map(|_| quote! { , ValidationError });
+    let examples: Vec<TokenStream> = form.fields.iter().map(valid_example).collect();
+    let invalid_test = form
+        .fields
+        .iter()
+        .find(|field| validation(field).is_some())
+        .map(|field| {
+            let name = field.ident();
+            quote! {
+                #[test]
+                fn an_empty_form_is_refused() {
+                    let errors: #errors = #ident::default().validate().unwrap_err().into();
+                    assert!(!errors.#name.is_empty());
+                }
+            }
+        });
+
+    let number_test = form
+        .fields
+        .iter()
+        .find(|field| number_check(field.ty).is_some())
+        .map(|field| {
+            let name = field.ident();
+            quote! {
+                #[test]
+                fn a_bad_number_is_refused() {
+                    let mut form = #ident {
+                        #(#examples)*
+                    };
+                    form.#name = "twelve".to_string();
+                    let errors: #errors = form.validate().unwrap_err().into();
+                    assert!(!errors.#name.is_empty());
+                }
+            }
+        });
+
+    let imports = view_imports(engine);
+    let context = quote! {
+        form: #ident,
+        errors: #errors,
+        /// Shows the thank you note.
+        sent: bool,
+    };
+    let page_view = view(
+        engine,
+        &Ident::new("PageTemplate", Span::call_site()),
+        &format!("{module}/page.html"),
+        quote! {},
+        quote! {
+            title: &'static str,
+            #context
+        },
+    );
+    let fragment_view = view(
+        engine,
+        &Ident::new("FragmentTemplate", Span::call_site()),
+        &format!("{module}/form.html"),
+        quote! {},
+        context,
+    );
+
+    let code = quote! {
+        use axum::extract::Query;
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::response::Redirect;
+        use axum::routing::get;
+        use axum::{Form, Router};
+        use serde::Deserialize;
+        use validator::{Validate #validation_error, ValidationErrors};
+        #imports
+
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            Router::new().route(#route, get(show).post(submit))
+        }
+
+        #[derive(Clone, Debug, Default, Deserialize, serde::Serialize, Validate)]
+        pub struct #ident {
+            #(#fields)*
+        }
+
+        #required
+
+        #(#parsers)*
+
+        /// One message per field, empty when the field is valid.
+        #[derive(Debug, Default, serde::Serialize)]
+        pub struct #errors {
+            #(pub #names: String,)*
+        }
+
+        impl From<ValidationErrors> for #errors {
+            fn from(invalid: ValidationErrors) -> Self {
+                let mut errors = Self::default();
+                for (field, problems) in invalid.field_errors() {
+                    let message = problems
+                        .first()
+                        .and_then(|problem| problem.message.as_ref())
+                        .map_or_else(|| "This value is not valid.".to_string(), |message| message.to_string());
+                    match field.as_ref() {
+                        #(#keys => errors.#names = message,)*
+                        _ => {}
+                    }
+                }
+                errors
+            }
+        }
+
+        #page_view
+
+        #fragment_view
+
+        /// What happens to a valid submission, e.g. store or mail it. Number fields
+        /// hold the text that was typed in, validated to parse with `.trim().parse()`.
+        /// Log that a form was sent rather than its values, they are personal.
+        async fn handle(_form: &#ident) {}
+
+        #[derive(Deserialize)]
+        struct SentQuery {
+            sent: Option<bool>,
+        }
+
+        async fn show(Query(query): Query<SentQuery>) -> impl IntoResponse {
+            PageTemplate {
+                title: #title,
+                form: #ident::default(),
+                errors: #errors::default(),
+                sent: query.sent.unwrap_or(false),
+            }
+        }
+
+        /// htmx gets the form fragment back, a plain post the page or a
+        /// redirect after success.
+        async fn submit(headers: HeaderMap, Form(form): Form<#ident>) -> axum::response::Response {
+            let htmx = headers.contains_key("hx-request");
+            let (form, errors, sent) = match form.validate() {
+                Ok(()) => {
+                    handle(&form).await;
+                    (#ident::default(), #errors::default(), true)
+                }
+                Err(invalid) => (form, invalid.into(), false),
+            };
+
+            if htmx {
+                // htmx only swaps 2xx responses
+                return FragmentTemplate { form, errors, sent }.into_response();
+            }
+            if sent {
+                return Redirect::to(#sent_route).into_response();
+            }
+            let page = PageTemplate {
+                title: #title,
+                form,
+                errors,
+                sent,
+            };
+            (StatusCode::UNPROCESSABLE_ENTITY, page).into_response()
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn a_filled_in_form_is_valid() {
+                let form = #ident {
+                    #(#examples)*
+                };
+                assert!(form.validate().is_ok());
+            }
+
+            #invalid_test
+
+            #number_test
+        }
+    };
+
+    write_rust_file(
+        &format!("src/kraken/{module}.rs"),
+        tracing::instrument(unparse(code)),
+    )
+}
+
+/// `templates/<form>/page.html` and the `form.html` fragment it includes,
+/// which is what htmx swaps.
+fn generate_form_templates(form: &Model) -> io::Result<()> {
+    let tailwind = get_config("features", "tailwindcss").is_some();
+    let c = |classes: &str| class(tailwind, classes);
+    let module = &form.module;
+    let route = route(form);
+    create_dir_all(format!("templates/{module}"))?;
+
+    fs::write(
+        format!("templates/{module}/page.html"),
+        format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block content %}}
+<main{main}>
+  <h1{h1}>{{{{ title }}}}</h1>
+  {{% include "{module}/form.html" %}}
+</main>
+{{% endblock %}}
+"#,
+            main = c("mx-auto max-w-2xl px-4 py-12"),
+            h1 = c("text-4xl font-black"),
+        ),
+    )?;
+
+    let inputs: String = form
+        .fields
+        .iter()
+        .map(|field| form_input(field, &c))
+        .collect();
+    write_template(
+        format!("templates/{module}/form.html"),
+        &format!(
+            r#"<form id="{module}-form"{form_class} method="post" action="{route}" hx-post="{route}" hx-swap="outerHTML">
+  {{% if sent %}}<p{sent}>Thank you, we got it.</p>{{% endif %}}{inputs}
+  <button{button} type="submit">Send</button>
+</form>
+"#,
+            form_class = c("mt-8 space-y-6"),
+            sent = c("rounded bg-green-50 px-3 py-2 text-green-700"),
+            button = c("rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"),
+        ),
+    )
+}
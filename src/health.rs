@@ -0,0 +1,392 @@
+// health.rs
+//! `krk add health`: `/healthz`, `/readyz` and `/version` for the load
+//! balancer and whoever is on call. Readiness pings every dependency the
+//! project has, e.g. the database pool.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add,
+    check_feature, get_config, merge_module_router_in_main_rs, unparse, write_rust_file,
+};
+use crate::database::Orm;
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info, warning};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const HEALTH_RS: &str = "src/kraken/health.rs";
+const BUILD_RS: &str = "build.rs";
+
+/// Something `/readyz` waits for, checked by an
+/// `async fn <name>(state: &AppState) -> Result<(), String>` with `probe` as
+/// its body.
+struct Dependency {
+    name: &'static str,
+    imports: TokenStream,
+    probe: TokenStream,
+}
+
+/// The dependencies the features added so far registered.
+fn dependencies() -> Vec<Dependency> {
+    let mut dependencies = vec![];
+    if get_config("features", "database").is_some() {
+        let (imports, probe) = match Orm::current() {
+            Orm::Sqlx => (
+                quote! {},
+                quote! {
+                    sqlx::query("SELECT 1")
+                        .execute(&state.pool)
+                        .await
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                },
+            ),
+            Orm::Sea => (
+                quote! {},
+                quote! {
+                    state.db.ping().await.map_err(|err| err.to_string())
+                },
+            ),
+            Orm::Diesel => (
+                quote! { use diesel_async::RunQueryDsl; },
+                quote! {
+                    let mut connection = state.pool.get().await.map_err(|err| err.to_string())?;
+                    diesel::sql_query("SELECT 1")
+                        .execute(&mut connection)
+                        .await
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                },
+            ),
+        };
+        dependencies.push(Dependency {
+            name: "database",
+            imports,
+            probe,
+        });
+    }
+    dependencies
+}
+
+/// Every file is restored if a step fails.
+pub fn add_health() -> io::Result<()> {
+    if check_feature("health").is_err() {
+        info("Failed to add health endpoints!!!")?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold() {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add health endpoints ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    let names: Vec<&str> = dependencies()
+        .iter()
+        .map(|dependency| dependency.name)
+        .collect();
+    if names.is_empty() {
+        info("/readyz has nothing to wait for yet, `krk add database` adds a database check.")?;
+    } else {
+        info(format!("/readyz checks: {}.", names.join(", ")))?;
+    }
+    if !kraken_build_rs() {
+        warning("build.rs is your own, /version reports `unknown` until it sets KRAKEN_GIT_SHA and KRAKEN_FEATURES.")?;
+    }
+    info("CI can set GIT_SHA for builds without a git checkout.")?;
+    outro("Successfully added /healthz, /readyz and /version. 🎉")
+}
+
+fn scaffold() -> io::Result<()> {
+    cargo_add(&["serde", "-F", "derive"]);
+    cargo_add(&["tokio", "-F", "time"]);
+
+    generate_build_rs()?;
+    add_feature("health")?;
+    generate_health_rs()?;
+    add_module_to_mod_rs("health")?;
+    add_module_to_main_rs("health")?;
+    add_kraken_to_main_rs()?;
+    merge_module_router_in_main_rs("health")
+}
+
+/// Regenerates the handlers once a feature registers a dependency, e.g. the
+/// database. Does nothing without health endpoints.
+pub fn refresh() -> io::Result<()> {
+    if Path::new(HEALTH_RS).exists() {
+        generate_health_rs()?;
+    }
+    Ok(())
+}
+
+/// Whether build.rs embeds what `/version` reports.
+fn kraken_build_rs() -> bool {
+    fs::read_to_string(BUILD_RS).is_ok_and(|build| build.contains("KRAKEN_GIT_SHA"))
+}
+
+/// Writes build.rs unless the project has its own. The one `krk add
+/// database` writes only reruns on new migrations, which this one does too.
+fn generate_build_rs() -> io::Result<()> {
+    if let Ok(existing) = fs::read_to_string(BUILD_RS) {
+        let stripped: String = existing.split_whitespace().collect();
+        if stripped != r#"fnmain(){println!("cargo:rerun-if-changed=migrations");}"# {
+            return Ok(());
+        }
+    }
+    write_rust_file(
+        BUILD_RS,
+        unparse(quote! {
+            //! Generated by kraken, embeds what `/version` reports.
+            use std::fs;
+            use std::path::Path;
+            use std::process::Command;
+
+            fn main() {
+                if Path::new("migrations").exists() {
+                    println!("cargo:rerun-if-changed=migrations");
+                }
+                println!("cargo:rerun-if-changed=src/kraken/Kraken.toml");
+                println!("cargo:rustc-env=KRAKEN_FEATURES={}", features().join(","));
+                println!("cargo:rustc-env=KRAKEN_GIT_SHA={}", git_sha());
+            }
+
+            /// The features of Kraken.toml that are turned on.
+            fn features() -> Vec<String> {
+                let kraken = fs::read_to_string("src/kraken/Kraken.toml").unwrap_or_default();
+                let mut in_features = false;
+                let mut features = vec![];
+                for line in kraken.lines().map(str::trim) {
+                    if line.starts_with('[') {
+                        in_features = line == "[features]";
+                    } else if let Some((name, value)) = line.split_once('=') {
+                        if in_features && value.trim() == "true" {
+                            features.push(name.trim().to_string());
+                        }
+                    }
+                }
+                features
+            }
+
+            /// `GIT_SHA` where CI sets it, else the commit checked out.
+            fn git_sha() -> String {
+                println!("cargo:rerun-if-env-changed=GIT_SHA");
+                if let Ok(sha) = std::env::var("GIT_SHA") {
+                    return sha;
+                }
+
+                let head = fs::read_to_string(".git/HEAD").unwrap_or_default();
+                let branch = head.strip_prefix("ref: ").map(|branch| format!(".git/{}", branch.trim()));
+                for path in [Some(".git/HEAD".to_string()), branch, Some(".git/packed-refs".to_string())]
+                    .into_iter()
+                    .flatten()
+                {
+                    if Path::new(&path).exists() {
+                        println!("cargo:rerun-if-changed={path}");
+                    }
+                }
+
+                match Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output() {
+                    Ok(output) if output.status.success() => {
+                        String::from_utf8_lossy(&output.stdout).trim().to_string()
+                    }
+                    _ => "unknown".to_string(),
+                }
+            }
+        }),
+    )
+}
+
+/// `src/kraken/health.rs`, rebuilt from the registered dependencies.
+fn generate_health_rs() -> io::Result<()> {
+    let dependencies = dependencies();
+    let names: Vec<&str> = dependencies
+        .iter()
+        .map(|dependency| dependency.name)
+        .collect();
+    let probes: Vec<Ident> = names
+        .iter()
+        .map(|name| Ident::new(name, Span::call_site()))
+        .collect();
+    let imports = dependencies.iter().map(|dependency| &dependency.imports);
+    let bodies = dependencies.iter().map(|dependency| &dependency.probe);
+
+    let (state_import, router, readyz, check) = if dependencies.is_empty() {
+        (
+            quote! {},
+            quote! {
+                pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S>
+            },
+            quote! {
+                /// Ready once serving, there is nothing to wait for.
+                async fn readyz() -> Response {
+                    report(vec![])
+                }
+            },
+            quote! {},
+        )
+    } else {
+        (
+            quote! {
+                use crate::kraken::state::AppState;
+                use std::future::Future;
+                use std::time::Duration;
+            },
+            quote! {
+                pub fn router() -> Router<AppState>
+            },
+            quote! {
+                /// Ready when every dependency answers within `CHECK_TIMEOUT`.
+                async fn readyz(State(state): State<AppState>) -> Response {
+                    report(vec![#((#names, check(#probes(&state)).await),)*])
+                }
+            },
+            quote! {
+                /// How long a dependency has to answer.
+                const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+                async fn check(probe: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+                    tokio::time::timeout(CHECK_TIMEOUT, probe)
+                        .await
+                        .unwrap_or_else(|_| Err(format!("no answer within {CHECK_TIMEOUT:?}")))
+                }
+
+                #(
+                    async fn #probes(state: &AppState) -> Result<(), String> {
+                        #bodies
+                    }
+                )*
+            },
+        )
+    };
+    let state_extractor = (!dependencies.is_empty()).then(|| quote! { use axum::extract::State; });
+    // The features only show up when build.rs is the one kraken wrote
+    let features_test = kraken_build_rs().then(|| {
+        quote! {
+            assert!(version.features.contains(&"health"));
+        }
+    });
+
+    write_rust_file(
+        HEALTH_RS,
+        tracing::instrument(unparse(quote! {
+            //! Generated by kraken, changes are overwritten when features add
+            //! dependencies. `/healthz` is for liveness, `/readyz` for readiness and
+            //! `/version` tells what is deployed.
+            #state_extractor
+            use axum::http::StatusCode;
+            use axum::response::{IntoResponse, Response};
+            use axum::routing::get;
+            use axum::{Json, Router};
+            use serde::Serialize;
+            use std::collections::BTreeMap;
+            #state_import
+            #(#imports)*
+
+            #router {
+                Router::new()
+                    .route("/healthz", get(healthz))
+                    .route("/readyz", get(readyz))
+                    .route("/version", get(version))
+            }
+
+            #[derive(Serialize)]
+            struct Health {
+                status: &'static str,
+            }
+
+            /// Alive as long as it answers.
+            async fn healthz() -> Json<Health> {
+                Json(Health { status: "ok" })
+            }
+
+            #[derive(Serialize)]
+            struct Readiness {
+                status: &'static str,
+                checks: BTreeMap<&'static str, &'static str>,
+            }
+
+            #readyz
+
+            /// 200 when every check passed, else 503. Why a check failed is
+            /// logged, never shown.
+            fn report(results: Vec<(&'static str, Result<(), String>)>) -> Response {
+                let mut ready = true;
+                let mut checks = BTreeMap::new();
+                for (name, result) in results {
+                    let outcome = match result {
+                        Ok(()) => "ok",
+                        Err(cause) => {
+                            eprintln!("Readiness check {name} failed: {cause}");
+                            ready = false;
+                            "failed"
+                        }
+                    };
+                    checks.insert(name, outcome);
+                }
+                let (status, body) = if ready {
+                    (StatusCode::OK, Readiness { status: "ready", checks })
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, Readiness { status: "unavailable", checks })
+                };
+                (status, Json(body)).into_response()
+            }
+
+            #check
+
+            #[derive(Serialize)]
+            struct Version {
+                name: &'static str,
+                version: &'static str,
+                git_sha: &'static str,
+                features: Vec<&'static str>,
+            }
+
+            /// The crate version, and the commit and krk features build.rs embedded.
+            async fn version() -> Json<Version> {
+                Json(Version {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                    git_sha: option_env!("KRAKEN_GIT_SHA").unwrap_or("unknown"),
+                    features: option_env!("KRAKEN_FEATURES")
+                        .unwrap_or_default()
+                        .split(',')
+                        .filter(|feature| !feature.is_empty())
+                        .collect(),
+                })
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+
+                #[tokio::test]
+                async fn alive() {
+                    assert_eq!(healthz().await.status, "ok");
+                }
+
+                #[test]
+                fn a_failed_check_makes_it_unavailable() {
+                    assert_eq!(report(vec![]).status(), StatusCode::OK);
+                    assert_eq!(report(vec![("database", Ok(()))]).status(), StatusCode::OK);
+
+                    let failed = report(vec![("database", Ok(())), ("queue", Err("refused".to_string()))]);
+                    assert_eq!(failed.status(), StatusCode::SERVICE_UNAVAILABLE);
+                }
+
+                #[tokio::test]
+                async fn version_tells_the_build() {
+                    let Json(version) = version().await;
+                    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+                    assert!(!version.git_sha.is_empty());
+                    #features_test
+                }
+            }
+        })),
+    )
+}
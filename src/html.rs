@@ -0,0 +1,623 @@
+// html.rs
+use cliclack::log::{info, warning};
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::ops::Range;
+
+/// Where a snippet goes inside an html / askama template.
+#[derive(Clone, Copy)]
+pub enum Target<'a> {
+    /// Right before `</head>`.
+    Head,
+    /// Right before `</body>`.
+    BodyEnd,
+    /// Right before the `{% endblock %}` of the named `{% block %}`.
+    Block(&'a str),
+    /// Right after another marked snippet, e.g. htmx extensions after htmx.
+    After(&'a str),
+}
+
+impl Target<'_> {
+    fn describe(&self) -> String {
+        match self {
+            Self::Head => "</head>".to_string(),
+            Self::BodyEnd => "</body>".to_string(),
+            Self::Block(name) => format!("{{% block {name} %}}"),
+            Self::After(asset) => close_marker(asset),
+        }
+    }
+}
+
+/// The `--into` argument of the asset commands: `head`, `body` or
+/// `block:<name>`.
+#[derive(Clone, Debug)]
+pub enum Placement {
+    Head,
+    Body,
+    Block(String),
+}
+
+impl Placement {
+    pub fn target(&self) -> Target<'_> {
+        match self {
+            Self::Head => Target::Head,
+            Self::Body => Target::BodyEnd,
+            Self::Block(name) => Target::Block(name),
+        }
+    }
+}
+
+impl std::str::FromStr for Placement {
+    type Err = String;
+
+    fn from_str(placement: &str) -> Result<Self, Self::Err> {
+        match placement {
+            "head" => Ok(Self::Head),
+            "body" => Ok(Self::Body),
+            _ => match placement.strip_prefix("block:") {
+                Some(name) if !name.is_empty() => Ok(Self::Block(name.to_string())),
+                _ => Err(format!(
+                    "expected head, body or block:<name>, found \"{placement}\""
+                )),
+            },
+        }
+    }
+}
+
+/// A piece of markup kraken owns inside a template.
+pub struct Snippet<'a> {
+    /// Marker name, e.g. `htmx` becomes `<!-- krk:htmx -->`.
+    pub asset: &'a str,
+    /// Texts that identify the asset when it was added without markers
+    /// (by hand or by an older kraken), usually its url.
+    pub identities: &'a [&'a str],
+    pub html: &'a str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Injection {
+    Added,
+    Updated,
+    Duplicate,
+}
+
+fn open_marker(asset: &str) -> String {
+    format!("<!-- krk:{asset} -->")
+}
+
+fn close_marker(asset: &str) -> String {
+    format!("<!-- /krk:{asset} -->")
+}
+
+/// Byte ranges of html comments, template comments and raw text elements,
+/// none of which may contain the tags we look for.
+fn opaque_ranges(content: &str) -> Vec<Range<usize>> {
+    let lower = content.to_ascii_lowercase();
+    let mut ranges = vec![];
+    let mut index = 0;
+    while index < content.len() {
+        let rest = &lower[index..];
+        // Attributes of raw text elements stay visible, only their body is opaque
+        let (start, end) = if rest.starts_with("<!--") {
+            (0, rest.find("-->").map(|end| end + 3))
+        } else if rest.starts_with("{#") {
+            (0, rest.find("#}").map(|end| end + 2))
+        } else if rest.starts_with("<script") {
+            (
+                rest.find('>').map_or(0, |end| end + 1),
+                rest.find("</script"),
+            )
+        } else if rest.starts_with("<style") {
+            (
+                rest.find('>').map_or(0, |end| end + 1),
+                rest.find("</style"),
+            )
+        } else {
+            index += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let end = end.map_or(content.len(), |end| index + end);
+        ranges.push(index + start..end);
+        index = end.max(index + 1);
+    }
+    ranges
+}
+
+fn is_opaque(ranges: &[Range<usize>], index: usize) -> bool {
+    ranges.iter().any(|range| range.contains(&index))
+}
+
+/// Finds the byte offset the snippet has to be inserted at.
+fn find_target(content: &str, target: Target) -> Option<usize> {
+    let ranges = opaque_ranges(content);
+    let lower = content.to_ascii_lowercase();
+    match target {
+        Target::Head => lower
+            .match_indices("</head")
+            .map(|(index, _)| index)
+            .find(|index| !is_opaque(&ranges, *index)),
+        Target::BodyEnd => lower
+            .rmatch_indices("</body")
+            .map(|(index, _)| index)
+            .find(|index| !is_opaque(&ranges, *index)),
+        Target::Block(name) => {
+            let block = Regex::new(r"\{%-?\s*(block\s+(\w+)|endblock)\b[^%]*-?%\}").unwrap();
+            let mut depth = 0;
+            let mut inside = false;
+            for captures in block.captures_iter(content) {
+                let tag = captures.get(0).unwrap();
+                if is_opaque(&ranges, tag.start()) {
+                    continue;
+                }
+                match captures.get(2) {
+                    Some(block_name) if !inside => {
+                        inside = block_name.as_str() == name;
+                        depth = 0;
+                    }
+                    Some(_) => depth += 1,
+                    None if inside && depth == 0 => return Some(tag.start()),
+                    None if inside => depth -= 1,
+                    None => {}
+                }
+            }
+            None
+        }
+        Target::After(asset) => marked_range(content, asset).map(|range| range.end),
+    }
+}
+
+/// The whitespace a line starts with.
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Removes the common leading whitespace and surrounding blank lines.
+fn dedent(html: &str) -> Vec<&str> {
+    let lines: Vec<&str> = html.trim_matches('\n').lines().map(str::trim_end).collect();
+    let common = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indentation(line).len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .into_iter()
+        .map(|line| line.get(common..).unwrap_or(""))
+        .collect()
+}
+
+fn marked(snippet: &Snippet, indent: &str) -> String {
+    let mut lines = vec![format!("{indent}{}", open_marker(snippet.asset))];
+    lines.extend(dedent(snippet.html).into_iter().map(|line| {
+        if line.is_empty() {
+            String::new()
+        } else {
+            format!("{indent}{line}")
+        }
+    }));
+    lines.push(format!("{indent}{}", close_marker(snippet.asset)));
+    lines.join("\n")
+}
+
+/// The range of a marked snippet, including its indentation and line break.
+fn marked_range(content: &str, asset: &str) -> Option<Range<usize>> {
+    let open = content.find(&open_marker(asset))?;
+    let close_marker = close_marker(asset);
+    let close = open + content[open..].find(&close_marker)? + close_marker.len();
+    let start = content[..open].rfind('\n').map_or(0, |index| index + 1);
+    let start = if content[start..open].trim().is_empty() {
+        start
+    } else {
+        open
+    };
+    let end = if content[close..].starts_with('\n') {
+        close + 1
+    } else {
+        close
+    };
+    Some(start..end)
+}
+
+/// Whether the marked snippet of `asset` is in `content`.
+pub fn has_marked(content: &str, asset: &str) -> bool {
+    marked_range(content, asset).is_some()
+}
+
+/// Whether one of `identities` is in `content` outside of comments, i.e. the
+/// asset was added without markers.
+pub fn has_unmarked_copy(content: &str, identities: &[&str]) -> bool {
+    let ranges = opaque_ranges(content);
+    identities.iter().any(|identity| {
+        content
+            .match_indices(identity)
+            .any(|(index, _)| !is_opaque(&ranges, index))
+    })
+}
+
+/// Inserts `snippet` at `target`, wrapped in krk marker comments. An already
+/// marked snippet is replaced when its markup changed.
+pub fn inject(path: &str, snippet: &Snippet, target: Target) -> io::Result<Injection> {
+    let mut content = fs::read_to_string(path)?;
+
+    if let Some(range) = marked_range(&content, snippet.asset) {
+        let existing = &content[range.clone()];
+        let indent = indentation(existing).to_string();
+        let replacement = marked(snippet, &indent) + "\n";
+        if existing.trim_end() == replacement.trim_end() {
+            return Ok(Injection::Duplicate);
+        }
+        content.replace_range(range, &replacement);
+        fs::write(path, content)?;
+        return Ok(Injection::Updated);
+    }
+
+    if has_unmarked_copy(&content, snippet.identities) {
+        return Ok(Injection::Duplicate);
+    }
+
+    let Some(index) = find_target(&content, target) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Failed to find {} in {path}", target.describe()),
+        ));
+    };
+
+    if let Target::After(asset) = target {
+        // Siblings share the indentation of the snippet they follow
+        let previous = marked_range(&content, asset).unwrap_or(index..index);
+        let indent = indentation(&content[previous]).to_string();
+        let prefix = if content[..index].ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        content.insert_str(index, &format!("{prefix}{}\n", marked(snippet, &indent)));
+        fs::write(path, content)?;
+        return Ok(Injection::Added);
+    }
+
+    let line_start = content[..index].rfind('\n').map_or(0, |start| start + 1);
+    let line = &content[line_start..];
+    let line_indent = indentation(line).to_string();
+    let child_indent = format!("{line_indent}{}", indent_unit(&content));
+
+    if content[line_start..index].trim().is_empty() {
+        // The closing tag starts its own line
+        content.insert_str(line_start, &(marked(snippet, &child_indent) + "\n"));
+    } else {
+        // Inline, e.g. `{% block head %}{% endblock %}`
+        content.insert_str(
+            index,
+            &format!("\n{}\n{line_indent}", marked(snippet, &child_indent)),
+        );
+    }
+
+    fs::write(path, content)?;
+    Ok(Injection::Added)
+}
+
+/// Removes every copy of a marked snippet, returns whether there was one.
+pub fn remove(path: &str, asset: &str) -> io::Result<bool> {
+    let mut content = fs::read_to_string(path)?;
+    let mut removed = false;
+    while let Some(range) = marked_range(&content, asset) {
+        content.replace_range(range, "");
+        removed = true;
+    }
+    if removed {
+        fs::write(path, content)?;
+    }
+    Ok(removed)
+}
+
+/// The `src` of every `<script>` outside of comments, in document order.
+pub fn script_sources(content: &str) -> Vec<String> {
+    let ranges = opaque_ranges(content);
+    let script = Regex::new(r#"(?i)<script\b[^>]*?\bsrc\s*=\s*["']([^"']+)["']"#).unwrap();
+    script
+        .captures_iter(content)
+        .filter(|captures| !is_opaque(&ranges, captures.get(0).unwrap().start()))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Adds `value` to the comma separated `attribute` of the first `<tag>`, e.g.
+/// `hx-ext="sse"` on `<body>`. Returns whether the template changed.
+pub fn add_attribute_value(
+    path: &str,
+    tag: &str,
+    attribute: &str,
+    value: &str,
+) -> io::Result<bool> {
+    edit_attribute_values(path, tag, attribute, |values| {
+        if values.iter().any(|existing| existing == value) {
+            return false;
+        }
+        values.push(value.to_string());
+        true
+    })
+}
+
+/// Removes `value` from the comma separated `attribute` of the first `<tag>`,
+/// dropping the attribute once it is empty.
+pub fn remove_attribute_value(
+    path: &str,
+    tag: &str,
+    attribute: &str,
+    value: &str,
+) -> io::Result<bool> {
+    edit_attribute_values(path, tag, attribute, |values| {
+        let count = values.len();
+        values.retain(|existing| existing != value);
+        count != values.len()
+    })
+}
+
+/// Sets `attribute` of the first `<tag>` to `value`, single quoted so the
+/// value may be json, e.g. `hx-headers` on `<body>`. Returns whether the
+/// template changed.
+pub fn set_attribute(path: &str, tag: &str, attribute: &str, value: &str) -> io::Result<bool> {
+    let mut content = fs::read_to_string(path)?;
+    let found = first_tag(&content, tag, path)?;
+    let tag_html = &content[found.clone()];
+    let attribute_html = format!(" {attribute}='{value}'");
+    let attribute_regex = Regex::new(&format!(r#"\s{attribute}=("[^"]*"|'[^']*')"#)).unwrap();
+    let new_tag = match attribute_regex.find(tag_html) {
+        Some(existing) if existing.as_str() == attribute_html => return Ok(false),
+        Some(existing) => format!(
+            "{}{attribute_html}{}",
+            &tag_html[..existing.start()],
+            &tag_html[existing.end()..]
+        ),
+        None => format!(
+            "{}{attribute_html}{}",
+            tag_html[..tag_html.len() - 1]
+                .trim_end_matches('/')
+                .trim_end(),
+            if tag_html.ends_with("/>") { " />" } else { ">" }
+        ),
+    };
+    content.replace_range(found, &new_tag);
+    fs::write(path, content)?;
+    Ok(true)
+}
+
+/// The range of the first `<tag ...>` outside of comments and scripts.
+fn first_tag(content: &str, tag: &str, path: &str) -> io::Result<Range<usize>> {
+    let ranges = opaque_ranges(content);
+    let open_tag = Regex::new(&format!(r"(?i)<{tag}(\s[^>]*)?>")).unwrap();
+    let found = open_tag
+        .find_iter(content)
+        .find(|found| !is_opaque(&ranges, found.start()))
+        .map(|found| found.range());
+    found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Failed to find <{tag}> in {path}"),
+        )
+    })
+}
+
+fn edit_attribute_values(
+    path: &str,
+    tag: &str,
+    attribute: &str,
+    edit: impl FnOnce(&mut Vec<String>) -> bool,
+) -> io::Result<bool> {
+    let mut content = fs::read_to_string(path)?;
+    let found = first_tag(&content, tag, path)?;
+
+    let attribute_regex = Regex::new(&format!(r#"\s{attribute}="([^"]*)""#)).unwrap();
+    let tag_html = &content[found.clone()];
+    let existing = attribute_regex.captures(tag_html);
+    let mut values: Vec<String> = existing
+        .as_ref()
+        .map(|captures| {
+            captures[1]
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if !edit(&mut values) {
+        return Ok(false);
+    }
+
+    let attribute_html = if values.is_empty() {
+        String::new()
+    } else {
+        format!(r#" {attribute}="{}""#, values.join(","))
+    };
+    let new_tag = if existing.is_some() {
+        attribute_regex
+            .replace(tag_html, attribute_html.as_str())
+            .to_string()
+    } else {
+        format!(
+            "{}{attribute_html}{}",
+            tag_html[..tag_html.len() - 1]
+                .trim_end_matches('/')
+                .trim_end(),
+            if tag_html.ends_with("/>") { " />" } else { ">" }
+        )
+    };
+    content.replace_range(found, &new_tag);
+    fs::write(path, content)?;
+    Ok(true)
+}
+
+/// The indentation step the template uses, two spaces if it has none.
+fn indent_unit(content: &str) -> String {
+    content
+        .lines()
+        .map(indentation)
+        .filter(|indent| !indent.is_empty())
+        .min_by_key(|indent| indent.len())
+        .unwrap_or("  ")
+        .to_string()
+}
+
+/// Injects the snippet and reports the outcome for its asset. Returns whether
+/// the marked snippet is now in the template.
+pub fn inject_and_report(path: &str, snippet: &Snippet, target: Target) -> io::Result<bool> {
+    match inject(path, snippet, target) {
+        Ok(Injection::Added) => info(format!("Added {} to {path}.", snippet.asset))?,
+        Ok(Injection::Updated) => info(format!("Updated {} in {path}.", snippet.asset))?,
+        Ok(Injection::Duplicate) => {
+            warning(format!("{} already exists in {path}!", snippet.asset))?;
+            return Ok(false);
+        }
+        Err(err) => {
+            warning(format!("Error editing {path}: {err}"))?;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str =
+        "<html>\n  <head>\n    <title>krk</title>\n  </head>\n  <body>\n  </body>\n</html>\n";
+
+    /// Writes `content` to a fresh file in the temp dir and returns its path.
+    fn template(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("krk-html-{}-{name}.html", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn htmx(html: &str) -> Snippet<'_> {
+        Snippet {
+            asset: "htmx",
+            identities: &[
+                "htmx.org@1.9.10/dist/htmx.min.js",
+                "https://unpkg.com/htmx.org@",
+            ],
+            html,
+        }
+    }
+
+    const SCRIPT: &str =
+        r#"<script src="https://unpkg.com/htmx.org@1.9.10/dist/htmx.min.js"></script>"#;
+
+    #[test]
+    fn inject_adds_a_marked_snippet_before_head() {
+        let path = template("inject", BASE);
+        assert_eq!(
+            inject(&path, &htmx(SCRIPT), Target::Head).unwrap(),
+            Injection::Added
+        );
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains(&format!(
+            "    <!-- krk:htmx -->\n    {SCRIPT}\n    <!-- /krk:htmx -->\n  </head>"
+        )));
+    }
+
+    #[test]
+    fn inject_ignores_tags_inside_comments() {
+        let path = template("comment", &BASE.replace("<html>", "<html><!-- </head> -->"));
+        inject(&path, &htmx(SCRIPT), Target::Head).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("<html><!-- </head> -->\n"));
+        assert!(content.contains("<!-- /krk:htmx -->\n  </head>"));
+    }
+
+    #[test]
+    fn inject_reports_duplicates() {
+        let path = template("duplicate", BASE);
+        inject(&path, &htmx(SCRIPT), Target::Head).unwrap();
+        assert_eq!(
+            inject(&path, &htmx(SCRIPT), Target::Head).unwrap(),
+            Injection::Duplicate
+        );
+
+        // Added by hand, without markers
+        let path = template(
+            "unmarked",
+            &BASE.replace("</head>", &format!("  {SCRIPT}\n  </head>")),
+        );
+        assert_eq!(
+            inject(&path, &htmx(SCRIPT), Target::Head).unwrap(),
+            Injection::Duplicate
+        );
+
+        // Any identity is enough, e.g. the bare package url of older versions
+        let legacy = r#"<script src="https://unpkg.com/htmx.org@1.9.10"></script>"#;
+        let path = template(
+            "legacy",
+            &BASE.replace("</head>", &format!("  {legacy}\n  </head>")),
+        );
+        assert_eq!(
+            inject(&path, &htmx(SCRIPT), Target::Head).unwrap(),
+            Injection::Duplicate
+        );
+    }
+
+    #[test]
+    fn script_sources_skip_comments() {
+        let content = r#"<head>
+  <!-- <script src="https://old.example.com/a.js"></script> -->
+  <script defer src="https://unpkg.com/alpinejs@3.13.5/dist/cdn.min.js"></script>
+  <SCRIPT src='/vendor/htmx.js'></SCRIPT>
+  <script>const src = "x";</script>
+</head>"#;
+        assert_eq!(
+            script_sources(content),
+            [
+                "https://unpkg.com/alpinejs@3.13.5/dist/cdn.min.js",
+                "/vendor/htmx.js"
+            ]
+        );
+    }
+
+    #[test]
+    fn inject_updates_changed_markup() {
+        let path = template("update", BASE);
+        inject(&path, &htmx(SCRIPT), Target::Head).unwrap();
+        let updated = SCRIPT.replace("1.9.10", "2.0.0");
+        assert_eq!(
+            inject(&path, &htmx(&updated), Target::Head).unwrap(),
+            Injection::Updated
+        );
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("2.0.0") && !content.contains("1.9.10"));
+    }
+
+    #[test]
+    fn inject_into_a_missing_target_fails() {
+        let path = template("missing", "<div></div>");
+        let err = inject(&path, &htmx(SCRIPT), Target::Head).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn remove_round_trips_inject() {
+        let path = template("round-trip", BASE);
+        inject(&path, &htmx(SCRIPT), Target::Head).unwrap();
+        assert!(remove(&path, "htmx").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), BASE);
+        assert!(!remove(&path, "htmx").unwrap());
+    }
+
+    #[test]
+    fn remove_drops_every_marked_copy() {
+        let marked = format!("    <!-- krk:htmx -->\n    {SCRIPT}\n    <!-- /krk:htmx -->\n");
+        let path = template(
+            "copies",
+            &BASE
+                .replace("  </head>", &format!("{marked}  </head>"))
+                .replace("  </body>", &format!("{marked}  </body>")),
+        );
+        assert!(remove(&path, "htmx").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), BASE);
+    }
+}
@@ -1,7 +1,12 @@
 // kraken.rs
 use crate::{
-    add::{add_askama, add_tailwindcss, Add},
+    add::{add_tailwindcss, Add},
+    client::Gen,
+    dev::dev,
+    engine::{add_template_engine, remove_from_layout, select_template_engine},
     execute::Execute,
+    openapi::{write_openapi, Format},
+    secrets::Secrets,
 };
 use clap::Subcommand;
 use cliclack::{
@@ -30,9 +35,7 @@ impl Theme for MagentaTheme {
 }
 
 fn logo() {
-    let text = format!(
-        "K  K RRRR   AA  K  K EEEE N   N\nK K  R   R A  A K K  E    NN  N\nKK   RRRR  AAAA KK   EEE  N N N\nK K  R R   A  A K K  E    N  NN\nK  K R  RR A  A K  K EEEE N   N"
-    );
+    let text = "K  K RRRR   AA  K  K EEEE N   N\nK K  R   R A  A K K  E    NN  N\nKK   RRRR  AAAA KK   EEE  N N N\nK K  R R   A  A K K  E    N  NN\nK  K R  RR A  A K  K EEEE N   N".to_string();
     println!("{}\n", text.gradient(Color::Magenta));
 }
 
@@ -68,10 +71,8 @@ fn add_kraken_toml(language: &str, framework: &str) -> std::io::Result<()> {
 }
 
 fn initialize() -> std::io::Result<()> {
-    if Command::new("clear").status().is_err() {
-        if Command::new("cls").status().is_err() {
-            error("Failed to run \"clear\" or \"cls\".")?;
-        }
+    if Command::new("clear").status().is_err() && Command::new("cls").status().is_err() {
+        error("Failed to run \"clear\" or \"cls\".")?;
     }
     // std::process::Command::new("cls").status().unwrap();
     logo();
@@ -92,20 +93,16 @@ fn initialize() -> std::io::Result<()> {
             "rs"
         }
     };
-    let framework: &str = match language {
-        "rs" | _ => select("Pick a project language")
-            .item("axum", "Axum", "Only Option")
-            .interact()?,
-    };
+    let framework: &str = select("Pick a project language")
+        .item("axum", "Axum", "Only Option")
+        .interact()?;
 
     add_kraken_toml(language, framework)
 }
 
 fn create() -> std::io::Result<()> {
-    if Command::new("clear").status().is_err() {
-        if Command::new("cls").status().is_err() {
-            error("Failed to run \"clear\" or \"cls\".")?;
-        }
+    if Command::new("clear").status().is_err() && Command::new("cls").status().is_err() {
+        error("Failed to run \"clear\" or \"cls\".")?;
     }
     logo();
     set_theme(MagentaTheme);
@@ -122,10 +119,8 @@ fn create() -> std::io::Result<()> {
         })
         .interact()?;
 
-    if Command::new("clear").status().is_err() {
-        if Command::new("cls").status().is_err() {
-            error("Failed to run \"clear\" or \"cls\".")?;
-        }
+    if Command::new("clear").status().is_err() && Command::new("cls").status().is_err() {
+        error("Failed to run \"clear\" or \"cls\".")?;
     }
 
     if Command::new("cargo")
@@ -151,10 +146,8 @@ fn create() -> std::io::Result<()> {
 
     add_kraken_toml("rs", "axum")?;
 
-    if Command::new("clear").status().is_err() {
-        if Command::new("cls").status().is_err() {
-            error("Failed to run \"clear\" or \"cls\".")?;
-        }
+    if Command::new("clear").status().is_err() && Command::new("cls").status().is_err() {
+        error("Failed to run \"clear\" or \"cls\".")?;
     }
     // std::process::Command::new("cls").status().unwrap();
     logo();
@@ -164,7 +157,7 @@ fn create() -> std::io::Result<()> {
         .initial_value(true)
         .interact()?
     {
-        add_askama()?;
+        add_template_engine(select_template_engine()?)?;
         add_tailwindcss()?;
         // add_htmx()?;
     }
@@ -187,6 +180,37 @@ pub enum Kraken {
         #[command(subcommand)]
         add_commands: Add,
     },
+    /// Remove an asset kraken injected into the layout
+    Remove {
+        /// The asset name of its krk marker, e.g. htmx
+        asset: String,
+    },
+    /// Write the OpenAPI spec of the generated apis
+    Openapi {
+        /// json or yaml
+        #[arg(long, default_value = "json")]
+        format: Format,
+        /// Defaults to openapi.json or openapi.yaml
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Generate code from the project
+    Gen {
+        #[command(subcommand)]
+        gen_commands: Gen,
+    },
+    /// Set, get, list or remove the secrets of Secrets.toml and
+    /// Secrets.dev.toml
+    Secrets {
+        #[command(subcommand)]
+        secrets_commands: Secrets,
+    },
+    /// Run the app, restarting it and regenerating the client on changes
+    Dev {
+        /// Passed on to `cargo shuttle run`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
 }
 
 impl Execute for Kraken {
@@ -201,6 +225,30 @@ impl Execute for Kraken {
                 Ok(())
             }
             Self::Add { add_commands } => add_commands.execute(),
+            Self::Remove { asset } => {
+                set_theme(MagentaTheme);
+                intro(style(" kraken ").on_magenta().black())?;
+                if remove_from_layout(asset)? {
+                    success(format!("Removed {asset} from the layout."))?;
+                } else {
+                    error(format!("No krk:{asset} marker in the layout."))?;
+                }
+                Ok(())
+            }
+            Self::Openapi { format, output } => {
+                set_theme(MagentaTheme);
+                intro(style(" kraken ").on_magenta().black())?;
+                write_openapi(*format, output.as_deref())?;
+                Ok(())
+            }
+            Self::Gen { gen_commands } => gen_commands.execute(),
+            Self::Secrets { secrets_commands } => secrets_commands.execute(),
+            Self::Dev { args } => {
+                set_theme(MagentaTheme);
+                intro(style(" kraken ").on_magenta().black())?;
+                dev(args)?;
+                Ok(())
+            }
         }
     }
 }
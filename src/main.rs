@@ -5,8 +5,35 @@ use clap::Parser;
 use execute::Execute;
 
 mod add;
+mod api;
+mod assets;
+mod ast;
+mod auth;
+mod client;
+mod content;
+mod csrf;
+mod database;
+mod dev;
+mod engine;
+mod error_pages;
 mod execute;
+mod field;
+mod form;
+mod health;
+mod html;
 mod kraken;
+mod metrics;
+mod middleware;
+mod model;
+mod oauth;
+mod openapi;
+mod rate_limit;
+mod resource;
+mod secrets;
+mod spec;
+mod state;
+mod tracing;
+mod transaction;
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
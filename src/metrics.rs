@@ -0,0 +1,249 @@
+// metrics.rs
+//! `krk add metrics`: `/metrics` in the Prometheus text format, and a route
+//! layer counting requests, timing them and tracking those in flight by the
+//! route they matched, e.g. `/posts/:id`.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add,
+    check_feature, merge_module_router_in_main_rs, unparse, write_rust_file,
+};
+use crate::ast;
+use crate::secrets::{add_secret, SECRET_STORE_PARAM};
+use crate::state::{add_state_field, StateField};
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info};
+use cliclack::outro;
+use quote::quote;
+use std::io;
+
+const METRICS_RS: &str = "src/kraken/metrics.rs";
+
+/// Every file is restored if a step fails.
+pub fn add_metrics() -> io::Result<()> {
+    if check_feature("metrics").is_err() {
+        info("Failed to add metrics!!!")?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold() {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add metrics ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    info("/metrics is open until METRICS_TOKEN is set, e.g. `krk secrets set METRICS_TOKEN <token>`, then scrapers send `Authorization: Bearer <token>`.")?;
+    info("Requests no route matched are not counted, their paths could be anything.")?;
+    outro("Successfully added /metrics. 🎉")
+}
+
+fn scaffold() -> io::Result<()> {
+    cargo_add(&["metrics@0.24"]);
+    cargo_add(&["metrics-exporter-prometheus@0.16", "--no-default-features"]);
+    cargo_add(&["tokio", "-F", "time"]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    add_secret("METRICS_TOKEN", "")?;
+    generate_metrics_rs()?;
+    add_module_to_mod_rs("metrics")?;
+    add_module_to_main_rs("metrics")?;
+    add_kraken_to_main_rs()?;
+    ast::add_main_param(SECRET_STORE_PARAM)?;
+    add_state_field(&StateField {
+        name: "metrics",
+        ty: "crate::kraken::metrics::Metrics",
+        init: Some(
+            "let metrics = kraken::metrics::Metrics::install(secrets.get(\"METRICS_TOKEN\"));",
+        ),
+        doc: "Renders /metrics, and the token scrapers need.",
+    })?;
+    merge_module_router_in_main_rs("metrics")?;
+    // A route layer runs once the route matched, so it knows the template
+    ast::chain_router_before_layers(
+        "route_layer",
+        ".route_layer(axum::middleware::from_fn(kraken::metrics::track))",
+    )?;
+    add_feature("metrics")
+}
+
+fn generate_metrics_rs() -> io::Result<()> {
+    write_rust_file(
+        METRICS_RS,
+        tracing::instrument(unparse(quote! {
+            //! Prometheus metrics. `track` records every routed request, `/metrics`
+            //! renders them, behind METRICS_TOKEN when Secrets.toml sets one.
+            use crate::kraken::state::AppState;
+            use axum::extract::{MatchedPath, Request, State};
+            use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+            use axum::http::{HeaderMap, StatusCode};
+            use axum::middleware::Next;
+            use axum::response::{IntoResponse, Response};
+            use axum::routing::get;
+            use axum::Router;
+            use metrics::{counter, gauge, histogram, Gauge};
+            use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+            use std::sync::{Arc, OnceLock};
+            use std::time::{Duration, Instant};
+
+            const REQUESTS: &str = "http_requests_total";
+            const DURATION: &str = "http_request_duration_seconds";
+            const IN_FLIGHT: &str = "http_requests_in_flight";
+            /// Seconds, from a fast cache hit to a slow report.
+            const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+            /// How often recorded timings are folded into the buckets.
+            const UPKEEP_EVERY: Duration = Duration::from_secs(5);
+
+            /// What `/metrics` renders, shared through the state.
+            #[derive(Clone)]
+            pub struct Metrics {
+                handle: PrometheusHandle,
+                token: Option<Arc<str>>,
+            }
+
+            impl Metrics {
+                /// Installs the recorder the first time. An empty token leaves
+                /// `/metrics` open.
+                pub fn install(token: Option<String>) -> Self {
+                    Self {
+                        handle: recorder().clone(),
+                        token: token.filter(|token| !token.is_empty()).map(Arc::from),
+                    }
+                }
+            }
+
+            /// There is one recorder per process, tests share it.
+            fn recorder() -> &'static PrometheusHandle {
+                static RECORDER: OnceLock<PrometheusHandle> = OnceLock::new();
+                RECORDER.get_or_init(|| {
+                    let handle = PrometheusBuilder::new()
+                        .set_buckets_for_metric(Matcher::Full(DURATION.to_string()), BUCKETS)
+                        .expect("BUCKETS is not empty")
+                        .install_recorder()
+                        .expect("no other metrics recorder is installed");
+                    let upkeep = handle.clone();
+                    tokio::spawn(async move {
+                        let mut interval = tokio::time::interval(UPKEEP_EVERY);
+                        loop {
+                            interval.tick().await;
+                            upkeep.run_upkeep();
+                        }
+                    });
+                    handle
+                })
+            }
+
+            pub fn router() -> Router<AppState> {
+                Router::new().route("/metrics", get(render))
+            }
+
+            async fn render(State(state): State<AppState>, headers: HeaderMap) -> Response {
+                if !authorized(&headers, state.metrics.token.as_deref()) {
+                    return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+                }
+                (
+                    [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    state.metrics.handle.render(),
+                )
+                    .into_response()
+            }
+
+            /// A bearer token compared in constant time, or anyone without one.
+            fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+                let Some(token) = token else {
+                    return true;
+                };
+                let given = headers
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .unwrap_or_default();
+                given.len() == token.len()
+                    && given
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |difference, (a, b)| difference | (a ^ b))
+                        == 0
+            }
+
+            /// The route layer of main.rs. Labels are the method, the route
+            /// template and the status, never the raw path.
+            pub async fn track(request: Request, next: Next) -> Response {
+                let started = Instant::now();
+                let method = request.method().to_string();
+                let route = request
+                    .extensions()
+                    .get::<MatchedPath>()
+                    .map(|path| path.as_str().to_string())
+                    .unwrap_or_default();
+
+                let in_flight = InFlight::start(gauge!(IN_FLIGHT, &[("method", method.clone()), ("route", route.clone())]));
+                let response = next.run(request).await;
+                drop(in_flight);
+
+                let labels = [
+                    ("method", method),
+                    ("route", route),
+                    ("status", response.status().as_u16().to_string()),
+                ];
+                counter!(REQUESTS, &labels).increment(1);
+                histogram!(DURATION, &labels).record(started.elapsed().as_secs_f64());
+                response
+            }
+
+            /// A request in flight until dropped, also when the client goes away
+            /// halfway.
+            struct InFlight(Gauge);
+
+            impl InFlight {
+                fn start(gauge: Gauge) -> Self {
+                    gauge.increment(1.0);
+                    Self(gauge)
+                }
+            }
+
+            impl Drop for InFlight {
+                fn drop(&mut self) {
+                    self.0.decrement(1.0);
+                }
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use axum::body::Body;
+                use axum::middleware::from_fn;
+                use tower::ServiceExt;
+
+                #[tokio::test]
+                async fn requests_are_counted_by_route() {
+                    let metrics = Metrics::install(None);
+                    let app: Router = Router::new()
+                        .route("/things/:id", get(|| async { "thing" }))
+                        .route_layer(from_fn(track));
+                    let request = Request::get("/things/7").body(Body::empty()).unwrap();
+                    app.oneshot(request).await.unwrap();
+
+                    let rendered = metrics.handle.render();
+                    assert!(rendered.contains(r#"http_requests_total{method="GET",route="/things/:id",status="200"} 1"#));
+                    assert!(rendered.contains(r#"http_request_duration_seconds_bucket{method="GET",route="/things/:id",status="200",le="0.005"}"#));
+                    assert!(rendered.contains(r#"http_requests_in_flight{method="GET",route="/things/:id"} 0"#));
+                    assert!(!rendered.contains("/things/7"));
+                }
+
+                #[test]
+                fn the_token_is_checked() {
+                    let mut headers = HeaderMap::new();
+                    assert!(authorized(&headers, None));
+                    assert!(!authorized(&headers, Some("s3cret")));
+
+                    headers.insert(AUTHORIZATION, "Bearer guess".parse().unwrap());
+                    assert!(!authorized(&headers, Some("s3cret")));
+                    headers.insert(AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+                    assert!(authorized(&headers, Some("s3cret")));
+                }
+            }
+        })),
+    )
+}
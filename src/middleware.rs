@@ -0,0 +1,477 @@
+// middleware.rs
+//! `krk add middleware <kind>`: tower-http layers in `src/kraken/middleware.rs`,
+//! applied to the whole router in one fixed order whatever order they were
+//! added in. Kraken.toml records them in `[middleware]`.
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, get_config, set_config, unparse,
+    write_rust_file, BASE_HTML,
+};
+use crate::assets;
+use crate::ast;
+use crate::engine::TemplateEngine;
+use crate::html;
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::ValueEnum;
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{Ident, Literal, Span, TokenStream};
+use quote::quote;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MIDDLEWARE_RS: &str = "src/kraken/middleware.rs";
+/// Seconds, unless `--timeout` says otherwise.
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Declared outermost first, the order requests pass the layers in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Middleware {
+    /// An x-request-id on every request, echoed in the response
+    RequestId,
+    /// A tracing span per request
+    Trace,
+    /// 408 for requests that take too long
+    Timeout,
+    /// Lets the `--origin`s call the app from the browser
+    Cors,
+    /// gzip and brotli responses
+    Compression,
+    /// Content-Security-Policy, HSTS, X-Frame-Options and friends
+    SecurityHeaders,
+}
+
+impl Middleware {
+    fn name(self) -> &'static str {
+        match self {
+            Self::RequestId => "request-id",
+            Self::Trace => "trace",
+            Self::Timeout => "timeout",
+            Self::Cors => "cors",
+            Self::Compression => "compression",
+            Self::SecurityHeaders => "security-headers",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+    }
+
+    fn tower_http_features(self) -> &'static str {
+        match self {
+            Self::RequestId => "request-id,util",
+            Self::Trace => "trace",
+            Self::Timeout => "timeout",
+            Self::Cors => "cors",
+            Self::Compression => "compression-gzip,compression-br",
+            Self::SecurityHeaders => "set-header",
+        }
+    }
+
+    /// The `.layer(..)` calls, innermost first as the router chains them.
+    fn layers(self) -> TokenStream {
+        match self {
+            Self::RequestId => quote! {
+                .layer(PropagateRequestIdLayer::x_request_id())
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            },
+            Self::Trace if tracing::traced() => quote! {
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(logging::request_span)
+                        .on_response(logging::on_response)
+                        .on_failure(()),
+                )
+            },
+            Self::Trace => quote! { .layer(TraceLayer::new_for_http()) },
+            Self::Timeout => {
+                quote! { .layer(TimeoutLayer::new(Duration::from_secs(TIMEOUT_SECS))) }
+            }
+            Self::Cors => quote! { .layer(cors()) },
+            Self::Compression => quote! { .layer(CompressionLayer::new()) },
+            Self::SecurityHeaders => quote! {
+                .layer(header(REFERRER_POLICY, "strict-origin-when-cross-origin"))
+                .layer(header(X_CONTENT_TYPE_OPTIONS, "nosniff"))
+                .layer(header(X_FRAME_OPTIONS, "DENY"))
+                .layer(header(STRICT_TRANSPORT_SECURITY, "max-age=63072000; includeSubDomains"))
+                .layer(header(CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_VALUE))
+            },
+        }
+    }
+
+    /// What `layers` and the helpers of the kind refer to.
+    fn imports(self) -> &'static [&'static str] {
+        match self {
+            Self::RequestId => &[
+                "tower_http::request_id::MakeRequestUuid",
+                "tower_http::request_id::PropagateRequestIdLayer",
+                "tower_http::request_id::SetRequestIdLayer",
+            ],
+            Self::Trace if tracing::traced() => {
+                &["crate::kraken::logging", "tower_http::trace::TraceLayer"]
+            }
+            Self::Trace => &["tower_http::trace::TraceLayer"],
+            Self::Timeout => &["std::time::Duration", "tower_http::timeout::TimeoutLayer"],
+            Self::Cors => &[
+                "axum::http::header::AUTHORIZATION",
+                "axum::http::header::CONTENT_TYPE",
+                "axum::http::HeaderName",
+                "axum::http::HeaderValue",
+                "axum::http::Method",
+                "tower_http::cors::AllowOrigin",
+                "tower_http::cors::CorsLayer",
+            ],
+            Self::Compression => &["tower_http::compression::CompressionLayer"],
+            Self::SecurityHeaders => &[
+                "axum::http::header::CONTENT_SECURITY_POLICY",
+                "axum::http::header::REFERRER_POLICY",
+                "axum::http::header::STRICT_TRANSPORT_SECURITY",
+                "axum::http::header::X_CONTENT_TYPE_OPTIONS",
+                "axum::http::header::X_FRAME_OPTIONS",
+                "axum::http::HeaderName",
+                "axum::http::HeaderValue",
+                "tower_http::set_header::SetResponseHeaderLayer",
+            ],
+        }
+    }
+}
+
+/// The recorded layers, in the order of [`Middleware`].
+fn enabled() -> Vec<Middleware> {
+    let mut kinds: Vec<Middleware> = get_config("middleware", "layers")
+        .and_then(|item| item.as_array().cloned())
+        .map(|layers| {
+            layers
+                .iter()
+                .filter_map(|layer| layer.as_str().and_then(Middleware::from_name))
+                .collect()
+        })
+        .unwrap_or_default();
+    kinds.sort();
+    kinds.dedup();
+    kinds
+}
+
+fn cors_origins() -> Vec<String> {
+    get_config("middleware", "cors_origins")
+        .and_then(|item| item.as_array().cloned())
+        .map(|origins| {
+            origins
+                .iter()
+                .filter_map(|origin| origin.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// An origin is a scheme and a host with an optional port, nothing else.
+fn valid_origin(origin: &str) -> bool {
+    let Some((scheme, host)) = origin.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https")
+        && !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
+
+/// Every file is restored if a step fails.
+pub fn add_middleware(
+    kinds: &[Middleware],
+    timeout: Option<u64>,
+    origins: &[String],
+) -> io::Result<()> {
+    if let Some(origin) = origins.iter().find(|origin| !valid_origin(origin)) {
+        return error(format!(
+            "{origin} is not an origin, e.g. https://example.com or http://localhost:3000."
+        ));
+    }
+    if kinds.contains(&Middleware::Cors) && origins.is_empty() && cors_origins().is_empty() {
+        return error(
+            "Give the origins allowed to call the app, e.g. `--origin https://example.com`.",
+        );
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(kinds, timeout, origins) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add middleware ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    let layers: Vec<&str> = enabled().into_iter().map(Middleware::name).collect();
+    info(format!("Requests pass: {}.", layers.join(" → ")))?;
+    outro("Middleware added successfully. 🎉")
+}
+
+fn scaffold(kinds: &[Middleware], timeout: Option<u64>, origins: &[String]) -> io::Result<()> {
+    let mut layers = enabled();
+    layers.extend_from_slice(kinds);
+    layers.sort();
+    layers.dedup();
+
+    let features: Vec<&str> = layers
+        .iter()
+        .map(|kind| kind.tower_http_features())
+        .collect();
+    cargo_add(&["tower-http@0.5", "-F", &features.join(",")]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    let names: toml_edit::Array = layers.iter().map(|kind| kind.name()).collect();
+    set_config("middleware", "layers", names)?;
+    if let Some(timeout) = timeout {
+        set_config("middleware", "timeout_secs", timeout as i64)?;
+    }
+    if !origins.is_empty() {
+        let mut recorded = cors_origins();
+        for origin in origins {
+            let origin = origin.trim_end_matches('/').to_string();
+            if !recorded.contains(&origin) {
+                recorded.push(origin);
+            }
+        }
+        let recorded: toml_edit::Array = recorded.iter().map(String::as_str).collect();
+        set_config("middleware", "cors_origins", recorded)?;
+    }
+
+    let first = !Path::new(MIDDLEWARE_RS).exists();
+    generate_middleware_rs()?;
+    if first {
+        add_module_to_mod_rs("middleware")?;
+        add_kraken_to_main_rs()?;
+        ast::insert_after_router(
+            "let router = kraken::middleware::apply(router);",
+            "kraken :: middleware :: apply",
+        )?;
+    }
+    Ok(())
+}
+
+/// Turns `kinds` on for features that build on them, e.g. tracing on
+/// request-id and trace.
+pub fn enable(kinds: &[Middleware]) -> io::Result<()> {
+    scaffold(kinds, None, &[])
+}
+
+/// Keeps the Content-Security-Policy in step with the scripts Kraken.toml
+/// records, called whenever an asset is added.
+pub fn refresh() -> io::Result<()> {
+    if Path::new(MIDDLEWARE_RS).exists() && enabled().contains(&Middleware::SecurityHeaders) {
+        generate_middleware_rs()?;
+    }
+    Ok(())
+}
+
+/// Vendored scripts and the tailwind build come from the app itself, cdn
+/// scripts from their origin. Scripts base.html has without Kraken.toml
+/// recording them, added by hand or by an older kraken, count too. Alpine
+/// evaluates its attributes at runtime.
+fn content_security_policy() -> Vec<String> {
+    let mut sources: Vec<String> = assets::recorded().into_iter().map(|(_, src)| src).collect();
+    if TemplateEngine::current().uses_template_files() {
+        if let Ok(base) = fs::read_to_string(BASE_HTML) {
+            sources.extend(html::script_sources(&base));
+        }
+    }
+
+    let mut script_src = vec!["'self'".to_string()];
+    for src in &sources {
+        let Some((scheme, rest)) = src.split_once("://") else {
+            continue;
+        };
+        let host = rest.split('/').next().unwrap_or_default();
+        let origin = format!("{scheme}://{host}");
+        if !script_src.contains(&origin) {
+            script_src.push(origin);
+        }
+    }
+    let alpine = get_config("features", "alpinejs").is_some()
+        || sources.iter().any(|src| src.contains("alpinejs"));
+    if alpine {
+        script_src.push("'unsafe-eval'".to_string());
+    }
+    vec![
+        "default-src 'self'".to_string(),
+        format!("script-src {}", script_src.join(" ")),
+        "style-src 'self' 'unsafe-inline'".to_string(),
+        "img-src 'self' data:".to_string(),
+        "object-src 'none'".to_string(),
+        "base-uri 'self'".to_string(),
+        "frame-ancestors 'none'".to_string(),
+    ]
+}
+
+/// `src/kraken/middleware.rs`, rebuilt from Kraken.toml.
+fn generate_middleware_rs() -> io::Result<()> {
+    let layers = enabled();
+    // One `use` per module
+    let mut modules: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for path in layers.iter().flat_map(|kind| kind.imports()) {
+        let (module, item) = path.rsplit_once("::").expect("import paths have a module");
+        modules.entry(module).or_default().insert(item);
+    }
+    let imports = modules.into_iter().map(|(module, items)| {
+        let module: syn::Path = syn::parse_str(module).expect("import paths are valid");
+        let items = items
+            .into_iter()
+            .map(|item| Ident::new(item, Span::call_site()));
+        quote! { use #module::{#(#items),*}; }
+    });
+    // The router wraps later layers around earlier ones
+    let chained = layers.iter().rev().map(|kind| kind.layers());
+
+    let timeout = layers.contains(&Middleware::Timeout).then(|| {
+        let seconds = get_config("middleware", "timeout_secs")
+            .and_then(|item| item.as_integer())
+            .and_then(|seconds| u64::try_from(seconds).ok())
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let seconds = Literal::u64_unsuffixed(seconds);
+        quote! {
+            /// `timeout_secs` of Kraken.toml.
+            const TIMEOUT_SECS: u64 = #seconds;
+        }
+    });
+    let cors = layers.contains(&Middleware::Cors).then(|| {
+        let origins = cors_origins();
+        quote! {
+            /// `cors_origins` of Kraken.toml, without cookies.
+            fn cors() -> CorsLayer {
+                CorsLayer::new()
+                    .allow_origin(AllowOrigin::list([#(HeaderValue::from_static(#origins)),*]))
+                    .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+                    .allow_headers([AUTHORIZATION, CONTENT_TYPE, HeaderName::from_static("x-csrf-token")])
+            }
+        }
+    });
+    let security_headers = layers.contains(&Middleware::SecurityHeaders).then(|| {
+        let directives = content_security_policy();
+        let last = directives.len() - 1;
+        let directives = directives
+            .iter()
+            .enumerate()
+            .map(|(index, directive)| if index == last { directive.clone() } else { format!("{directive}; ") });
+        quote! {
+            /// Allows the scripts recorded in Kraken.toml and those in base.html,
+            /// kraken updates it when one is added.
+            const CONTENT_SECURITY_POLICY_VALUE: &str = concat!(#(#directives),*);
+
+            /// Sets the header unless the handler did.
+            fn header(name: HeaderName, value: &'static str) -> SetResponseHeaderLayer<HeaderValue> {
+                SetResponseHeaderLayer::if_not_present(name, HeaderValue::from_static(value))
+            }
+        }
+    });
+    let tests = tests(&layers);
+
+    write_rust_file(
+        MIDDLEWARE_RS,
+        unparse(quote! {
+            //! Generated by kraken from the `[middleware]` of Kraken.toml, changes are
+            //! overwritten. `krk add middleware <kind>` adds a layer.
+            use axum::Router;
+            #(#imports)*
+
+            #timeout
+
+            /// Wraps the whole router. A request passes request-id, trace,
+            /// timeout, cors, compression and security-headers, in that order, as
+            /// far as they are enabled.
+            pub fn apply(router: Router) -> Router {
+                router
+                    #(#chained)*
+            }
+
+            #cors
+
+            #security_headers
+
+            #tests
+        }),
+    )
+}
+
+/// A test per layer with something to check in the response.
+fn tests(layers: &[Middleware]) -> Option<TokenStream> {
+    let tests: Vec<TokenStream> = layers
+        .iter()
+        .filter_map(|kind| match kind {
+            Middleware::RequestId => Some(quote! {
+                #[tokio::test]
+                async fn responses_carry_a_request_id() {
+                    let response = send(Request::get("/")).await;
+                    assert!(response.headers().contains_key("x-request-id"));
+                }
+            }),
+            Middleware::Cors => {
+                let origin = cors_origins().into_iter().next().unwrap_or_default();
+                Some(quote! {
+                    #[tokio::test]
+                    async fn allowed_origins_pass_the_preflight() {
+                        let response = send(
+                            Request::options("/")
+                                .header("origin", #origin)
+                                .header("access-control-request-method", "POST"),
+                        )
+                        .await;
+                        assert_eq!(response.headers()["access-control-allow-origin"], #origin);
+
+                        let response = send(
+                            Request::options("/")
+                                .header("origin", "https://evil.example")
+                                .header("access-control-request-method", "POST"),
+                        )
+                        .await;
+                        assert!(!response.headers().contains_key("access-control-allow-origin"));
+                    }
+                })
+            }
+            Middleware::Compression => Some(quote! {
+                #[tokio::test]
+                async fn responses_are_compressed() {
+                    let response = send(Request::get("/").header("accept-encoding", "gzip")).await;
+                    assert_eq!(response.headers()["content-encoding"], "gzip");
+                }
+            }),
+            Middleware::SecurityHeaders => Some(quote! {
+                #[tokio::test]
+                async fn responses_carry_security_headers() {
+                    let response = send(Request::get("/")).await;
+                    assert_eq!(response.headers()["x-frame-options"], "DENY");
+                    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+                    assert!(response.headers().contains_key("content-security-policy"));
+                }
+            }),
+            Middleware::Trace | Middleware::Timeout => None,
+        })
+        .collect();
+    if tests.is_empty() {
+        return None;
+    }
+    Some(quote! {
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use axum::body::Body;
+            use axum::extract::Request;
+            use axum::http::request::Builder;
+            use axum::response::Response;
+            use axum::routing::get;
+            use tower::ServiceExt;
+
+            async fn send(request: Builder) -> Response {
+                let app = apply(Router::new().route("/", get(|| async { "Hello, world! ".repeat(100) })));
+                app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+            }
+
+            #(#tests)*
+        }
+    })
+}
@@ -0,0 +1,680 @@
+// model.rs
+use crate::add::{cargo_add, get_config, set_config, unparse, write_rust_file};
+use crate::database::Orm;
+use crate::field::{pascal_case, plural, snake_case, Field, FieldType};
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs::{self, create_dir_all, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The names one `krk add model` argument turns into.
+pub struct Model {
+    /// `blog_post`, the module in src/kraken/models.
+    pub module: String,
+    /// `BlogPost`, the row struct.
+    pub name: String,
+    /// `blog_posts`
+    pub table: String,
+    pub fields: Vec<Field>,
+}
+
+impl Model {
+    pub fn new(name: &str, fields: &[Field]) -> Result<Self, String> {
+        let module = snake_case(name);
+        if syn::parse_str::<Ident>(&module).is_err() || module.starts_with('_') {
+            return Err(format!("\"{name}\" has to be a rust identifier, e.g. Post"));
+        }
+        if fields.is_empty() {
+            return Err("A model needs at least one name:Type field".to_string());
+        }
+        Ok(Self {
+            name: pascal_case(&module),
+            table: plural(&module),
+            module,
+            fields: fields.to_vec(),
+        })
+    }
+
+    pub fn path(&self) -> String {
+        format!("src/kraken/models/{}.rs", self.module)
+    }
+
+    pub fn ident(&self) -> Ident {
+        Ident::new(&self.name, Span::call_site())
+    }
+
+    /// The struct the model is created and updated from, e.g. `PostForm`.
+    pub fn form_ident(&self) -> Ident {
+        Ident::new(&format!("{}Form", self.name), Span::call_site())
+    }
+
+    /// The columns but `id`, comma separated.
+    pub fn columns(&self) -> String {
+        self.fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The fields `krk add model` recorded for `module` in Kraken.toml.
+pub fn recorded_fields(module: &str) -> Option<Vec<Field>> {
+    let item = get_config("models", module)?;
+    item.as_array()?
+        .iter()
+        .map(|value| value.as_str()?.parse().ok())
+        .collect()
+}
+
+/// `krk add model Post title:String body:Text`
+pub fn add_model(name: &str, fields: &[Field]) -> io::Result<()> {
+    if get_config("features", "database").is_none() {
+        error("Models need a database, run `krk add database` first.")?;
+        return Ok(());
+    }
+    let model = match Model::new(name, fields) {
+        Ok(model) => model,
+        Err(err) => return error(err),
+    };
+    if Path::new(&model.path()).exists() {
+        error(format!("Model {} already exists.", model.name))?;
+        return Ok(());
+    }
+
+    generate_model(&model)?;
+
+    for field in &model.fields {
+        info(format!(
+            "{}: {} | {} | <{}>",
+            field.name,
+            field.ty.sql(),
+            field.ty.rust(),
+            field.ty.input()
+        ))?;
+    }
+    outro(format!(
+        "Successfully created: {} in src/kraken/models/{}.rs. 🎉",
+        model.name, model.module
+    ))
+}
+
+/// Writes the migration, the model module and its queries for the configured
+/// orm, and registers the module.
+pub fn generate_model(model: &Model) -> io::Result<()> {
+    let orm = Orm::current();
+    cargo_add(&["serde", "-F", "derive"]);
+
+    add_migration(
+        &format!("create_{}", model.table),
+        &create_table_sql(model),
+        &format!("DROP TABLE {};\n", model.table),
+    )?;
+    if orm == Orm::Diesel {
+        append_diesel_table(model)?;
+    }
+
+    create_dir_all("src/kraken/models")?;
+    let code = match orm {
+        Orm::Sqlx => sqlx_model(model),
+        Orm::Sea => sea_model(model).to_string(),
+        Orm::Diesel => diesel_model(model).to_string(),
+    };
+    write_rust_file(&model.path(), unparse(code))?;
+    add_module_to_models_rs(&model.module)?;
+
+    let fields: toml_edit::Array = model.fields.iter().map(|field| field.to_string()).collect();
+    set_config("models", &model.module, fields)
+}
+
+fn create_table_sql(model: &Model) -> String {
+    let mut columns = vec!["    id BIGSERIAL PRIMARY KEY".to_string()];
+    columns.extend(
+        model
+            .fields
+            .iter()
+            .map(|field| format!("    {} {}", field.name, field.ty.sql())),
+    );
+    format!(
+        "CREATE TABLE {} (\n{}\n);\n",
+        model.table,
+        columns.join(",\n")
+    )
+}
+
+/// `[year, month, day, hour, minute, second]` in utc of `seconds` since the
+/// epoch.
+fn utc(seconds: u64) -> [u64; 6] {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    [
+        year as u64,
+        month as u64,
+        day as u64,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+    ]
+}
+
+/// A migration version that sorts after every existing one. Two models added
+/// within the same second get consecutive seconds.
+fn migration_version(dir: &str, format: fn([u64; 6]) -> String) -> io::Result<String> {
+    let existing: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    let mut seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    loop {
+        let version = format(utc(seconds));
+        if !existing.iter().any(|name| name.contains(&version)) {
+            return Ok(version);
+        }
+        seconds += 1;
+    }
+}
+
+/// A migration named e.g. `create_posts` in the layout of the configured
+/// orm's cli, applied on the next startup.
+pub fn add_migration(name: &str, up: &str, down: &str) -> io::Result<()> {
+    match Orm::current() {
+        Orm::Sqlx => create_sql_migration(name, up),
+        Orm::Sea => create_sea_migration(name, up, down),
+        Orm::Diesel => create_diesel_migration(name, up, down),
+    }
+}
+
+/// `migrations/<version>_<name>.sql`, the layout of `sqlx migrate add`.
+fn create_sql_migration(name: &str, up: &str) -> io::Result<()> {
+    create_dir_all("migrations")?;
+    let version = migration_version("migrations", |[y, mo, d, h, mi, s]| {
+        format!("{y:04}{mo:02}{d:02}{h:02}{mi:02}{s:02}")
+    })?;
+    fs::write(format!("migrations/{version}_{name}.sql"), up)
+}
+
+/// `migrations/<version>_<name>/{up,down}.sql`, the layout of
+/// `diesel migration generate`.
+fn create_diesel_migration(name: &str, up: &str, down: &str) -> io::Result<()> {
+    let version = migration_version("migrations", |[y, mo, d, h, mi, s]| {
+        format!("{y:04}-{mo:02}-{d:02}-{h:02}{mi:02}{s:02}")
+    })?;
+    let dir = format!("migrations/{version}_{name}");
+    create_dir_all(&dir)?;
+    fs::write(format!("{dir}/up.sql"), up)?;
+    fs::write(format!("{dir}/down.sql"), down)
+}
+
+/// `migration/src/m<version>_<name>.rs`, the layout of
+/// `sea-orm-cli migrate generate`, then the migrator is regenerated.
+fn create_sea_migration(name: &str, up: &str, down: &str) -> io::Result<()> {
+    let version = migration_version("migration/src", |[y, mo, d, h, mi, s]| {
+        format!("m{y:04}{mo:02}{d:02}_{h:02}{mi:02}{s:02}")
+    })?;
+    let up = up.trim_end();
+    let down = down.trim_end();
+    write_rust_file(
+        &format!("migration/src/{version}_{name}.rs"),
+        quote! {
+            use sea_orm_migration::prelude::*;
+
+            #[derive(DeriveMigrationName)]
+            pub struct Migration;
+
+            #[async_trait::async_trait]
+            impl MigrationTrait for Migration {
+                async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+                    manager.get_connection().execute_unprepared(#up).await?;
+                    Ok(())
+                }
+
+                async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+                    manager.get_connection().execute_unprepared(#down).await?;
+                    Ok(())
+                }
+            }
+        },
+    )?;
+    generate_migrator()
+}
+
+/// `migration/src/lib.rs`, running every `m*.rs` migration in order.
+fn generate_migrator() -> io::Result<()> {
+    let mut migrations: Vec<String> = fs::read_dir("migration/src")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file| {
+            file.starts_with('m')
+                && file[1..].starts_with(|c: char| c.is_ascii_digit())
+                && file.ends_with(".rs")
+        })
+        .map(|file| file.trim_end_matches(".rs").to_string())
+        .collect();
+    migrations.sort();
+    let migrations: Vec<Ident> = migrations
+        .iter()
+        .map(|migration| Ident::new(migration, Span::call_site()))
+        .collect();
+
+    write_rust_file(
+        "migration/src/lib.rs",
+        quote! {
+            pub use sea_orm_migration::prelude::*;
+
+            #(mod #migrations;)*
+
+            pub struct Migrator;
+
+            #[async_trait::async_trait]
+            impl MigratorTrait for Migrator {
+                fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+                    vec![#(Box::new(#migrations::Migration),)*]
+                }
+            }
+        },
+    )
+}
+
+/// What `diesel print-schema` would add to schema.rs for the new table.
+/// rustfmt leaves macro bodies alone, so it is written laid out.
+fn append_diesel_table(model: &Model) -> io::Result<()> {
+    let columns: String = model
+        .fields
+        .iter()
+        .map(|field| format!("        {} -> {},\n", field.name, field.ty.diesel()))
+        .collect();
+    let table = format!(
+        "\ndiesel::table! {{\n    {} (id) {{\n        id -> Int8,\n{columns}    }}\n}}\n",
+        model.table
+    );
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("src/kraken/schema.rs")?;
+    file.write_all(table.as_bytes())
+}
+
+/// The fields of the row struct, `id` first.
+fn row_fields(model: &Model) -> TokenStream {
+    let fields = model.fields.iter().map(|field| {
+        let name = field.ident();
+        let ty = field.ty.rust();
+        quote! { pub #name: #ty, }
+    });
+    quote! {
+        pub id: i64,
+        #(#fields)*
+    }
+}
+
+/// The fields of the form struct. Unchecked checkboxes are missing from the
+/// form, so bools default to false.
+fn form_fields(model: &Model) -> TokenStream {
+    let fields = model.fields.iter().map(|field| {
+        let name = field.ident();
+        let ty = field.ty.rust();
+        let default = (field.ty == FieldType::Bool).then(|| quote! { #[serde(default)] });
+        quote! {
+            #default
+            pub #name: #ty,
+        }
+    });
+    quote! { #(#fields)* }
+}
+
+fn sqlx_model(model: &Model) -> String {
+    let ident = model.ident();
+    let form = model.form_ident();
+    let row_fields = row_fields(model);
+    let form_fields = form_fields(model);
+
+    let table = &model.table;
+    let columns = model.columns();
+    let placeholders: Vec<String> = (1..=model.fields.len())
+        .map(|index| format!("${index}"))
+        .collect();
+    let assignments: Vec<String> = model
+        .fields
+        .iter()
+        .zip(&placeholders)
+        .map(|(field, placeholder)| format!("{} = {placeholder}", field.name))
+        .collect();
+    let id_placeholder = format!("${}", model.fields.len() + 1);
+
+    let insert = format!(
+        "INSERT INTO {table} ({columns}) VALUES ({}) RETURNING id, {columns}",
+        placeholders.join(", ")
+    );
+    let select = format!("SELECT id, {columns} FROM {table} WHERE id = $1");
+    let list = format!("SELECT id, {columns} FROM {table} ORDER BY id DESC LIMIT $1 OFFSET $2");
+    let count = format!("SELECT COUNT(*) FROM {table}");
+    let update = format!(
+        "UPDATE {table} SET {} WHERE id = {id_placeholder} RETURNING id, {columns}",
+        assignments.join(", ")
+    );
+    let delete = format!("DELETE FROM {table} WHERE id = $1");
+    let binds: Vec<TokenStream> = model
+        .fields
+        .iter()
+        .map(|field| {
+            let name = field.ident();
+            if field.ty.is_copy() {
+                quote! { .bind(form.#name) }
+            } else {
+                quote! { .bind(&form.#name) }
+            }
+        })
+        .collect();
+
+    // rustfmt keeps lines it cannot shorten as they were, so the long queries
+    // are written laid out
+    let queries: String = [
+        ("INSERT", insert),
+        ("SELECT", select),
+        ("LIST", list),
+        ("COUNT", count),
+        ("UPDATE", update),
+        ("DELETE", delete),
+    ]
+    .iter()
+    .map(|(name, sql)| format!("const {name}: &str = {sql:?};\n"))
+    .collect();
+
+    let imports = quote! {
+        use serde::{Deserialize, Serialize};
+        use sqlx::{FromRow, PgPool};
+    };
+    let code = quote! {
+        #[derive(Clone, Debug, FromRow, Serialize)]
+        pub struct #ident {
+            #row_fields
+        }
+
+        /// The columns a row is created or updated with.
+        #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+        pub struct #form {
+            #form_fields
+        }
+
+        pub async fn create(pool: &PgPool, form: &#form) -> sqlx::Result<#ident> {
+            sqlx::query_as(INSERT)
+                #(#binds)*
+                .fetch_one(pool)
+                .await
+        }
+
+        pub async fn get(pool: &PgPool, id: i64) -> sqlx::Result<Option<#ident>> {
+            sqlx::query_as(SELECT).bind(id).fetch_optional(pool).await
+        }
+
+        /// Newest first.
+        pub async fn list(pool: &PgPool, limit: i64, offset: i64) -> sqlx::Result<Vec<#ident>> {
+            sqlx::query_as(LIST)
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(pool)
+                .await
+        }
+
+        pub async fn count(pool: &PgPool) -> sqlx::Result<i64> {
+            sqlx::query_scalar(COUNT).fetch_one(pool).await
+        }
+
+        pub async fn update(pool: &PgPool, id: i64, form: &#form) -> sqlx::Result<Option<#ident>> {
+            sqlx::query_as(UPDATE)
+                #(#binds)*
+                .bind(id)
+                .fetch_optional(pool)
+                .await
+        }
+
+        /// Whether there was a row to delete.
+        pub async fn delete(pool: &PgPool, id: i64) -> sqlx::Result<bool> {
+            let result = sqlx::query(DELETE).bind(id).execute(pool).await?;
+            Ok(result.rows_affected() > 0)
+        }
+    };
+
+    format!("{imports}\n{queries}\n{code}")
+}
+
+fn sea_model(model: &Model) -> TokenStream {
+    let ident = model.ident();
+    let form = model.form_ident();
+    let table = &model.table;
+    let form_fields = form_fields(model);
+
+    let columns = model.fields.iter().map(|field| {
+        let name = field.ident();
+        let ty = field.ty.rust();
+        let column_type =
+            (field.ty == FieldType::Text).then(|| quote! { #[sea_orm(column_type = "Text")] });
+        quote! {
+            #column_type
+            pub #name: #ty,
+        }
+    });
+    let sets: Vec<TokenStream> = model
+        .fields
+        .iter()
+        .map(|field| {
+            let name = field.ident();
+            if field.ty.is_copy() {
+                quote! { #name: Set(form.#name), }
+            } else {
+                quote! { #name: Set(form.#name.clone()), }
+            }
+        })
+        .collect();
+
+    quote! {
+        use sea_orm::entity::prelude::*;
+        use sea_orm::{ActiveValue::Set, QueryOrder, QuerySelect};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+        #[sea_orm(table_name = #table)]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            #(#columns)*
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+
+        pub type #ident = Model;
+
+        /// The columns a row is created or updated with.
+        #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+        pub struct #form {
+            #form_fields
+        }
+
+        pub async fn create(db: &DatabaseConnection, form: &#form) -> Result<#ident, DbErr> {
+            ActiveModel {
+                #(#sets)*
+                ..Default::default()
+            }
+            .insert(db)
+            .await
+        }
+
+        pub async fn get(db: &DatabaseConnection, id: i64) -> Result<Option<#ident>, DbErr> {
+            Entity::find_by_id(id).one(db).await
+        }
+
+        /// Newest first.
+        pub async fn list(db: &DatabaseConnection, limit: i64, offset: i64) -> Result<Vec<#ident>, DbErr> {
+            Entity::find()
+                .order_by_desc(Column::Id)
+                .limit(limit as u64)
+                .offset(offset as u64)
+                .all(db)
+                .await
+        }
+
+        pub async fn count(db: &DatabaseConnection) -> Result<i64, DbErr> {
+            Entity::find().count(db).await.map(|count| count as i64)
+        }
+
+        pub async fn update(db: &DatabaseConnection, id: i64, form: &#form) -> Result<Option<#ident>, DbErr> {
+            if get(db, id).await?.is_none() {
+                return Ok(None);
+            }
+            ActiveModel {
+                id: Set(id),
+                #(#sets)*
+            }
+            .update(db)
+            .await
+            .map(Some)
+        }
+
+        /// Whether there was a row to delete.
+        pub async fn delete(db: &DatabaseConnection, id: i64) -> Result<bool, DbErr> {
+            let result = Entity::delete_by_id(id).exec(db).await?;
+            Ok(result.rows_affected > 0)
+        }
+    }
+}
+
+fn diesel_model(model: &Model) -> TokenStream {
+    let ident = model.ident();
+    let form = model.form_ident();
+    let table = Ident::new(&model.table, Span::call_site());
+    let row_fields = row_fields(model);
+    let form_fields = form_fields(model);
+
+    quote! {
+        use crate::kraken::database::DbPool;
+        use crate::kraken::schema::#table;
+        use diesel::prelude::*;
+        use diesel_async::RunQueryDsl;
+        use serde::{Deserialize, Serialize};
+        use std::error::Error;
+
+        type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+        #[derive(Clone, Debug, Queryable, Selectable, Identifiable, Serialize)]
+        #[diesel(table_name = #table)]
+        pub struct #ident {
+            #row_fields
+        }
+
+        /// The columns a row is created or updated with.
+        #[derive(Clone, Debug, Default, Deserialize, Serialize, Insertable, AsChangeset)]
+        #[diesel(table_name = #table)]
+        pub struct #form {
+            #form_fields
+        }
+
+        pub async fn create(pool: &DbPool, form: &#form) -> Result<#ident> {
+            let mut connection = pool.get().await?;
+            Ok(diesel::insert_into(#table::table)
+                .values(form)
+                .returning(#ident::as_returning())
+                .get_result(&mut connection)
+                .await?)
+        }
+
+        pub async fn get(pool: &DbPool, id: i64) -> Result<Option<#ident>> {
+            let mut connection = pool.get().await?;
+            Ok(#table::table
+                .find(id)
+                .select(#ident::as_select())
+                .first(&mut connection)
+                .await
+                .optional()?)
+        }
+
+        /// Newest first.
+        pub async fn list(pool: &DbPool, limit: i64, offset: i64) -> Result<Vec<#ident>> {
+            let mut connection = pool.get().await?;
+            Ok(#table::table
+                .order(#table::id.desc())
+                .limit(limit)
+                .offset(offset)
+                .select(#ident::as_select())
+                .load(&mut connection)
+                .await?)
+        }
+
+        pub async fn count(pool: &DbPool) -> Result<i64> {
+            let mut connection = pool.get().await?;
+            Ok(#table::table.count().get_result(&mut connection).await?)
+        }
+
+        pub async fn update(pool: &DbPool, id: i64, form: &#form) -> Result<Option<#ident>> {
+            let mut connection = pool.get().await?;
+            Ok(diesel::update(#table::table.find(id))
+                .set(form)
+                .returning(#ident::as_returning())
+                .get_result(&mut connection)
+                .await
+                .optional()?)
+        }
+
+        /// Whether there was a row to delete.
+        pub async fn delete(pool: &DbPool, id: i64) -> Result<bool> {
+            let mut connection = pool.get().await?;
+            let deleted = diesel::delete(#table::table.find(id))
+                .execute(&mut connection)
+                .await?;
+            Ok(deleted > 0)
+        }
+    }
+}
+
+/// Declares `pub mod <model>;` in src/kraken/models.rs.
+fn add_module_to_models_rs(module: &str) -> io::Result<()> {
+    let declaration = format!("pub mod {module};");
+    let content = fs::read_to_string("src/kraken/models.rs").unwrap_or_default();
+    if content.lines().any(|line| line.trim() == declaration) {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("src/kraken/models.rs")?;
+    writeln!(file, "{declaration}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_splits_seconds_into_a_date() {
+        assert_eq!(utc(0), [1970, 1, 1, 0, 0, 0]);
+        // A leap day, and the last second of a year
+        assert_eq!(utc(951_782_400), [2000, 2, 29, 0, 0, 0]);
+        assert_eq!(utc(1_704_067_199), [2023, 12, 31, 23, 59, 59]);
+        assert_eq!(utc(1_718_454_645), [2024, 6, 15, 12, 30, 45]);
+    }
+}
@@ -0,0 +1,939 @@
+// oauth.rs
+//! `krk add auth --provider github|google|oidc`: login through an OAuth2 or
+//! OpenID Connect provider on top of the password login of `krk add auth`.
+use crate::add::{
+    add_module_to_main_rs, add_module_to_mod_rs, cargo_add, get_config, get_section,
+    merge_module_router_in_main_rs, set_config, unparse, write_rust_file,
+};
+use crate::ast;
+use crate::auth::append_find_by;
+use crate::database::Orm;
+use crate::field::Field;
+use crate::model::{add_migration, generate_model, Model};
+use crate::resource::class;
+use crate::secrets::{add_secret, SECRET_STORE_PARAM};
+use crate::state::{add_state_field, StateField};
+use crate::tracing;
+use clap::ValueEnum;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    /// GitHub's OAuth app flow and user api
+    Github,
+    /// Google's OpenID Connect endpoints
+    Google,
+    /// Any OpenID Connect issuer, found through its discovery document
+    Oidc,
+}
+
+impl Provider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Github => "github",
+            Self::Google => "google",
+            Self::Oidc => "oidc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "github" => Some(Self::Github),
+            "google" => Some(Self::Google),
+            "oidc" => Some(Self::Oidc),
+            _ => None,
+        }
+    }
+
+    /// What the login button calls it.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Github => "GitHub",
+            Self::Google => "Google",
+            Self::Oidc => "SSO",
+        }
+    }
+
+    /// The Secrets.toml keys it is configured with.
+    pub fn secrets(self) -> Vec<String> {
+        let prefix = self.name().to_uppercase();
+        let mut keys = vec![
+            format!("{prefix}_CLIENT_ID"),
+            format!("{prefix}_CLIENT_SECRET"),
+        ];
+        if self == Self::Oidc {
+            keys.insert(0, "OIDC_ISSUER".to_string());
+        }
+        keys
+    }
+
+    /// The statement of `Providers::load` that registers it when its
+    /// secrets are set.
+    fn load(self) -> TokenStream {
+        let name = self.name();
+        let label = self.label();
+        let prefix = name.to_uppercase();
+        let fixed = |authorization: &str, token: &str, userinfo: &str, scope: &str| {
+            quote! {
+                if let Some((client_id, client_secret)) = credentials(&secret, #prefix, #label) {
+                    let endpoints = Endpoints {
+                        authorization_endpoint: #authorization.to_string(),
+                        token_endpoint: #token.to_string(),
+                        userinfo_endpoint: #userinfo.to_string(),
+                    };
+                    providers.insert(#name, Provider {
+                        name: #name,
+                        label: #label,
+                        client_id,
+                        client_secret,
+                        scope: #scope,
+                        endpoints,
+                    });
+                }
+            }
+        };
+        match self {
+            Self::Github => fixed(
+                "https://github.com/login/oauth/authorize",
+                "https://github.com/login/oauth/access_token",
+                "https://api.github.com/user",
+                "read:user user:email",
+            ),
+            Self::Google => fixed(
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "https://oauth2.googleapis.com/token",
+                "https://openidconnect.googleapis.com/v1/userinfo",
+                "openid email",
+            ),
+            Self::Oidc => quote! {
+                if let (Some(issuer), Some((client_id, client_secret))) =
+                    (secret("OIDC_ISSUER").filter(|issuer| !issuer.is_empty()), credentials(&secret, #prefix, #label))
+                {
+                    match Endpoints::discover(client, &issuer).await {
+                        Ok(endpoints) => {
+                            providers.insert(#name, Provider {
+                                name: #name,
+                                label: #label,
+                                client_id,
+                                client_secret,
+                                scope: "openid email",
+                                endpoints,
+                            });
+                        }
+                        Err(err) => eprintln!("SSO login is off, discovery at {issuer} failed: {err}"),
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// The providers recorded in the `[auth]` table of Kraken.toml.
+fn recorded_providers() -> Vec<Provider> {
+    get_section("auth")
+        .and_then(|auth| auth.get("providers").cloned())
+        .and_then(|providers| providers.as_array().cloned())
+        .map(|providers| {
+            providers
+                .iter()
+                .filter_map(|name| name.as_str().and_then(Provider::from_name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn provider_added(provider: Provider) -> bool {
+    recorded_providers().contains(&provider)
+}
+
+/// Adds `provider` to a project with auth: the first one brings the
+/// identities table, the routes and the main.rs wiring, every one
+/// regenerates src/kraken/oauth.rs and gets a button on the login pages.
+pub fn scaffold_provider(provider: Provider) -> io::Result<()> {
+    let orm = Orm::current();
+    let first = !Path::new("src/kraken/oauth.rs").exists();
+    if first {
+        cargo_add(&[
+            "reqwest@0.12",
+            "--no-default-features",
+            "-F",
+            "json,rustls-tls",
+        ]);
+        cargo_add(&["sha2@0.10"]);
+        cargo_add(&["base64@0.22"]);
+        cargo_add(&["serde_json"]);
+
+        let fields: Vec<Field> = ["provider:String", "subject:String", "user_id:i64"]
+            .iter()
+            .map(|field| field.parse().expect("valid field"))
+            .collect();
+        let identities = Model::new("identity", &fields).map_err(io::Error::other)?;
+        generate_model(&identities)?;
+        append_find_by(orm, &identities, &["provider", "subject"])?;
+        add_migration(
+            "add_identities",
+            "CREATE UNIQUE INDEX identities_provider_subject ON identities (provider, subject);\n\
+             ALTER TABLE identities ADD CONSTRAINT identities_user_id \
+             FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;\n",
+            "ALTER TABLE identities DROP CONSTRAINT identities_user_id;\n\
+             DROP INDEX identities_provider_subject;\n",
+        )?;
+    }
+
+    let mut providers = recorded_providers();
+    providers.push(provider);
+    // rustfmt follows `mod mock;`, so the mock goes first
+    generate_mock_rs()?;
+    generate_oauth_rs(orm, &providers)?;
+    add_login_buttons(provider)?;
+
+    for key in provider.secrets() {
+        add_secret(&key, "")?;
+    }
+    add_secret("APP_URL", "http://127.0.0.1:8000")?;
+
+    if first {
+        add_module_to_mod_rs("oauth")?;
+        add_module_to_main_rs("oauth")?;
+        merge_module_router_in_main_rs("oauth")?;
+        ast::add_main_param(SECRET_STORE_PARAM)?;
+        add_state_field(&StateField {
+            name: "http",
+            ty: "reqwest::Client",
+            init: Some("let http = reqwest::Client::new();"),
+            doc: "The http client for calls to other services, it pools connections.",
+        })?;
+        add_state_field(&StateField {
+            name: "oauth",
+            ty: "crate::kraken::oauth::Providers",
+            init: Some(
+                "let oauth = kraken::oauth::Providers::load(&http, |key| secrets.get(key)).await;",
+            ),
+            doc: "The OAuth2 and OpenID Connect login providers.",
+        })?;
+    }
+
+    let names: toml_edit::Array = providers.iter().map(|provider| provider.name()).collect();
+    set_config("auth", "providers", names)
+}
+
+/// A "Continue with ..." form on login.html and register.html, carrying the
+/// `next` of the page along.
+fn add_login_buttons(provider: Provider) -> io::Result<()> {
+    let tailwind = get_config("features", "tailwindcss").is_some();
+    let c = |classes: &str| class(tailwind, classes);
+    let action = format!("/auth/{}", provider.name());
+    let button = format!(
+        r#"  <form{form} method="get" action="{action}">
+    <input type="hidden" name="next" value="{{{{ next }}}}" />
+    <button{button} type="submit">Continue with {label}</button>
+  </form>
+"#,
+        form = c("mt-4"),
+        button = c("w-full rounded border px-4 py-2 font-bold hover:bg-gray-50"),
+        label = provider.label(),
+    );
+    for page in ["login", "register"] {
+        let path = format!("templates/{page}.html");
+        let Ok(mut html) = fs::read_to_string(&path) else {
+            continue;
+        };
+        if html.contains(&format!(r#"action="{action}""#)) {
+            continue;
+        }
+        let at = html
+            .rfind("</main>")
+            .or_else(|| html.rfind("{% endblock %}"))
+            .unwrap_or(html.len());
+        html.insert_str(at, &button);
+        fs::write(&path, html)?;
+    }
+    Ok(())
+}
+
+/// `src/kraken/oauth.rs` for every recorded provider.
+fn generate_oauth_rs(orm: Orm, providers: &[Provider]) -> io::Result<()> {
+    let connection = Ident::new(orm.state_field(), Span::call_site());
+    let loads = providers.iter().map(|provider| provider.load());
+
+    let code = quote! {
+        //! Login through OAuth2 and OpenID Connect providers: the authorization
+        //! code flow with PKCE and a state check. Client ids and secrets come
+        //! from Secrets.toml, `krk add auth --provider` regenerates this file.
+        use crate::kraken::auth::{log_in, safe_next, CurrentUser, RequireAuth};
+        use crate::kraken::models::identity::{self, IdentityForm};
+        use crate::kraken::models::user::{self, UserForm};
+        use crate::kraken::state::AppState;
+        use argon2::password_hash::rand_core::{OsRng, RngCore};
+        use axum::extract::{Path, Query, State};
+        use axum::http::header::{ACCEPT, USER_AGENT};
+        use axum::http::StatusCode;
+        use axum::response::Redirect;
+        use axum::routing::get;
+        use axum::Router;
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+        use reqwest::Url;
+        use serde::{Deserialize, Serialize};
+        use sha2::{Digest, Sha256};
+        use std::collections::HashMap;
+        use std::fmt::Display;
+        use std::sync::Arc;
+        use tower_sessions::Session;
+
+        #[cfg(test)]
+        pub mod mock;
+
+        /// The session key of a login that went to the provider and hasn't
+        /// come back yet.
+        const PENDING: &str = "oauth";
+
+        type Failure = (StatusCode, String);
+
+        pub fn router() -> Router<AppState> {
+            Router::new()
+                .route("/auth/:provider", get(start))
+                .route("/auth/:provider/callback", get(callback))
+        }
+
+        /// Where a provider logs users in and hands out tokens.
+        #[derive(Clone, Debug, Deserialize)]
+        pub struct Endpoints {
+            pub authorization_endpoint: String,
+            pub token_endpoint: String,
+            pub userinfo_endpoint: String,
+        }
+
+        impl Endpoints {
+            /// The endpoints an OpenID Connect issuer publishes.
+            pub async fn discover(client: &reqwest::Client, issuer: &str) -> reqwest::Result<Self> {
+                let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+                client.get(url).send().await?.error_for_status()?.json().await
+            }
+        }
+
+        #[derive(Clone, Debug)]
+        pub struct Provider {
+            /// The path segment, e.g. `/auth/github`.
+            pub name: &'static str,
+            pub label: &'static str,
+            pub client_id: String,
+            pub client_secret: String,
+            pub scope: &'static str,
+            pub endpoints: Endpoints,
+        }
+
+        /// Who the provider says logged in.
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub struct Profile {
+            /// The id at the provider, it stays the same when the email changes.
+            pub subject: String,
+            /// Only a verified address.
+            pub email: Option<String>,
+        }
+
+        impl Provider {
+            pub fn authorize_url(&self, redirect_uri: &str, state: &str, challenge: &str) -> Result<Url, String> {
+                let mut url = Url::parse(&self.endpoints.authorization_endpoint).map_err(|err| err.to_string())?;
+                url.query_pairs_mut()
+                    .append_pair("response_type", "code")
+                    .append_pair("client_id", &self.client_id)
+                    .append_pair("redirect_uri", redirect_uri)
+                    .append_pair("scope", self.scope)
+                    .append_pair("state", state)
+                    .append_pair("code_challenge", challenge)
+                    .append_pair("code_challenge_method", "S256");
+                Ok(url)
+            }
+
+            /// Trades the code of the callback for an access token.
+            pub async fn exchange(
+                &self,
+                client: &reqwest::Client,
+                code: &str,
+                verifier: &str,
+                redirect_uri: &str,
+            ) -> Result<String, String> {
+                #[derive(Deserialize)]
+                struct Token {
+                    access_token: String,
+                }
+
+                let token: Token = client
+                    .post(&self.endpoints.token_endpoint)
+                    .header(ACCEPT, "application/json")
+                    .form(&[
+                        ("grant_type", "authorization_code"),
+                        ("code", code),
+                        ("redirect_uri", redirect_uri),
+                        ("client_id", &self.client_id),
+                        ("client_secret", &self.client_secret),
+                        ("code_verifier", verifier),
+                    ])
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|err| err.to_string())?
+                    .json()
+                    .await
+                    .map_err(|err| err.to_string())?;
+                Ok(token.access_token)
+            }
+
+            /// GitHub has its own user api, everybody else OpenID Connect
+            /// userinfo.
+            pub async fn profile(&self, client: &reqwest::Client, token: &str) -> Result<Profile, String> {
+                let fetch = |url: String| async move {
+                    client
+                        .get(url)
+                        .bearer_auth(token)
+                        .header(ACCEPT, "application/json")
+                        .header(USER_AGENT, "kraken")
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                        .map_err(|err| err.to_string())?
+                        .json::<serde_json::Value>()
+                        .await
+                        .map_err(|err| err.to_string())
+                };
+                let userinfo = fetch(self.endpoints.userinfo_endpoint.clone()).await?;
+
+                if self.name == "github" {
+                    let subject = userinfo["id"].as_i64().ok_or("GitHub sent no user id")?;
+                    let emails = fetch(format!("{}/emails", self.endpoints.userinfo_endpoint)).await?;
+                    let email = emails.as_array().into_iter().flatten().find(|email| {
+                        email["primary"].as_bool() == Some(true) && email["verified"].as_bool() == Some(true)
+                    });
+                    return Ok(Profile {
+                        subject: subject.to_string(),
+                        email: email.and_then(|email| email["email"].as_str()).map(str::to_string),
+                    });
+                }
+
+                let subject = userinfo["sub"].as_str().ok_or("The provider sent no subject")?;
+                // Some providers send "true" as a string
+                let verified = matches!(
+                    &userinfo["email_verified"],
+                    serde_json::Value::Bool(true)
+                ) || userinfo["email_verified"].as_str() == Some("true");
+                Ok(Profile {
+                    subject: subject.to_string(),
+                    email: userinfo["email"].as_str().filter(|_| verified).map(str::to_string),
+                })
+            }
+        }
+
+        /// The configured providers, `oauth` of the `AppState`.
+        #[derive(Clone)]
+        pub struct Providers {
+            /// e.g. https://example.com, the start of every callback url.
+            app_url: String,
+            providers: Arc<HashMap<&'static str, Provider>>,
+        }
+
+        impl Providers {
+            /// Every provider whose secrets are set, the others are off. OpenID
+            /// Connect discovery goes through `client`.
+            pub async fn load(client: &reqwest::Client, secret: impl Fn(&str) -> Option<String>) -> Self {
+                let mut providers = HashMap::new();
+                #(#loads)*
+                Self {
+                    app_url: secret("APP_URL").unwrap_or_else(|| "http://127.0.0.1:8000".to_string()),
+                    providers: Arc::new(providers),
+                }
+            }
+
+            fn get(&self, name: &str) -> Result<&Provider, Failure> {
+                self.providers
+                    .get(name)
+                    .ok_or((StatusCode::NOT_FOUND, format!("There is no {name} login.")))
+            }
+
+            /// What to register as the callback url at the provider.
+            pub fn redirect_uri(&self, name: &str) -> String {
+                format!("{}/auth/{name}/callback", self.app_url.trim_end_matches('/'))
+            }
+        }
+
+        /// `<PREFIX>_CLIENT_ID` and `<PREFIX>_CLIENT_SECRET`, unless one is
+        /// missing or still empty.
+        fn credentials(
+            secret: &impl Fn(&str) -> Option<String>,
+            prefix: &str,
+            label: &str,
+        ) -> Option<(String, String)> {
+            let id = secret(&format!("{prefix}_CLIENT_ID")).filter(|id| !id.is_empty());
+            let key = secret(&format!("{prefix}_CLIENT_SECRET")).filter(|key| !key.is_empty());
+            if id.is_none() || key.is_none() {
+                eprintln!("{label} login is off, set {prefix}_CLIENT_ID and {prefix}_CLIENT_SECRET in Secrets.toml");
+            }
+            id.zip(key)
+        }
+
+        /// A login that went to the provider, kept in the session.
+        #[derive(Deserialize, Serialize)]
+        struct Pending {
+            provider: String,
+            state: String,
+            verifier: String,
+            next: String,
+        }
+
+        /// 32 random bytes, url safe.
+        fn random() -> String {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        }
+
+        /// The S256 PKCE challenge of a verifier.
+        pub fn challenge(verifier: &str) -> String {
+            URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+        }
+
+        /// The callback has to answer the login this browser started,
+        /// otherwise anybody's code could log it in.
+        fn check_state(pending: Option<Pending>, provider: &str, state: Option<&str>) -> Result<Pending, String> {
+            match pending {
+                Some(pending) if pending.provider == provider && Some(pending.state.as_str()) == state => {
+                    Ok(pending)
+                }
+                _ => Err("This login expired or was started elsewhere, please try again.".to_string()),
+            }
+        }
+
+        fn internal(err: impl Display) -> Failure {
+            eprintln!("{err}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, please try again.".to_string(),
+            )
+        }
+
+        fn bad_gateway(err: impl Display) -> Failure {
+            eprintln!("{err}");
+            (
+                StatusCode::BAD_GATEWAY,
+                "The login provider did not answer as expected, please try again.".to_string(),
+            )
+        }
+
+        #[derive(Deserialize)]
+        struct StartQuery {
+            next: Option<String>,
+        }
+
+        async fn start(
+            State(app): State<AppState>,
+            Path(name): Path<String>,
+            session: Session,
+            Query(query): Query<StartQuery>,
+        ) -> Result<Redirect, Failure> {
+            let providers = &app.oauth;
+            let provider = providers.get(&name)?;
+            let pending = Pending {
+                provider: name.clone(),
+                state: random(),
+                verifier: random(),
+                next: query.next.unwrap_or_default(),
+            };
+            let url = provider
+                .authorize_url(&providers.redirect_uri(&name), &pending.state, &challenge(&pending.verifier))
+                .map_err(internal)?;
+            session.insert(PENDING, pending).await.map_err(internal)?;
+            Ok(Redirect::to(url.as_str()))
+        }
+
+        #[derive(Deserialize)]
+        struct CallbackQuery {
+            code: Option<String>,
+            state: Option<String>,
+            error: Option<String>,
+        }
+
+        async fn callback(
+            State(app): State<AppState>,
+            Path(name): Path<String>,
+            session: Session,
+            logged_in: Option<RequireAuth>,
+            Query(query): Query<CallbackQuery>,
+        ) -> Result<Redirect, Failure> {
+            let providers = &app.oauth;
+            let provider = providers.get(&name)?;
+            let pending = session.remove::<Pending>(PENDING).await.map_err(internal)?;
+            let pending = check_state(pending, &name, query.state.as_deref())
+                .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+            if let Some(error) = query.error {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    format!("The {} login did not go through: {error}", provider.label),
+                ));
+            }
+            let code = query
+                .code
+                .ok_or((StatusCode::BAD_REQUEST, "The provider sent no code.".to_string()))?;
+
+            let token = provider
+                .exchange(&app.http, &code, &pending.verifier, &providers.redirect_uri(&name))
+                .await
+                .map_err(bad_gateway)?;
+            let profile = provider.profile(&app.http, &token).await.map_err(bad_gateway)?;
+            let logged_in = logged_in.map(|RequireAuth(user)| user);
+            let user = find_or_create(&app, &name, profile, logged_in).await?;
+            log_in(&session, user)
+                .await
+                .map_err(|status| (status, String::new()))?;
+            Ok(Redirect::to(safe_next(&pending.next)))
+        }
+
+        /// The user linked to the identity. A new identity is linked to the user
+        /// who is logged in, or gets a new user without a password. Nothing is
+        /// linked by email, whoever runs a provider can claim any address.
+        async fn find_or_create(
+            app: &AppState,
+            provider: &str,
+            profile: Profile,
+            logged_in: Option<CurrentUser>,
+        ) -> Result<CurrentUser, Failure> {
+            let db = &app.#connection;
+            if let Some(identity) = identity::find_by_provider_and_subject(db, provider, &profile.subject)
+                .await
+                .map_err(internal)?
+            {
+                let row = user::get(db, identity.user_id)
+                    .await
+                    .map_err(internal)?
+                    .ok_or_else(|| internal("identity without a user"))?;
+                return Ok(CurrentUser { id: row.id, email: row.email });
+            }
+
+            let user = match logged_in {
+                Some(user) => user,
+                None => {
+                    let Some(email) = profile.email else {
+                        return Err((
+                            StatusCode::FORBIDDEN,
+                            "Logging in needs a verified email address at the provider.".to_string(),
+                        ));
+                    };
+                    let email = email.trim().to_lowercase();
+                    if user::find_by_email(db, &email).await.map_err(internal)?.is_some() {
+                        return Err((
+                            StatusCode::CONFLICT,
+                            format!("{email} already has an account. Log in to it, then visit /auth/{provider} to link the two."),
+                        ));
+                    }
+                    // An empty hash never verifies, only the provider logs in
+                    let row = user::create(db, &UserForm { email, password_hash: String::new() })
+                        .await
+                        .map_err(internal)?;
+                    CurrentUser { id: row.id, email: row.email }
+                }
+            };
+            let form = IdentityForm {
+                provider: provider.to_string(),
+                subject: profile.subject,
+                user_id: user.id,
+            };
+            identity::create(db, &form).await.map_err(internal)?;
+            Ok(user)
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::mock::{MockOidc, MockUser, CLIENT_ID, CLIENT_SECRET};
+            use super::*;
+
+            const REDIRECT_URI: &str = "http://127.0.0.1:8000/auth/oidc/callback";
+
+            async fn provider(mock: &MockOidc) -> Provider {
+                let endpoints = Endpoints::discover(&reqwest::Client::new(), &mock.issuer)
+                    .await
+                    .unwrap();
+                Provider {
+                    name: "oidc",
+                    label: "SSO",
+                    client_id: CLIENT_ID.to_string(),
+                    client_secret: CLIENT_SECRET.to_string(),
+                    scope: "openid email",
+                    endpoints,
+                }
+            }
+
+            /// Follows the authorization redirect like a browser would and
+            /// returns the code and state of the callback.
+            async fn authorize(provider: &Provider, state: &str, verifier: &str) -> (String, String) {
+                let url = provider
+                    .authorize_url(REDIRECT_URI, state, &challenge(verifier))
+                    .unwrap();
+                let client = reqwest::Client::builder()
+                    .redirect(reqwest::redirect::Policy::none())
+                    .build()
+                    .unwrap();
+                let response = client.get(url).send().await.unwrap();
+                let location = response.headers()["location"].to_str().unwrap();
+                let callback = Url::parse(location).unwrap();
+                assert!(callback.as_str().starts_with(REDIRECT_URI));
+                let query: HashMap<String, String> = callback.query_pairs().into_owned().collect();
+                (query["code"].clone(), query["state"].clone())
+            }
+
+            #[tokio::test]
+            async fn logs_in_through_the_mock_provider() {
+                let mock = MockOidc::start(MockUser::verified("alice", "alice@example.com")).await;
+                let provider = provider(&mock).await;
+                let (state, verifier) = (random(), random());
+
+                let (code, returned) = authorize(&provider, &state, &verifier).await;
+                assert!(check_state(
+                    Some(Pending {
+                        provider: "oidc".to_string(),
+                        state,
+                        verifier: verifier.clone(),
+                        next: String::new(),
+                    }),
+                    "oidc",
+                    Some(&returned),
+                )
+                .is_ok());
+
+                let client = reqwest::Client::new();
+                let token = provider.exchange(&client, &code, &verifier, REDIRECT_URI).await.unwrap();
+                let profile = provider.profile(&client, &token).await.unwrap();
+                assert_eq!(
+                    profile,
+                    Profile {
+                        subject: "alice".to_string(),
+                        email: Some("alice@example.com".to_string()),
+                    }
+                );
+            }
+
+            #[tokio::test]
+            async fn a_wrong_verifier_gets_no_token() {
+                let mock = MockOidc::start(MockUser::verified("alice", "alice@example.com")).await;
+                let provider = provider(&mock).await;
+                let (code, _) = authorize(&provider, &random(), &random()).await;
+                let client = reqwest::Client::new();
+                assert!(provider.exchange(&client, &code, &random(), REDIRECT_URI).await.is_err());
+            }
+
+            #[tokio::test]
+            async fn unverified_emails_are_dropped() {
+                let mut user = MockUser::verified("bob", "bob@example.com");
+                user.email_verified = false;
+                let mock = MockOidc::start(user).await;
+                let provider = provider(&mock).await;
+                let verifier = random();
+                let (code, _) = authorize(&provider, &random(), &verifier).await;
+                let client = reqwest::Client::new();
+                let token = provider.exchange(&client, &code, &verifier, REDIRECT_URI).await.unwrap();
+                assert_eq!(provider.profile(&client, &token).await.unwrap().email, None);
+            }
+
+            #[test]
+            fn the_state_has_to_match() {
+                let pending = || {
+                    Some(Pending {
+                        provider: "oidc".to_string(),
+                        state: "abc".to_string(),
+                        verifier: String::new(),
+                        next: String::new(),
+                    })
+                };
+                assert!(check_state(pending(), "oidc", Some("abc")).is_ok());
+                assert!(check_state(pending(), "oidc", Some("abd")).is_err());
+                assert!(check_state(pending(), "github", Some("abc")).is_err());
+                assert!(check_state(pending(), "oidc", None).is_err());
+                assert!(check_state(None, "oidc", Some("abc")).is_err());
+            }
+
+            #[test]
+            fn challenge_is_the_rfc_7636_example() {
+                assert_eq!(
+                    challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+                    "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+                );
+            }
+        }
+    };
+
+    write_rust_file("src/kraken/oauth.rs", tracing::instrument(unparse(code)))
+}
+
+/// `src/kraken/oauth/mock.rs`, an OpenID Connect provider for the tests.
+fn generate_mock_rs() -> io::Result<()> {
+    let code = quote! {
+        //! An OpenID Connect provider on a local port for tests. It approves
+        //! every authorization request as one user, and checks the client
+        //! secret, the redirect uri and PKCE like a real provider.
+        use super::challenge;
+        use axum::extract::{Query, State};
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::response::{IntoResponse, Redirect, Response};
+        use axum::routing::{get, post};
+        use axum::{Form, Json, Router};
+        use reqwest::Url;
+        use serde_json::json;
+        use std::collections::{HashMap, HashSet};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        pub const CLIENT_ID: &str = "kraken-test";
+        pub const CLIENT_SECRET: &str = "kraken-test-secret";
+
+        /// Who the mock logs in.
+        #[derive(Clone, Debug)]
+        pub struct MockUser {
+            pub subject: String,
+            pub email: String,
+            pub email_verified: bool,
+        }
+
+        impl MockUser {
+            pub fn verified(subject: &str, email: &str) -> Self {
+                Self {
+                    subject: subject.to_string(),
+                    email: email.to_string(),
+                    email_verified: true,
+                }
+            }
+        }
+
+        pub struct MockOidc {
+            /// e.g. http://127.0.0.1:43121, for `Endpoints::discover`.
+            pub issuer: String,
+        }
+
+        struct Grant {
+            challenge: String,
+            redirect_uri: String,
+        }
+
+        struct Mock {
+            issuer: String,
+            user: MockUser,
+            counter: AtomicU64,
+            grants: Mutex<HashMap<String, Grant>>,
+            tokens: Mutex<HashSet<String>>,
+        }
+
+        impl MockOidc {
+            /// Serves on a free port until the test ends.
+            pub async fn start(user: MockUser) -> Self {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let issuer = format!("http://{}", listener.local_addr().unwrap());
+                let mock = Arc::new(Mock {
+                    issuer: issuer.clone(),
+                    user,
+                    counter: AtomicU64::new(0),
+                    grants: Mutex::default(),
+                    tokens: Mutex::default(),
+                });
+                let app = Router::new()
+                    .route("/.well-known/openid-configuration", get(discovery))
+                    .route("/authorize", get(authorize))
+                    .route("/token", post(token))
+                    .route("/userinfo", get(userinfo))
+                    .with_state(mock);
+                tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+                Self { issuer }
+            }
+        }
+
+        fn refuse(status: StatusCode, error: &str) -> Response {
+            (status, Json(json!({ "error": error }))).into_response()
+        }
+
+        async fn discovery(State(mock): State<Arc<Mock>>) -> Json<serde_json::Value> {
+            let issuer = &mock.issuer;
+            Json(json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+                "userinfo_endpoint": format!("{issuer}/userinfo"),
+                "response_types_supported": ["code"],
+                "code_challenge_methods_supported": ["S256"],
+            }))
+        }
+
+        async fn authorize(
+            State(mock): State<Arc<Mock>>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> Response {
+            let param = |name: &str| query.get(name).map(String::as_str).unwrap_or_default();
+            if param("client_id") != CLIENT_ID || param("response_type") != "code" {
+                return refuse(StatusCode::BAD_REQUEST, "unauthorized_client");
+            }
+            if param("code_challenge_method") != "S256" || param("code_challenge").is_empty() {
+                return refuse(StatusCode::BAD_REQUEST, "invalid_request");
+            }
+            let Ok(mut callback) = Url::parse(param("redirect_uri")) else {
+                return refuse(StatusCode::BAD_REQUEST, "invalid_request");
+            };
+
+            let code = format!("code-{}", mock.counter.fetch_add(1, Ordering::Relaxed));
+            mock.grants.lock().unwrap().insert(
+                code.clone(),
+                Grant {
+                    challenge: param("code_challenge").to_string(),
+                    redirect_uri: param("redirect_uri").to_string(),
+                },
+            );
+            callback
+                .query_pairs_mut()
+                .append_pair("code", &code)
+                .append_pair("state", param("state"));
+            Redirect::to(callback.as_str()).into_response()
+        }
+
+        async fn token(
+            State(mock): State<Arc<Mock>>,
+            Form(form): Form<HashMap<String, String>>,
+        ) -> Response {
+            let param = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+            if param("client_id") != CLIENT_ID || param("client_secret") != CLIENT_SECRET {
+                return refuse(StatusCode::UNAUTHORIZED, "invalid_client");
+            }
+            // A code is good for one try
+            let Some(grant) = mock.grants.lock().unwrap().remove(param("code")) else {
+                return refuse(StatusCode::BAD_REQUEST, "invalid_grant");
+            };
+            if param("grant_type") != "authorization_code"
+                || param("redirect_uri") != grant.redirect_uri
+                || challenge(param("code_verifier")) != grant.challenge
+            {
+                return refuse(StatusCode::BAD_REQUEST, "invalid_grant");
+            }
+
+            let token = format!("token-{}", mock.counter.fetch_add(1, Ordering::Relaxed));
+            mock.tokens.lock().unwrap().insert(token.clone());
+            Json(json!({ "access_token": token, "token_type": "Bearer", "expires_in": 3600 })).into_response()
+        }
+
+        async fn userinfo(State(mock): State<Arc<Mock>>, headers: HeaderMap) -> Response {
+            let token = headers
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            if !mock.tokens.lock().unwrap().contains(token) {
+                return refuse(StatusCode::UNAUTHORIZED, "invalid_token");
+            }
+            Json(json!({
+                "sub": mock.user.subject,
+                "email": mock.user.email,
+                "email_verified": mock.user.email_verified,
+            }))
+            .into_response()
+        }
+    };
+
+    create_dir_all("src/kraken/oauth")?;
+    write_rust_file("src/kraken/oauth/mock.rs", unparse(code))
+}
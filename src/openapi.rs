@@ -0,0 +1,209 @@
+// openapi.rs
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add,
+    check_feature, merge_module_router_in_main_rs, set_config, unparse, write_rust_file,
+};
+use crate::assets::{vendor_asset, SCALAR};
+use crate::spec::spec_sources;
+use crate::tracing;
+use clap::ValueEnum;
+use cliclack::log::{error, info};
+use cliclack::{outro, spinner};
+use quote::quote;
+use std::env;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+const API_MOD_RS: &str = "src/kraken/api/mod.rs";
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+        }
+    }
+}
+
+/// `krk openapi`: the spec utoipa builds from the annotated handlers, written
+/// by an ignored test of src/kraken/api so it matches what the app serves.
+/// The test writes next to the output, which is only replaced once it passed.
+pub fn write_openapi(format: Format, output: Option<&str>) -> io::Result<()> {
+    if !Path::new(API_MOD_RS).exists() {
+        error("No api yet, run `krk add api <name>` first.")?;
+        return Ok(());
+    }
+
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => format!("openapi.{}", format.extension()),
+    };
+    let path = env::current_dir()?.join(&output);
+    if is_spec_source(&path) {
+        error(format!(
+            "{output} is the spec `krk add api --from` generates from, give another --output."
+        ))?;
+        return Ok(());
+    }
+    let written = env::current_dir()?.join(format!("{output}.tmp"));
+    // A spec left over from an earlier run must not pass for this one
+    match fs::remove_file(&written) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    let mut spinner = spinner();
+    spinner.start("Building the spec!");
+    let result = Command::new("cargo")
+        .args(["test", "--quiet", "kraken::api::tests::write_openapi"])
+        .args(["--", "--ignored", "--exact"])
+        .env("KRK_OPENAPI", &written)
+        .env("KRK_OPENAPI_FORMAT", format.extension())
+        .output()?;
+    spinner.stop("Done!");
+
+    if !result.status.success() {
+        error(String::from_utf8_lossy(&result.stderr))?;
+        error("Failed to build the spec, does `cargo test` compile?")?;
+        return Ok(());
+    }
+    // cargo test passes when the filter matches no test
+    if !written.exists() {
+        error(format!(
+            "No test wrote {output}, is kraken::api::tests::write_openapi still in {API_MOD_RS}?"
+        ))?;
+        return Ok(());
+    }
+    fs::rename(&written, &path)?;
+
+    outro(format!("Successfully wrote {output}. 🎉"))
+}
+
+/// Whether `path` is a spec apis are generated from, which the written one
+/// would replace.
+fn is_spec_source(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    spec_sources().iter().any(|from| {
+        Path::new(from)
+            .canonicalize()
+            .is_ok_and(|from| from == path)
+    })
+}
+
+/// The offline api reference `krk add docs` serves at `/docs`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DocsUi {
+    /// Swagger UI, embedded by utoipa-swagger-ui
+    Swagger,
+    /// Scalar, vendored into assets/vendor
+    Scalar,
+}
+
+impl DocsUi {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Swagger => "swagger",
+            Self::Scalar => "scalar",
+        }
+    }
+}
+
+/// `/docs` for the spec the api router serves at `/api/openapi.json`. Both
+/// uis are embedded into the binary, the page works without a network.
+pub fn add_docs(ui: DocsUi) -> io::Result<()> {
+    if !Path::new(API_MOD_RS).exists() {
+        error("The docs describe the apis, run `krk add api <name>` first.")?;
+        return Ok(());
+    }
+    if check_feature("docs").is_err() {
+        info("Failed to add docs!!!")?;
+        return Ok(());
+    }
+
+    match ui {
+        DocsUi::Swagger => {
+            cargo_add(&["utoipa-swagger-ui@8", "-F", "axum,vendored"]);
+            generate_swagger_docs_rs()?;
+        }
+        DocsUi::Scalar => {
+            if let Err(err) = vendor_asset(&SCALAR) {
+                error(format!("Failed to vendor scalar: {err}"))?;
+                return Ok(());
+            }
+            generate_scalar_docs_rs()?;
+        }
+    }
+
+    add_module_to_mod_rs("docs")?;
+    add_module_to_main_rs("docs")?;
+    add_kraken_to_main_rs()?;
+    merge_module_router_in_main_rs("docs")?;
+
+    set_config("docs", "ui", ui.name())?;
+    add_feature("docs")?;
+
+    outro("Successfully added /docs. 🎉")
+}
+
+fn generate_swagger_docs_rs() -> io::Result<()> {
+    let code = quote! {
+        use axum::Router;
+        use utoipa_swagger_ui::{Config, SwaggerUi};
+
+        /// The spec itself is served by the api router.
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            SwaggerUi::new("/docs")
+                .config(Config::from("/api/openapi.json"))
+                .into()
+        }
+    };
+
+    write_rust_file("src/kraken/docs.rs", tracing::instrument(unparse(code)))
+}
+
+fn generate_scalar_docs_rs() -> io::Result<()> {
+    create_dir_all("assets")?;
+    fs::write(
+        "assets/docs.html",
+        r#"<!doctype html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+    <title>API docs</title>
+  </head>
+  <body>
+    <script id="api-reference" data-url="/api/openapi.json"></script>
+    <script src="/vendor/scalar.js"></script>
+  </body>
+</html>
+"#,
+    )?;
+
+    let code = quote! {
+        use axum::response::Html;
+        use axum::routing::get;
+        use axum::Router;
+
+        /// The spec itself is served by the api router.
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            Router::new().route("/docs", get(page))
+        }
+
+        async fn page() -> Html<&'static str> {
+            Html(include_str!("../../assets/docs.html"))
+        }
+    };
+
+    write_rust_file("src/kraken/docs.rs", tracing::instrument(unparse(code)))
+}
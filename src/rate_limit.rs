@@ -0,0 +1,447 @@
+// rate_limit.rs
+//! `krk add rate-limit auth --per-minute 10`: a governor limiter around one of
+//! the routers main.rs nests or merges. The limits live in the `[rate_limit]`
+//! table of Kraken.toml, one entry per router. X-Forwarded-For only counts
+//! behind the proxies in `trusted` of its `[proxy]` table.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, get_config, get_section,
+    set_config, unparse, write_rust_file,
+};
+use crate::ast;
+use crate::error_pages;
+use crate::tracing::has_dependency;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::ValueEnum;
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+const RATE_LIMIT_RS: &str = "src/kraken/rate_limit.rs";
+
+/// What a visitor is counted by.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LimitKey {
+    /// The client address
+    Ip,
+    /// The login session, the address until there is one
+    Session,
+}
+
+impl LimitKey {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::Session => "session",
+        }
+    }
+
+    fn variant(self) -> TokenStream {
+        match self {
+            Self::Ip => quote! { Key::Ip },
+            Self::Session => quote! { Key::Session },
+        }
+    }
+}
+
+/// One entry of `[rate_limit]`, e.g. `auth = { per_minute = 10, burst = 5, key = "ip" }`.
+struct Limit {
+    group: String,
+    per_minute: u32,
+    burst: u32,
+    key: LimitKey,
+}
+
+fn recorded() -> Vec<Limit> {
+    let Some(item) = get_section("rate_limit") else {
+        return vec![];
+    };
+    let Some(table) = item.as_table_like() else {
+        return vec![];
+    };
+    table
+        .iter()
+        .filter_map(|(group, limit)| {
+            let limit = limit.as_table_like()?;
+            let number = |name: &str| {
+                limit
+                    .get(name)
+                    .and_then(|item| item.as_integer())
+                    .and_then(|number| u32::try_from(number).ok())
+                    .filter(|number| *number > 0)
+            };
+            Some(Limit {
+                group: group.to_string(),
+                per_minute: number("per_minute")?,
+                burst: number("burst")?,
+                key: match limit.get("key").and_then(|item| item.as_str()) {
+                    Some("session") => LimitKey::Session,
+                    _ => LimitKey::Ip,
+                },
+            })
+        })
+        .collect()
+}
+
+/// The `trusted` of the `[proxy]` table, addresses or `*` for any peer.
+fn recorded_proxies() -> Vec<String> {
+    get_config("proxy", "trusted")
+        .and_then(|trusted| trusted.as_array().cloned())
+        .map(|trusted| {
+            trusted
+                .iter()
+                .filter_map(|proxy| proxy.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Every file is restored if a step fails.
+pub fn add_rate_limit(
+    group: &str,
+    per_minute: u32,
+    burst: u32,
+    key: LimitKey,
+    trusted_proxies: &[String],
+) -> io::Result<()> {
+    if syn::parse_str::<Ident>(group).is_err() {
+        return error(format!(
+            "{group} is not a module, give the name of a router in main.rs, e.g. auth."
+        ));
+    }
+    if per_minute == 0 || burst == 0 {
+        return error("--per-minute and --burst have to be at least 1.");
+    }
+    if key == LimitKey::Session && get_config("features", "auth").is_none() {
+        return error("Sessions come with `krk add auth`, limit by --key ip instead.");
+    }
+    if let Some(proxy) = trusted_proxies
+        .iter()
+        .find(|proxy| *proxy != "*" && proxy.parse::<IpAddr>().is_err())
+    {
+        return error(format!(
+            "{proxy} is not an address, give the one of the proxy, e.g. 10.0.0.1, or *."
+        ));
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(group, per_minute, burst, key, trusted_proxies) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to limit {group} ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    if get_config("features", "error_pages").is_none() {
+        info("`krk add error-pages` renders a 429 page for browsers.")?;
+    }
+    if recorded_proxies().is_empty() {
+        info("Visitors are told apart by the peer address, serve the app with connect info or add --trusted-proxy <address> behind a proxy.")?;
+    }
+    outro(format!(
+        "{group} allows {per_minute} requests a minute per {}, bursts of {burst}. 🎉",
+        key.name()
+    ))
+}
+
+fn scaffold(
+    group: &str,
+    per_minute: u32,
+    burst: u32,
+    key: LimitKey,
+    trusted_proxies: &[String],
+) -> io::Result<()> {
+    cargo_add(&["governor@0.6"]);
+    cargo_add(&["--dev", "tower", "-F", "util"]);
+
+    let mut limit = toml_edit::InlineTable::new();
+    limit.insert("per_minute", i64::from(per_minute).into());
+    limit.insert("burst", i64::from(burst).into());
+    limit.insert("key", key.name().into());
+    set_config("rate_limit", group, limit)?;
+    if !trusted_proxies.is_empty() {
+        let trusted: toml_edit::Array = trusted_proxies.iter().collect();
+        set_config("proxy", "trusted", trusted)?;
+    } else if get_config("proxy", "trusted").is_none() && has_dependency("shuttle-runtime")? {
+        // shuttle's proxy sets the header and the app never sees the peer
+        info("Trusting X-Forwarded-For from shuttle's proxy, `trusted` in the [proxy] of Kraken.toml.")?;
+        set_config("proxy", "trusted", toml_edit::Array::from_iter(["*"]))?;
+    }
+    add_feature("rate_limit")?;
+
+    let first = !Path::new(RATE_LIMIT_RS).exists();
+    generate_rate_limit_rs()?;
+    if first {
+        add_module_to_mod_rs("rate_limit")?;
+        add_kraken_to_main_rs()?;
+    }
+    ast::wrap_module_router(group, &format!("kraken::rate_limit::{group}"))?;
+    error_pages::refresh()
+}
+
+/// Regenerates the limiter once error pages exist, so it answers with the
+/// 429 page.
+pub fn refresh() -> io::Result<()> {
+    if Path::new(RATE_LIMIT_RS).exists() {
+        error_pages::refresh()?;
+        generate_rate_limit_rs()?;
+    }
+    Ok(())
+}
+
+/// `src/kraken/rate_limit.rs`, rebuilt from Kraken.toml.
+fn generate_rate_limit_rs() -> io::Result<()> {
+    let limits = recorded();
+    let error_pages = get_config("features", "error_pages").is_some();
+    let by_ip = limits.iter().any(|limit| limit.key == LimitKey::Ip);
+    let by_session = limits.iter().any(|limit| limit.key == LimitKey::Session);
+    let trusted = recorded_proxies();
+
+    let groups = limits.iter().map(|limit| {
+        let group = Ident::new(&limit.group, Span::call_site());
+        let per_minute = limit.per_minute;
+        let burst = limit.burst;
+        let key = limit.key.variant();
+        let doc = format!(
+            " `{}` of Kraken.toml: {per_minute} requests a minute per {}, bursts of {burst}.",
+            limit.group,
+            limit.key.name()
+        );
+        quote! {
+            #[doc = #doc]
+            pub fn #group<S: Clone + Send + Sync + 'static>(router: Router<S>) -> Router<S> {
+                limited(router, Limit { per_minute: #per_minute, burst: #burst, key: #key })
+            }
+        }
+    });
+
+    let ip_variant = by_ip.then(|| {
+        quote! {
+            /// The client address.
+            Ip,
+        }
+    });
+    let session_variant = by_session.then(|| {
+        quote! {
+            /// The login session, the address until there is one.
+            Session,
+        }
+    });
+    let ip_arm = by_ip.then(|| quote! { Key::Ip => address(&request, TRUSTED_PROXIES), });
+    let session_arm = by_session.then(|| {
+        quote! {
+            Key::Session => request
+                .extensions()
+                .get::<Session>()
+                .and_then(Session::id)
+                .map(|id| format!("session:{id}"))
+                .or_else(|| address(&request, TRUSTED_PROXIES)),
+        }
+    });
+    let session_import = by_session.then(|| quote! { use tower_sessions::Session; });
+
+    let too_many_requests = if error_pages {
+        quote! {
+            /// The 429 page, or JSON for api requests.
+            fn too_many_requests(retry_after: u64) -> Response {
+                ([(RETRY_AFTER, retry_after.to_string())], AppError::TooManyRequests).into_response()
+            }
+        }
+    } else {
+        quote! {
+            fn too_many_requests(retry_after: u64) -> Response {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    "Too many requests, try again in a moment.",
+                )
+                    .into_response()
+            }
+        }
+    };
+    let error_import = if error_pages {
+        quote! { use crate::kraken::errors::AppError; }
+    } else {
+        quote! { use axum::http::StatusCode; }
+    };
+
+    // The tests count by the key of the first limit, without a session that is
+    // the address either way
+    let test_key = limits
+        .first()
+        .map(|limit| limit.key.variant())
+        .unwrap_or_else(|| LimitKey::Ip.variant());
+
+    write_rust_file(
+        RATE_LIMIT_RS,
+        unparse(quote! {
+            //! Generated by kraken from the `[rate_limit]` of Kraken.toml, changes are
+            //! overwritten. `krk add rate-limit <router>` limits another router.
+            #error_import
+            use axum::extract::{ConnectInfo, Request};
+            use axum::http::header::RETRY_AFTER;
+            use axum::middleware::{from_fn, Next};
+            use axum::response::{IntoResponse, Response};
+            use axum::Router;
+            use governor::clock::{Clock, DefaultClock};
+            use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+            use std::net::{IpAddr, SocketAddr};
+            use std::num::NonZeroU32;
+            use std::sync::Arc;
+            #session_import
+
+            /// Visitors counted at once before the ones whose counters ran full
+            /// again are forgotten.
+            const TRACKED: usize = 10_000;
+
+            /// The `trusted` of the `[proxy]` in Kraken.toml, the peers whose
+            /// X-Forwarded-For is believed. `*` is any peer, for proxies that
+            /// hide it.
+            const TRUSTED_PROXIES: &[&str] = &[#(#trusted),*];
+
+            /// What a visitor is counted by.
+            #[derive(Clone, Copy)]
+            enum Key {
+                #ip_variant
+                #session_variant
+            }
+
+            #[derive(Clone, Copy)]
+            struct Limit {
+                per_minute: u32,
+                /// Requests allowed in a row before the rate kicks in.
+                burst: u32,
+                key: Key,
+            }
+
+            #(#groups)*
+
+            /// Every limited router has counters of its own.
+            fn limited<S: Clone + Send + Sync + 'static>(router: Router<S>, limit: Limit) -> Router<S> {
+                let quota = Quota::per_minute(NonZeroU32::new(limit.per_minute).unwrap_or(NonZeroU32::MIN))
+                    .allow_burst(NonZeroU32::new(limit.burst).unwrap_or(NonZeroU32::MIN));
+                let limiter: Arc<DefaultKeyedRateLimiter<String>> = Arc::new(RateLimiter::keyed(quota));
+                router.layer(from_fn(move |request: Request, next: Next| {
+                    let limiter = limiter.clone();
+                    async move { check(&limiter, limit.key, request, next).await }
+                }))
+            }
+
+            async fn check(limiter: &DefaultKeyedRateLimiter<String>, key: Key, request: Request, next: Next) -> Response {
+                let visitor = match key {
+                    #ip_arm
+                    #session_arm
+                };
+                // Visitors without an address would all share one counter
+                let Some(visitor) = visitor else {
+                    eprintln!("Refusing a request without an address, serve the app with connect info or set `trusted` in the [proxy] of Kraken.toml");
+                    return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                };
+                if let Err(not_until) = limiter.check_key(&visitor) {
+                    let wait = not_until.wait_time_from(DefaultClock::default().now());
+                    return too_many_requests(wait.as_secs().max(1));
+                }
+                if limiter.len() > TRACKED {
+                    limiter.retain_recent();
+                }
+                next.run(request).await
+            }
+
+            /// The last hop of X-Forwarded-For when the peer is a trusted proxy, as
+            /// anyone else can send the header, else the peer when the app is served
+            /// with connect info. `None` when neither is known.
+            fn address(request: &Request, trusted: &[&str]) -> Option<String> {
+                let peer = request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(peer)| peer.ip());
+                let proxied = trusted.iter().any(|proxy| {
+                    *proxy == "*" || peer.is_some_and(|peer| proxy.parse::<IpAddr>() == Ok(peer))
+                });
+                if proxied {
+                    return request
+                        .headers()
+                        .get("x-forwarded-for")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.rsplit(',').next())
+                        .map(|address| address.trim().to_string())
+                        .filter(|address| !address.is_empty());
+                }
+                peer.map(|peer| peer.to_string())
+            }
+
+            #too_many_requests
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use axum::body::Body;
+                use axum::http::StatusCode;
+                use axum::routing::get;
+                use tower::ServiceExt;
+
+                fn request(peer: Option<&str>, forwarded_for: &str) -> Request {
+                    let mut request = Request::get("/")
+                        .header("x-forwarded-for", forwarded_for)
+                        .body(Body::empty())
+                        .unwrap();
+                    if let Some(peer) = peer {
+                        let peer = SocketAddr::new(peer.parse().unwrap(), 40_000);
+                        request.extensions_mut().insert(ConnectInfo(peer));
+                    }
+                    request
+                }
+
+                /// A request from `address`, forwarded or not.
+                async fn send(app: &Router, address: &str) -> Response {
+                    let request = request(Some(address), address);
+                    app.clone().oneshot(request).await.unwrap()
+                }
+
+                #[tokio::test]
+                async fn refuses_requests_over_the_burst() {
+                    let app = limited(
+                        Router::new().route("/", get(|| async { "ok" })),
+                        Limit { per_minute: 1, burst: 2, key: #test_key },
+                    );
+                    assert_eq!(send(&app, "203.0.113.7").await.status(), StatusCode::OK);
+                    assert_eq!(send(&app, "203.0.113.7").await.status(), StatusCode::OK);
+
+                    let refused = send(&app, "203.0.113.7").await;
+                    assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+                    assert!(refused.headers().contains_key(RETRY_AFTER));
+
+                    // Others have counters of their own
+                    assert_eq!(send(&app, "198.51.100.1").await.status(), StatusCode::OK);
+                }
+
+                #[test]
+                fn believes_forwarded_for_from_trusted_proxies_only() {
+                    let forwarded = "198.51.100.1, 203.0.113.7";
+                    let from_proxy = request(Some("10.0.0.1"), forwarded);
+                    assert_eq!(address(&from_proxy, &["10.0.0.1"]).as_deref(), Some("203.0.113.7"));
+                    let from_visitor = request(Some("192.0.2.9"), forwarded);
+                    assert_eq!(address(&from_visitor, &["10.0.0.1"]).as_deref(), Some("192.0.2.9"));
+                    let hidden_peer = request(None, forwarded);
+                    assert_eq!(address(&hidden_peer, &["*"]).as_deref(), Some("203.0.113.7"));
+                    assert_eq!(address(&hidden_peer, &[]), None);
+                    assert_eq!(address(&request(None, " "), &["*"]), None);
+                }
+
+                #[tokio::test]
+                async fn refuses_requests_without_an_address() {
+                    let app = limited(
+                        Router::new().route("/", get(|| async { "ok" })),
+                        Limit { per_minute: 1, burst: 2, key: #test_key },
+                    );
+                    let response = app.oneshot(request(None, "")).await.unwrap();
+                    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }),
+    )
+}
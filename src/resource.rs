@@ -0,0 +1,660 @@
+// resource.rs
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, capitalize, get_config,
+    nest_module_router_in_main_rs, unparse, write_rust_file,
+};
+use crate::assets::{add_asset, HTMX};
+use crate::csrf::write_template;
+use crate::database::Orm;
+use crate::engine::{require_template_files, view, view_imports, TemplateEngine};
+use crate::field::{snake_case, Field, FieldType};
+use crate::html::Placement;
+use crate::model::{generate_model, recorded_fields, Model};
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, warning};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use std::fs::{self, create_dir_all};
+use std::io;
+use std::path::Path;
+
+/// `krk add resource post title:String body:Text`: the model plus list, show,
+/// new and edit pages under `/posts`. Every file is restored if a step fails.
+pub fn add_resource(name: &str, fields: &[Field]) -> io::Result<()> {
+    if !require_template_files("resource")? {
+        return Ok(());
+    }
+    if get_config("features", "database").is_none() {
+        error("Resources need a database, run `krk add database` first.")?;
+        return Ok(());
+    }
+
+    // Without fields an existing model gets the pages
+    let existing = fields.is_empty();
+    let fields = if existing {
+        match recorded_fields(&snake_case(name)) {
+            Some(fields) => fields,
+            None => return error(format!("No model {name} yet, give it name:Type fields.")),
+        }
+    } else {
+        fields.to_vec()
+    };
+    let model = match Model::new(name, &fields) {
+        Ok(model) => model,
+        Err(err) => return error(err),
+    };
+    if !existing && Path::new(&model.path()).exists() {
+        error(format!(
+            "Model {} already exists, leave out the fields to reuse it.",
+            model.name
+        ))?;
+        return Ok(());
+    }
+    if Path::new(&format!("src/kraken/{}.rs", model.table)).exists() {
+        error(format!("Resource {} already exists.", model.table))?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold(&model, existing) {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add {} ({err}), every change was rolled back.",
+            model.table
+        ))?;
+        return Ok(());
+    }
+
+    outro(format!(
+        "Successfully created: /{} with list, show, new and edit pages. 🎉",
+        model.table
+    ))
+}
+
+fn scaffold(model: &Model, existing: bool) -> io::Result<()> {
+    if get_config("features", HTMX.name).is_none() {
+        warning("Inline delete and pagination use htmx, adding it first.")?;
+        add_asset(&HTMX, &Placement::Head, false)?;
+    }
+    if !existing {
+        generate_model(model)?;
+    }
+
+    generate_resource_templates(model)?;
+    generate_resource_mod_rs(TemplateEngine::current(), Orm::current(), model)?;
+
+    add_module_to_mod_rs(&model.table)?;
+    add_module_to_main_rs(&model.table)?;
+    add_kraken_to_main_rs()?;
+    nest_module_router_in_main_rs(&model.table, &format!("/{}", model.table))
+}
+
+fn generate_resource_mod_rs(engine: TemplateEngine, orm: Orm, model: &Model) -> io::Result<()> {
+    let table = &model.table;
+    let base = format!("/{table}");
+    let module = Ident::new(&model.module, Span::call_site());
+    let row = model.ident();
+    let form = model.form_ident();
+    let input = Ident::new(&format!("{}Input", model.name), Span::call_site());
+    let errors = Ident::new(&format!("{}Errors", model.name), Span::call_site());
+    let connection = Ident::new(orm.state_field(), Span::call_site());
+
+    let list_title = capitalize(table);
+    let show_title = model.name.clone();
+    let new_title = format!("New {}", model.module.replace('_', " "));
+    let edit_title = format!("Edit {}", model.module.replace('_', " "));
+
+    let imports = view_imports(engine);
+    let index_view = view(
+        engine,
+        &Ident::new("IndexTemplate", Span::call_site()),
+        &format!("{table}/index.html"),
+        quote! {},
+        quote! {
+            title: &'static str,
+            rows: Vec<#row>,
+            page: i64,
+            /// 0 on the first page.
+            prev: i64,
+            /// 0 on the last page.
+            next: i64,
+        },
+    );
+    let show_view = view(
+        engine,
+        &Ident::new("ShowTemplate", Span::call_site()),
+        &format!("{table}/show.html"),
+        quote! {},
+        quote! {
+            title: &'static str,
+            row: #row,
+        },
+    );
+    let form_view = view(
+        engine,
+        &Ident::new("FormTemplate", Span::call_site()),
+        &format!("{table}/form.html"),
+        quote! {},
+        quote! {
+            title: &'static str,
+            action: String,
+            form: #input,
+            errors: #errors,
+        },
+    );
+
+    let names: Vec<Ident> = model.fields.iter().map(Field::ident).collect();
+    let input_fields = model.fields.iter().map(|field| {
+        let name = field.ident();
+        let ty = if is_number(field.ty) {
+            quote! { String }
+        } else {
+            field.ty.rust()
+        };
+        // Unchecked checkboxes are missing from the form
+        let default = (field.ty == FieldType::Bool).then(|| quote! { #[serde(default)] });
+        quote! {
+            #default
+            #name: #ty,
+        }
+    });
+    let checks = model.fields.iter().filter_map(validation);
+    let values = model.fields.iter().map(|field| {
+        let name = field.ident();
+        match field.ty {
+            // Parsed by its check
+            ty if is_number(ty) => quote! { #name, },
+            FieldType::Bool => quote! { #name: input.#name, },
+            _ => quote! { #name: input.#name.clone(), },
+        }
+    });
+    let from_row = model.fields.iter().map(|field| {
+        let name = field.ident();
+        if is_number(field.ty) {
+            quote! { #name: row.#name.to_string(), }
+        } else {
+            quote! { #name: row.#name, }
+        }
+    });
+
+    let code = quote! {
+        use crate::kraken::models::#module::{self, #row, #form};
+        use crate::kraken::state::AppState;
+        use axum::extract::{Path, Query, State};
+        use axum::http::StatusCode;
+        use axum::response::Redirect;
+        use axum::routing::{self, get};
+        use axum::{Form, Router};
+        use serde::Deserialize;
+        #imports
+
+        const PER_PAGE: i64 = 20;
+
+        pub fn router() -> Router<AppState> {
+            Router::new()
+                .route("/", get(index).post(create))
+                .route("/new", get(new))
+                .route("/:id", get(show).post(update).delete(delete))
+                .route("/:id/edit", get(edit))
+                .route("/:id/delete", routing::post(delete_and_redirect))
+        }
+
+        #index_view
+
+        #show_view
+
+        #form_view
+
+        /// What the form sends. Numbers are kept as they were typed, so a bad
+        /// one is shown again with its message.
+        #[derive(Default, Deserialize, serde::Serialize)]
+        struct #input {
+            #(#input_fields)*
+        }
+
+        /// One message per field, empty when the field is valid.
+        #[derive(Default, serde::Serialize)]
+        struct #errors {
+            #(#names: String,)*
+        }
+
+        impl #errors {
+            fn is_empty(&self) -> bool {
+                #(self.#names.is_empty())&&*
+            }
+        }
+
+        /// The columns to save, or a message per invalid field.
+        fn validate(input: &#input) -> Result<#form, #errors> {
+            let mut errors = #errors::default();
+            #(#checks)*
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+            Ok(#form {
+                #(#values)*
+            })
+        }
+
+        #[derive(Deserialize)]
+        struct Pagination {
+            page: Option<i64>,
+        }
+
+        fn internal(err: impl std::fmt::Display) -> StatusCode {
+            eprintln!("{err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+
+        async fn index(
+            State(state): State<AppState>,
+            Query(pagination): Query<Pagination>,
+        ) -> Result<impl IntoResponse, StatusCode> {
+            let page = pagination.page.unwrap_or(1).max(1);
+            let rows = #module::list(&state.#connection, PER_PAGE, (page - 1) * PER_PAGE)
+                .await
+                .map_err(internal)?;
+            let count = #module::count(&state.#connection).await.map_err(internal)?;
+            Ok(IndexTemplate {
+                title: #list_title,
+                rows,
+                page,
+                prev: page - 1,
+                next: if page * PER_PAGE < count { page + 1 } else { 0 },
+            })
+        }
+
+        async fn show(
+            State(state): State<AppState>,
+            Path(id): Path<i64>,
+        ) -> Result<impl IntoResponse, StatusCode> {
+            let row = #module::get(&state.#connection, id)
+                .await
+                .map_err(internal)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            Ok(ShowTemplate { title: #show_title, row })
+        }
+
+        async fn new() -> impl IntoResponse {
+            FormTemplate {
+                title: #new_title,
+                action: #base.to_string(),
+                form: #input::default(),
+                errors: #errors::default(),
+            }
+        }
+
+        async fn create(
+            State(state): State<AppState>,
+            Form(input): Form<#input>,
+        ) -> Result<axum::response::Response, StatusCode> {
+            let form = match validate(&input) {
+                Ok(form) => form,
+                Err(errors) => {
+                    let page = FormTemplate {
+                        title: #new_title,
+                        action: #base.to_string(),
+                        form: input,
+                        errors,
+                    };
+                    return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+                }
+            };
+            let row = #module::create(&state.#connection, &form).await.map_err(internal)?;
+            Ok(Redirect::to(&format!("{}/{}", #base, row.id)).into_response())
+        }
+
+        async fn edit(
+            State(state): State<AppState>,
+            Path(id): Path<i64>,
+        ) -> Result<impl IntoResponse, StatusCode> {
+            let row = #module::get(&state.#connection, id)
+                .await
+                .map_err(internal)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            Ok(FormTemplate {
+                title: #edit_title,
+                action: format!("{}/{}", #base, id),
+                form: #input {
+                    #(#from_row)*
+                },
+                errors: #errors::default(),
+            })
+        }
+
+        async fn update(
+            State(state): State<AppState>,
+            Path(id): Path<i64>,
+            Form(input): Form<#input>,
+        ) -> Result<axum::response::Response, StatusCode> {
+            let form = match validate(&input) {
+                Ok(form) => form,
+                Err(errors) => {
+                    let page = FormTemplate {
+                        title: #edit_title,
+                        action: format!("{}/{}", #base, id),
+                        form: input,
+                        errors,
+                    };
+                    return Ok((StatusCode::UNPROCESSABLE_ENTITY, page).into_response());
+                }
+            };
+            #module::update(&state.#connection, id, &form)
+                .await
+                .map_err(internal)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            Ok(Redirect::to(&format!("{}/{}", #base, id)).into_response())
+        }
+
+        /// htmx swaps the row for the empty response.
+        async fn delete(State(state): State<AppState>, Path(id): Path<i64>) -> StatusCode {
+            match #module::delete(&state.#connection, id).await {
+                Ok(true) => StatusCode::OK,
+                Ok(false) => StatusCode::NOT_FOUND,
+                Err(err) => internal(err),
+            }
+        }
+
+        /// The same without javascript, from the form's post.
+        async fn delete_and_redirect(
+            State(state): State<AppState>,
+            Path(id): Path<i64>,
+        ) -> Result<Redirect, StatusCode> {
+            #module::delete(&state.#connection, id).await.map_err(internal)?;
+            Ok(Redirect::to(#base))
+        }
+    };
+
+    write_rust_file(
+        &format!("src/kraken/{table}.rs"),
+        tracing::instrument(unparse(code)),
+    )
+}
+
+fn is_number(ty: FieldType) -> bool {
+    matches!(ty, FieldType::I32 | FieldType::I64 | FieldType::F64)
+}
+
+/// The checks of one field: text is required and has to fit its column, a
+/// number has to parse and is bound to a variable of its name.
+fn validation(field: &Field) -> Option<TokenStream> {
+    let name = field.ident();
+    let required = format!("{} is required.", field.label());
+    let too_long = format!("{} is at most 255 characters.", field.label());
+    let not_email = format!("{} is not an email address.", field.label());
+    match field.ty {
+        FieldType::String => Some(quote! {
+            if input.#name.trim().is_empty() {
+                errors.#name = #required.to_string();
+            } else if input.#name.chars().count() > 255 {
+                errors.#name = #too_long.to_string();
+            }
+        }),
+        FieldType::Email => Some(quote! {
+            if input.#name.trim().is_empty() {
+                errors.#name = #required.to_string();
+            } else if input.#name.chars().count() > 255 {
+                errors.#name = #too_long.to_string();
+            } else if !input.#name.contains('@') {
+                errors.#name = #not_email.to_string();
+            }
+        }),
+        FieldType::Text => Some(quote! {
+            if input.#name.trim().is_empty() {
+                errors.#name = #required.to_string();
+            }
+        }),
+        ty if is_number(ty) => {
+            let rust = ty.rust();
+            let not_number = if ty == FieldType::F64 {
+                format!("{} is not a number.", field.label())
+            } else {
+                format!("{} is not a whole number.", field.label())
+            };
+            Some(quote! {
+                let #name = match input.#name.trim().parse::<#rust>() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        errors.#name = if input.#name.trim().is_empty() {
+                            #required.to_string()
+                        } else {
+                            #not_number.to_string()
+                        };
+                        Default::default()
+                    }
+                };
+            })
+        }
+        _ => None,
+    }
+}
+
+/// ` class="..."` with tailwindcss, nothing without it.
+pub fn class(tailwind: bool, classes: &str) -> String {
+    if tailwind {
+        format!(r#" class="{classes}""#)
+    } else {
+        String::new()
+    }
+}
+
+fn generate_resource_templates(model: &Model) -> io::Result<()> {
+    let tailwind = get_config("features", "tailwindcss").is_some();
+    let c = |classes: &str| class(tailwind, classes);
+    let table = &model.table;
+    let singular = model.module.replace('_', " ");
+    create_dir_all(format!("templates/{table}"))?;
+
+    // Long text would blow up the table, it is on the show page
+    let listed: Vec<&Field> = model
+        .fields
+        .iter()
+        .filter(|field| field.ty != FieldType::Text)
+        .collect();
+    let head: String = listed
+        .iter()
+        .map(|field| {
+            format!(
+                "\n          <th{}>{}</th>",
+                c("px-3 py-2 text-left"),
+                field.label()
+            )
+        })
+        .collect();
+    let cells: String = listed
+        .iter()
+        .map(|field| format!("\n          <td{}>{}</td>", c("px-3 py-2"), display(field)))
+        .collect();
+    let pager = |label: &str, page: &str| {
+        format!(
+            r##"{{% if {page} > 0 %}}
+      <a{} href="/{table}?page={{{{ {page} }}}}" hx-get="/{table}?page={{{{ {page} }}}}" hx-target="#{table}" hx-select="#{table}" hx-swap="outerHTML" hx-push-url="true">{label}</a>
+      {{% endif %}}"##,
+            c("hover:underline")
+        )
+    };
+
+    write_template(
+        format!("templates/{table}/index.html"),
+        &format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block content %}}
+<main{main}>
+  <div{header}>
+    <h1{h1}>{{{{ title }}}}</h1>
+    <a{button} href="/{table}/new">New {singular}</a>
+  </div>
+  <div id="{table}">
+    <table{table_class}>
+      <thead>
+        <tr>{head}
+          <th></th>
+        </tr>
+      </thead>
+      <tbody>
+        {{% for row in rows %}}
+        <tr{row_class}>{cells}
+          <td{actions}>
+            <a{link} href="/{table}/{{{{ row.id }}}}">Show</a>
+            <a{link} href="/{table}/{{{{ row.id }}}}/edit">Edit</a>
+            <form{inline} method="post" action="/{table}/{{{{ row.id }}}}/delete" hx-delete="/{table}/{{{{ row.id }}}}" hx-target="closest tr" hx-swap="outerHTML" hx-confirm="Delete this {singular}?">
+              <button{danger} type="submit">Delete</button>
+            </form>
+          </td>
+        </tr>
+        {{% endfor %}}
+      </tbody>
+    </table>
+    <nav{nav}>
+      {prev}
+      <span>Page {{{{ page }}}}</span>
+      {next}
+    </nav>
+  </div>
+</main>
+{{% endblock %}}
+"#,
+            main = c("mx-auto max-w-4xl px-4 py-12"),
+            header = c("flex items-center justify-between"),
+            h1 = c("text-4xl font-black"),
+            button = c("rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"),
+            table_class = c("mt-8 w-full border-collapse"),
+            row_class = c("border-t"),
+            actions = c("space-x-2 px-3 py-2 text-right"),
+            link = c("hover:underline"),
+            inline = c("inline"),
+            danger = c("text-red-600 hover:underline"),
+            nav = c("mt-8 flex justify-center gap-4"),
+            prev = pager("&larr; Previous", "prev"),
+            next = pager("Next &rarr;", "next"),
+        ),
+    )?;
+
+    let details: String = model
+        .fields
+        .iter()
+        .map(|field| {
+            format!(
+                "\n    <dt{}>{}</dt>\n    <dd{}>{}</dd>",
+                c("mt-4 text-sm font-bold text-gray-500"),
+                field.label(),
+                c("mt-1"),
+                display(field)
+            )
+        })
+        .collect();
+    fs::write(
+        format!("templates/{table}/show.html"),
+        format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block content %}}
+<main{main}>
+  <a{link} href="/{table}">&larr; {table}</a>
+  <h1{h1}>{{{{ title }}}} #{{{{ row.id }}}}</h1>
+  <dl>{details}
+  </dl>
+  <a{button} href="/{table}/{{{{ row.id }}}}/edit">Edit</a>
+</main>
+{{% endblock %}}
+"#,
+            main = c("mx-auto max-w-2xl px-4 py-12"),
+            link = c("text-sm hover:underline"),
+            h1 = c("mt-4 text-4xl font-black"),
+            button = c(
+                "mt-8 inline-block rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"
+            ),
+        ),
+    )?;
+
+    let inputs: String = model
+        .fields
+        .iter()
+        .map(|field| form_input(field, &c))
+        .collect();
+    write_template(
+        format!("templates/{table}/form.html"),
+        &format!(
+            r#"{{% extends "base.html" %}}
+{{% block title %}}{{{{ title }}}}{{% endblock %}}
+{{% block content %}}
+<main{main}>
+  <a{link} href="/{table}">&larr; {table}</a>
+  <h1{h1}>{{{{ title }}}}</h1>
+  <form{form} method="post" action="{{{{ action }}}}">{inputs}
+    <button{button} type="submit">Save</button>
+  </form>
+</main>
+{{% endblock %}}
+"#,
+            main = c("mx-auto max-w-2xl px-4 py-12"),
+            link = c("text-sm hover:underline"),
+            h1 = c("mt-4 text-4xl font-black"),
+            form = c("mt-8 space-y-6"),
+            button = c("rounded bg-indigo-600 px-4 py-2 font-bold text-white hover:bg-indigo-500"),
+        ),
+    )
+}
+
+/// A column of `row`. Engines print booleans differently, so they are spelled
+/// out.
+fn display(field: &Field) -> String {
+    match field.ty {
+        FieldType::Bool => format!(
+            "{{% if row.{} %}}Yes{{% else %}}No{{% endif %}}",
+            field.name
+        ),
+        _ => format!("{{{{ row.{} }}}}", field.name),
+    }
+}
+
+/// The label, input and error message of one field.
+pub fn form_input(field: &Field, c: &dyn Fn(&str) -> String) -> String {
+    let name = &field.name;
+    let label = field.label();
+    let input = match field.ty {
+        FieldType::Text => format!(
+            r#"<textarea{} id="{name}" name="{name}" rows="6" required>{{{{ form.{name} }}}}</textarea>"#,
+            c("mt-1 w-full rounded border px-3 py-2")
+        ),
+        FieldType::Bool => format!(
+            r#"<input{} type="checkbox" id="{name}" name="{name}" value="true" {{% if form.{name} %}}checked{{% endif %}} />"#,
+            c("mr-2")
+        ),
+        ty => {
+            let step = if ty == FieldType::F64 {
+                r#" step="any""#
+            } else {
+                ""
+            };
+            format!(
+                r#"<input{} type="{}" id="{name}" name="{name}" value="{{{{ form.{name} }}}}"{step} required />"#,
+                c("mt-1 w-full rounded border px-3 py-2"),
+                ty.input()
+            )
+        }
+    };
+    let field_html = if field.ty == FieldType::Bool {
+        format!(
+            r#"<label{} for="{name}">{input}{label}</label>"#,
+            c("flex items-center font-bold")
+        )
+    } else {
+        format!(
+            r#"<label{} for="{name}">{label}</label>
+      {input}"#,
+            c("block font-bold")
+        )
+    };
+    format!(
+        r#"
+    <div>
+      {field_html}
+      {{% if errors.{name} != "" %}}<p{}>{{{{ errors.{name} }}}}</p>{{% endif %}}
+    </div>"#,
+        c("mt-1 text-sm text-red-600")
+    )
+}
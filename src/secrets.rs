@@ -0,0 +1,433 @@
+// secrets.rs
+//! `krk secrets`: edits Secrets.toml and Secrets.dev.toml, and generates a
+//! typed `Config` from the secrets recorded in the `[secrets]` of Kraken.toml.
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_mod_rs, get_config, get_section, kraken_toml_exists,
+    remove_config, set_config, unparse, write_rust_file,
+};
+use crate::ast;
+use crate::execute::Execute;
+use crate::kraken::MagentaTheme;
+use crate::state::{add_state_field, StateField};
+use crate::transaction::{Transaction, PROJECT_FILES};
+use clap::Subcommand;
+use cliclack::log::{error, success, warning};
+use cliclack::{intro, note, outro, set_theme};
+use console::style;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml_edit::Document;
+
+/// What `shuttle deploy` reads.
+pub const SECRETS_TOML: &str = "Secrets.toml";
+/// What `shuttle run` reads instead, when it exists.
+pub const SECRETS_DEV_TOML: &str = "Secrets.dev.toml";
+/// The main parameter shuttle passes the secrets of the running app in.
+pub const SECRET_STORE_PARAM: &str =
+    "#[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore";
+const CONFIG_RS: &str = "src/kraken/config.rs";
+
+#[derive(Subcommand)]
+pub enum Secrets {
+    /// Set a secret, e.g. `krk secrets set STRIPE_KEY sk_test_...`
+    Set {
+        key: String,
+        value: String,
+        /// In Secrets.dev.toml, which `shuttle run` reads instead
+        #[arg(long)]
+        dev: bool,
+        /// The app starts without it, its `Config` field is an `Option`
+        #[arg(long)]
+        optional: bool,
+    },
+    /// Print a secret
+    Get {
+        key: String,
+        /// From Secrets.dev.toml
+        #[arg(long)]
+        dev: bool,
+    },
+    /// Every secret and the files that set it, without their values
+    List,
+    /// Remove a secret
+    Rm {
+        key: String,
+        /// From Secrets.dev.toml
+        #[arg(long)]
+        dev: bool,
+    },
+}
+
+impl Execute for Secrets {
+    fn execute(&self) -> anyhow::Result<()> {
+        // Only the value, so it can be piped
+        if let Self::Get { key, dev } = self {
+            match read(file(*dev))?.get(key).and_then(|item| item.as_str()) {
+                Some(value) => println!("{value}"),
+                None => error(format!("{} has no {key}.", file(*dev)))?,
+            }
+            return Ok(());
+        }
+
+        set_theme(MagentaTheme);
+        intro(style(" kraken ").on_magenta().black())?;
+        if !kraken_toml_exists() {
+            error("Kraken not initialized.")?;
+            return Ok(());
+        }
+
+        match self {
+            Self::Set {
+                key,
+                value,
+                dev,
+                optional,
+            } => set_secret(key, value, *dev, *optional)?,
+            Self::List => list_secrets()?,
+            Self::Rm { key, dev } => remove_secret(key, *dev)?,
+            Self::Get { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn file(dev: bool) -> &'static str {
+    if dev {
+        SECRETS_DEV_TOML
+    } else {
+        SECRETS_TOML
+    }
+}
+
+/// The secrets of `path`, empty when there is no such file.
+fn read(path: &str) -> io::Result<Document> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(io::Error::new(err.kind(), format!("{path}: {err}"))),
+    };
+    source
+        .parse::<Document>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {err}")))
+}
+
+/// Keys in the `[secrets]` of Kraken.toml, with whether the app needs them.
+pub fn recorded() -> Vec<(String, bool)> {
+    get_section("secrets")
+        .and_then(|section| {
+            section.as_table_like().map(|table| {
+                table
+                    .iter()
+                    .map(|(key, need)| (key.to_string(), need.as_str() != Some("optional")))
+                    .collect()
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Keeps Secrets.toml and Secrets.dev.toml out of git.
+fn ignore_secrets() -> io::Result<()> {
+    let gitignore = fs::read_to_string(".gitignore").unwrap_or_default();
+    if gitignore.lines().any(|line| line.trim() == "Secrets*.toml") {
+        return Ok(());
+    }
+    let separator = if gitignore.is_empty() || gitignore.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    fs::write(
+        ".gitignore",
+        format!("{gitignore}{separator}Secrets*.toml\n"),
+    )
+}
+
+/// Adds `key` to Secrets.toml unless it is set already, as an optional
+/// secret. For generators that need one, e.g. oauth client ids.
+pub fn add_secret(key: &str, value: &str) -> io::Result<()> {
+    if let Some(recorded) = clashing_key(key) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{key} would be the same Config field as {recorded}"),
+        ));
+    }
+    let mut doc = read(SECRETS_TOML)?;
+    if doc.get(key).is_none() {
+        doc[key] = toml_edit::value(value);
+        fs::write(SECRETS_TOML, doc.to_string())?;
+    }
+    ignore_secrets()?;
+    if get_config("secrets", key).is_none() {
+        set_config("secrets", key, "optional")?;
+    }
+    // Only once `krk secrets` wired the config into main.rs
+    if Path::new(CONFIG_RS).exists() {
+        generate_config_rs()?;
+    }
+    Ok(())
+}
+
+/// Secrets become `Config` fields, so they have to be identifiers. `self`
+/// and friends cannot even be raw ones.
+fn valid_key(key: &str) -> bool {
+    key.starts_with(|first: char| first.is_ascii_alphabetic())
+        && key
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && !["crate", "self", "super"].contains(&key.to_lowercase().as_str())
+}
+
+/// A recorded key that is another spelling of `key`, e.g. `api_key` for
+/// `API_KEY`. Both would be the same `Config` field.
+fn clashing_key(key: &str) -> Option<String> {
+    recorded()
+        .into_iter()
+        .map(|(recorded, _)| recorded)
+        .find(|recorded| recorded != key && recorded.to_lowercase() == key.to_lowercase())
+}
+
+fn set_secret(key: &str, value: &str, dev: bool, optional: bool) -> io::Result<()> {
+    if !valid_key(key) {
+        error(format!(
+            "{key} is not a valid key, use letters, digits and _, e.g. STRIPE_KEY."
+        ))?;
+        return Ok(());
+    }
+    if let Some(recorded) = clashing_key(key) {
+        error(format!(
+            "{key} would be the same Config field as {recorded}, set {recorded} instead."
+        ))?;
+        return Ok(());
+    }
+    let path = file(dev);
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    let result = (|| {
+        let mut doc = read(path)?;
+        doc[key] = toml_edit::value(value);
+        fs::write(path, doc.to_string())?;
+        ignore_secrets()?;
+
+        // A recorded secret keeps its need unless --optional is given
+        if optional || get_config("secrets", key).is_none() {
+            set_config(
+                "secrets",
+                key,
+                if optional { "optional" } else { "required" },
+            )?;
+        }
+        let first = !Path::new(CONFIG_RS).exists();
+        generate_config_rs()?;
+        if first {
+            wire_config()?;
+        }
+        Ok::<(), io::Error>(())
+    })();
+    if let Err(err) = result {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to set {key} ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    let required = recorded()
+        .iter()
+        .any(|(recorded, required)| recorded == key && *required);
+    let other = file(!dev);
+    if required && Path::new(other).exists() && read(other)?.get(key).is_none() {
+        warning(format!(
+            "{other} has no {key}, the app refuses to start with it."
+        ))?;
+    }
+    outro(format!("Set {key} in {path}. 🎉"))
+}
+
+fn list_secrets() -> io::Result<()> {
+    let (secrets, dev) = (read(SECRETS_TOML)?, read(SECRETS_DEV_TOML)?);
+    let recorded = recorded();
+    let mut keys: Vec<String> = recorded.iter().map(|(key, _)| key.clone()).collect();
+    for (key, _) in secrets.iter().chain(dev.iter()) {
+        if !keys.iter().any(|known| known == key) {
+            keys.push(key.to_string());
+        }
+    }
+    if keys.is_empty() {
+        return outro("No secrets yet, add one with `krk secrets set <KEY> <value>`.");
+    }
+
+    let width = keys.iter().map(String::len).max().unwrap_or(0);
+    let set_in = |doc: &Document, key: &str| match doc.get(key).and_then(|item| item.as_str()) {
+        Some("") => "empty",
+        Some(_) => "set",
+        None => "-",
+    };
+    let lines: Vec<String> = keys
+        .iter()
+        .map(|key| {
+            let need = match recorded.iter().find(|(recorded, _)| recorded == key) {
+                Some((_, true)) => "required",
+                Some((_, false)) => "optional",
+                None => "not in Config",
+            };
+            format!(
+                "{key:width$}  {need:13}  {SECRETS_TOML} {:5}  {SECRETS_DEV_TOML} {}",
+                set_in(&secrets, key),
+                set_in(&dev, key),
+            )
+        })
+        .collect();
+    note("Secrets", lines.join("\n"))?;
+    outro("Read them from the `Config` in src/kraken/config.rs.")
+}
+
+fn remove_secret(key: &str, dev: bool) -> io::Result<()> {
+    let path = file(dev);
+    let mut doc = read(path)?;
+    if doc.remove(key).is_none() {
+        error(format!("{path} has no {key}."))?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    let result = (|| {
+        fs::write(path, doc.to_string())?;
+        // The Config field goes once neither file sets it
+        if read(file(!dev))?.get(key).is_none() {
+            remove_config("secrets", key)?;
+            if Path::new(CONFIG_RS).exists() {
+                generate_config_rs()?;
+            }
+        }
+        Ok::<(), io::Error>(())
+    })();
+    if let Err(err) = result {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to remove {key} ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+    success(format!("Removed {key} from {path}."))?;
+    outro("Done. 🎉")
+}
+
+/// Loads the config before the router is built, so a missing secret stops
+/// the app right at startup.
+fn wire_config() -> io::Result<()> {
+    add_module_to_mod_rs("config")?;
+    add_kraken_to_main_rs()?;
+    ast::add_main_param(SECRET_STORE_PARAM)?;
+    add_state_field(&StateField {
+        name: "config",
+        ty: "crate::kraken::config::Config",
+        init: Some(
+            "let config = kraken::config::Config::load(|key| secrets.get(key)).unwrap_or_else(|missing| panic!(\"{missing}\"));",
+        ),
+        doc: "The secrets of Secrets.toml, checked at startup.",
+    })
+}
+
+fn field(key: &str) -> Ident {
+    let name = key.to_lowercase();
+    // e.g. TYPE becomes r#type
+    if syn::parse_str::<Ident>(&name).is_ok() {
+        Ident::new(&name, Span::call_site())
+    } else {
+        Ident::new_raw(&name, Span::call_site())
+    }
+}
+
+/// `src/kraken/config.rs`, rebuilt from Kraken.toml whenever a secret is set
+/// or removed.
+fn generate_config_rs() -> io::Result<()> {
+    let recorded = recorded();
+    let required: Vec<&str> = recorded
+        .iter()
+        .filter(|(_, required)| *required)
+        .map(|(key, _)| key.as_str())
+        .collect();
+    let optional: Vec<&str> = recorded
+        .iter()
+        .filter(|(_, required)| !*required)
+        .map(|(key, _)| key.as_str())
+        .collect();
+    let required_fields: Vec<Ident> = required.iter().map(|key| field(key)).collect();
+    let optional_fields: Vec<Ident> = optional.iter().map(|key| field(key)).collect();
+
+    write_rust_file(
+        CONFIG_RS,
+        unparse(quote! {
+            //! Generated by kraken from the `[secrets]` of Kraken.toml, changes are
+            //! overwritten. `krk secrets set` adds a secret.
+            use std::fmt;
+
+            /// The secrets the app doesn't start without.
+            pub const REQUIRED: &[&str] = &[#(#required),*];
+
+            /// Handlers read it from `State<AppState>` as `state.config`. There is
+            /// no Debug, so secrets don't end up in logs.
+            #[derive(Clone)]
+            pub struct Config {
+                #(pub #required_fields: String,)*
+                #(pub #optional_fields: Option<String>,)*
+            }
+
+            /// Every required secret that is missing or empty.
+            #[derive(Debug)]
+            pub struct MissingSecrets(pub Vec<&'static str>);
+
+            impl fmt::Display for MissingSecrets {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(
+                        f,
+                        "Missing secrets: {}. Set them with `krk secrets set <KEY> <value>`.",
+                        self.0.join(", ")
+                    )
+                }
+            }
+
+            impl std::error::Error for MissingSecrets {}
+
+            impl Config {
+                /// Reads every secret through `secret`, e.g. `|key| secrets.get(key)`.
+                pub fn load(secret: impl Fn(&str) -> Option<String>) -> Result<Self, MissingSecrets> {
+                    let value = |key: &str| secret(key).filter(|value| !value.is_empty());
+                    let missing: Vec<&'static str> = REQUIRED
+                        .iter()
+                        .copied()
+                        .filter(|key| value(key).is_none())
+                        .collect();
+                    if !missing.is_empty() {
+                        return Err(MissingSecrets(missing));
+                    }
+                    Ok(Self {
+                        #(#required_fields: value(#required).unwrap_or_default(),)*
+                        #(#optional_fields: value(#optional),)*
+                    })
+                }
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use std::collections::HashMap;
+
+                #[test]
+                fn the_required_secrets_are_enough() {
+                    let secrets: HashMap<&str, &str> = REQUIRED.iter().map(|key| (*key, "set")).collect();
+                    assert!(Config::load(|key| secrets.get(key).map(|value| value.to_string())).is_ok());
+                }
+
+                #[test]
+                fn every_missing_secret_is_reported() {
+                    let missing = Config::load(|_| Some(String::new())).err();
+                    assert_eq!(missing.map(|missing| missing.0).unwrap_or_default(), REQUIRED);
+                }
+            }
+        }),
+    )
+}
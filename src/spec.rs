@@ -0,0 +1,1278 @@
+// spec.rs
+//! `krk add api --from openapi.yaml`: serde types, handler stubs and routes
+//! for every operation of a spec. Re-running it rewrites the routes, types and
+//! handler signatures but keeps what the handlers do.
+use crate::add::{
+    add_kraken_to_main_rs, add_module_to_main_rs, add_module_to_mod_rs, cargo_add, get_config,
+    get_section, nest_module_router_in_main_rs, set_config, unparse, write_rust_file,
+};
+use crate::api::{generate_api_mod_rs, recorded_apis, responses, Method};
+use crate::ast::source_text;
+use crate::field::{pascal_case, snake_case};
+use crate::tracing;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info, warning};
+use cliclack::outro;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, create_dir_all};
+use std::io;
+use syn::{FnArg, Item, ItemFn, ItemUse, Pat};
+
+const SCHEMAS_RS: &str = "src/kraken/api/schemas.rs";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A rust type and how `#[utoipa::path]` spells it.
+#[derive(Clone)]
+struct Type {
+    rust: String,
+    /// `[Pet]` for `Vec<Pet>`.
+    schema: String,
+    /// Whether it names something in schemas.rs.
+    generated: bool,
+}
+
+impl Type {
+    fn plain(rust: &str) -> Self {
+        Self {
+            rust: rust.to_string(),
+            schema: rust.to_string(),
+            generated: false,
+        }
+    }
+
+    fn tokens(&self) -> TokenStream {
+        self.rust.parse().expect("a rust type")
+    }
+}
+
+struct Param {
+    /// As the spec spells it, e.g. `petId`.
+    name: String,
+    ty: Type,
+    description: Option<String>,
+}
+
+struct Response {
+    /// `200`, `"default"` or `"4XX"`.
+    status: String,
+    description: String,
+    body: Option<Type>,
+}
+
+struct Operation {
+    method: Method,
+    /// The module in src/kraken/api, from the first path segment.
+    module: String,
+    /// `/:petId`, below the module's nest.
+    route: String,
+    /// `/api/pets/{petId}`, what the app serves.
+    path: String,
+    handler: String,
+    operation_id: String,
+    summary: Option<String>,
+    path_params: Vec<Param>,
+    /// The `IntoParams` struct of the query parameters.
+    query: Option<String>,
+    body: Option<Type>,
+    responses: Vec<Response>,
+}
+
+/// A rust identifier for any spec name, `r#type` for keywords.
+fn ident(name: &str) -> Ident {
+    let mut snake = snake_case(name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if snake.starts_with(|c: char| c.is_ascii_digit()) || snake.is_empty() {
+        snake.insert(0, '_');
+    }
+    syn::parse_str::<Ident>(&snake).unwrap_or_else(|_| Ident::new_raw(&snake, Span::call_site()))
+}
+
+/// `PetStatus` for `pet-status` or `pet.status`.
+fn type_name(name: &str) -> String {
+    let words: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    pascal_case(&snake_case(&words).replace("__", "_"))
+}
+
+fn text(value: &Value, key: &str) -> Option<String> {
+    value.get(key)?.as_str().map(str::to_string)
+}
+
+/// The spec plus everything schemas.rs gets.
+struct Spec {
+    root: Value,
+    items: Vec<TokenStream>,
+    names: HashSet<String>,
+    /// `pub type Pets = Vec<Pet>;` is `[Pet]` for utoipa.
+    aliases: HashMap<String, String>,
+}
+
+impl Spec {
+    fn read(file: &str) -> io::Result<Self> {
+        Self::parse(file, &fs::read_to_string(file)?)
+    }
+
+    fn parse(file: &str, content: &str) -> io::Result<Self> {
+        // JSON is valid YAML, so one parser reads both
+        let root: Value = serde_yaml::from_str(content)
+            .map_err(|err| invalid(format!("{file} is not yaml or json: {err}")))?;
+        if root.get("openapi").is_none() {
+            return Err(invalid(format!(
+                "{file} has no `openapi` version, only OpenAPI 3 is supported"
+            )));
+        }
+        Ok(Self {
+            root,
+            items: Vec::new(),
+            names: HashSet::new(),
+            aliases: HashMap::new(),
+        })
+    }
+
+    /// Follows a local `$ref` like `#/components/schemas/Pet`.
+    fn resolve<'a>(&'a self, value: &'a Value) -> &'a Value {
+        let Some(reference) = value.get("$ref").and_then(Value::as_str) else {
+            return value;
+        };
+        let mut target = &self.root;
+        for part in reference.trim_start_matches("#/").split('/') {
+            let part = part.replace("~1", "/").replace("~0", "~");
+            match target.get(part.as_str()) {
+                Some(next) => target = next,
+                None => return value,
+            }
+        }
+        self.resolve(target)
+    }
+
+    fn components(&self) -> Vec<(String, Value)> {
+        self.root
+            .get("components")
+            .and_then(|components| components.get("schemas"))
+            .and_then(Value::as_mapping)
+            .map(|schemas| {
+                schemas
+                    .iter()
+                    .filter_map(|(name, schema)| Some((name.as_str()?.to_string(), schema.clone())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every component becomes a struct, or a type alias if it has no
+    /// properties. Aliases go first so the structs can spell them for utoipa.
+    fn generate_components(&mut self) {
+        let components = self.components();
+        for (name, schema) in &components {
+            if !is_struct(schema) {
+                let alias = type_name(name);
+                self.names.insert(alias.clone());
+                let ty = self.ty(schema, &alias);
+                self.aliases.insert(alias, ty.schema);
+            }
+        }
+        for (name, schema) in &components {
+            let name = type_name(name);
+            if is_struct(schema) {
+                self.generate_struct(&name, schema);
+            } else {
+                let ident = Ident::new(&name, Span::call_site());
+                let ty = self.ty(schema, &name).tokens();
+                let doc = doc(schema);
+                self.items.push(quote! {
+                    #doc
+                    pub type #ident = #ty;
+                });
+            }
+        }
+    }
+
+    /// The type of `schema`, `hint` names it if it is an inline object.
+    fn ty(&mut self, schema: &Value, hint: &str) -> Type {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if let Some(name) = reference.strip_prefix("#/components/schemas/") {
+                let name = type_name(name);
+                return Type {
+                    schema: self.aliases.get(&name).cloned().unwrap_or(name.clone()),
+                    rust: name,
+                    generated: true,
+                };
+            }
+            let resolved = self.resolve(schema).clone();
+            return self.ty(&resolved, hint);
+        }
+
+        // OpenAPI 3.1 spells nullable as `type: [string, "null"]`
+        let kind = match schema.get("type") {
+            Some(Value::Sequence(kinds)) => kinds
+                .iter()
+                .filter_map(Value::as_str)
+                .find(|kind| *kind != "null"),
+            Some(kind) => kind.as_str(),
+            None => None,
+        };
+        let format = text(schema, "format");
+        match kind {
+            Some("string") => Type::plain("String"),
+            Some("integer") if format.as_deref() == Some("int32") => Type::plain("i32"),
+            Some("integer") => Type::plain("i64"),
+            Some("number") => Type::plain("f64"),
+            Some("boolean") => Type::plain("bool"),
+            Some("array") => {
+                let items = schema.get("items").cloned().unwrap_or(Value::Null);
+                let item = self.ty(&items, &format!("{hint}Item"));
+                Type {
+                    rust: format!("Vec<{}>", item.rust),
+                    schema: format!("[{}]", item.schema),
+                    generated: item.generated,
+                }
+            }
+            _ if is_struct(schema) => {
+                self.generate_struct(hint, schema);
+                Type {
+                    rust: hint.to_string(),
+                    schema: hint.to_string(),
+                    generated: true,
+                }
+            }
+            _ => Type {
+                rust: "serde_json::Value".to_string(),
+                schema: "Object".to_string(),
+                generated: false,
+            },
+        }
+    }
+
+    fn generate_struct(&mut self, name: &str, schema: &Value) {
+        if !self.names.insert(name.to_string()) {
+            return;
+        }
+        let required: HashSet<&str> = schema
+            .get("required")
+            .and_then(Value::as_sequence)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let properties: Vec<(String, Value)> = schema
+            .get("properties")
+            .and_then(Value::as_mapping)
+            .map(|properties| {
+                properties
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.as_str()?.to_string(), value.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let fields: Vec<TokenStream> = properties
+            .iter()
+            .map(|(property, value)| {
+                let field = ident(property);
+                let mut ty = self.ty(value, &format!("{name}{}", type_name(property)));
+                if ty.rust == name {
+                    ty.rust = format!("Box<{name}>");
+                }
+                let schema_type = (ty.schema == "Object").then(|| {
+                    quote! { #[schema(value_type = Object)] }
+                });
+                let rename = (field.to_string().trim_start_matches("r#") != property).then(|| {
+                    quote! { #[serde(rename = #property)] }
+                });
+                let doc = doc(value);
+                let ty = ty.tokens();
+                if required.contains(property.as_str()) {
+                    quote! {
+                        #doc
+                        #rename
+                        #schema_type
+                        pub #field: #ty,
+                    }
+                } else {
+                    quote! {
+                        #doc
+                        #rename
+                        #schema_type
+                        #[serde(default, skip_serializing_if = "Option::is_none")]
+                        pub #field: Option<#ty>,
+                    }
+                }
+            })
+            .collect();
+
+        let ident = Ident::new(name, Span::call_site());
+        let doc = doc(schema);
+        self.items.push(quote! {
+            #doc
+            #[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+            pub struct #ident {
+                #(#fields)*
+            }
+        });
+    }
+
+    /// The parameters of the path item and the operation, resolved.
+    fn parameters(&self, path_item: &Value, operation: &Value) -> Vec<Value> {
+        let mut parameters: Vec<Value> = Vec::new();
+        for list in [path_item.get("parameters"), operation.get("parameters")] {
+            for parameter in list.and_then(Value::as_sequence).into_iter().flatten() {
+                let parameter = self.resolve(parameter).clone();
+                // An operation parameter overrides the path item's
+                parameters.retain(|existing| {
+                    existing.get("name") != parameter.get("name")
+                        || existing.get("in") != parameter.get("in")
+                });
+                parameters.push(parameter);
+            }
+        }
+        parameters
+    }
+
+    /// The json schema of a request body or response, if it has one.
+    fn json_content<'a>(&'a self, value: &'a Value) -> Option<&'a Value> {
+        let content = self.resolve(value).get("content")?.as_mapping()?;
+        content
+            .iter()
+            .find(|(media, _)| media.as_str().is_some_and(|media| media.contains("json")))
+            .and_then(|(_, media)| media.get("schema"))
+    }
+
+    fn operations(&mut self) -> Vec<Operation> {
+        let paths: Vec<(String, Value)> = self
+            .root
+            .get("paths")
+            .and_then(Value::as_mapping)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|(path, item)| Some((path.as_str()?.to_string(), item.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut operations = Vec::new();
+        let mut handlers: HashSet<(String, String)> = HashSet::new();
+        for (path, item) in paths {
+            let item = self.resolve(&item).clone();
+            let mut segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+            // krk serves every api below /api already
+            if segments.first() == Some(&"api") && segments.len() > 1 {
+                segments.remove(0);
+            }
+            let first = segments[0];
+            if first.is_empty() || first.starts_with('{') {
+                let _ = warning(format!("Skipped {path}, it needs a fixed first segment."));
+                continue;
+            }
+            let module = ident(first)
+                .to_string()
+                .trim_start_matches("r#")
+                .to_string();
+            let rest = &segments[1..];
+            let route = format!(
+                "/{}",
+                rest.iter()
+                    .map(|segment| match segment.strip_prefix('{') {
+                        Some(param) => format!(":{}", param.trim_end_matches('}')),
+                        None => segment.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/")
+            );
+            let served = format!("/api/{}", segments.join("/"));
+
+            let Some(methods) = item.as_mapping() else {
+                continue;
+            };
+            for (method, operation) in methods {
+                let Some(name) = method.as_str() else {
+                    continue;
+                };
+                if name == "parameters" || name == "summary" || name == "description" {
+                    continue;
+                }
+                let Some(method) = Method::from_name(name) else {
+                    let _ = warning(format!("Skipped {} {path}.", name.to_uppercase()));
+                    continue;
+                };
+
+                let operation_id = text(operation, "operationId").unwrap_or_else(|| {
+                    let words: Vec<String> = segments
+                        .iter()
+                        .map(|segment| segment.trim_matches(|c| c == '{' || c == '}').to_string())
+                        .collect();
+                    format!("{}_{}", method.name(), words.join("_"))
+                });
+                let mut handler = ident(&operation_id)
+                    .to_string()
+                    .trim_start_matches("r#")
+                    .to_string();
+                if handler == "router" || !handlers.insert((module.clone(), handler.clone())) {
+                    handler = format!("{handler}_{}", method.name());
+                    handlers.insert((module.clone(), handler.clone()));
+                }
+
+                operations.push(self.operation(
+                    method,
+                    &module,
+                    &route,
+                    &served,
+                    handler,
+                    operation_id,
+                    &item,
+                    operation,
+                ));
+            }
+        }
+        operations
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn operation(
+        &mut self,
+        method: Method,
+        module: &str,
+        route: &str,
+        path: &str,
+        handler: String,
+        operation_id: String,
+        item: &Value,
+        operation: &Value,
+    ) -> Operation {
+        let pascal = pascal_case(&handler);
+        let parameters = self.parameters(item, operation);
+
+        // Path parameters in the order of the path, that is how axum hands them out
+        let mut path_params = Vec::new();
+        for segment in path.split('/') {
+            let Some(name) = segment.strip_prefix('{') else {
+                continue;
+            };
+            let name = name.trim_end_matches('}').to_string();
+            let parameter = parameters
+                .iter()
+                .find(|parameter| text(parameter, "name").as_deref() == Some(name.as_str()));
+            let ty = parameter
+                .and_then(|parameter| parameter.get("schema"))
+                .cloned()
+                .map_or(Type::plain("String"), |schema| self.ty(&schema, "String"));
+            path_params.push(Param {
+                description: parameter.and_then(|parameter| text(parameter, "description")),
+                name,
+                ty,
+            });
+        }
+
+        let query_params: Vec<Value> = parameters
+            .into_iter()
+            .filter(|parameter| text(parameter, "in").as_deref() == Some("query"))
+            .collect();
+        let query = (!query_params.is_empty()).then(|| {
+            let name = format!("{pascal}Query");
+            self.generate_query(&name, &query_params);
+            name
+        });
+
+        let body = operation.get("requestBody").and_then(|body| {
+            let schema = self.json_content(body)?.clone();
+            Some(self.ty(&schema, &format!("{pascal}Body")))
+        });
+        if body.is_none() && operation.get("requestBody").is_some() {
+            let _ = warning(format!(
+                "{handler} takes a body that is not json, add its extractor by hand."
+            ));
+        }
+
+        let mut responses = Vec::new();
+        if let Some(spec_responses) = operation.get("responses").and_then(Value::as_mapping) {
+            for (status, response) in spec_responses {
+                let status = match status {
+                    Value::Number(number) => number.to_string(),
+                    Value::String(status) => status.clone(),
+                    _ => continue,
+                };
+                let resolved = self.resolve(response).clone();
+                let body = self.json_content(&resolved).cloned().map(|schema| {
+                    let hint = if status.starts_with('2') {
+                        format!("{pascal}Response")
+                    } else {
+                        format!("{pascal}{}", type_name(&format!("error_{status}")))
+                    };
+                    self.ty(&schema, &hint)
+                });
+                responses.push(Response {
+                    description: text(&resolved, "description").unwrap_or_default(),
+                    status,
+                    body,
+                });
+            }
+        }
+
+        Operation {
+            method,
+            module: module.to_string(),
+            route: route.to_string(),
+            path: path.to_string(),
+            handler,
+            operation_id,
+            summary: text(operation, "summary"),
+            path_params,
+            query,
+            body,
+            responses,
+        }
+    }
+
+    fn generate_query(&mut self, name: &str, parameters: &[Value]) {
+        if !self.names.insert(name.to_string()) {
+            return;
+        }
+        let fields: Vec<TokenStream> = parameters
+            .iter()
+            .filter_map(|parameter| {
+                let property = text(parameter, "name")?;
+                let field = ident(&property);
+                let schema = parameter.get("schema").cloned().unwrap_or(Value::Null);
+                let ty = self
+                    .ty(&schema, &format!("{name}{}", type_name(&property)))
+                    .tokens();
+                let rename = (field.to_string().trim_start_matches("r#") != property).then(|| {
+                    quote! { #[serde(rename = #property)] }
+                });
+                let doc = doc(parameter);
+                let required = parameter.get("required").and_then(Value::as_bool) == Some(true);
+                Some(if required {
+                    quote! { #doc #rename pub #field: #ty, }
+                } else {
+                    quote! { #doc #rename pub #field: Option<#ty>, }
+                })
+            })
+            .collect();
+
+        let ident = Ident::new(name, Span::call_site());
+        self.items.push(quote! {
+            #[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+            #[into_params(parameter_in = Query)]
+            pub struct #ident {
+                #(#fields)*
+            }
+        });
+    }
+}
+
+fn is_struct(schema: &Value) -> bool {
+    schema.get("properties").is_some()
+}
+
+fn doc(value: &Value) -> TokenStream {
+    let lines = text(value, "description").unwrap_or_default();
+    let lines = lines.lines().map(|line| format!(" {line}"));
+    quote! { #(#[doc = #lines])* }
+}
+
+/// The specs recorded as `from` in `[api]`, the files apis are generated from.
+pub fn spec_sources() -> Vec<String> {
+    get_section("api")
+        .and_then(|section| {
+            section.as_table_like().map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(_, record)| record.get("from")?.as_str().map(str::to_string))
+                    .collect()
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// `krk add api --from openapi.yaml`
+pub fn add_api_from_spec(file: &str) -> io::Result<()> {
+    let mut spec = match Spec::read(file) {
+        Ok(spec) => spec,
+        Err(err) => return error(err),
+    };
+    spec.generate_components();
+    let operations = spec.operations();
+    if operations.is_empty() {
+        error(format!("{file} has no operations krk can generate."))?;
+        return Ok(());
+    }
+
+    let mut modules: BTreeMap<&str, Vec<&Operation>> = BTreeMap::new();
+    for operation in &operations {
+        modules
+            .entry(&operation.module)
+            .or_default()
+            .push(operation);
+    }
+    // Hand made apis are not overwritten by a spec
+    for module in modules.keys() {
+        let from = get_config("api", module).and_then(|record| {
+            record
+                .get("from")
+                .and_then(|from| from.as_str().map(str::to_string))
+        });
+        if recorded_apis().iter().any(|api| api == module) && from.as_deref() != Some(file) {
+            error(format!(
+                "/api/{module} exists and is not generated from {file}, rename it first."
+            ))?;
+            return Ok(());
+        }
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    let report = match scaffold(file, &spec, &modules) {
+        Ok(report) => report,
+        Err(err) => {
+            transaction.rollback()?;
+            error(format!(
+                "Failed to add {file} ({err}), every change was rolled back."
+            ))?;
+            return Ok(());
+        }
+    };
+
+    for line in report {
+        info(line)?;
+    }
+    outro(format!(
+        "Successfully generated {} operations from {file}. 🎉",
+        operations.len()
+    ))
+}
+
+fn scaffold(
+    file: &str,
+    spec: &Spec,
+    modules: &BTreeMap<&str, Vec<&Operation>>,
+) -> io::Result<Vec<String>> {
+    cargo_add(&["serde", "-F", "derive"]);
+    cargo_add(&["serde_json"]);
+    cargo_add(&["utoipa@5", "-F", "yaml"]);
+
+    create_dir_all("src/kraken/api")?;
+    let first_api = recorded_apis().is_empty();
+    generate_schemas_rs(spec)?;
+
+    let mut report = Vec::new();
+    for (module, operations) in modules {
+        report.extend(generate_module(file, module, operations, spec)?);
+        let mut record = toml_edit::InlineTable::new();
+        record.insert("from", file.into());
+        set_config("api", module, record)?;
+    }
+    generate_api_mod_rs()?;
+
+    if first_api {
+        add_module_to_mod_rs("api")?;
+        add_module_to_main_rs("api")?;
+        add_kraken_to_main_rs()?;
+        nest_module_router_in_main_rs("api", "/api")?;
+    }
+    Ok(report)
+}
+
+/// schemas.rs is generated whole every time, edits belong in the spec.
+fn generate_schemas_rs(spec: &Spec) -> io::Result<()> {
+    let items = &spec.items;
+    let code = quote! {
+        //! The types of the spec, rewritten by every `krk add api --from`.
+        #![allow(dead_code)]
+        use serde::{Deserialize, Serialize};
+        use utoipa::{IntoParams, ToSchema};
+
+        #(#items)*
+    };
+    write_rust_file(SCHEMAS_RS, unparse(code))
+}
+
+/// The success response a stub returns: its status and body.
+fn success(operation: &Operation) -> (String, Option<&Type>) {
+    operation
+        .responses
+        .iter()
+        .find(|response| response.status.starts_with('2'))
+        .or_else(|| {
+            operation
+                .responses
+                .iter()
+                .find(|response| response.status == "default")
+        })
+        .map_or(("200".to_string(), None), |response| {
+            (response.status.clone(), response.body.as_ref())
+        })
+}
+
+/// None for 200, `default` and `2XX`, a plain `Json` says those.
+fn status_code(status: &str) -> Option<TokenStream> {
+    match status {
+        "201" => Some(quote! { StatusCode::CREATED }),
+        "202" => Some(quote! { StatusCode::ACCEPTED }),
+        "204" => Some(quote! { StatusCode::NO_CONTENT }),
+        _ => match status.parse::<u16>() {
+            Ok(code) if code != 200 => Some(quote! { StatusCode::from_u16(#code).unwrap() }),
+            _ => None,
+        },
+    }
+}
+
+/// One extractor of a handler: what the stub binds and the type it extracts.
+struct Input {
+    /// `Path`, `Query` or `Json`, matched against an existing handler.
+    extractor: &'static str,
+    pattern: String,
+    ty: String,
+    arity: usize,
+}
+
+fn inputs(operation: &Operation) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    match operation.path_params.as_slice() {
+        [] => {}
+        [param] => inputs.push(Input {
+            extractor: "Path",
+            pattern: format!("Path(_{})", ident(&param.name)),
+            ty: format!("Path<{}>", param.ty.rust),
+            arity: 1,
+        }),
+        params => {
+            let names: Vec<String> = params
+                .iter()
+                .map(|param| format!("_{}", ident(&param.name)))
+                .collect();
+            let types: Vec<&str> = params.iter().map(|param| param.ty.rust.as_str()).collect();
+            inputs.push(Input {
+                extractor: "Path",
+                pattern: format!("Path(({}))", names.join(", ")),
+                ty: format!("Path<({})>", types.join(", ")),
+                arity: params.len(),
+            });
+        }
+    }
+    if let Some(query) = &operation.query {
+        inputs.push(Input {
+            extractor: "Query",
+            pattern: "Query(_query)".to_string(),
+            ty: format!("Query<{query}>"),
+            arity: 1,
+        });
+    }
+    // The body extractor has to come last
+    if let Some(body) = &operation.body {
+        inputs.push(Input {
+            extractor: "Json",
+            pattern: "Json(_payload)".to_string(),
+            ty: format!("Json<{}>", body.rust),
+            arity: 1,
+        });
+    }
+    inputs
+}
+
+/// The extractors [`inputs`] generates, the spec decides about these.
+const SPEC_EXTRACTORS: [&str; 3] = ["Path", "Query", "Json"];
+
+/// An argument of an existing handler.
+struct ExistingInput<'a> {
+    /// The last segment of its type, e.g. `State`, empty for other types.
+    extractor: String,
+    pattern: &'a str,
+    /// The whole `pattern: Type` as written.
+    text: &'a str,
+    arity: usize,
+}
+
+/// The arguments of an existing handler, in order.
+fn existing_inputs<'a>(source: &'a str, function: &ItemFn) -> Vec<ExistingInput<'a>> {
+    let mut inputs = Vec::new();
+    for input in &function.sig.inputs {
+        let FnArg::Typed(typed) = input else {
+            continue;
+        };
+        let extractor = match typed.ty.as_ref() {
+            syn::Type::Path(ty) => ty
+                .path
+                .segments
+                .last()
+                .map(|segment| segment.ident.to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let arity = match typed.pat.as_ref() {
+            Pat::TupleStruct(pattern) => match pattern.elems.first() {
+                Some(Pat::Tuple(tuple)) => tuple.elems.len(),
+                _ => 1,
+            },
+            _ => 1,
+        };
+        inputs.push(ExistingInput {
+            extractor,
+            pattern: source_text(source, &typed.pat),
+            text: source_text(source, typed),
+            arity,
+        });
+    }
+    inputs
+}
+
+fn path_attribute(operation: &Operation) -> String {
+    let mut lines = vec![
+        operation.method.name().to_string(),
+        format!("path = {:?}", operation.path),
+        format!("operation_id = {:?}", operation.operation_id),
+        format!("tag = {:?}", operation.module),
+    ];
+    let mut params: Vec<String> = operation
+        .path_params
+        .iter()
+        .map(|param| match &param.description {
+            Some(description) => format!(
+                "({:?} = {}, Path, description = {description:?})",
+                param.name, param.ty.schema
+            ),
+            None => format!("({:?} = {}, Path)", param.name, param.ty.schema),
+        })
+        .collect();
+    if let Some(query) = &operation.query {
+        params.push(query.clone());
+    }
+    if !params.is_empty() {
+        lines.push(format!("params({})", params.join(", ")));
+    }
+    if let Some(body) = &operation.body {
+        lines.push(format!("request_body = {}", body.schema));
+    }
+    let statuses: Vec<String> = operation
+        .responses
+        .iter()
+        .map(|response| {
+            let status = match response.status.parse::<u16>() {
+                Ok(code) => code.to_string(),
+                Err(_) => format!("{:?}", response.status),
+            };
+            let mut parts = vec![
+                format!("status = {status}"),
+                format!("description = {:?}", response.description),
+            ];
+            if let Some(body) = &response.body {
+                parts.push(format!("body = {}", body.schema));
+            }
+            format!("({})", parts.join(", "))
+        })
+        .collect();
+    if !statuses.is_empty() {
+        let statuses: Vec<&str> = statuses.iter().map(String::as_str).collect();
+        lines.push(responses(&statuses));
+    }
+    format!("#[utoipa::path(\n    {}\n)]\n", lines.join(",\n    "))
+}
+
+/// A handler for `operation`. An existing one keeps its body, its other
+/// attributes, the patterns of extractors that still fit and the arguments
+/// the spec knows nothing about, e.g. `State`.
+fn handler(operation: &Operation, source: &str, existing: Option<&ItemFn>) -> String {
+    let existing_inputs = existing
+        .map(|function| existing_inputs(source, function))
+        .unwrap_or_default();
+    let mut spec_arguments: Vec<(String, String)> = inputs(operation)
+        .into_iter()
+        .map(|input| {
+            let pattern = match existing_inputs
+                .iter()
+                .find(|existing| existing.extractor == input.extractor)
+            {
+                Some(existing) if existing.arity == input.arity => existing.pattern.to_string(),
+                _ => input.pattern,
+            };
+            (
+                input.extractor.to_string(),
+                format!("{pattern}: {}", input.ty),
+            )
+        })
+        .collect();
+
+    // The spec's arguments take the place of the first one it owned
+    let mut arguments = Vec::new();
+    for input in &existing_inputs {
+        if SPEC_EXTRACTORS.contains(&input.extractor.as_str()) {
+            arguments.append(&mut spec_arguments);
+        } else {
+            arguments.push((input.extractor.clone(), input.text.to_string()));
+        }
+    }
+    arguments.append(&mut spec_arguments);
+    // The body extractor has to come last
+    arguments.sort_by_key(|(extractor, _)| extractor == "Json");
+    let arguments: Vec<String> = arguments.into_iter().map(|(_, text)| text).collect();
+
+    let (status, body) = success(operation);
+    let (output, stub) = match (status_code(&status), body) {
+        (None, Some(body)) => (
+            format!("Json<{}>", body.rust),
+            quote! { Json(Default::default()) },
+        ),
+        (Some(code), Some(body)) => (
+            format!("(StatusCode, Json<{}>)", body.rust),
+            quote! { (#code, Json(Default::default())) },
+        ),
+        (code, None) => (
+            "StatusCode".to_string(),
+            code.unwrap_or(quote! { StatusCode::OK }),
+        ),
+    };
+
+    let mut text = String::new();
+    match existing {
+        Some(function) => {
+            for attr in &function.attrs {
+                if !is_utoipa_path(attr) {
+                    text += source_text(source, attr);
+                    text += "\n";
+                }
+            }
+        }
+        None => {
+            if let Some(summary) = &operation.summary {
+                text += &format!("/// {summary}\n");
+            }
+        }
+    }
+    text += &path_attribute(operation);
+    text += &format!(
+        "pub async fn {}({}) -> {output} ",
+        operation.handler,
+        arguments.join(", ")
+    );
+    match existing {
+        Some(function) => text += source_text(source, &function.block),
+        None => text += &format!("{{ {stub} }}"),
+    }
+    text + "\n\n"
+}
+
+/// The parts of a handler the spec decides: its route attribute and signature.
+fn signature(function: &ItemFn) -> String {
+    let attrs = function.attrs.iter().filter(|attr| is_utoipa_path(attr));
+    // Compared input by input, rustfmt may leave a trailing comma
+    let inputs = function.sig.inputs.iter();
+    let output = &function.sig.output;
+    quote! { #(#attrs)* #(#inputs)|* #output }.to_string()
+}
+
+fn is_utoipa_path(attr: &syn::Attribute) -> bool {
+    let segments: Vec<String> = attr
+        .path()
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments == ["utoipa", "path"]
+}
+
+/// Every import [`module_head`] can emit.
+const GENERATED_IMPORTS: [&str; 9] = [
+    "use axum::extract::{Path, Query};",
+    "use axum::extract::Path;",
+    "use axum::extract::Query;",
+    "use axum::http::StatusCode;",
+    "use axum::routing;",
+    "use axum::{Json, Router};",
+    "use axum::Router;",
+    "use utoipa::OpenApi;",
+    "use super::schemas::*;",
+];
+
+/// krk's imports, the router and the ApiDoc are regenerated, imports the user
+/// added stay.
+fn is_generated(item: &Item) -> bool {
+    match item {
+        Item::Use(item) => {
+            let import = quote! { #item }.to_string();
+            GENERATED_IMPORTS.iter().any(|generated| {
+                syn::parse_str::<ItemUse>(generated)
+                    .is_ok_and(|generated| quote! { #generated }.to_string() == import)
+            })
+        }
+        Item::Fn(function) => function.sig.ident == "router",
+        Item::Struct(item) => item.ident == "ApiDoc",
+        _ => false,
+    }
+}
+
+/// Writes src/kraken/api/<module>.rs, merged into the file that is there.
+fn generate_module(
+    file: &str,
+    module: &str,
+    operations: &[&Operation],
+    spec: &Spec,
+) -> io::Result<Vec<String>> {
+    let path = format!("src/kraken/api/{module}.rs");
+    let source = fs::read_to_string(&path).unwrap_or_default();
+    let (source, report) = merge_module(file, module, operations, spec, &source)
+        .map_err(|err| invalid(format!("Failed to parse {path}: {err}")))?;
+    write_rust_file(&path, tracing::instrument(source))?;
+    Ok(report)
+}
+
+/// The module for `operations` merged into `source`, the module as it is now,
+/// and what changed.
+fn merge_module(
+    file: &str,
+    module: &str,
+    operations: &[&Operation],
+    spec: &Spec,
+    source: &str,
+) -> syn::Result<(String, Vec<String>)> {
+    let parsed = if source.is_empty() {
+        syn::File {
+            shebang: None,
+            attrs: Vec::new(),
+            items: Vec::new(),
+        }
+    } else {
+        syn::parse_file(source)?
+    };
+    let functions: HashMap<String, &ItemFn> = parsed
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(function) => Some((function.sig.ident.to_string(), function)),
+            _ => None,
+        })
+        .collect();
+
+    let mut report = Vec::new();
+    let mut handlers = String::new();
+    for operation in operations {
+        let existing = functions.get(&operation.handler).copied();
+        let text = handler(operation, source, existing);
+        let method = operation.method.name().to_uppercase();
+        match existing {
+            None => report.push(format!("added {method} {}", operation.path)),
+            Some(function) => {
+                let new: ItemFn = syn::parse_str(&text).map_err(|err| {
+                    syn::Error::new(err.span(), format!("{}: {err}", operation.handler))
+                })?;
+                if signature(function) != signature(&new) {
+                    report.push(format!("updated {method} {}", operation.path));
+                }
+            }
+        }
+        handlers += &text;
+    }
+
+    // Whatever else is in the file stays, after the handlers
+    let routed: HashSet<&str> = operations.iter().map(|op| op.handler.as_str()).collect();
+    let mut kept = String::new();
+    for item in &parsed.items {
+        if is_generated(item) {
+            continue;
+        }
+        if let Item::Fn(function) = item {
+            let name = function.sig.ident.to_string();
+            if routed.contains(name.as_str()) {
+                continue;
+            }
+            if function.attrs.iter().any(is_utoipa_path) {
+                let _ = warning(format!(
+                    "{module}::{name} is not in {file} anymore, it is kept but not routed."
+                ));
+            }
+        }
+        kept += source_text(source, item);
+        kept += "\n\n";
+    }
+
+    let head = module_head(file, operations, spec);
+    Ok((format!("{}\n{handlers}{kept}", unparse(head)), report))
+}
+
+fn module_head(file: &str, operations: &[&Operation], spec: &Spec) -> TokenStream {
+    let mut routes: Vec<(&str, Vec<TokenStream>)> = Vec::new();
+    for operation in operations {
+        let method = Ident::new(operation.method.name(), Span::call_site());
+        let handler = Ident::new(&operation.handler, Span::call_site());
+        let call = quote! { #method(#handler) };
+        match routes
+            .iter_mut()
+            .find(|(route, _)| *route == operation.route)
+        {
+            Some((_, calls)) => calls.push(call),
+            None => routes.push((&operation.route, vec![call])),
+        }
+    }
+    let routes = routes.iter().map(|(route, calls)| {
+        let mut calls = calls.iter();
+        let first = calls.next();
+        quote! { .route(#route, routing::#first #(.#calls)*) }
+    });
+    let paths = operations
+        .iter()
+        .map(|operation| Ident::new(&operation.handler, Span::call_site()));
+
+    let any_path = operations.iter().any(|op| !op.path_params.is_empty());
+    let any_query = operations.iter().any(|op| op.query.is_some());
+    let extract = match (any_path, any_query) {
+        (true, true) => quote! { use axum::extract::{Path, Query}; },
+        (true, false) => quote! { use axum::extract::Path; },
+        (false, true) => quote! { use axum::extract::Query; },
+        (false, false) => quote! {},
+    };
+    let outputs: Vec<(String, Option<&Type>)> = operations.iter().map(|op| success(op)).collect();
+    let uses_status = outputs
+        .iter()
+        .any(|(status, body)| body.is_none() || status_code(status).is_some());
+    let status = uses_status.then(|| quote! { use axum::http::StatusCode; });
+    let uses_json = outputs.iter().any(|(_, body)| body.is_some())
+        || operations.iter().any(|op| op.body.is_some());
+    let json = if uses_json {
+        quote! { use axum::{Json, Router}; }
+    } else {
+        quote! { use axum::Router; }
+    };
+    let uses_schemas = operations.iter().any(|op| {
+        op.query.is_some()
+            || op.body.as_ref().is_some_and(|ty| ty.generated)
+            || op.path_params.iter().any(|param| param.ty.generated)
+            || op
+                .responses
+                .iter()
+                .any(|response| response.body.as_ref().is_some_and(|ty| ty.generated))
+    });
+    let schemas =
+        (uses_schemas && !spec.items.is_empty()).then(|| quote! { use super::schemas::*; });
+    let doc = format!(" Generated from {file} by `krk add api --from`.");
+
+    quote! {
+        #![doc = #doc]
+        //! Re-running it rewrites the routes and handler signatures, the handler
+        //! bodies and everything else in this file stay.
+        #extract
+        #status
+        use axum::routing;
+        #json
+        use utoipa::OpenApi;
+        #schemas
+
+        pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+            Router::new()
+                #(#routes)*
+        }
+
+        /// The routes `krk openapi` documents.
+        #[derive(OpenApi)]
+        #[openapi(paths(#(#paths),*))]
+        pub struct ApiDoc;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PETS: &str = r##"
+openapi: 3.0.0
+paths:
+  /pets/{petId}:
+    get:
+      operationId: showPet
+      parameters:
+        - name: petId
+          in: path
+          required: true
+          schema:
+            type: integer
+      responses:
+        "200":
+          description: A pet
+          content:
+            application/json:
+              schema:
+                $ref: "#/components/schemas/Pet"
+components:
+  schemas:
+    Pet:
+      type: object
+      properties:
+        name:
+          type: string
+"##;
+
+    /// Runs the generator for `spec` over `source` like `krk add api --from`.
+    fn merge(spec: &str, source: &str) -> (String, Vec<String>) {
+        let mut spec = Spec::parse("openapi.yaml", spec).unwrap();
+        spec.generate_components();
+        let operations = spec.operations();
+        let operations: Vec<&Operation> = operations.iter().collect();
+        merge_module("openapi.yaml", "pets", &operations, &spec, source).unwrap()
+    }
+
+    /// Makes the edits a user would: a body, an extractor, an attribute and an
+    /// import.
+    fn edit(source: &str) -> String {
+        assert!(source.contains("{ Json (Default :: default ()) }"));
+        let source = source
+            .replace(
+                "{ Json (Default :: default ()) }",
+                "{ Json(Pet { name: Some(format!(\"pet {_pet_id}\")) }) }",
+            )
+            .replace(
+                "pub async fn show_pet(",
+                "#[allow(unused_variables)]\npub async fn show_pet(State(pool): State<PgPool>, ",
+            );
+        format!("{source}\nuse sqlx::PgPool;\n")
+    }
+
+    #[test]
+    fn regenerating_keeps_handler_edits() {
+        let (first, report) = merge(PETS, "");
+        assert_eq!(report, ["added GET /api/pets/{petId}"]);
+        let edited = edit(&first);
+
+        let (second, report) = merge(PETS, &edited);
+        assert!(report.is_empty(), "{report:?}");
+        for kept in [
+            "use sqlx::PgPool;",
+            "#[allow(unused_variables)]",
+            "State(pool): State<PgPool>, Path(_pet_id): Path<i64>",
+            "{ Json(Pet { name: Some(format!(\"pet {_pet_id}\")) }) }",
+        ] {
+            assert!(second.contains(kept), "lost `{kept}`:\n{second}");
+        }
+        // Nothing is duplicated by a second run
+        assert_eq!(second.matches("pub async fn show_pet").count(), 1);
+        assert_eq!(second.matches("use sqlx::PgPool;").count(), 1);
+        assert_eq!(merge(PETS, &second).0, second);
+    }
+
+    #[test]
+    fn regenerating_updates_the_signature_around_edits() {
+        let (first, _) = merge(PETS, "");
+        let edited = edit(&first);
+
+        // The id becomes a string and a query parameter is added
+        let changed = PETS
+            .replace("type: integer", "type: string")
+            .replace(
+                "          required: true\n          schema:\n            type: string\n",
+                "          required: true\n          schema:\n            type: string\n        - name: fields\n          in: query\n          schema:\n            type: string\n",
+            );
+        let (second, report) = merge(&changed, &edited);
+        assert_eq!(report, ["updated GET /api/pets/{petId}"]);
+        assert!(
+            second.contains(
+                "State(pool): State<PgPool>, Path(_pet_id): Path<String>, Query(_query): Query<ShowPetQuery>"
+            ),
+            "{second}"
+        );
+        assert!(second.contains("{ Json(Pet { name: Some(format!(\"pet {_pet_id}\")) }) }"));
+        assert!(second.contains("use sqlx::PgPool;"));
+    }
+}
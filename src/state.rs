@@ -0,0 +1,98 @@
+// state.rs
+//! The `AppState` in `src/kraken/state.rs`. Features register the fields
+//! they share with handlers here, and main.rs builds the state from locals of
+//! the same name.
+use crate::add::{add_kraken_to_main_rs, add_module_to_mod_rs, unparse, write_rust_file};
+use crate::ast::{self, offset};
+use quote::quote;
+use std::fs;
+use std::io;
+use std::path::Path;
+use syn::{Fields, Item};
+
+const STATE_RS: &str = "src/kraken/state.rs";
+
+/// A field of `AppState`.
+pub struct StateField<'a> {
+    pub name: &'a str,
+    /// A full path, e.g. `sqlx::PgPool`, so state.rs needs no imports.
+    pub ty: &'a str,
+    /// Defines a local named like the field in main.rs, e.g.
+    /// `let http = reqwest::Client::new();`. None for a main parameter.
+    pub init: Option<&'a str>,
+    pub doc: &'a str,
+}
+
+/// Adds `field` to `AppState`, builds it in main.rs and hands the state to
+/// the router with `Router::with_state`. The state is created first when the
+/// project has none.
+pub fn add_state_field(field: &StateField) -> io::Result<()> {
+    if !Path::new(STATE_RS).exists() {
+        generate_state_rs()?;
+        add_module_to_mod_rs("state")?;
+        add_kraken_to_main_rs()?;
+    }
+    add_field_to_state_rs(field)?;
+    ast::add_state_field(field.name, field.init)
+}
+
+fn generate_state_rs() -> io::Result<()> {
+    write_rust_file(
+        STATE_RS,
+        unparse(quote! {
+            //! Features add their fields with kraken, handlers take
+            //! `State(state): State<AppState>`.
+
+            /// Shared with every handler through `Router::with_state`.
+            #[derive(Clone)]
+            pub struct AppState {}
+        }),
+    )
+}
+
+/// Splices the field into the struct, keeping whatever else state.rs has.
+fn add_field_to_state_rs(field: &StateField) -> io::Result<()> {
+    let mut source = fs::read_to_string(STATE_RS)?;
+    let file = syn::parse_file(&source).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to parse {STATE_RS}: {err}"),
+        )
+    })?;
+    let fields = file.items.iter().find_map(|item| match item {
+        Item::Struct(state) if state.ident == "AppState" => match &state.fields {
+            Fields::Named(fields) => Some(fields),
+            _ => None,
+        },
+        _ => None,
+    });
+    let Some(fields) = fields else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No `pub struct AppState {{ .. }}` in {STATE_RS}"),
+        ));
+    };
+    if fields.named.iter().any(|existing| {
+        existing
+            .ident
+            .as_ref()
+            .is_some_and(|ident| ident == field.name)
+    }) {
+        return Ok(());
+    }
+
+    let at = offset(&source, fields.brace_token.span.close().start());
+    let separator = if fields.named.is_empty() || fields.named.trailing_punct() {
+        ""
+    } else {
+        ","
+    };
+    source.insert_str(
+        at,
+        &format!(
+            "{separator}/// {}\npub {}: {},\n",
+            field.doc, field.name, field.ty
+        ),
+    );
+    write_rust_file(STATE_RS, source)
+}
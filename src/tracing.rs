@@ -0,0 +1,297 @@
+// tracing.rs
+//! `krk add tracing`: logs through `tracing` in `src/kraken/logging.rs`, a
+//! span per request with its id, method, path, status and latency, and
+//! `#[instrument]` on every handler a generated router routes to.
+use crate::add::{
+    add_feature, add_kraken_to_main_rs, add_module_to_mod_rs, cargo_add, check_feature, get_config,
+    unparse, write_rust_file,
+};
+use crate::ast::{self, offset};
+use crate::error_pages;
+use crate::middleware::{self, Middleware};
+use crate::secrets::SECRET_STORE_PARAM;
+use crate::transaction::{Transaction, PROJECT_FILES};
+use cliclack::log::{error, info};
+use cliclack::outro;
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use syn::spanned::Spanned;
+use syn::{Item, Visibility};
+
+const LOGGING_RS: &str = "src/kraken/logging.rs";
+/// What every routed handler gets, its arguments are rarely `Debug`.
+const INSTRUMENT: &str = "#[tracing::instrument(skip_all)]";
+
+/// Every file is restored if a step fails.
+pub fn add_tracing() -> io::Result<()> {
+    if check_feature("tracing").is_err() {
+        info("Failed to add tracing!!!")?;
+        return Ok(());
+    }
+
+    let transaction = Transaction::begin(&PROJECT_FILES)?;
+    if let Err(err) = scaffold() {
+        transaction.rollback()?;
+        error(format!(
+            "Failed to add tracing ({err}), every change was rolled back."
+        ))?;
+        return Ok(());
+    }
+
+    info("Logs are JSON in release builds and pretty in debug ones, `krk secrets set LOG_FORMAT json --optional` picks one.")?;
+    info("RUST_LOG filters them, e.g. RUST_LOG=debug,sqlx=warn.")?;
+    outro("Successfully added tracing. 🎉")
+}
+
+fn scaffold() -> io::Result<()> {
+    cargo_add(&["tracing"]);
+    cargo_add(&["tracing-subscriber", "-F", "env-filter,json"]);
+    // shuttle sets up logs of its own unless told not to
+    if has_dependency("shuttle-runtime")? {
+        cargo_add(&["shuttle-runtime", "--no-default-features"]);
+    }
+
+    add_feature("tracing")?;
+    generate_logging_rs()?;
+    add_module_to_mod_rs("logging")?;
+    add_kraken_to_main_rs()?;
+    ast::add_main_param(SECRET_STORE_PARAM)?;
+    ast::prepend_main_stmts(
+        "kraken::logging::init(secrets.get(\"LOG_FORMAT\"));",
+        "kraken :: logging :: init",
+    )?;
+    // The request span lives in the trace layer and takes the id from the
+    // request-id one
+    middleware::enable(&[Middleware::RequestId, Middleware::Trace])?;
+    error_pages::refresh()?;
+    instrument_existing("src/kraken")
+}
+
+/// Whether Cargo.toml depends on `name`.
+pub fn has_dependency(name: &str) -> io::Result<bool> {
+    let manifest = fs::read_to_string("Cargo.toml")?
+        .parse::<toml_edit::Document>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Cargo.toml: {err}")))?;
+    Ok(manifest
+        .get("dependencies")
+        .and_then(|dependencies| dependencies.get(name))
+        .is_some())
+}
+
+/// Handlers generated before tracing was added.
+fn instrument_existing(dir: &str) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            instrument_existing(&path.to_string_lossy())?;
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            let source = fs::read_to_string(&path)?;
+            let instrumented = instrument_handlers(&source);
+            if instrumented != source {
+                write_rust_file(&path.to_string_lossy(), instrumented)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether generated code logs through `tracing`.
+pub fn traced() -> bool {
+    get_config("features", "tracing").is_some()
+}
+
+/// Gives the handlers of a generated router a span of their own once
+/// `krk add tracing` ran.
+pub fn instrument(source: String) -> String {
+    if traced() {
+        instrument_handlers(&source)
+    } else {
+        source
+    }
+}
+
+/// Puts [`INSTRUMENT`] on the async functions the `router()` of `source`
+/// routes to, unless they are instrumented already. Returns `source` as is
+/// when it has no router.
+pub fn instrument_handlers(source: &str) -> String {
+    let Ok(file) = syn::parse_file(source) else {
+        return source.to_string();
+    };
+    let Some(router) = file.items.iter().find_map(|item| match item {
+        Item::Fn(function) if function.sig.ident == "router" => Some(function),
+        _ => None,
+    }) else {
+        return source.to_string();
+    };
+    let mut routed = HashSet::new();
+    idents(router.block.to_token_stream(), &mut routed);
+
+    let mut at: Vec<usize> = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(handler)
+                if handler.sig.asyncness.is_some()
+                    && routed.contains(&handler.sig.ident.to_string())
+                    && !handler.attrs.iter().any(|attr| {
+                        attr.path()
+                            .segments
+                            .last()
+                            .is_some_and(|segment| segment.ident == "instrument")
+                    }) =>
+            {
+                let start = match &handler.vis {
+                    Visibility::Inherited => handler.sig.span(),
+                    vis => vis.span(),
+                };
+                Some(offset(source, start.start()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut instrumented = source.to_string();
+    at.sort_unstable();
+    for at in at.into_iter().rev() {
+        instrumented.insert_str(at, &format!("{INSTRUMENT}\n"));
+    }
+    instrumented
+}
+
+fn idents(tokens: TokenStream, found: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                found.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => idents(group.stream(), found),
+            _ => {}
+        }
+    }
+}
+
+fn generate_logging_rs() -> io::Result<()> {
+    write_rust_file(
+        LOGGING_RS,
+        unparse(quote! {
+            //! Logs go through `tracing`: JSON in release builds and pretty in debug
+            //! ones, unless `LOG_FORMAT` in Secrets.toml is `json` or `pretty`.
+            //! RUST_LOG filters them, `info` by default.
+            use axum::extract::Request;
+            use axum::http::Response;
+            use std::time::Duration;
+            use tracing::field::Empty;
+            use tracing::Span;
+            use tracing_subscriber::EnvFilter;
+
+            /// Called first thing in main. A logger set up before, e.g. by a test,
+            /// is kept.
+            pub fn init(log_format: Option<String>) {
+                let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+                let json = match log_format.as_deref() {
+                    Some("json") => true,
+                    Some("pretty") => false,
+                    _ => !cfg!(debug_assertions),
+                };
+                let logs = tracing_subscriber::fmt().with_env_filter(filter);
+                let installed = if json {
+                    logs.json().try_init()
+                } else {
+                    logs.pretty().try_init()
+                };
+                if let Err(err) = installed {
+                    eprintln!("Keeping the logger that was set up before: {err}");
+                }
+            }
+
+            /// The span every log line of a request carries. The id is the one the
+            /// request-id middleware set.
+            pub fn request_span(request: &Request) -> Span {
+                let request_id = request
+                    .headers()
+                    .get("x-request-id")
+                    .and_then(|id| id.to_str().ok())
+                    .unwrap_or_default();
+                tracing::info_span!(
+                    "request",
+                    request_id,
+                    method = request.method().as_str(),
+                    path = request.uri().path(),
+                    status = Empty,
+                    latency_ms = Empty,
+                )
+            }
+
+            /// Records the outcome on the span and logs it, server errors as errors.
+            pub fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+                let status = response.status();
+                span.record("status", status.as_u16());
+                span.record("latency_ms", u64::try_from(latency.as_millis()).unwrap_or(u64::MAX));
+                if status.is_server_error() {
+                    tracing::error!("failed");
+                } else {
+                    tracing::info!("finished");
+                }
+            }
+
+            #[cfg(test)]
+            mod tests {
+                use super::*;
+                use axum::body::Body;
+                use std::io::{self, Write};
+                use std::sync::{Arc, Mutex};
+                use tracing_subscriber::fmt::MakeWriter;
+
+                /// Collects what is logged.
+                #[derive(Clone, Default)]
+                struct Lines(Arc<Mutex<Vec<u8>>>);
+
+                impl Write for Lines {
+                    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                        self.0.lock().unwrap().extend_from_slice(bytes);
+                        Ok(bytes.len())
+                    }
+
+                    fn flush(&mut self) -> io::Result<()> {
+                        Ok(())
+                    }
+                }
+
+                impl<'a> MakeWriter<'a> for Lines {
+                    type Writer = Lines;
+
+                    fn make_writer(&'a self) -> Self::Writer {
+                        self.clone()
+                    }
+                }
+
+                #[test]
+                fn lines_carry_the_request() {
+                    let lines = Lines::default();
+                    let subscriber = tracing_subscriber::fmt().json().with_writer(lines.clone()).finish();
+                    tracing::subscriber::with_default(subscriber, || {
+                        let request = Request::get("/posts/7")
+                            .header("x-request-id", "3f2a")
+                            .body(Body::empty())
+                            .unwrap();
+                        let span = request_span(&request);
+                        let _entered = span.enter();
+                        let response = Response::builder().status(503).body(()).unwrap();
+                        on_response(&response, Duration::from_millis(12), &span);
+                    });
+
+                    let logged = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+                    assert!(logged.contains(r#""level":"ERROR""#));
+                    assert!(logged.contains(r#""request_id":"3f2a""#));
+                    assert!(logged.contains(r#""method":"GET""#));
+                    assert!(logged.contains(r#""path":"/posts/7""#));
+                    assert!(logged.contains(r#""status":503"#));
+                    assert!(logged.contains(r#""latency_ms":12"#));
+                }
+            }
+        }),
+    )
+}
@@ -0,0 +1,87 @@
+// transaction.rs
+//! Lets a generator that touches many files undo all of them when one step
+//! fails, so a project is never left half scaffolded.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything a generator may write to, relative to the project root.
+pub const PROJECT_FILES: [&str; 12] = [
+    "src",
+    "templates",
+    "migrations",
+    "migration",
+    "assets",
+    "Cargo.toml",
+    "Cargo.lock",
+    "build.rs",
+    "diesel.toml",
+    "Secrets.toml",
+    "Secrets.dev.toml",
+    ".gitignore",
+];
+
+pub struct Transaction {
+    roots: Vec<PathBuf>,
+    files: HashMap<PathBuf, Vec<u8>>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl Transaction {
+    /// Snapshots every file below `roots`.
+    pub fn begin(roots: &[&str]) -> io::Result<Self> {
+        let mut transaction = Self {
+            roots: roots.iter().map(PathBuf::from).collect(),
+            files: HashMap::new(),
+            dirs: HashSet::new(),
+        };
+        for root in transaction.roots.clone() {
+            transaction.snapshot(&root)?;
+        }
+        Ok(transaction)
+    }
+
+    fn snapshot(&mut self, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            self.dirs.insert(path.to_path_buf());
+            for entry in fs::read_dir(path)? {
+                self.snapshot(&entry?.path())?;
+            }
+        } else if path.is_file() {
+            self.files.insert(path.to_path_buf(), fs::read(path)?);
+        }
+        Ok(())
+    }
+
+    /// Restores the snapshot: changed files get their content back, new files
+    /// and directories are removed.
+    pub fn rollback(self) -> io::Result<()> {
+        for root in &self.roots {
+            self.remove_new(root)?;
+        }
+        for (path, content) in &self.files {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::read(path).ok().as_ref() != Some(content) {
+                fs::write(path, content)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_new(&self, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            if !self.dirs.contains(path) {
+                return fs::remove_dir_all(path);
+            }
+            for entry in fs::read_dir(path)? {
+                self.remove_new(&entry?.path())?;
+            }
+        } else if path.is_file() && !self.files.contains_key(path) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}